@@ -0,0 +1,41 @@
+// Vercel serverless entry point (vercel-rust runtime, see vercel.json).
+// Serves the same Router as the standalone server. The Vercel runtime only
+// supports buffered responses, so SSE streams are collected in full before
+// being returned; use the `lambda` feature of the main binary where
+// incremental streaming is required.
+
+use axum::Router;
+use rust_proxy::{app, telemetry};
+use rust_proxy::config::ServerConfig;
+use rust_proxy::dev_client::DevApiClient;
+use tower::ServiceExt;
+use vercel_runtime::{run, Body, Error, Request, Response};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Vercel injects env vars directly; .env is only useful for `vercel dev`
+    let _ = dotenvy::dotenv();
+    telemetry::init_tracing();
+    #[cfg(feature = "sentry")]
+    let _sentry_guard = rust_proxy::error_reporting::init_sentry();
+
+    let dev_client = DevApiClient::new()?;
+    let router = app::build_router(dev_client, &ServerConfig::from_env());
+
+    run(|req: Request| handler(router.clone(), req)).await
+}
+
+async fn handler(router: Router, req: Request) -> Result<Response<Body>, Error> {
+    let response = router.oneshot(req).await?;
+    let (parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX).await?;
+    let body = if bytes.is_empty() {
+        Body::Empty
+    } else {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(text) => Body::Text(text),
+            Err(e) => Body::Binary(e.into_bytes()),
+        }
+    };
+    Ok(Response::from_parts(parts, body))
+}
@@ -0,0 +1,244 @@
+// Per-request access log. One event is emitted on the `access_log` target
+// when the response body finishes (or the client goes away), so streamed
+// responses are logged with their real duration and size. The telemetry
+// setup can route this target to its own file, away from debug tracing.
+// The body sizes in and out also go to the `http_request_bytes` and
+// `http_response_bytes` histograms, by matched route.
+
+use axum::body::{Body, BodyDataStream};
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use bytes::Bytes;
+use futures_util::stream::Stream;
+use once_cell::sync::Lazy;
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::info;
+
+use crate::request_id::X_REQUEST_ID;
+
+/// Tracing target used for access log events.
+pub const TARGET: &str = "access_log";
+
+/// Request body sizes by `route`, registered by `Metrics`.
+pub static REQUEST_BYTES: Lazy<HistogramVec> = Lazy::new(|| size_histogram("http_request_bytes", "Request body size"));
+
+/// Response body sizes by `route`, registered by `Metrics`.
+pub static RESPONSE_BYTES: Lazy<HistogramVec> = Lazy::new(|| size_histogram("http_response_bytes", "Response body size sent"));
+
+/// 256 B to 16 MiB.
+fn size_histogram(name: &str, help: &str) -> HistogramVec {
+    let buckets = exponential_buckets(256.0, 4.0, 9).expect("valid buckets");
+    HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), &["route"]).expect("valid histogram")
+}
+
+#[derive(Debug, Default)]
+struct Annotations {
+    model: Option<String>,
+    api_key_id: Option<String>,
+}
+
+/// Handle stored in the request extensions so inner layers and handlers can
+/// add details that are only known after routing (model, API key id).
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogContext(Arc<Mutex<Annotations>>);
+
+impl AccessLogContext {
+    pub fn set_model(&self, model: impl Into<String>) {
+        self.0.lock().unwrap().model = Some(model.into());
+    }
+
+    pub fn set_api_key_id(&self, api_key_id: impl Into<String>) {
+        self.0.lock().unwrap().api_key_id = Some(api_key_id.into());
+    }
+}
+
+struct AccessLogEntry {
+    method: String,
+    path: String,
+    /// The matched route, or `unmatched`.
+    route: String,
+    request_id: String,
+    status: u16,
+    context: AccessLogContext,
+    started: Instant,
+    /// Request body bytes read so far.
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: u64,
+    chunks: u64,
+    completed: bool,
+}
+
+impl Drop for AccessLogEntry {
+    fn drop(&mut self) {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        REQUEST_BYTES.with_label_values(&[&self.route]).observe(bytes_in as f64);
+        RESPONSE_BYTES.with_label_values(&[&self.route]).observe(self.bytes_out as f64);
+        let annotations = self.context.0.lock().unwrap();
+        info!(
+            target: TARGET,
+            method = %self.method,
+            path = %self.path,
+            request_id = %self.request_id,
+            api_key_id = annotations.api_key_id.as_deref().unwrap_or("-"),
+            model = annotations.model.as_deref().unwrap_or("-"),
+            status = self.status,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            bytes_in,
+            bytes_out = self.bytes_out,
+            chunks = self.chunks,
+            completed = self.completed,
+            "access"
+        );
+    }
+}
+
+/// Response body wrapper that counts frames and bytes and emits the access
+/// log entry when dropped.
+struct LoggedBody {
+    inner: BodyDataStream,
+    entry: AccessLogEntry,
+}
+
+impl Stream for LoggedBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(bytes))) => {
+                self.entry.bytes_out += bytes.len() as u64;
+                self.entry.chunks += 1;
+            }
+            Poll::Ready(None) => self.entry.completed = true,
+            _ => {}
+        }
+        poll
+    }
+}
+
+/// Request body wrapper that counts the bytes read.
+struct CountedBody {
+    inner: BodyDataStream,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Stream for CountedBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            self.bytes.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Middleware recording one access log line per request.
+pub async fn record(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let context = AccessLogContext::default();
+    let bytes_in = Arc::new(AtomicU64::new(0));
+    let (parts, body) = request.into_parts();
+    let body = CountedBody { inner: body.into_data_stream(), bytes: bytes_in.clone() };
+    let mut request = Request::from_parts(parts, Body::from_stream(body));
+    request.extensions_mut().insert(context.clone());
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let route = request.extensions().get::<MatchedPath>().map_or("unmatched", MatchedPath::as_str).to_string();
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let entry = AccessLogEntry {
+        method,
+        path,
+        route,
+        request_id,
+        status: parts.status.as_u16(),
+        context,
+        started,
+        bytes_in,
+        bytes_out: 0,
+        chunks: 0,
+        completed: false,
+    };
+    let body = LoggedBody { inner: body.into_data_stream(), entry };
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Extension, Router};
+    use futures_util::stream::StreamExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_logged_body_counts_frames_and_bytes() {
+        let body = Body::from_stream(futures_util::stream::iter(vec![
+            Ok::<_, std::io::Error>(Bytes::from_static(b"data: a\n\n")),
+            Ok(Bytes::from_static(b"data: bc\n\n")),
+        ]));
+        let mut logged = LoggedBody {
+            inner: body.into_data_stream(),
+            entry: AccessLogEntry {
+                method: "POST".to_string(),
+                path: "/v1/chat/completions".to_string(),
+                route: "/v1/chat/completions".to_string(),
+                request_id: "-".to_string(),
+                status: 200,
+                context: AccessLogContext::default(),
+                started: Instant::now(),
+                bytes_in: Arc::default(),
+                bytes_out: 0,
+                chunks: 0,
+                completed: false,
+            },
+        };
+        while logged.next().await.is_some() {}
+        assert_eq!(logged.entry.chunks, 2);
+        assert_eq!(logged.entry.bytes_out, 19);
+        assert!(logged.entry.completed);
+    }
+
+    #[tokio::test]
+    async fn test_context_is_available_to_handlers() {
+        let app = Router::new()
+            .route("/", get(|Extension(ctx): Extension<AccessLogContext>| async move {
+                ctx.set_model("gpt-4o");
+                "ok"
+            }))
+            .layer(middleware::from_fn(record));
+        let request = http::Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_body_sizes_by_route() {
+        let app = Router::new()
+            .route("/sized/:id", axum::routing::post(|body: Bytes| async move { body.repeat(2) }))
+            .layer(middleware::from_fn(record));
+        let request = http::Request::builder().method("POST").uri("/sized/1").body(Body::from("12345")).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let (request_bytes, response_bytes) =
+            (REQUEST_BYTES.with_label_values(&["/sized/:id"]), RESPONSE_BYTES.with_label_values(&["/sized/:id"]));
+        assert_eq!((request_bytes.get_sample_count(), request_bytes.get_sample_sum()), (1, 5.0));
+        assert_eq!((response_bytes.get_sample_count(), response_bytes.get_sample_sum()), (1, 10.0));
+    }
+}
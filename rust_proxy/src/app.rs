@@ -0,0 +1,725 @@
+use axum::{middleware, routing::{delete, get, post}, Extension, Router, Json};
+use axum::extract::{DefaultBodyLimit, FromRef, State};
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event as SseEvent, Sse};
+use futures_util::future::{AbortHandle, FutureExt};
+use futures_util::stream::{Abortable, StreamExt};
+use http::StatusCode;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{PropagateRequestIdLayer, RequestId, SetRequestIdLayer};
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn, error, debug, instrument};
+
+use crate::{access_log, assistants, auth, dashboard, embeddings, error, health, inflight, log_sampling, oidc, openapi, replay, request_id, scripting, service, signer, sse_processor, streams, threads, tokenizer, usage};
+use crate::access_log::AccessLogContext;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::auth::{AdminAuth, ApiKeyId, ApiKeys, ModelAccess};
+use crate::canary::{self, Variant};
+use crate::config::{env_or, ServerConfig};
+use crate::concurrency::{self, StreamLimiter};
+use crate::dev_client::{DevApiClient, DevRequestOptions, UpstreamQueueTimeout, UpstreamStatusError};
+use crate::dev_errors::{DevError, DevErrorKind};
+use crate::error_reporting::{ErrorReporter, ReportContext};
+use crate::failover::{CircuitOpen, Upstreams};
+use crate::metrics::{self, Metrics, Outcome};
+use crate::sse_processor::{process_dev_bytes_stream_with_retry, ChunkError, DevByteStream, NoLogprobs, StreamErrorMode, StreamRetry};
+use crate::models::{DevvOptions, OpenAiChatRequest};
+use crate::language::LanguageSelector;
+use crate::model_modes::ModelModes;
+use crate::model_catalog::{self, ModelCatalog};
+use crate::request_policy::RequestPolicy;
+use crate::system_prompt::SystemPrompts;
+use crate::prompt_template::PromptTemplates;
+use crate::moderation::Moderation;
+use crate::footers::Footers;
+use crate::transform::{Builtins, ChoiceContext, TransformChain};
+use crate::scripting::Scripts;
+use crate::citations::CitationMode;
+use crate::webhooks::{CompletionInfo, Webhooks};
+use crate::event_bus::EventBus;
+use crate::billing::{Billing, BillingRecord};
+use crate::history::{self, History};
+use crate::embeddings::Embeddings;
+use crate::assistants::AssistantsStore;
+use crate::capture::{Capture, CaptureSink};
+use crate::slow_requests::SlowRequests;
+use crate::inflight::InFlight;
+use crate::key_store::{self, KeyStore};
+use crate::files::{self, FileStore};
+use crate::replay::{EventPayload, ReplayStore};
+use crate::state_store::StateStore;
+use crate::streams::{StreamMeta, StreamRegistry, StreamTap};
+use crate::usage::UsageTracker;
+
+/// State shared by the API handlers; each field can be extracted on its own
+/// with `State<T>`.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub upstreams: Arc<Upstreams>,
+    pub audit: Arc<AuditLog>,
+    pub reporter: Arc<ErrorReporter>,
+    pub metrics: Arc<Metrics>,
+    pub usage: Arc<UsageTracker>,
+    pub streams: StreamRegistry,
+    pub replay: ReplayStore,
+    pub policy: Arc<RequestPolicy>,
+    pub files: FileStore,
+    pub modes: Arc<ModelModes>,
+    pub languages: Arc<LanguageSelector>,
+    pub system_prompts: Arc<SystemPrompts>,
+    pub templates: Arc<PromptTemplates>,
+    pub moderation: Arc<Moderation>,
+    pub transforms: TransformChain,
+    pub scripts: Scripts,
+    pub citations: CitationMode,
+    pub footers: Footers,
+    pub webhooks: Arc<Webhooks>,
+    pub event_bus: EventBus,
+    pub billing: Billing,
+    pub history: History,
+    pub embeddings: Arc<Embeddings>,
+    pub assistants: AssistantsStore,
+    pub capture: Capture,
+    pub slow_requests: SlowRequests,
+    pub stream_errors: StreamErrorMode,
+    pub inflight: InFlight,
+    pub key_store: KeyStore,
+    pub model_catalog: Arc<ModelCatalog>,
+}
+
+const CHAT_COMPLETIONS_ROUTE: &str = "/v1/chat/completions";
+
+/// Builds the complete application router with all middleware. Shared by the
+/// standalone server, the Lambda adapter and the Vercel handler.
+pub fn build_router(dev_client: DevApiClient, server_config: &ServerConfig) -> Router {
+    let stream_limiter = StreamLimiter::new(server_config.max_concurrent_streams)
+        .with_queue(server_config.stream_queue_size, server_config.stream_queue_timeout)
+        .with_batch_keys(server_config.batch_api_key_ids.clone())
+        .with_key_weights(server_config.stream_key_weights.clone());
+    let modes = ModelModes::from_env();
+    let store = StateStore::from_env();
+    let key_store = KeyStore::from_env(&store);
+    let scripts = Scripts::from_env();
+    let moderation = Arc::new(Moderation::from_env());
+    let state = AppState {
+        upstreams: Arc::new(Upstreams::from_env(dev_client.clone())),
+        audit: Arc::new(AuditLog::from_env()),
+        reporter: Arc::new(ErrorReporter::from_env()),
+        metrics: Arc::new(Metrics::new()),
+        usage: Arc::new(UsageTracker::from_env()),
+        streams: StreamRegistry::new(),
+        replay: ReplayStore::from_env(&store),
+        policy: Arc::new(RequestPolicy::from_env()),
+        files: FileStore::from_env(),
+        model_catalog: Arc::new(ModelCatalog::from_env(&modes)),
+        modes: Arc::new(modes),
+        languages: Arc::new(LanguageSelector::from_env()),
+        system_prompts: Arc::new(SystemPrompts::from_env()),
+        templates: Arc::new(PromptTemplates::from_env()),
+        transforms: TransformChain::from_env(&Builtins::from_env(&scripts, &moderation)),
+        moderation,
+        scripts,
+        citations: env_or("CITATION_MARKERS", CitationMode::Keep),
+        footers: Footers::from_env(),
+        webhooks: Arc::new(Webhooks::from_env()),
+        event_bus: EventBus::from_env(),
+        billing: Billing::from_env(),
+        history: History::from_env(),
+        embeddings: Arc::new(Embeddings::from_env()),
+        assistants: AssistantsStore::from_env(&store),
+        capture: Capture::from_env(),
+        slow_requests: SlowRequests::from_env(),
+        stream_errors: env_or("STREAM_ERROR_MODE", StreamErrorMode::Content),
+        inflight: InFlight::from_env(&store),
+        key_store: key_store.clone(),
+    };
+    state.usage.clone().spawn_persistence();
+    state.history.clone().spawn_retention();
+    log_sampling::spawn_summaries();
+    let api_keys = ApiKeys::from_env(key_store, state.usage.clone());
+    let admin_auth = AdminAuth::from_env(&store);
+
+    Router::new()
+        .route("/api/ping", get(ping_handler)
+            .layer(TimeoutLayer::new(server_config.request_timeout)))
+        .route("/metrics", get(metrics::metrics_handler)
+            .layer(TimeoutLayer::new(server_config.request_timeout))
+            .layer(middleware::from_fn_with_state(admin_auth.clone(), auth::require_metrics_auth)))
+        // The streaming route gets its own, longer timeout
+        .route(CHAT_COMPLETIONS_ROUTE, post(chat_completions_handler)
+            .layer(TimeoutLayer::new(server_config.stream_timeout))
+            // Queue by priority and fairly across keys, or shed load, once the
+            // in-flight stream cap is reached
+            .layer(middleware::from_fn_with_state(stream_limiter, concurrency::shed_load))
+            // Authenticate before a stream permit is taken
+            .layer(middleware::from_fn_with_state(api_keys.clone(), auth::require_api_key)))
+        // Embeddings, forwarded to EMBEDDINGS_URL or refused with a 501
+        .route("/v1/embeddings", post(embeddings::embeddings_handler)
+            .layer(TimeoutLayer::new(server_config.request_timeout))
+            .layer(middleware::from_fn_with_state(api_keys.clone(), auth::require_api_key)))
+        // The models chat completions accept
+        .route("/v1/models", get(model_catalog::list_models_handler)
+            .layer(TimeoutLayer::new(server_config.request_timeout))
+            .layer(middleware::from_fn_with_state(api_keys.clone(), auth::require_api_key)))
+        .route("/v1/models/:id", get(model_catalog::get_model_handler)
+            .layer(TimeoutLayer::new(server_config.request_timeout))
+            .layer(middleware::from_fn_with_state(api_keys.clone(), auth::require_api_key)))
+        // Uploads that chat messages can attach
+        .merge(files_router(api_keys.clone(), server_config))
+        // Dev's server-side conversations, and the Assistants API threads
+        .merge(threads_router(api_keys.clone(), server_config))
+        // Runs stream like chat completions, so they get the stream timeout
+        .route("/v1/threads/:id/runs", post(assistants::create_run_handler)
+            .layer(TimeoutLayer::new(server_config.stream_timeout))
+            .layer(middleware::from_fn_with_state(api_keys.clone(), auth::require_api_key)))
+        .merge(assistants_router(api_keys, server_config))
+        // API description and Swagger UI
+        .route("/openapi.json", get(openapi::openapi_handler))
+        .route("/docs", get(openapi::swagger_ui_handler))
+        // Dashboard shell; the data it shows comes from the admin API
+        .route("/admin", get(dashboard::dashboard_handler))
+        // Tails last as long as the stream they mirror
+        .route("/admin/streams/:id/tail", get(streams::tail_stream_handler)
+            .layer(TimeoutLayer::new(server_config.stream_timeout))
+            .layer(middleware::from_fn_with_state(admin_auth.clone(), auth::require_admin)))
+        .merge(admin_router(admin_auth.clone(), server_config))
+        // Add shared handler state
+        .with_state(state)
+        // OIDC login for the admin API
+        .merge(oidc::router(admin_auth))
+        // Liveness/readiness probes
+        .merge(health::router(dev_client, server_config))
+        // Replace axum's implicit 2MB limit with the configured one
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(server_config.max_request_body_bytes))
+        // Render 413/408 from the layers above as OpenAI error objects
+        .layer(middleware::map_response(error::openai_error_for_status))
+        // Answer browser preflights before any limits apply
+        .layer(server_config.cors.layer())
+        // Add tracing layer; the span carries the request id
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+        // One access log line per request, written when the body completes
+        .layer(middleware::from_fn(access_log::record))
+        // Accept or generate X-Request-Id and echo it on every response
+        .layer(PropagateRequestIdLayer::new(request_id::X_REQUEST_ID))
+        .layer(SetRequestIdLayer::new(request_id::X_REQUEST_ID, request_id::MakeRequestUuid))
+}
+
+/// The OpenAI files routes, behind the API keys.
+fn files_router(api_keys: ApiKeys, server_config: &ServerConfig) -> Router<AppState> {
+    Router::new()
+        .route("/v1/files", get(files::list_files_handler).post(files::upload_file_handler))
+        .route("/v1/files/:id", get(files::get_file_handler).delete(files::delete_file_handler))
+        .route_layer(TimeoutLayer::new(server_config.request_timeout))
+        .route_layer(middleware::from_fn_with_state(api_keys, auth::require_api_key))
+}
+
+/// The Dev thread routes, behind the API keys. Threads created here belong
+/// to the Assistants API.
+fn threads_router(api_keys: ApiKeys, server_config: &ServerConfig) -> Router<AppState> {
+    Router::new()
+        .route("/v1/threads", get(threads::list_threads_handler).post(assistants::create_thread_handler))
+        .route("/v1/threads/:id", get(threads::get_thread_handler).delete(threads::delete_thread_handler))
+        .route_layer(TimeoutLayer::new(server_config.request_timeout))
+        .route_layer(middleware::from_fn_with_state(api_keys, auth::require_api_key))
+}
+
+/// The Assistants API routes other than run creation, behind the API keys.
+fn assistants_router(api_keys: ApiKeys, server_config: &ServerConfig) -> Router<AppState> {
+    Router::new()
+        .route("/v1/assistants", get(assistants::list_assistants_handler).post(assistants::create_assistant_handler))
+        .route("/v1/assistants/:id", get(assistants::get_assistant_handler).delete(assistants::delete_assistant_handler))
+        .route("/v1/threads/:id/messages", get(assistants::list_messages_handler).post(assistants::create_message_handler))
+        .route("/v1/threads/:id/runs/:run_id", get(assistants::get_run_handler))
+        .route_layer(TimeoutLayer::new(server_config.request_timeout))
+        .route_layer(middleware::from_fn_with_state(api_keys, auth::require_api_key))
+}
+
+/// Routes behind ADMIN_TOKEN.
+fn admin_router(admin_auth: AdminAuth, server_config: &ServerConfig) -> Router<AppState> {
+    Router::new()
+        // Usage totals per key and model
+        .route("/v1/usage", get(usage::usage_handler))
+        // Search the stored conversations
+        .route("/admin/history", get(history::history_handler))
+        // Create, relabel and revoke client API keys
+        .route("/admin/keys", get(key_store::list_keys_handler).post(key_store::create_key_handler))
+        .route("/admin/keys/:id", get(key_store::get_key_handler)
+            .patch(key_store::update_key_handler)
+            .delete(key_store::revoke_key_handler))
+        // Inspect and cancel in-flight chat streams
+        .route("/admin/streams", get(streams::list_streams_handler))
+        .route("/admin/streams/:id", delete(streams::cancel_stream_handler))
+        // Data for the dashboard
+        .route("/admin/summary", get(dashboard::summary_handler))
+        // Inspect and hot-reload the WASM signer module
+        .route("/admin/signer", get(signer::signer_info_handler))
+        .route("/admin/signer/reload", post(signer::reload_signer_handler))
+        // Signed headers for a payload, to diagnose upstream 401s
+        .route("/debug/sign", post(signer::debug_sign_handler))
+        .route_layer(TimeoutLayer::new(server_config.request_timeout))
+        .route_layer(middleware::from_fn_with_state(admin_auth, auth::require_admin))
+}
+
+#[utoipa::path(get, path = "/api/ping", tag = "health", responses((status = 200, body = String, example = json!("pong"))))]
+async fn ping_handler() -> &'static str {
+    info!("Ping handler called");
+    "pong"
+}
+
+/// Streams a chat completion as OpenAI `chat.completion.chunk` events,
+/// terminated by `data: [DONE]`. Events are numbered with `id:`; a request
+/// repeating the X-Request-Id of a resumable stream with `Last-Event-ID`
+/// continues that stream instead of starting a new completion.
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions",
+    tag = "chat",
+    request_body = OpenAiChatRequest,
+    params(("X-Request-Id" = Option<String>, Header,
+        description = "Correlation id; generated when absent, echoed on the response and forwarded to Dev"),
+        ("X-Priority" = Option<String>, Header,
+            description = "`interactive` (default) or `batch`; queued interactive requests are admitted first"),
+        ("Last-Event-ID" = Option<u64>, Header,
+            description = "Resume the stream of this X-Request-Id after the given event id (needs SSE_REPLAY_EVENTS)"),
+        ("X-Devv-Options" = Option<String>, Header,
+            description = "`DevvOptions` as JSON; fields of the `x_devv` body object take precedence"),
+        ("X-Capture-Upstream" = Option<bool>, Header,
+            description = "Write Dev's raw response to CAPTURE_DIR (needs CAPTURE_HEADER)")),
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Server-sent events, one `ChatCompletionChunk` per `data:` line; \
+            with STREAM_ERROR_MODE `event` or `data`, an error ends the stream with an OpenAI error object",
+            content_type = "text/event-stream", body = ChatCompletionChunk),
+        (status = 400, description = "Invalid request, e.g. `n` out of range", body = ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 413, description = "Request body too large", body = ErrorBody),
+        (status = 429, description = "Too many concurrent streams, or Dev rate limiting (with `Retry-After`)", body = ErrorBody),
+    ),
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, request_id, access_log, headers, req), fields(api_key_id = api_key_id.as_str()))]
+async fn chat_completions_handler(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Extension(access_log): Extension<AccessLogContext>,
+    access: Option<Extension<ModelAccess>>,
+    headers: http::HeaderMap,
+    Json(mut req): Json<OpenAiChatRequest>,
+) -> Response {
+    let AppState { upstreams, audit, reporter, metrics, usage, streams, replay, policy, files, modes, languages, system_prompts, templates, moderation, transforms, scripts, citations, footers, webhooks, event_bus, billing, history, capture, slow_requests, stream_errors, inflight, model_catalog, .. } = state;
+    // Metadata only: prompts reach the logs through the audit log's redaction
+    let stream = req.extra.get("stream").and_then(serde_json::Value::as_bool).unwrap_or(false);
+    info!(model = ?req.model, messages = req.messages.len(), stream, n = ?req.n, "Received chat completions request");
+
+    // Correlation id; it also keys resumable streams
+    let request_id = request_id::as_string(&request_id);
+
+    // A reconnecting client continues its stream where it left off
+    if let Some(last_event_id) = replay::last_event_id(&headers) {
+        match replay.resume(&request_id, api_key_id.as_str(), last_event_id).await {
+            Some(events) => {
+                info!(last_event_id, "Resuming chat stream");
+                return sse_response(events, Vec::new());
+            }
+            None => warn!(last_event_id, "No resumable stream for this request id, starting a new completion"),
+        }
+    }
+    // Requests without a known model go to the default one
+    if let Some(default) = model_catalog.default_for(req.model.as_deref()) {
+        debug!(requested = ?req.model, default, "Using the default model");
+        req.model = Some(default.to_string());
+    }
+    // The operator's script may reroute or refuse the request, and tag the response
+    let script_changes = scripts.on_request(&req, api_key_id.as_str(), &headers);
+    if let Some(reason) = script_changes.reject {
+        return error::ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", reason)
+            .with_code("rejected_by_script")
+            .into_response();
+    }
+    if let Some(model) = script_changes.model {
+        debug!(requested = ?req.model, model, "Script changed the model");
+        req.model = Some(model);
+    }
+    if let Some(model) = &req.model {
+        access_log.set_model(model.clone());
+    }
+
+    // Models the client may not use or that are disabled are refused, then
+    // fields Dev cannot honor are rejected or noted before anything else
+    if let Some(Extension(access)) = &access
+        && let Err(e) = access.check(req.model.as_deref())
+    {
+        return e.into_response();
+    }
+    if let Err(e) = model_catalog.check(req.model.as_deref()) {
+        return e.into_response();
+    }
+    let answer = match policy.check(&req) {
+        Ok(answer) => answer,
+        Err(e) => return e.into_response(),
+    };
+
+    // Dev-specific options from the header, overridden by the body
+    let devv = match DevvOptions::from_headers(&headers) {
+        Ok(from_header) => from_header.unwrap_or_default().merge(req.x_devv.take().unwrap_or_default()),
+        Err(e) => return e.into_response(),
+    };
+
+    // Uploaded files referenced by the messages go along as attachments
+    let attachments = match files.attachments(api_key_id.as_str(), &req.messages) {
+        Ok(attachments) => attachments,
+        Err(e) => return e.into_response(),
+    };
+
+    // Extract content and options from the request
+    // For simplicity, concatenate messages or take the last user message
+    let content = req.messages.last().map(|m| m.text()).unwrap_or_default();
+    if content.is_empty() {
+        warn!("Request content is empty");
+        return error::ApiError::invalid_param("messages", "Request messages are empty or missing content").into_response();
+    }
+
+    // The prompt Dev gets: the conversation rendered by the model's template
+    // if there is one, otherwise the proxy's system prompt and the last message
+    let system_prompt = system_prompts.get(api_key_id.as_str(), req.model.as_deref());
+    let prompt = match templates.render(req.model.as_deref(), &req.messages, system_prompt) {
+        Some(Ok(prompt)) => prompt,
+        Some(Err(e)) => {
+            error!("{:#}", e);
+            return error::ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", format!("{:#}", e))
+                .with_code("prompt_template_error")
+                .into_response();
+        }
+        None => system_prompts.apply(api_key_id.as_str(), req.model.as_deref(), content.clone()),
+    };
+    let n = req.n.unwrap_or(1);
+    if n == 0 || n > upstreams.max_choices() {
+        return error::ApiError::invalid_param("n", format!("n must be between 1 and {}", upstreams.max_choices()))
+            .with_code("invalid_n")
+            .into_response();
+    }
+
+    // Prompts the content filter flags never reach Dev
+    if let Err(e) = moderation.check_prompt(&prompt).await {
+        return e.into_response();
+    }
+
+    let model = req.model.clone();
+    audit.record(AuditRecord {
+        request_id: &request_id,
+        subject: Some(api_key_id.as_str()),
+        model: model.as_deref(),
+        message_count: req.messages.len(),
+        prompt: &content,
+    });
+
+    // Create Dev options from OpenAI request: the model name selects a mode
+    // (its suffix), which the request's own Dev options refine
+    let variant = upstreams.variant_for(&request_id);
+    let options_for = |model: Option<&str>| {
+        let (dev_model, mode) = modes.resolve(model);
+        let mut options = DevRequestOptions {
+            model: dev_model,
+            request_id: Some(request_id.clone()),
+            variant,
+            attachments: attachments.clone(),
+            citations,
+            footers,
+            ..Default::default()
+        };
+        mode.merge(devv.clone()).apply(&mut options);
+        if options.language.is_none() {
+            options.language = Some(languages.select(&headers, &content));
+        }
+        options
+    };
+    let mut dev_options = options_for(req.model.as_deref());
+    let content = prompt;
+
+    // An identical request still streaming answers this one too
+    let dedup_key = inflight
+        .is_enabled()
+        .then(|| inflight::fingerprint(api_key_id.as_str(), &content, n, &dev_options, &answer));
+    let joined = match dedup_key.as_deref() {
+        Some(key) => inflight.join(key).await,
+        None => None,
+    };
+    if let Some(events) = joined {
+        info!("Sharing the stream of an identical in-flight request");
+        let headers = vec![(inflight::X_DEDUPLICATED, http::HeaderValue::from_static("true"))];
+        return if replay.enabled() {
+            sse_response(replay.spawn(request_id, api_key_id.as_str().to_string(), events).await, headers)
+        } else {
+            sse_response(replay::numbered(events), headers)
+        };
+    }
+
+    // Metrics are labeled with catalog names only, unknown models count as `other`
+    let metric_model = model.as_deref().map(|model| model_catalog.metric_label(model).to_string());
+    let mut observer = metrics.observe_stream(metric_model.as_deref());
+    observer.set_variant(variant);
+
+    // Usage accounting: prompt tokens now, completion tokens when the stream ends
+    let usage_model = model.clone().unwrap_or_else(|| "unknown".to_string());
+    let prompt_tokens: usize = req.messages.iter().map(|m| tokenizer::count_tokens(&m.text())).sum::<usize>()
+        + dev_options.attachments.iter().map(|a| tokenizer::count_tokens(&a.text)).sum::<usize>();
+    usage.record_request(api_key_id.as_str(), &usage_model, prompt_tokens as u64);
+    let usage_key = api_key_id.clone();
+    observer.on_finish(move |summary| {
+        usage.record_completion(usage_key.as_str(), &usage_model, summary.completion_tokens);
+    });
+
+    // Lifecycle webhooks: started once Dev answers, finished or failed when
+    // the stream ends. The event bus and the history get each answer's
+    // snapshot, and the billing export a record once the stream ends.
+    let completion_info = (webhooks.is_enabled() || event_bus.is_enabled() || history.is_enabled() || billing.is_enabled()).then(|| CompletionInfo {
+        request_id: request_id.clone(),
+        model: model.clone(),
+        api_key_id: api_key_id.as_str().to_string(),
+        prompt_tokens: prompt_tokens as u64,
+    });
+    if let Some(info) = completion_info.clone().filter(|_| webhooks.is_enabled()) {
+        if webhooks.include_text() {
+            observer.collect_text();
+        }
+        let webhooks = webhooks.clone();
+        observer.on_finish(move |summary| webhooks.ended(&info, summary));
+    }
+    if let Some(info) = completion_info.clone().filter(|_| billing.is_enabled()) {
+        observer.on_finish(move |summary| billing.record(BillingRecord::new(&info, CHAT_COMPLETIONS_ROUTE, summary)));
+    }
+    if scripts.defines(scripting::ON_FINISH) {
+        let (script_id, script_model, script_key) = (request_id.clone(), model.clone(), api_key_id.clone());
+        observer.on_finish(move |summary| scripts.on_finish(&script_id, script_model.as_deref(), script_key.as_str(), summary));
+    }
+    if slow_requests.is_enabled() {
+        let (slow_id, slow_model, slow_info, webhooks) = (request_id.clone(), metric_model.clone(), completion_info.clone(), webhooks.clone());
+        observer.on_finish(move |summary| {
+            if slow_requests.report(&slow_id, slow_model.as_deref(), summary)
+                && let Some(info) = &slow_info
+            {
+                webhooks.slow(info, summary);
+            }
+        });
+    }
+
+    // Call the Dev API (or its fallback) to get the Response; each of the n
+    // choices is its own request, sent concurrently. When that fails, the
+    // model's fallbacks the client may use are tried in turn.
+    let report_ctx = ReportContext { request_id: &request_id, model: model.as_deref(), route: CHAT_COMPLETIONS_ROUTE };
+    let mut fallbacks = model_catalog
+        .fallbacks(model.as_deref())
+        .into_iter()
+        .filter(|fallback| access.as_ref().is_none_or(|Extension(access)| access.allows(fallback)));
+    let mut fallback_model = None;
+    let routed = loop {
+        let sends = (0..n).map(|_| upstreams.send(&content, dev_options.clone()));
+        let e = match futures_util::future::join_all(sends).await.into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(routed) => break Ok(routed),
+            Err(e) => e,
+        };
+        let Some(fallback) = fallbacks.next().filter(|_| model_catalog::should_fall_back(&e)) else { break Err(e) };
+        warn!(model = ?fallback_model.as_ref().or(model.as_ref()), fallback, "Dev request failed, trying the fallback model: {}", e);
+        let status = e.downcast_ref::<UpstreamStatusError>().map(|e| e.status);
+        reporter.upstream_failure(status, &e.to_string(), report_ctx);
+        dev_options = options_for(Some(&fallback));
+        fallback_model = Some(fallback);
+    };
+    let routed = match routed {
+        Ok(routed) => routed,
+        Err(e) => {
+            error!("Failed to send request to Dev API: {}", e);
+            observer.fail(Outcome::UpstreamError);
+            // Our own upstream cap, an open circuit and Dev rate limiting
+            // are not Dev failures worth reporting
+            let status_error = e.downcast_ref::<UpstreamStatusError>();
+            let reported = match status_error {
+                Some(status_error) => DevError::parse(Some(status_error.status), &status_error.body).kind != DevErrorKind::RateLimited,
+                None => !e.is::<UpstreamQueueTimeout>() && !e.is::<CircuitOpen>(),
+            };
+            if reported {
+                reporter.upstream_failure(status_error.map(|e| e.status), &e.to_string(), report_ctx);
+            }
+            return service::upstream_error(&e).into_response();
+        }
+    };
+
+    // The primary failed but the fallback answered: still worth reporting
+    for e in routed.iter().filter_map(|r| r.primary_error.as_ref()) {
+        let status = e.downcast_ref::<UpstreamStatusError>().map(|e| e.status);
+        reporter.upstream_failure(status, &e.to_string(), report_ctx);
+    }
+    let mut upstream_headers = routed[0].headers();
+    upstream_headers.extend(answer.warnings());
+    upstream_headers.extend(script_changes.headers);
+    if variant == Variant::Canary {
+        upstream_headers.push((canary::X_SIGNER_VARIANT, http::HeaderValue::from_static(variant.as_str())));
+    }
+    if let Some(value) = fallback_model.and_then(|m| http::HeaderValue::from_str(&m).ok()) {
+        upstream_headers.push((model_catalog::X_MODEL_FALLBACK, value));
+    }
+
+    if let Some(info) = completion_info.as_ref().filter(|_| webhooks.is_enabled()) {
+        webhooks.started(info);
+    }
+
+    // Register the stream so admins can list and cancel it; the guard lives
+    // in the body and unregisters it when the stream ends
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let replay_key = request_id.clone();
+    let active_stream = streams.register(StreamMeta {
+        request_id: request_id.clone(),
+        api_key_id: api_key_id.as_str().to_string(),
+        model: model.clone(),
+    }, abort_handle);
+
+    // A stream that breaks midway is re-issued (same options, so the same
+    // thread) instead of ending in an error, if STREAM_RETRY_ATTEMPTS allows.
+    // Dev's bytes are mirrored to the stream's tails and captured if asked.
+    let tapped = |byte_stream: DevByteStream, tap: StreamTap, capture: Option<CaptureSink>, index: u32| -> DevByteStream {
+        Box::pin(byte_stream.inspect(move |bytes| {
+            if let Ok(bytes) = bytes {
+                tap.dev(index, bytes);
+                if let Some(capture) = &capture {
+                    let _ = capture.send(bytes.clone());
+                }
+            }
+        }))
+    };
+    let retry = |index: u32, capture: Option<CaptureSink>| (upstreams.stream_retries() > 0).then(|| {
+        let (upstreams, content, options) = (upstreams.clone(), content.clone(), dev_options.clone());
+        let tap = active_stream.tap().clone();
+        StreamRetry {
+            attempts: upstreams.stream_retries(),
+            reconnect: Box::new(move || {
+                let (upstreams, content, options) = (upstreams.clone(), content.clone(), options.clone());
+                let (tap, capture) = (tap.clone(), capture.clone());
+                async move {
+                    let routed = upstreams.send(&content, options).await?;
+                    Ok(tapped(Box::pin(routed.response.bytes_stream()), tap, capture, index))
+                }
+                .boxed()
+            }),
+        }
+    });
+
+    // Process each Dev byte stream into OpenAI chunks for its choice index
+    // and interleave them; every choice finishes on its own
+    let completion_id = sse_processor::new_completion_id();
+    info!(completion_id, n, "Streaming chat completion");
+    let null_logprobs = answer.null_logprobs;
+    let captured = capture.wants(&headers);
+    let choices = routed.into_iter().zip(0..).map(|(routed, index)| {
+        let capture_sink = captured.then(|| capture.sink(&request_id, index)).flatten();
+        let byte_stream = tapped(Box::pin(routed.response.bytes_stream()), active_stream.tap().clone(), capture_sink.clone(), index);
+        let mut options = dev_options.clone();
+        if let Some(info) = &completion_info {
+            options.snapshots.extend(event_bus.sink(info.clone(), index));
+            options.snapshots.extend(history.sink(info.clone(), content.clone(), index));
+        }
+        let context = ChoiceContext::attach(&mut options);
+        let chunks = process_dev_bytes_stream_with_retry(byte_stream, options, completion_id.clone(), retry(index, capture_sink))
+            .map(move |chunk_result| {
+                chunk_result.map(|mut chunk| {
+                    for choice in &mut chunk.choices {
+                        choice.index = index;
+                        choice.logprobs = null_logprobs.then_some(NoLogprobs);
+                    }
+                    chunk
+                })
+            });
+        // Citations, footers, redaction, moderation, pacing, ... in
+        // CHUNK_TRANSFORMS order
+        transforms.apply(chunks, &context)
+    });
+    let openai_chunk_stream = futures_util::stream::select_all(choices);
+
+    // Serialize each chunk into an SSE payload; an error ends the stream
+    // unless errors are sent as content
+    let event_stream = openai_chunk_stream.map(move |chunk_result| -> (EventPayload, bool) {
+        observer.on_chunk(&chunk_result);
+        if let Err(e) = &chunk_result {
+            reporter.stream_error(&e.to_string(), ReportContext {
+                request_id: &request_id,
+                model: model.as_deref(),
+                route: CHAT_COMPLETIONS_ROUTE,
+            });
+        }
+        match chunk_result {
+            Ok(chunk) => {
+                if let Some((event, data)) = chunk.error.as_ref().and_then(|error| stream_errors.error_event(error)) {
+                    active_stream.tap().chunk(event, &data);
+                    return ((event, data), true);
+                }
+                // Serialize the chunk to JSON and create an SSE event
+                match serde_json::to_string(&chunk) {
+                    Ok(json_data) => {
+                        active_stream.record_chunk(json_data.len());
+                        active_stream.tap().chunk(None, &json_data);
+                        ((None, json_data), false)
+                    }
+                    Err(e) => {
+                        warn!("Failed to serialize OpenAI chunk: {}", e);
+                        // Send an error event (or just close the stream?)
+                        ((Some("error"), format!("{{\"error\": \"Serialization failed: {}\"}}", e)), false)
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Error processing Dev stream chunk: {}", e);
+                let error = ChunkError { message: e.to_string(), error_type: "server_error", code: "stream_error" };
+                if let Some((event, data)) = stream_errors.error_event(&error) {
+                    active_stream.tap().chunk(event, &data);
+                    return ((event, data), true);
+                }
+                // Send an error event
+                let data = format!("{{\"error\": \"{}\"}}", e);
+                active_stream.tap().chunk(Some("error"), &data);
+                ((Some("error"), data), false)
+            }
+        }
+    });
+
+    // Add a final [DONE] message as per OpenAI spec for streams, unless an
+    // error ended the stream
+    let done_stream = futures_util::stream::once(async { ((None, "[DONE]".to_string()), false) });
+    let event_stream = event_stream.chain(done_stream).scan(false, |ended, (payload, ends)| {
+        let payload = (!*ended).then_some(payload);
+        *ended |= ends;
+        futures_util::future::ready(payload)
+    });
+    // A cancelled stream ends immediately, without [DONE]
+    let event_stream = Abortable::new(event_stream, abort_registration);
+    let event_stream = match dedup_key {
+        Some(key) => inflight.lead(key, event_stream).await.left_stream(),
+        None => event_stream.right_stream(),
+    };
+
+    info!("Starting SSE stream response...");
+    // Resumable streams run in the background and outlive the connection
+    let sse_stream = if replay.enabled() {
+        replay.spawn(replay_key, api_key_id.as_str().to_string(), event_stream).await.left_stream()
+    } else {
+        replay::numbered(event_stream).right_stream()
+    };
+    sse_response(sse_stream, upstream_headers)
+}
+
+/// The SSE response for a chat completion, with keep-alive comments.
+fn sse_response(
+    events: impl futures_util::Stream<Item = SseEvent> + Send + 'static,
+    headers: Vec<(http::HeaderName, http::HeaderValue)>,
+) -> Response {
+    // Convert SseEvent into Result<_, Infallible> for Sse::new
+    let mut response = Sse::new(events.map(Ok::<_, Infallible>))
+        .keep_alive(axum::response::sse::KeepAlive::new().interval(Duration::from_secs(15)))
+        .into_response();
+    response.headers_mut().extend(headers);
+    response
+}
\ No newline at end of file
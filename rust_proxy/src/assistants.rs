@@ -0,0 +1,1005 @@
+// A subset of the OpenAI Assistants API, for frameworks built on that flow.
+// Assistants (a model and instructions) and threads live in the state store,
+// so every replica serves them, visible only to the API key that created
+// them, up to ASSISTANTS_MAX_OBJECTS (10000) of both. Creating a run sends
+// the user messages added since the last run to Dev as one prompt: the first
+// run starts a Dev thread, with the instructions ahead of the prompt, and
+// later runs continue it. With `"stream": true` the run streams the
+// Assistants events (`thread.run.created` ... `thread.message.delta` ...
+// `thread.run.completed`, then `done`); otherwise it runs in the background
+// and `GET /v1/threads/{id}/runs/{run_id}` reports its status. Tools, run
+// steps, file search and updates of existing objects are not supported.
+//
+// These threads have `thread_` ids; other ids under `/v1/threads` are Dev's
+// own threads (see threads.rs).
+
+use axum::extract::{Path, Query, State};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use futures_util::stream::{BoxStream, StreamExt};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tower_http::request_id::RequestId;
+use tracing::{info, warn};
+
+use crate::app::AppState;
+use crate::auth::{ApiKeyId, ModelAccess};
+use crate::billing::{Billing, BillingRecord};
+use crate::config::env_or;
+use crate::dev_client::DevRequestOptions;
+use crate::error::ApiError;
+use crate::metrics::{Outcome, StreamSummary};
+use crate::models::MessageContent;
+use crate::state_store::StateStore;
+use crate::sse_processor::{self, process_dev_bytes_stream_unfold, ChatCompletionChunk, SseAccumulator, STREAM_ERROR_PREFIX};
+use crate::transform::{ChoiceContext, TransformChain};
+use crate::usage::UsageTracker;
+use crate::webhooks::CompletionInfo;
+use crate::{request_id, tokenizer, utils};
+
+type Metadata = serde_json::Map<String, Value>;
+
+/// How many assistants and threads there are.
+const OBJECTS_KEY: &str = "assistants:objects";
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn new_id(prefix: &str) -> String {
+    format!("{}_{}", prefix, utils::generate_uuidv4().replace('-', ""))
+}
+
+/// Whether `id` names a thread of this store rather than a Dev thread.
+pub fn is_assistants_thread(id: &str) -> bool {
+    id.starts_with("thread_")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assistant {
+    pub id: String,
+    pub object: String,
+    pub created_at: u64,
+    pub model: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub instructions: Option<String>,
+    pub tools: Vec<Value>,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: String,
+    pub object: String,
+    pub created_at: u64,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub id: String,
+    pub object: String,
+    pub created_at: u64,
+    pub thread_id: String,
+    pub role: String,
+    /// `in_progress`, `completed` or `incomplete`.
+    pub status: String,
+    pub content: Vec<Value>,
+    pub assistant_id: Option<String>,
+    pub run_id: Option<String>,
+    pub metadata: Metadata,
+}
+
+impl Message {
+    fn text(&self) -> String {
+        self.content.iter().filter_map(|c| c["text"]["value"].as_str()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+fn text_content(value: &str) -> Vec<Value> {
+    vec![json!({ "type": "text", "text": { "value": value, "annotations": [] } })]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Queued,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunError {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub id: String,
+    pub object: String,
+    pub created_at: u64,
+    pub thread_id: String,
+    pub assistant_id: String,
+    pub status: RunStatus,
+    pub model: String,
+    pub instructions: Option<String>,
+    pub started_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub last_error: Option<RunError>,
+    pub tools: Vec<Value>,
+    pub metadata: Metadata,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredThread {
+    owner: String,
+    thread: Thread,
+    messages: Vec<Message>,
+    runs: Vec<Run>,
+    /// How many of `messages` Dev has seen.
+    sent: usize,
+    /// The Dev thread later runs continue.
+    dev_thread_id: Option<String>,
+}
+
+/// What a new run sends to Dev.
+struct StartedRun {
+    run: Run,
+    message: Message,
+    prompt: String,
+    dev_thread_id: Option<String>,
+}
+
+fn not_found(kind: &str, id: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No {} found with id '{}'.", kind, id))
+}
+
+fn unavailable(e: anyhow::Error) -> ApiError {
+    warn!("Assistants storage failed: {:#}", e);
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", "Assistants storage is unavailable")
+        .with_code("assistants_storage_unavailable")
+}
+
+/// Assistants and threads in the state store: the assistants of each owner
+/// under one key, each thread under its own, and a count of both.
+#[derive(Clone)]
+pub struct AssistantsStore {
+    store: StateStore,
+    max_objects: usize,
+}
+
+impl AssistantsStore {
+    pub fn new(store: StateStore, max_objects: usize) -> Self {
+        Self { store, max_objects }
+    }
+
+    pub fn from_env(store: &StateStore) -> Self {
+        Self::new(store.clone(), env_or("ASSISTANTS_MAX_OBJECTS", 10_000))
+    }
+
+    fn assistants_key(owner: &str) -> String {
+        format!("assistants:owner:{}", owner)
+    }
+
+    fn thread_key(id: &str) -> String {
+        format!("assistants:thread:{}", id)
+    }
+
+    /// Takes room for one more object.
+    async fn reserve(&self) -> Result<(), ApiError> {
+        let count = self.store.add(OBJECTS_KEY, 1, None).await.map_err(unavailable)?;
+        if count > self.max_objects as i64 {
+            self.release().await;
+            return Err(ApiError::new(StatusCode::INSUFFICIENT_STORAGE, "server_error", "Assistants storage is full")
+                .with_code("assistants_storage_full"));
+        }
+        Ok(())
+    }
+
+    async fn release(&self) {
+        if let Err(e) = self.store.add(OBJECTS_KEY, -1, None).await {
+            warn!("Could not count a removed Assistants object: {:#}", e);
+        }
+    }
+
+    pub async fn create_assistant(&self, owner: &str, req: CreateAssistant) -> Result<Assistant, ApiError> {
+        self.reserve().await?;
+        let assistant = Assistant {
+            id: new_id("asst"),
+            object: "assistant".to_string(),
+            created_at: now_secs(),
+            model: req.model,
+            name: req.name,
+            description: req.description,
+            instructions: req.instructions,
+            tools: Vec::new(),
+            metadata: req.metadata,
+        };
+        let key = Self::assistants_key(owner);
+        let added = self.store.update(&key, |list: &mut Option<Vec<Assistant>>| {
+            list.get_or_insert_with(Vec::new).push(assistant.clone());
+            Ok::<_, ApiError>(())
+        });
+        if let Err(e) = added.await {
+            self.release().await;
+            return Err(unavailable(e));
+        }
+        Ok(assistant)
+    }
+
+    pub async fn assistant(&self, owner: &str, id: &str) -> Result<Assistant, ApiError> {
+        self.assistants(owner).await?.into_iter().find(|a| a.id == id).ok_or_else(|| not_found("assistant", id))
+    }
+
+    /// Assistants of `owner`, newest first.
+    pub async fn assistants(&self, owner: &str) -> Result<Vec<Assistant>, ApiError> {
+        let list = self.store.get_json(&Self::assistants_key(owner)).await.map_err(unavailable)?;
+        let mut list: Vec<Assistant> = list.unwrap_or_default();
+        list.sort_by(|a, b| (b.created_at, &b.id).cmp(&(a.created_at, &a.id)));
+        Ok(list)
+    }
+
+    pub async fn delete_assistant(&self, owner: &str, id: &str) -> Result<(), ApiError> {
+        let key = Self::assistants_key(owner);
+        let removed = self.store.update(&key, |list: &mut Option<Vec<Assistant>>| {
+            let assistants = list.as_mut().ok_or_else(|| not_found("assistant", id))?;
+            let index = assistants.iter().position(|a| a.id == id).ok_or_else(|| not_found("assistant", id))?;
+            assistants.remove(index);
+            if assistants.is_empty() {
+                *list = None;
+            }
+            Ok(())
+        });
+        removed.await.map_err(unavailable)??;
+        self.release().await;
+        Ok(())
+    }
+
+    pub async fn create_thread(&self, owner: &str, req: CreateThread) -> Result<Thread, ApiError> {
+        let thread =
+            Thread { id: new_id("thread"), object: "thread".to_string(), created_at: now_secs(), metadata: req.metadata };
+        let messages = req.messages.into_iter().map(|m| user_message(&thread.id, m)).collect::<Result<_, _>>()?;
+        self.reserve().await?;
+        let stored =
+            StoredThread { owner: owner.to_string(), thread: thread.clone(), messages, runs: Vec::new(), sent: 0, dev_thread_id: None };
+        if let Err(e) = self.store.set_json(&Self::thread_key(&thread.id), &stored).await {
+            self.release().await;
+            return Err(unavailable(e));
+        }
+        Ok(thread)
+    }
+
+    /// Runs `f` on the thread `id` of `owner` (any owner's for `None`) and
+    /// stores what it leaves, unless it fails.
+    async fn with_thread<T>(
+        &self,
+        owner: Option<&str>,
+        id: &str,
+        mut f: impl FnMut(&mut StoredThread) -> Result<T, ApiError>,
+    ) -> Result<T, ApiError> {
+        let key = Self::thread_key(id);
+        let updated = self.store.update(&key, |thread: &mut Option<StoredThread>| {
+            let thread =
+                thread.as_mut().filter(|t| owner.is_none_or(|owner| t.owner == owner)).ok_or_else(|| not_found("thread", id))?;
+            f(thread)
+        });
+        updated.await.map_err(unavailable)?
+    }
+
+    async fn stored_thread(&self, owner: &str, id: &str) -> Result<StoredThread, ApiError> {
+        let thread: Option<StoredThread> = self.store.get_json(&Self::thread_key(id)).await.map_err(unavailable)?;
+        thread.filter(|t| t.owner == owner).ok_or_else(|| not_found("thread", id))
+    }
+
+    pub async fn thread(&self, owner: &str, id: &str) -> Result<Thread, ApiError> {
+        Ok(self.stored_thread(owner, id).await?.thread)
+    }
+
+    pub async fn delete_thread(&self, owner: &str, id: &str) -> Result<(), ApiError> {
+        let key = Self::thread_key(id);
+        let removed = self.store.update(&key, |thread: &mut Option<StoredThread>| {
+            if thread.as_ref().is_none_or(|t| t.owner != owner) {
+                return Err(not_found("thread", id));
+            }
+            *thread = None;
+            Ok(())
+        });
+        removed.await.map_err(unavailable)??;
+        self.release().await;
+        Ok(())
+    }
+
+    pub async fn add_message(&self, owner: &str, thread_id: &str, req: CreateMessage) -> Result<Message, ApiError> {
+        let message = user_message(thread_id, req)?;
+        self.with_thread(Some(owner), thread_id, |t| {
+            t.messages.push(message.clone());
+            Ok(message.clone())
+        })
+        .await
+    }
+
+    /// Messages of a thread, oldest first.
+    pub async fn messages(&self, owner: &str, thread_id: &str) -> Result<Vec<Message>, ApiError> {
+        Ok(self.stored_thread(owner, thread_id).await?.messages)
+    }
+
+    pub async fn run(&self, owner: &str, thread_id: &str, run_id: &str) -> Result<Run, ApiError> {
+        let thread = self.stored_thread(owner, thread_id).await?;
+        thread.runs.into_iter().find(|r| r.id == run_id).ok_or_else(|| not_found("run", run_id))
+    }
+
+    /// Creates a run for the user messages Dev has not seen, with the
+    /// assistant's reply as a message in progress.
+    async fn start_run(
+        &self,
+        owner: &str,
+        thread_id: &str,
+        assistant_id: &str,
+        model: String,
+        instructions: Option<String>,
+    ) -> Result<StartedRun, ApiError> {
+        self.with_thread(Some(owner), thread_id, |t| {
+            if let Some(active) = t.runs.iter().find(|r| matches!(r.status, RunStatus::Queued | RunStatus::InProgress)) {
+                return Err(ApiError::invalid_param(
+                    "thread_id",
+                    format!("Thread {} already has an active run {}.", thread_id, active.id),
+                ));
+            }
+            let prompt = t.messages[t.sent..]
+                .iter()
+                .filter(|m| m.role == "user")
+                .map(Message::text)
+                .filter(|text| !text.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            if prompt.is_empty() {
+                return Err(ApiError::invalid_param("thread_id", "The thread has no new user messages to run."));
+            }
+            let run = Run {
+                id: new_id("run"),
+                object: "thread.run".to_string(),
+                created_at: now_secs(),
+                thread_id: thread_id.to_string(),
+                assistant_id: assistant_id.to_string(),
+                status: RunStatus::Queued,
+                model: model.clone(),
+                instructions: instructions.clone(),
+                started_at: None,
+                completed_at: None,
+                failed_at: None,
+                last_error: None,
+                tools: Vec::new(),
+                metadata: Metadata::new(),
+            };
+            let message = Message {
+                id: new_id("msg"),
+                object: "thread.message".to_string(),
+                created_at: now_secs(),
+                thread_id: thread_id.to_string(),
+                role: "assistant".to_string(),
+                status: "in_progress".to_string(),
+                content: Vec::new(),
+                assistant_id: Some(assistant_id.to_string()),
+                run_id: Some(run.id.clone()),
+                metadata: Metadata::new(),
+            };
+            t.runs.push(run.clone());
+            t.messages.push(message.clone());
+            t.sent = t.messages.len();
+            Ok(StartedRun { run, message, prompt, dev_thread_id: t.dev_thread_id.clone() })
+        })
+        .await
+    }
+
+    /// Applies `update` to the run and the reply message; returns them.
+    async fn update_run(
+        &self,
+        thread_id: &str,
+        run_id: &str,
+        update: impl Fn(&mut StoredThread, usize, Option<usize>),
+    ) -> Option<(Run, Option<Message>)> {
+        let updated = self.with_thread(None, thread_id, |thread| {
+            let run = thread.runs.iter().position(|r| r.id == run_id).ok_or_else(|| not_found("run", run_id))?;
+            let message = thread.messages.iter().position(|m| m.run_id.as_deref() == Some(run_id));
+            update(thread, run, message);
+            Ok((thread.runs[run].clone(), message.map(|m| thread.messages[m].clone())))
+        });
+        updated.await.inspect_err(|e| warn!(run_id, "Could not update an Assistants run: {}", e.message)).ok()
+    }
+
+    async fn fail_run(&self, thread_id: &str, run_id: &str, error: String) -> Option<Run> {
+        self.update_run(thread_id, run_id, |t, run, message| {
+            let run = &mut t.runs[run];
+            run.status = RunStatus::Failed;
+            run.failed_at = Some(now_secs());
+            run.last_error = Some(RunError { code: "server_error".to_string(), message: error.clone() });
+            if let Some(message) = message {
+                t.messages[message].status = "incomplete".to_string();
+            }
+        })
+        .await
+        .map(|(run, _)| run)
+    }
+}
+
+fn user_message(thread_id: &str, req: CreateMessage) -> Result<Message, ApiError> {
+    if req.role != "user" && req.role != "assistant" {
+        return Err(ApiError::invalid_param("role", "role must be 'user' or 'assistant'"));
+    }
+    Ok(Message {
+        id: new_id("msg"),
+        object: "thread.message".to_string(),
+        created_at: now_secs(),
+        thread_id: thread_id.to_string(),
+        role: req.role,
+        status: "completed".to_string(),
+        content: text_content(&req.content.text()),
+        assistant_id: None,
+        run_id: None,
+        metadata: req.metadata,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAssistant {
+    pub model: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateThread {
+    #[serde(default)]
+    pub messages: Vec<CreateMessage>,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateMessage {
+    pub role: String,
+    pub content: MessageContent,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRun {
+    pub assistant_id: String,
+    pub model: Option<String>,
+    /// Replaces the assistant's instructions.
+    pub instructions: Option<String>,
+    /// Appended to the instructions.
+    pub additional_instructions: Option<String>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// At most this many (20, up to 100).
+    pub limit: Option<usize>,
+    /// `asc` or `desc` (default) by creation time.
+    pub order: Option<String>,
+}
+
+/// An OpenAI list object over `data`, ordered and cut as `query` asks.
+fn list_response<T: Serialize>(mut data: Vec<T>, query: &ListQuery, id: impl Fn(&T) -> &str, oldest_first: bool) -> Json<Value> {
+    if oldest_first != (query.order.as_deref() == Some("asc")) {
+        data.reverse();
+    }
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let has_more = data.len() > limit;
+    data.truncate(limit);
+    Json(json!({
+        "object": "list",
+        "first_id": data.first().map(&id),
+        "last_id": data.last().map(&id),
+        "has_more": has_more,
+        "data": data,
+    }))
+}
+
+/// Assistants events as SSE.
+type RunEvent = (&'static str, Value);
+
+const RUNS_ROUTE: &str = "/v1/threads/:id/runs";
+
+/// Exports the billing record of a run that ended with `outcome`.
+fn bill(billing: &Billing, completion: &CompletionInfo, started: Instant, outcome: Outcome, completion_tokens: u64) {
+    let summary = StreamSummary { outcome, completion_tokens, ttfb: None, duration: started.elapsed(), text: None };
+    billing.record(BillingRecord::new(completion, RUNS_ROUTE, &summary));
+}
+
+/// Drives a run from the Dev chunks of its answer: keeps the store current
+/// and produces the run's events.
+struct RunDriver {
+    store: AssistantsStore,
+    usage: Arc<UsageTracker>,
+    billing: Billing,
+    /// The request, for its billing record.
+    completion: CompletionInfo,
+    started: Instant,
+    owner: String,
+    thread_id: String,
+    run_id: String,
+    message_id: String,
+    model: String,
+    chunks: BoxStream<'static, anyhow::Result<ChatCompletionChunk>>,
+    snapshot: mpsc::UnboundedReceiver<SseAccumulator>,
+    queued: VecDeque<RunEvent>,
+    text: String,
+    done: bool,
+}
+
+impl RunDriver {
+    async fn events(mut self, run: Run, message: Message) -> BoxStream<'static, RunEvent> {
+        self.queued.push_back(("thread.run.created", json!(run)));
+        let started_at = now_secs();
+        let started = self.store.update_run(&self.thread_id, &self.run_id, |t, run, _| {
+            t.runs[run].status = RunStatus::InProgress;
+            t.runs[run].started_at = Some(started_at);
+        });
+        let started = started.await;
+        if let Some((run, _)) = started {
+            self.queued.push_back(("thread.run.in_progress", json!(run)));
+        }
+        self.queued.push_back(("thread.message.created", json!(message)));
+        self.queued.push_back(("thread.message.in_progress", json!(message)));
+
+        futures_util::stream::unfold(self, |mut driver| async move {
+            loop {
+                if let Some(event) = driver.queued.pop_front() {
+                    return Some((event, driver));
+                }
+                if driver.done {
+                    return None;
+                }
+                match driver.chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        let content = chunk.choices.first().and_then(|c| c.delta.content.clone()).unwrap_or_default();
+                        if let Some(error) = content.strip_prefix(STREAM_ERROR_PREFIX) {
+                            driver.fail(error.to_string()).await;
+                        } else if !content.is_empty() {
+                            driver.text.push_str(&content);
+                            let delta = json!({
+                                "id": driver.message_id,
+                                "object": "thread.message.delta",
+                                "delta": { "content": [{ "index": 0, "type": "text", "text": { "value": content } }] },
+                            });
+                            return Some((("thread.message.delta", delta), driver));
+                        }
+                    }
+                    Some(Err(e)) => driver.fail(format!("{:#}", e)).await,
+                    None => driver.complete().await,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    async fn complete(&mut self) {
+        self.done = true;
+        // The processor hands over the accumulator before its stream ends
+        let dev_thread_id = self.snapshot.try_recv().ok().and_then(|a| a.thread_id);
+        let completion_tokens = tokenizer::count_tokens(&self.text) as u64;
+        self.usage.record_completion(&self.owner, &self.model, completion_tokens);
+        bill(&self.billing, &self.completion, self.started, Outcome::Ok, completion_tokens);
+        let text = std::mem::take(&mut self.text);
+        let completed_at = now_secs();
+        let finished = self.store.update_run(&self.thread_id, &self.run_id, |t, run, message| {
+            if dev_thread_id.is_some() {
+                t.dev_thread_id = dev_thread_id.clone();
+            }
+            t.runs[run].status = RunStatus::Completed;
+            t.runs[run].completed_at = Some(completed_at);
+            if let Some(message) = message {
+                t.messages[message].status = "completed".to_string();
+                t.messages[message].content = text_content(&text);
+            }
+        });
+        let finished = finished.await;
+        if let Some((run, message)) = finished {
+            self.queued.extend(message.map(|m| ("thread.message.completed", json!(m))));
+            self.queued.push_back(("thread.run.completed", json!(run)));
+        }
+    }
+
+    async fn fail(&mut self, error: String) {
+        self.done = true;
+        warn!(run_id = self.run_id, error, "Assistants run failed");
+        bill(&self.billing, &self.completion, self.started, Outcome::StreamError, tokenizer::count_tokens(&self.text) as u64);
+        if let Some(run) = self.store.fail_run(&self.thread_id, &self.run_id, error).await {
+            self.queued.push_back(("thread.run.failed", json!(run)));
+        }
+    }
+}
+
+/// `POST /v1/assistants`
+#[utoipa::path(post, path = "/v1/assistants", tag = "assistants", security(("api_key" = [])),
+    request_body(content = Object, description = "`model`, optional `name`, `description`, `instructions`, `metadata`"),
+    responses((status = 200, description = "The assistant", body = Object), (status = 507, body = ErrorBody)),
+)]
+pub async fn create_assistant_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Json(req): Json<CreateAssistant>,
+) -> Response {
+    match store.create_assistant(api_key_id.as_str(), req).await {
+        Ok(assistant) => Json(assistant).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /v1/assistants`
+#[utoipa::path(get, path = "/v1/assistants", tag = "assistants", security(("api_key" = [])), params(ListQuery),
+    responses((status = 200, description = "`{\"object\": \"list\", \"data\": [assistant]}`", body = Object)),
+)]
+pub async fn list_assistants_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    match store.assistants(api_key_id.as_str()).await {
+        Ok(assistants) => list_response(assistants, &query, |a| &a.id, false).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /v1/assistants/{id}`
+#[utoipa::path(get, path = "/v1/assistants/{id}", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Assistant id")),
+    responses((status = 200, description = "The assistant", body = Object), (status = 404, body = ErrorBody)),
+)]
+pub async fn get_assistant_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    match store.assistant(api_key_id.as_str(), &id).await {
+        Ok(assistant) => Json(assistant).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `DELETE /v1/assistants/{id}`
+#[utoipa::path(delete, path = "/v1/assistants/{id}", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Assistant id")),
+    responses((status = 200, description = "`assistant.deleted`", body = Object), (status = 404, body = ErrorBody)),
+)]
+pub async fn delete_assistant_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(e) = store.delete_assistant(api_key_id.as_str(), &id).await {
+        return e.into_response();
+    }
+    Json(json!({ "id": id, "object": "assistant.deleted", "deleted": true })).into_response()
+}
+
+/// `POST /v1/threads`
+#[utoipa::path(post, path = "/v1/threads", tag = "assistants", security(("api_key" = [])),
+    request_body(content = Object, description = "Optional `messages` and `metadata`"),
+    responses((status = 200, description = "The thread", body = Object), (status = 507, body = ErrorBody)),
+)]
+pub async fn create_thread_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    body: Option<Json<CreateThread>>,
+) -> Response {
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    match store.create_thread(api_key_id.as_str(), req).await {
+        Ok(thread) => Json(thread).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /v1/threads/{id}` for a `thread_` id.
+pub async fn get_thread(store: &AssistantsStore, owner: &str, id: &str) -> Response {
+    match store.thread(owner, id).await {
+        Ok(thread) => Json(thread).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `DELETE /v1/threads/{id}` for a `thread_` id.
+pub async fn delete_thread(store: &AssistantsStore, owner: &str, id: &str) -> Response {
+    if let Err(e) = store.delete_thread(owner, id).await {
+        return e.into_response();
+    }
+    Json(json!({ "id": id, "object": "thread.deleted", "deleted": true })).into_response()
+}
+
+/// `POST /v1/threads/{id}/messages`
+#[utoipa::path(post, path = "/v1/threads/{id}/messages", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Thread id")),
+    request_body(content = Object, description = "`role` (`user` or `assistant`), `content`, optional `metadata`"),
+    responses((status = 200, description = "The message", body = Object), (status = 404, body = ErrorBody)),
+)]
+pub async fn create_message_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(thread_id): Path<String>,
+    Json(req): Json<CreateMessage>,
+) -> Response {
+    match store.add_message(api_key_id.as_str(), &thread_id, req).await {
+        Ok(message) => Json(message).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /v1/threads/{id}/messages`
+#[utoipa::path(get, path = "/v1/threads/{id}/messages", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Thread id"), ListQuery),
+    responses((status = 200, description = "`{\"object\": \"list\", \"data\": [message]}`", body = Object), (status = 404, body = ErrorBody)),
+)]
+pub async fn list_messages_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(thread_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    match store.messages(api_key_id.as_str(), &thread_id).await {
+        Ok(messages) => list_response(messages, &query, |m| &m.id, true).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `POST /v1/threads/{id}/runs`: the run object, or its events with
+/// `"stream": true`.
+#[utoipa::path(post, path = "/v1/threads/{id}/runs", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Thread id")),
+    request_body(content = Object, description = "`assistant_id`, optional `model`, `instructions`, `additional_instructions`, `stream`"),
+    responses(
+        (status = 200, description = "The run, or with `stream` the Assistants events", body = Object),
+        (status = 400, description = "An active run, or no new user messages", body = ErrorBody),
+        (status = 404, body = ErrorBody), (status = 502, body = ErrorBody),
+    ),
+)]
+pub async fn create_run_handler(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    access: Option<Extension<ModelAccess>>,
+    Path(thread_id): Path<String>,
+    Json(req): Json<CreateRun>,
+) -> Response {
+    let AppState { upstreams, assistants, modes, system_prompts, moderation, usage, billing, citations, model_catalog, .. } = state;
+    let owner = api_key_id.as_str();
+    let assistant = match assistants.assistant(owner, &req.assistant_id).await {
+        Ok(assistant) => assistant,
+        Err(e) => return e.into_response(),
+    };
+    let model = req.model.unwrap_or(assistant.model);
+    if let Some(Extension(access)) = &access
+        && let Err(e) = access.check(Some(&model))
+    {
+        return e.into_response();
+    }
+    if let Err(e) = model_catalog.check(Some(&model)) {
+        return e.into_response();
+    }
+    let instructions = match (req.instructions.or(assistant.instructions), req.additional_instructions) {
+        (Some(base), Some(extra)) => Some(format!("{}\n\n{}", base.trim_end(), extra)),
+        (base, extra) => base.or(extra),
+    };
+    let started = match assistants.start_run(owner, &thread_id, &assistant.id, model.clone(), instructions.clone()).await {
+        Ok(started) => started,
+        Err(e) => return e.into_response(),
+    };
+    let run_id = started.run.id.clone();
+    info!(thread_id, run_id, model, "Starting Assistants run");
+
+    // A new Dev thread gets the instructions; a continued one has them
+    let prompt = match (&started.dev_thread_id, &instructions) {
+        (Some(_), _) => started.prompt.clone(),
+        (None, Some(instructions)) => format!("{}\n\n{}", instructions.trim_end(), started.prompt),
+        (None, None) => system_prompts.apply(owner, Some(&model), started.prompt.clone()),
+    };
+    if let Err(e) = moderation.check_prompt(&prompt).await {
+        assistants.fail_run(&thread_id, &run_id, "The prompt was flagged by the content filter".to_string()).await;
+        return e.into_response();
+    }
+
+    let (dev_model, mode) = modes.resolve(Some(&model));
+    let mut options = DevRequestOptions {
+        model: dev_model,
+        request_id: Some(request_id::as_string(&request_id)),
+        citations,
+        ..Default::default()
+    };
+    mode.apply(&mut options);
+    options.thread_id = started.dev_thread_id.clone().or(options.thread_id);
+    let (snapshot_tx, snapshot_rx) = mpsc::unbounded_channel();
+    options.snapshots.push(snapshot_tx);
+
+    let prompt_tokens = tokenizer::count_tokens(&prompt) as u64;
+    usage.record_request(owner, &model, prompt_tokens);
+    let completion = CompletionInfo {
+        request_id: request_id::as_string(&request_id),
+        model: Some(model.clone()),
+        api_key_id: owner.to_string(),
+        prompt_tokens,
+    };
+    let started_at = Instant::now();
+    let routed = match upstreams.send(&prompt, options.clone()).await {
+        Ok(routed) => routed,
+        Err(e) => {
+            bill(&billing, &completion, started_at, Outcome::UpstreamError, 0);
+            assistants.fail_run(&thread_id, &run_id, format!("{:#}", e)).await;
+            return ApiError::new(StatusCode::BAD_GATEWAY, "server_error", format!("Failed to contact backend service: {}", e))
+                .into_response();
+        }
+    };
+    // The request's citation mode and footers apply; the rest of the chain
+    // belongs to /v1/chat/completions
+    let context = ChoiceContext::attach(&mut options);
+    let chunks = process_dev_bytes_stream_unfold(routed.response.bytes_stream(), options, sse_processor::new_completion_id());
+    let chunks = TransformChain::request_options().apply(chunks, &context);
+
+    // The run is driven to its end even if the client goes away
+    let driver = RunDriver {
+        store: assistants.clone(),
+        usage,
+        billing,
+        completion,
+        started: started_at,
+        owner: owner.to_string(),
+        thread_id: thread_id.clone(),
+        run_id: run_id.clone(),
+        message_id: started.message.id.clone(),
+        model,
+        chunks,
+        snapshot: snapshot_rx,
+        queued: VecDeque::new(),
+        text: String::new(),
+        done: false,
+    };
+    let mut events = driver.events(started.run.clone(), started.message).await;
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            let _ = tx.send(event);
+        }
+    });
+    if !req.stream {
+        return Json(started.run).into_response();
+    }
+    let events = futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) })
+        .map(|(name, data)| SseEvent::default().event(name).data(data.to_string()))
+        .chain(futures_util::stream::once(async { SseEvent::default().event("done").data("[DONE]") }))
+        .map(Ok::<_, Infallible>);
+    Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15))).into_response()
+}
+
+/// `GET /v1/threads/{id}/runs/{run_id}`
+#[utoipa::path(get, path = "/v1/threads/{id}/runs/{run_id}", tag = "assistants", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Thread id"), ("run_id" = String, Path, description = "Run id")),
+    responses((status = 200, description = "The run", body = Object), (status = 404, body = ErrorBody)),
+)]
+pub async fn get_run_handler(
+    State(store): State<AssistantsStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path((thread_id, run_id)): Path<(String, String)>,
+) -> Response {
+    match store.run(api_key_id.as_str(), &thread_id, &run_id).await {
+        Ok(run) => Json(run).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    fn message(text: &str) -> CreateMessage {
+        CreateMessage { role: "user".to_string(), content: MessageContent::Text(text.to_string()), metadata: Metadata::new() }
+    }
+
+    async fn thread(store: &AssistantsStore) -> Thread {
+        let create = CreateThread { messages: vec![message("Hi")], metadata: Metadata::new() };
+        store.create_thread("key_a", create).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_runs_send_new_user_messages() {
+        let store = AssistantsStore::new(StateStore::default(), 10);
+        let thread = thread(&store).await;
+        store.add_message("key_a", &thread.id, message("How are you?")).await.unwrap();
+        assert!(store.thread("key_b", &thread.id).await.is_err());
+
+        let started = store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.unwrap();
+        assert_eq!(started.prompt, "Hi\n\nHow are you?");
+        // One run at a time, and only for new messages
+        assert!(store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.is_err());
+        store.fail_run(&thread.id, &started.run.id, "boom".to_string()).await;
+        assert!(store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.is_err());
+        store.add_message("key_a", &thread.id, message("Again")).await.unwrap();
+        assert_eq!(store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.unwrap().prompt, "Again");
+    }
+
+    #[tokio::test]
+    async fn test_objects_are_counted_against_the_limit() {
+        let store = AssistantsStore::new(StateStore::default(), 2);
+        let create = || CreateAssistant { model: "m".to_string(), name: None, description: None, instructions: None, metadata: Metadata::new() };
+        let assistant = store.create_assistant("key_a", create()).await.unwrap();
+        let thread = thread(&store).await;
+        let full = store.create_assistant("key_a", create()).await.unwrap_err();
+        assert_eq!(full.code, Some("assistants_storage_full"));
+        assert!(store.delete_assistant("key_b", &assistant.id).await.is_err());
+        store.delete_assistant("key_a", &assistant.id).await.unwrap();
+        assert!(store.assistants("key_a").await.unwrap().is_empty());
+        store.create_assistant("key_a", create()).await.unwrap();
+        store.delete_thread("key_a", &thread.id).await.unwrap();
+        assert_eq!(store.thread("key_a", &thread.id).await.unwrap_err().status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_run_events() {
+        let store = AssistantsStore::new(StateStore::default(), 10);
+        let thread = thread(&store).await;
+        let started = store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.unwrap();
+        let (snapshot_tx, snapshot_rx) = mpsc::unbounded_channel();
+        let mut accumulator = SseAccumulator::default();
+        accumulator.thread_id = Some("dev-thread".to_string());
+        snapshot_tx.send(accumulator).unwrap();
+        let driver = RunDriver {
+            store: store.clone(),
+            usage: Arc::new(UsageTracker::new(None, Default::default())),
+            billing: Billing::default(),
+            completion: CompletionInfo {
+                request_id: "req-1".to_string(),
+                model: Some("m".to_string()),
+                api_key_id: "key_a".to_string(),
+                prompt_tokens: 1,
+            },
+            started: Instant::now(),
+            owner: "key_a".to_string(),
+            thread_id: thread.id.clone(),
+            run_id: started.run.id.clone(),
+            message_id: started.message.id.clone(),
+            model: "m".to_string(),
+            chunks: futures_util::stream::iter([Ok(test_chunk(Some("Hel"), None)), Ok(test_chunk(Some("lo"), None))]).boxed(),
+            snapshot: snapshot_rx,
+            queued: VecDeque::new(),
+            text: String::new(),
+            done: false,
+        };
+        let events: Vec<RunEvent> = driver.events(started.run.clone(), started.message).await.collect().await;
+        let names: Vec<&str> = events.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, [
+            "thread.run.created",
+            "thread.run.in_progress",
+            "thread.message.created",
+            "thread.message.in_progress",
+            "thread.message.delta",
+            "thread.message.delta",
+            "thread.message.completed",
+            "thread.run.completed",
+        ]);
+        assert_eq!(events[6].1["content"][0]["text"]["value"], "Hello");
+        assert_eq!(store.run("key_a", &thread.id, &started.run.id).await.unwrap().status, RunStatus::Completed);
+
+        // The next run continues the Dev thread
+        store.add_message("key_a", &thread.id, message("More")).await.unwrap();
+        let next = store.start_run("key_a", &thread.id, "asst_1", "m".to_string(), None).await.unwrap();
+        assert_eq!(next.dev_thread_id.as_deref(), Some("dev-thread"));
+    }
+}
@@ -0,0 +1,199 @@
+// Audit log: who asked what and when. Prompts pass through configurable
+// redaction before they are recorded, so compliance logging does not mean
+// storing raw user content. Events use the `audit_log` target, which the
+// telemetry setup can route to a dedicated file.
+
+use regex::Regex;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::utils;
+
+/// Tracing target used for audit events.
+pub const TARGET: &str = "audit_log";
+
+const REDACTED: &str = "[REDACTED]";
+
+// Applied unless AUDIT_REDACT_BUILTINS=false
+const BUILTIN_PATTERNS: &[&str] = &[
+    // Email addresses
+    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+    // OpenAI-style secret keys
+    r"\bsk-[A-Za-z0-9_-]{16,}",
+    // Bearer tokens
+    r"(?i)\bbearer\s+[A-Za-z0-9._~+/-]+=*",
+    // AWS access key ids
+    r"\bAKIA[0-9A-Z]{16}\b",
+];
+
+/// How the prompt text is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptMode {
+    /// Only the salted SHA-256 of the prompt (default).
+    Hash,
+    /// The prompt with every redaction rule applied.
+    Redact,
+    /// The prompt verbatim; only for trusted, non-regulated deployments.
+    Raw,
+    /// Nothing about the prompt except its length.
+    Omit,
+}
+
+impl FromStr for PromptMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hash" => Ok(Self::Hash),
+            "redact" => Ok(Self::Redact),
+            "raw" => Ok(Self::Raw),
+            "omit" | "none" => Ok(Self::Omit),
+            other => Err(format!("unknown prompt mode '{}', expected hash, redact, raw or omit", other)),
+        }
+    }
+}
+
+/// Replaces every match of the configured patterns with `[REDACTED]`.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(rules: Vec<Regex>) -> Self {
+        Self { rules }
+    }
+
+    pub fn builtin() -> Self {
+        Self::new(BUILTIN_PATTERNS.iter().map(|p| Regex::new(p).expect("valid builtin pattern")).collect())
+    }
+
+    pub fn with_rule(mut self, rule: Regex) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for rule in &self.rules {
+            if rule.is_match(&text) {
+                text = rule.replace_all(&text, REDACTED).into_owned();
+            }
+        }
+        text
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    enabled: bool,
+    prompt_mode: PromptMode,
+    redactor: Redactor,
+    hash_salt: String,
+}
+
+/// One audited chat request.
+#[derive(Debug)]
+pub struct AuditRecord<'a> {
+    pub request_id: &'a str,
+    pub subject: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub message_count: usize,
+    pub prompt: &'a str,
+}
+
+impl AuditLog {
+    pub fn new(enabled: bool, prompt_mode: PromptMode, redactor: Redactor, hash_salt: String) -> Self {
+        Self { enabled, prompt_mode, redactor, hash_salt }
+    }
+
+    pub fn from_env() -> Self {
+        let mut redactor = if env_or("AUDIT_REDACT_BUILTINS", true) { Redactor::builtin() } else { Redactor::default() };
+        if let Some(pattern) = std::env::var("AUDIT_REDACT_REGEX").ok().filter(|v| !v.is_empty()) {
+            match Regex::new(&pattern) {
+                Ok(rule) => redactor = redactor.with_rule(rule),
+                Err(e) => warn!(error = %e, "Invalid AUDIT_REDACT_REGEX, ignoring"),
+            }
+        }
+        let audit = Self::new(
+            env_or("AUDIT_LOG", false),
+            env_or("AUDIT_PROMPT_MODE", PromptMode::Hash),
+            redactor,
+            std::env::var("AUDIT_HASH_SALT").unwrap_or_default(),
+        );
+        info!(enabled = audit.enabled, prompt_mode = ?audit.prompt_mode, rules = audit.redactor.rules.len(), "Audit log configured");
+        audit
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The prompt as it will appear in the audit log.
+    pub fn render_prompt(&self, prompt: &str) -> Option<String> {
+        match self.prompt_mode {
+            PromptMode::Hash => {
+                let salted = format!("{}{}", self.hash_salt, prompt);
+                Some(format!("sha256:{}", utils::sha256_hex(salted.as_bytes())))
+            }
+            PromptMode::Redact => Some(self.redactor.redact(prompt)),
+            PromptMode::Raw => Some(prompt.to_string()),
+            PromptMode::Omit => None,
+        }
+    }
+
+    pub fn record(&self, record: AuditRecord<'_>) {
+        if !self.enabled {
+            return;
+        }
+        let prompt = self.render_prompt(record.prompt);
+        info!(
+            target: TARGET,
+            request_id = record.request_id,
+            subject = record.subject.unwrap_or("anonymous"),
+            model = record.model.unwrap_or("-"),
+            message_count = record.message_count,
+            prompt_chars = record.prompt.chars().count(),
+            prompt = prompt.as_deref().unwrap_or("-"),
+            "chat request"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_redaction() {
+        let redactor = Redactor::builtin();
+        let text = "mail me at jane.doe@example.com with key sk-abcdefghijklmnop1234 or Bearer abc.def";
+        assert_eq!(
+            redactor.redact(text),
+            "mail me at [REDACTED] with key [REDACTED] or [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_custom_rule() {
+        let redactor = Redactor::default().with_rule(Regex::new(r"\d{3}-\d{2}-\d{4}").unwrap());
+        assert_eq!(redactor.redact("ssn 123-45-6789"), "ssn [REDACTED]");
+    }
+
+    #[test]
+    fn test_render_prompt_modes() {
+        let audit = |mode| AuditLog::new(true, mode, Redactor::builtin(), "salt".to_string());
+        let hashed = audit(PromptMode::Hash).render_prompt("hello").unwrap();
+        assert_eq!(hashed, format!("sha256:{}", utils::sha256_hex(b"salthello")));
+        assert_eq!(audit(PromptMode::Redact).render_prompt("a@b.io").unwrap(), "[REDACTED]");
+        assert_eq!(audit(PromptMode::Raw).render_prompt("a@b.io").unwrap(), "a@b.io");
+        assert_eq!(audit(PromptMode::Omit).render_prompt("a@b.io"), None);
+    }
+
+    #[test]
+    fn test_prompt_mode_parse() {
+        assert_eq!("REDACT".parse::<PromptMode>(), Ok(PromptMode::Redact));
+        assert!("plain".parse::<PromptMode>().is_err());
+    }
+}
@@ -0,0 +1,556 @@
+// Bearer-token authentication, mirroring the dev_proxy middleware: client
+// keys come from ALLOWED_API_KEYS (comma separated), the admin API is guarded
+// by ADMIN_TOKEN, an OIDC login session (see `oidc`) or the basic auth users
+// of ADMIN_BASIC_AUTH: comma-separated `user:bcrypt-hash` entries, as
+// `htpasswd -nbB user password` prints them. With basic auth users set,
+// `/metrics` requires the admin credentials too. Keys are never logged; each
+// key is identified by a short hash-derived id that is safe to put in logs
+// and usage reports. Keys created at runtime come from the key store
+// (KEYS_DB), with their token quotas checked against the usage accounting,
+// and JWTs from an identity provider are verified by `jwt_auth`.
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header;
+use base64::Engine as _;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::access_log::AccessLogContext;
+use crate::config::parse_list;
+use crate::error::ApiError;
+use crate::jwt_auth::{self, JwtAuth};
+use crate::key_store::{KeyStore, TokenQuota};
+use crate::oidc::Oidc;
+use crate::state_store::StateStore;
+use crate::usage::UsageTracker;
+use crate::utils;
+
+/// Identifier of the authenticated client key, inserted into the request
+/// extensions by `require_api_key`. "anonymous" when auth is disabled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApiKeyId(pub String);
+
+impl ApiKeyId {
+    pub fn anonymous() -> Self {
+        Self("anonymous".to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The models a client is limited to, inserted into the request extensions
+/// next to its `ApiKeyId` when it is limited.
+#[derive(Debug, Clone)]
+pub struct ModelAccess {
+    models: Arc<Vec<String>>,
+    /// What set the limit, for the error message.
+    source: &'static str,
+}
+
+impl ModelAccess {
+    pub fn new(models: Vec<String>, source: &'static str) -> Self {
+        Self { models: Arc::new(models), source }
+    }
+
+    /// The limit to `models`, none if they include `*`.
+    pub fn restrict(models: Vec<String>, source: &'static str) -> Option<Self> {
+        (!models.iter().any(|m| m == "*")).then(|| Self::new(models, source))
+    }
+
+    pub fn allows(&self, model: &str) -> bool {
+        self.models.iter().any(|m| m == model)
+    }
+
+    /// Refuses models outside the client's list, and requests naming none.
+    pub fn check(&self, model: Option<&str>) -> Result<(), ApiError> {
+        if model.is_some_and(|model| self.allows(model)) {
+            return Ok(());
+        }
+        let allowed = if self.models.is_empty() { "none".to_string() } else { self.models.join(", ") };
+        let message = match model {
+            Some(model) => format!("The model '{}' is not allowed by the {} (allowed: {})", model, self.source, allowed),
+            None => format!("A model allowed by the {} is required (allowed: {})", self.source, allowed),
+        };
+        Err(ApiError::forbidden(message).with_code("model_not_allowed").with_param("model"))
+    }
+}
+
+/// A client `ApiKeys::authenticate` accepted.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: ApiKeyId,
+    /// The models it is limited to.
+    pub access: Option<ModelAccess>,
+    /// What is left of its token budgets.
+    pub quota: Option<TokenQuota>,
+}
+
+impl Client {
+    fn new(id: ApiKeyId) -> Self {
+        Self { id, access: None, quota: None }
+    }
+}
+
+/// Derives the public id of a key: `key_` plus the first 12 hex digits of
+/// its SHA-256.
+pub fn key_id(key: &str) -> String {
+    format!("key_{}", &utils::sha256_hex(key.as_bytes())[..12])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    // SHA-256 of the key -> key id
+    by_hash: Arc<HashMap<String, ApiKeyId>>,
+    store: KeyStore,
+    jwt: JwtAuth,
+    /// Counts the tokens the store keys' quotas budget.
+    usage: Option<Arc<UsageTracker>>,
+}
+
+impl ApiKeys {
+    pub fn new<I: IntoIterator<Item = String>>(keys: I) -> Self {
+        let by_hash = keys
+            .into_iter()
+            .map(|key| (utils::sha256_hex(key.as_bytes()), ApiKeyId(key_id(&key))))
+            .collect();
+        Self { by_hash: Arc::new(by_hash), store: KeyStore::default(), jwt: JwtAuth::default(), usage: None }
+    }
+
+    /// Also accepts the active keys of `store`.
+    pub fn with_store(mut self, store: KeyStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Also accepts the JWTs `jwt` verifies.
+    pub fn with_jwt(mut self, jwt: JwtAuth) -> Self {
+        self.jwt = jwt;
+        self
+    }
+
+    /// Enforces the store keys' token quotas with the totals of `usage`.
+    pub fn with_usage(mut self, usage: Arc<UsageTracker>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    pub fn from_env(store: KeyStore, usage: Arc<UsageTracker>) -> Self {
+        let keys = Self::new(parse_list(&std::env::var("ALLOWED_API_KEYS").unwrap_or_default()))
+            .with_store(store)
+            .with_jwt(JwtAuth::from_env())
+            .with_usage(usage);
+        if keys.is_enabled() {
+            info!(
+                count = keys.by_hash.len(),
+                key_store = keys.store.is_enabled(),
+                jwt = keys.jwt.is_enabled(),
+                "API key authentication enabled"
+            );
+        } else {
+            warn!("ALLOWED_API_KEYS, KEYS_DB and JWT_JWKS_URL are all unset, API key authentication is disabled");
+        }
+        keys
+    }
+
+    /// Without configured keys, a key store or JWTs every request is
+    /// accepted as anonymous.
+    pub fn is_enabled(&self) -> bool {
+        !self.by_hash.is_empty() || self.store.is_enabled() || self.jwt.is_enabled()
+    }
+
+    /// The client `token` identifies, or why it is refused: unknown,
+    /// invalid, out of token quota or over its rate limit.
+    pub async fn authenticate(&self, token: &str) -> Result<Client, ApiError> {
+        if self.jwt.is_enabled() && jwt_auth::is_jwt(token) {
+            let (id, access) = self.jwt.verify(token).await?;
+            return Ok(Client { access, ..Client::new(id) });
+        }
+        if let Some(id) = self.by_hash.get(&utils::sha256_hex(token.as_bytes())) {
+            return Ok(Client::new(id.clone()));
+        }
+        let Some(key) = self.store.lookup(token) else {
+            warn!("Invalid API key provided");
+            return Err(ApiError::unauthorized("Incorrect API key provided."));
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let quota = self.usage.as_ref().and_then(|usage| key.settings.remaining_tokens(&usage.period_tokens(&key.id, now)));
+        if let Some(quota) = quota.filter(TokenQuota::is_exhausted) {
+            let period = if quota.daily == Some(0) { "daily" } else { "monthly" };
+            warn!(api_key_id = key.id, period, "API key out of token quota");
+            return Err(ApiError::new(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                "insufficient_quota",
+                format!("You exceeded the {} token quota of {}.", period, key.id),
+            )
+            .with_code("insufficient_quota"));
+        }
+        if let Err(retry_after) = self.store.check_rate(&key, now).await {
+            warn!(api_key_id = key.id, "API key over its rate limit");
+            return Err(ApiError::new(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                "requests",
+                format!("Rate limit reached for {}: {} requests per minute", key.id, key.settings.requests_per_minute.unwrap_or_default()),
+            )
+            .with_code("rate_limit_exceeded")
+            .with_retry_after(retry_after));
+        }
+        let access = key.settings.models.and_then(|models| ModelAccess::restrict(models, "API key's model allowlist"));
+        Ok(Client { access, quota, ..Client::new(ApiKeyId(key.id)) })
+    }
+}
+
+/// The basic auth users of the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct BasicAuth {
+    /// User -> bcrypt hash of the password.
+    users: Arc<HashMap<String, String>>,
+    /// SHA-256 of credentials that passed, as bcrypt is slow on purpose.
+    verified: Arc<Mutex<HashSet<String>>>,
+}
+
+impl BasicAuth {
+    /// `entries` are `user:bcrypt-hash`.
+    pub fn new<I: IntoIterator<Item = String>>(entries: I) -> Self {
+        let users = entries
+            .into_iter()
+            .filter_map(|entry| match entry.split_once(':') {
+                Some((user, hash)) if !user.is_empty() && hash.starts_with("$2") => Some((user.to_string(), hash.to_string())),
+                _ => {
+                    warn!("Ignoring an ADMIN_BASIC_AUTH entry, expected user:bcrypt-hash");
+                    None
+                }
+            })
+            .collect();
+        Self { users: Arc::new(users), verified: Arc::default() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// The user whose `user:password` these are, if the password matches.
+    pub async fn verify(&self, user: &str, password: &str) -> Option<String> {
+        let hash = self.users.get(user)?.clone();
+        let fingerprint = utils::sha256_hex(format!("{}:{}", user, password).as_bytes());
+        if self.verified.lock().unwrap().contains(&fingerprint) {
+            return Some(user.to_string());
+        }
+        let password = password.to_string();
+        let matches = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap_or(false);
+        if !matches {
+            return None;
+        }
+        self.verified.lock().unwrap().insert(fingerprint);
+        Some(user.to_string())
+    }
+}
+
+/// Credentials for the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct AdminAuth {
+    token_hash: Option<Arc<String>>,
+    oidc: Option<Arc<Oidc>>,
+    basic: BasicAuth,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token_hash: token.filter(|t| !t.is_empty()).map(|t| Arc::new(utils::sha256_hex(t.as_bytes()))),
+            oidc: None,
+            basic: BasicAuth::default(),
+        }
+    }
+
+    /// Also accepts the users of `basic`, and protects `/metrics` with them.
+    pub fn with_basic(mut self, basic: BasicAuth) -> Self {
+        self.basic = basic;
+        self
+    }
+
+    /// Also accepts the sessions of `oidc` logins.
+    pub fn with_oidc(mut self, oidc: Oidc) -> Self {
+        self.oidc = Some(Arc::new(oidc));
+        self
+    }
+
+    /// OIDC logins keep their sessions in `store`.
+    pub fn from_env(store: &StateStore) -> Self {
+        let basic = BasicAuth::new(parse_list(&std::env::var("ADMIN_BASIC_AUTH").unwrap_or_default()));
+        let mut auth = Self::new(std::env::var("ADMIN_TOKEN").ok()).with_basic(basic);
+        if let Some(oidc) = Oidc::from_env(store) {
+            auth = auth.with_oidc(oidc);
+        }
+        if auth.basic.is_enabled() {
+            info!(users = auth.basic.users.len(), "Basic auth enabled for the admin API and /metrics");
+        }
+        if !auth.is_enabled() {
+            info!("None of ADMIN_TOKEN, OIDC_ISSUER and ADMIN_BASIC_AUTH is set, admin API is disabled");
+        }
+        auth
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.token_hash.is_some() || self.oidc.is_some() || self.basic.is_enabled()
+    }
+
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_deref()
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        self.token_hash.as_deref().is_some_and(|hash| *hash == utils::sha256_hex(token.as_bytes()))
+    }
+}
+
+/// Extracts the token from `Authorization: Bearer <token>`.
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer ").or_else(|| v.strip_prefix("bearer ")))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+}
+
+/// Extracts the user and password from `Authorization: Basic <base64>`.
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let encoded = request.headers().get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let (user, password) = String::from_utf8(decoded).ok()?.split_once(':').map(|(u, p)| (u.to_string(), p.to_string()))?;
+    Some((user, password))
+}
+
+/// Middleware for client routes: validates the bearer key and records its id
+/// for handlers and the access log. Responses to keys with token quotas get
+/// the tokens left as of the request.
+pub async fn require_api_key(State(keys): State<ApiKeys>, mut request: Request, next: Next) -> Response {
+    let Client { id: key_id, access, quota } = if keys.is_enabled() {
+        let Some(token) = bearer_token(&request).map(str::to_string) else {
+            warn!("Missing bearer token");
+            return ApiError::unauthorized("Missing API key. Provide it as 'Authorization: Bearer <key>'.").into_response();
+        };
+        match keys.authenticate(&token).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        Client::new(ApiKeyId::anonymous())
+    };
+
+    debug!(api_key_id = key_id.as_str(), "Authenticated request");
+    if let Some(access_log) = request.extensions().get::<AccessLogContext>() {
+        access_log.set_api_key_id(key_id.as_str());
+    }
+    request.extensions_mut().insert(key_id);
+    if let Some(access) = access {
+        request.extensions_mut().insert(access);
+    }
+    let mut response = next.run(request).await;
+    for (name, remaining) in quota.iter().flat_map(TokenQuota::headers) {
+        response.headers_mut().insert(name, remaining.into());
+    }
+    response
+}
+
+/// Middleware for admin routes.
+pub async fn require_admin(State(admin): State<AdminAuth>, request: Request, next: Next) -> Response {
+    if !admin.is_enabled() {
+        return ApiError::forbidden("The admin API is disabled; set ADMIN_TOKEN, ADMIN_BASIC_AUTH or OIDC_ISSUER to enable it.").into_response();
+    }
+    let session = match admin.oidc() {
+        Some(oidc) => oidc.session(request.headers()).await,
+        None => None,
+    };
+    if let Some(subject) = session {
+        debug!(subject, "Admin request with a login session");
+        return next.run(request).await;
+    }
+    if admin.basic.is_enabled()
+        && let Some((user, password)) = basic_credentials(&request)
+    {
+        return match admin.basic.verify(&user, &password).await {
+            Some(user) => {
+                debug!(user, "Admin request with basic auth");
+                next.run(request).await
+            }
+            None => {
+                warn!(user, "Invalid admin basic auth credentials");
+                basic_challenge(ApiError::unauthorized("Incorrect username or password."))
+            }
+        };
+    }
+    match bearer_token(&request) {
+        Some(token) if admin.verify(token) => next.run(request).await,
+        Some(_) => {
+            warn!("Invalid admin token provided");
+            ApiError::unauthorized("Incorrect admin token provided.").into_response()
+        }
+        // The dashboard offers the login when it sees this code
+        None if admin.oidc.is_some() => {
+            ApiError::unauthorized("Missing admin token; sign in at /admin/login.").with_code("login_required").into_response()
+        }
+        None if admin.basic.is_enabled() => basic_challenge(ApiError::unauthorized("Missing admin credentials.")),
+        None => ApiError::unauthorized("Missing admin token.").into_response(),
+    }
+}
+
+/// A 401 that makes browsers ask for the basic auth user.
+fn basic_challenge(error: ApiError) -> Response {
+    let mut response = error.into_response();
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, http::HeaderValue::from_static("Basic realm=\"rust_proxy admin\""));
+    response
+}
+
+/// Middleware for `/metrics`: public unless basic auth users are set, then
+/// it takes the admin credentials.
+pub async fn require_metrics_auth(State(admin): State<AdminAuth>, request: Request, next: Next) -> Response {
+    if !admin.basic.is_enabled() {
+        return next.run(request).await;
+    }
+    require_admin(State(admin), request, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Extension, Router};
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    fn app(keys: ApiKeys) -> Router {
+        Router::new()
+            .route("/", get(|Extension(id): Extension<ApiKeyId>| async move { id.0 }))
+            .layer(middleware::from_fn_with_state(keys, require_api_key))
+    }
+
+    fn request(auth: Option<&str>) -> Request {
+        let mut builder = http::Request::builder().uri("/");
+        if let Some(auth) = auth {
+            builder = builder.header(header::AUTHORIZATION, auth);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_valid_key_is_identified() {
+        let keys = ApiKeys::new(vec!["sk-one".to_string(), "sk-two".to_string()]);
+        let response = app(keys).oneshot(request(Some("Bearer sk-two"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body), key_id("sk-two"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_and_invalid_keys_are_rejected() {
+        let keys = ApiKeys::new(vec!["sk-one".to_string()]);
+        let missing = app(keys.clone()).oneshot(request(None)).await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let invalid = app(keys).oneshot(request(Some("Bearer sk-nope"))).await.unwrap();
+        assert_eq!(invalid.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_no_keys_means_anonymous() {
+        let response = app(ApiKeys::default()).oneshot(request(None)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"anonymous");
+    }
+
+    #[tokio::test]
+    async fn test_store_keys_are_accepted_until_revoked() {
+        use crate::key_store::{CreateKey, KeySettings};
+        let store = KeyStore::open(":memory:").unwrap();
+        let settings = KeySettings { requests_per_minute: Some(1), ..Default::default() };
+        let (key, secret) = store.create(CreateKey { label: None, settings }).unwrap();
+        let keys = ApiKeys::new(vec!["sk-one".to_string()]).with_store(store.clone());
+        let bearer = format!("Bearer {}", secret);
+        let response = app(keys.clone()).oneshot(request(Some(&bearer))).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body), key.id);
+        let limited = app(keys.clone()).oneshot(request(Some(&bearer))).await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(limited.headers().contains_key(header::RETRY_AFTER));
+
+        store.revoke(&key.id).unwrap();
+        let revoked = app(keys).oneshot(request(Some(&bearer))).await.unwrap();
+        assert_eq!(revoked.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_store_key_model_allowlist() {
+        use crate::key_store::{CreateKey, KeySettings};
+        let store = KeyStore::open(":memory:").unwrap();
+        let keys = ApiKeys::default().with_store(store.clone());
+        let create = |models: Option<Vec<&str>>| {
+            let models = models.map(|models| models.into_iter().map(String::from).collect());
+            store.create(CreateKey { label: None, settings: KeySettings { models, ..Default::default() } }).unwrap().1
+        };
+
+        let access = keys.authenticate(&create(Some(vec!["gpt-4o"]))).await.unwrap().access.unwrap();
+        assert!(access.check(Some("gpt-4o")).is_ok());
+        let err = access.check(Some("claude-3-opus")).unwrap_err();
+        assert_eq!((err.status, err.code), (StatusCode::FORBIDDEN, Some("model_not_allowed")));
+        assert!(err.message.contains("API key's model allowlist"));
+        assert!(keys.authenticate(&create(Some(vec!["*"]))).await.unwrap().access.is_none());
+        assert!(keys.authenticate(&create(None)).await.unwrap().access.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_key_token_quota() {
+        use crate::key_store::{CreateKey, KeySettings, X_QUOTA_REMAINING_TOKENS_DAY};
+        use crate::usage::PriceTable;
+        let store = KeyStore::open(":memory:").unwrap();
+        let usage = Arc::new(UsageTracker::new(None, PriceTable::default()));
+        let keys = ApiKeys::default().with_store(store.clone()).with_usage(usage.clone());
+        let settings = KeySettings { tokens_per_day: Some(100), ..Default::default() };
+        let (key, secret) = store.create(CreateKey { label: None, settings }).unwrap();
+        let bearer = format!("Bearer {}", secret);
+
+        usage.record_request(&key.id, "gpt-4o", 60);
+        let response = app(keys.clone()).oneshot(request(Some(&bearer))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[X_QUOTA_REMAINING_TOKENS_DAY], "40");
+
+        usage.record_completion(&key.id, "gpt-4o", 45);
+        let exhausted = app(keys).oneshot(request(Some(&bearer))).await.unwrap();
+        assert_eq!(exhausted.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = axum::body::to_bytes(exhausted.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "insufficient_quota");
+    }
+
+    #[tokio::test]
+    async fn test_admin_basic_auth() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let admin = AdminAuth::new(Some("secret".to_string())).with_basic(BasicAuth::new(vec![format!("ops:{}", hash)]));
+        let app = Router::new()
+            .route("/", get(|| async { "metrics" }))
+            .layer(middleware::from_fn_with_state(admin, require_metrics_auth));
+        let basic = |credentials: &str| format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials));
+        let ok = app.clone().oneshot(request(Some(&basic("ops:hunter2")))).await.unwrap();
+        assert_eq!(ok.status(), StatusCode::OK);
+        let wrong = app.clone().oneshot(request(Some(&basic("ops:hunter3")))).await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let missing = app.clone().oneshot(request(None)).await.unwrap();
+        assert!(missing.headers().get(header::WWW_AUTHENTICATE).unwrap().to_str().unwrap().starts_with("Basic"));
+        // The admin token still works
+        let token = app.oneshot(request(Some("Bearer secret"))).await.unwrap();
+        assert_eq!(token.status(), StatusCode::OK);
+        assert!(!BasicAuth::new(vec!["ops:plaintext".to_string()]).is_enabled());
+    }
+
+    #[test]
+    fn test_admin_verify() {
+        assert!(!AdminAuth::new(None).verify("x"));
+        assert!(AdminAuth::new(Some("secret".to_string())).verify("secret"));
+        assert!(!AdminAuth::new(Some("secret".to_string())).verify("Secret"));
+    }
+}
@@ -0,0 +1,243 @@
+// Load generator behind `devchat bench`: fires `requests` streaming chat
+// completions at a proxy, `concurrency` at a time, and reports time to first
+// token, tokens per second and the error rate with percentiles, so capacity
+// limits and regressions can be measured. `mock_dev_router` is a Dev stand-in
+// that streams a fixed answer, for measuring the proxy without Dev.
+
+use anyhow::{bail, Context, Result};
+use axum::body::Body;
+use axum::response::Response;
+use axum::routing::post;
+use axum::Router;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http::{header, StatusCode};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::sse_parser::SseParser;
+use crate::tokenizer;
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Proxy base URL, without `/v1`.
+    pub url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    pub prompt: String,
+    pub requests: usize,
+    pub concurrency: usize,
+    /// Deadline for one request, from sending to the end of its stream.
+    pub timeout: Duration,
+}
+
+/// The outcome of one request.
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    /// Until the first content delta.
+    pub ttfb: Option<Duration>,
+    pub duration: Duration,
+    pub tokens: usize,
+    pub error: Option<String>,
+}
+
+impl Sample {
+    /// Tokens per second after the first one arrived.
+    fn tokens_per_sec(&self) -> Option<f64> {
+        let streaming = self.duration.checked_sub(self.ttfb?)?.as_secs_f64();
+        (self.tokens > 1 && streaming > 0.0).then(|| (self.tokens - 1) as f64 / streaming)
+    }
+}
+
+/// Runs the benchmark; requests that fail are samples with an error.
+pub async fn run(config: &BenchConfig) -> Report {
+    let client = reqwest::Client::new();
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..config.concurrency.clamp(1, config.requests.max(1)))
+        .map(|_| {
+            let (client, config, next) = (client.clone(), config.clone(), next.clone());
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                while next.fetch_add(1, Ordering::Relaxed) < config.requests {
+                    samples.push(one_request(&client, &config).await);
+                }
+                samples
+            })
+        })
+        .collect();
+    let mut samples = Vec::with_capacity(config.requests);
+    for worker in workers {
+        samples.extend(worker.await.unwrap_or_default());
+    }
+    Report { samples, elapsed: started.elapsed() }
+}
+
+async fn one_request(client: &reqwest::Client, config: &BenchConfig) -> Sample {
+    let started = Instant::now();
+    let mut sample = Sample::default();
+    let result = tokio::time::timeout(config.timeout, stream_one(client, config, started, &mut sample)).await;
+    sample.duration = started.elapsed();
+    sample.error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{:#}", e)),
+        Err(_) => Some(format!("timed out after {:?}", config.timeout)),
+    };
+    sample
+}
+
+async fn stream_one(client: &reqwest::Client, config: &BenchConfig, started: Instant, sample: &mut Sample) -> Result<()> {
+    let body = json!({
+        "model": config.model,
+        "stream": true,
+        "messages": [{ "role": "user", "content": config.prompt }],
+    });
+    let mut request = client.post(format!("{}/v1/chat/completions", config.url)).json(&body);
+    if let Some(key) = &config.api_key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await.context("connect")?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("HTTP {}", status.as_u16());
+    }
+    let mut text = String::new();
+    let mut parser = SseParser::new();
+    let mut bytes = response.bytes_stream();
+    'stream: while let Some(chunk) = bytes.next().await {
+        for event in parser.feed(&chunk.context("stream broke")?) {
+            if event.data.trim() == "[DONE]" {
+                break 'stream;
+            }
+            let chunk: Value = serde_json::from_str(&event.data).unwrap_or_default();
+            if chunk["error"].is_object() {
+                bail!("stream error");
+            }
+            if let Some(content) = chunk["choices"][0]["delta"]["content"].as_str().filter(|c| !c.is_empty()) {
+                sample.ttfb.get_or_insert_with(|| started.elapsed());
+                text.push_str(content);
+            }
+        }
+    }
+    sample.tokens = tokenizer::count_tokens(&text);
+    if sample.ttfb.is_none() {
+        bail!("no content");
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile `p` (0-100) of ascending `sorted`.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub samples: Vec<Sample>,
+    /// Wall time of the whole run.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn errors(&self) -> usize {
+        self.samples.iter().filter(|s| s.error.is_some()).count()
+    }
+
+    /// A plain-text table of the results.
+    pub fn summary(&self) -> String {
+        let ok: Vec<&Sample> = self.samples.iter().filter(|s| s.error.is_none()).collect();
+        let sorted = |values: Vec<f64>| {
+            let mut values = values;
+            values.sort_by(f64::total_cmp);
+            values
+        };
+        let ttfb = sorted(ok.iter().filter_map(|s| s.ttfb).map(|d| d.as_secs_f64() * 1000.0).collect());
+        let total = sorted(ok.iter().map(|s| s.duration.as_secs_f64() * 1000.0).collect());
+        let rates = sorted(ok.iter().filter_map(|s| s.tokens_per_sec()).collect());
+        let tokens: usize = ok.iter().map(|s| s.tokens).sum();
+        let wall = self.elapsed.as_secs_f64().max(f64::EPSILON);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "requests: {}  ok: {}  errors: {} ({:.1}%)  wall: {:.2}s  throughput: {:.1} req/s, {:.0} tokens/s",
+            self.samples.len(),
+            ok.len(),
+            self.errors(),
+            100.0 * self.errors() as f64 / self.samples.len().max(1) as f64,
+            wall,
+            ok.len() as f64 / wall,
+            tokens as f64 / wall,
+        );
+        let _ = writeln!(out, "{:<16}{:>10}{:>10}{:>10}{:>10}{:>10}", "", "p50", "p90", "p95", "p99", "max");
+        for (name, values) in [("ttfb ms", &ttfb), ("total ms", &total), ("tokens/s", &rates)] {
+            let _ = write!(out, "{:<16}", name);
+            for p in [50.0, 90.0, 95.0, 99.0, 100.0] {
+                let _ = write!(out, "{:>10.1}", percentile(values, p));
+            }
+            let _ = writeln!(out);
+        }
+        let mut errors: BTreeMap<&str, usize> = BTreeMap::new();
+        for error in self.samples.iter().filter_map(|s| s.error.as_deref()) {
+            *errors.entry(error).or_default() += 1;
+        }
+        for (error, count) in errors {
+            let _ = writeln!(out, "error x{}: {}", count, error);
+        }
+        out
+    }
+}
+
+/// A Dev stand-in: every request streams a thread id and `tokens` content
+/// events, one per `interval`.
+pub fn mock_dev_router(tokens: usize, interval: Duration) -> Router {
+    Router::new().fallback(post(move || async move {
+        let head = futures_util::stream::iter([Ok::<_, std::convert::Infallible>(Bytes::from_static(b"event: threadId\ndata: bench-thread\n\n"))]);
+        let content = futures_util::stream::iter(0..tokens).then(move |i| async move {
+            if !interval.is_zero() {
+                tokio::time::sleep(interval).await;
+            }
+            Ok(Bytes::from(format!("data: token{} \n\n", i)))
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .body(Body::from_stream(head.chain(content)))
+            .unwrap()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+        assert_eq!(percentile(&values, 50.0), 5.0);
+        assert_eq!(percentile(&values, 90.0), 9.0);
+        assert_eq!(percentile(&values, 100.0), 10.0);
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn test_summary_counts_errors() {
+        let ok = Sample { ttfb: Some(Duration::from_millis(100)), duration: Duration::from_millis(1100), tokens: 11, error: None };
+        let failed = Sample { error: Some("HTTP 429".to_string()), ..Default::default() };
+        let report = Report { samples: vec![ok.clone(), ok, failed.clone(), failed], elapsed: Duration::from_secs(2) };
+        let summary = report.summary();
+        assert!(summary.starts_with("requests: 4  ok: 2  errors: 2 (50.0%)"), "{}", summary);
+        assert!(summary.contains("error x2: HTTP 429"));
+        // 10 tokens after the first, in one second
+        assert!(summary.lines().find(|l| l.starts_with("tokens/s")).unwrap().contains("10.0"));
+    }
+}
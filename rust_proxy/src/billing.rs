@@ -0,0 +1,292 @@
+// Billing export: one record per chat completion or assistants run, once it
+// ended, so finance tooling can consume costs without access to the proxy's
+// usage store or logs. BILLING_SINK picks where records go:
+//
+// - `file`: appended as JSON lines to BILLING_FILE.
+// - `webhook`: POSTed one by one to BILLING_WEBHOOK_URL, signed like the
+//   lifecycle webhooks when BILLING_WEBHOOK_SECRET is set, with their
+//   WEBHOOK_TIMEOUT_SECS.
+// - `event_bus`: published on the EVENT_BUS broker, to BILLING_TOPIC
+//   (`billing.records`), keyed by request id.
+//
+// Records follow a stable schema, named in their `schema` field
+// (`rust_proxy.billing.v1`): fields may be added, never renamed or removed.
+// `cost_usd` is priced with USAGE_PRICES, null for unpriced models. Records
+// are sent in the background and dropped if the sink fails.
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::config::env_or;
+use crate::event_bus::{self, Publisher};
+use crate::metrics::StreamSummary;
+use crate::usage::{PriceTable, UsageCounters};
+use crate::webhooks::{self, CompletionInfo};
+
+pub const SCHEMA: &str = "rust_proxy.billing.v1";
+
+/// Records sent by `sink` and `result` (ok / error), registered by `Metrics`.
+pub static RECORDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("billing_records_total", "Billing records exported by sink and result"), &["sink", "result"])
+        .expect("valid counter")
+});
+
+/// What one request cost.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BillingRecord {
+    pub schema: &'static str,
+    /// When the request ended, in Unix seconds.
+    pub timestamp: u64,
+    pub request_id: String,
+    pub route: &'static str,
+    pub api_key_id: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub duration_ms: u64,
+    /// `ok`, `upstream_error`, `stream_error` or `cancelled`.
+    pub outcome: &'static str,
+    pub cost_usd: Option<f64>,
+}
+
+impl BillingRecord {
+    pub fn new(completion: &CompletionInfo, route: &'static str, summary: &StreamSummary) -> Self {
+        Self {
+            schema: SCHEMA,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            request_id: completion.request_id.clone(),
+            route,
+            api_key_id: completion.api_key_id.clone(),
+            model: completion.model.clone().unwrap_or_else(|| "unknown".to_string()),
+            prompt_tokens: completion.prompt_tokens,
+            completion_tokens: summary.completion_tokens,
+            total_tokens: completion.prompt_tokens + summary.completion_tokens,
+            duration_ms: summary.duration.as_millis() as u64,
+            outcome: summary.outcome.as_str(),
+            cost_usd: None,
+        }
+    }
+
+    fn price(&mut self, prices: &PriceTable) {
+        let counters = UsageCounters { requests: 1, prompt_tokens: self.prompt_tokens, completion_tokens: self.completion_tokens };
+        self.cost_usd = prices.price_for(&self.model).map(|price| price.cost(&counters));
+    }
+}
+
+enum Sink {
+    File(Arc<Mutex<File>>),
+    Webhook { client: reqwest::Client, url: String, secret: Option<String>, timeout: Duration },
+    EventBus(Arc<dyn Publisher>),
+}
+
+impl Sink {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::File(_) => "file",
+            Self::Webhook { .. } => "webhook",
+            Self::EventBus(_) => "event_bus",
+        }
+    }
+
+    async fn send(&self, record: &BillingRecord) -> Result<()> {
+        let payload = serde_json::to_vec(record)?;
+        match self {
+            Self::File(file) => {
+                let (file, mut line) = (file.clone(), payload);
+                line.push(b'\n');
+                // One write per line, so concurrent records never interleave
+                tokio::task::spawn_blocking(move || file.lock().unwrap().write_all(&line)).await??;
+            }
+            Self::Webhook { client, url, secret, timeout } => {
+                let mut request = client
+                    .post(url)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .timeout(*timeout);
+                if let Some(secret) = secret {
+                    request = request.header(webhooks::SIGNATURE_HEADER, webhooks::sign(secret, &payload));
+                }
+                request.body(payload).send().await?.error_for_status()?;
+            }
+            Self::EventBus(publisher) => publisher.publish(&record.request_id, payload).await?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Billing {
+    sink: Option<Arc<Sink>>,
+    prices: PriceTable,
+}
+
+impl Billing {
+    /// Appends records to `path`.
+    pub fn file(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).with_context(|| format!("open {}", path))?;
+        Ok(Self::with_sink(Sink::File(Arc::new(Mutex::new(file)))))
+    }
+
+    /// POSTs records to `url`, signed with `secret`.
+    pub fn webhook(url: String, secret: Option<String>, timeout: Duration) -> Self {
+        Self::with_sink(Sink::Webhook { client: reqwest::Client::new(), url, secret, timeout })
+    }
+
+    /// Publishes records with `publisher`.
+    pub fn event_bus(publisher: Arc<dyn Publisher>) -> Self {
+        Self::with_sink(Sink::EventBus(publisher))
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        Self { sink: Some(Arc::new(sink)), prices: PriceTable::default() }
+    }
+
+    /// Prices records with `prices`.
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    pub fn from_env() -> Self {
+        match sink_from_env() {
+            Ok(Some(billing)) => {
+                let billing = billing.with_prices(PriceTable::parse(&std::env::var("USAGE_PRICES").unwrap_or_default()));
+                info!(sink = billing.sink.as_ref().map(|s| s.name()), "Exporting billing records");
+                billing
+            }
+            Ok(None) => Self::default(),
+            Err(e) => {
+                warn!("Billing export disabled: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Prices `record` and sends it in the background.
+    pub fn record(&self, mut record: BillingRecord) {
+        let Some(sink) = self.sink.clone() else { return };
+        record.price(&self.prices);
+        tokio::spawn(async move {
+            match sink.send(&record).await {
+                Ok(()) => {
+                    debug!(sink = sink.name(), request_id = record.request_id, "Exported billing record");
+                    RECORDS.with_label_values(&[sink.name(), "ok"]).inc();
+                }
+                Err(e) => {
+                    warn!(sink = sink.name(), request_id = record.request_id, "Failed to export billing record: {:#}", e);
+                    RECORDS.with_label_values(&[sink.name(), "error"]).inc();
+                }
+            }
+        });
+    }
+}
+
+/// BILLING_SINK; `None` when unset.
+fn sink_from_env() -> Result<Option<Billing>> {
+    let sink = std::env::var("BILLING_SINK").unwrap_or_default().trim().to_ascii_lowercase();
+    let setting = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let billing = match sink.as_str() {
+        "" => return Ok(None),
+        "file" => {
+            let Some(path) = setting("BILLING_FILE") else { bail!("BILLING_SINK is 'file' but BILLING_FILE is not set") };
+            Billing::file(&path)?
+        }
+        "webhook" => {
+            let Some(url) = setting("BILLING_WEBHOOK_URL") else { bail!("BILLING_SINK is 'webhook' but BILLING_WEBHOOK_URL is not set") };
+            let timeout = Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 5));
+            Billing::webhook(url, setting("BILLING_WEBHOOK_SECRET"), timeout)
+        }
+        "event_bus" => {
+            let topic = setting("BILLING_TOPIC").unwrap_or_else(|| "billing.records".to_string());
+            let Some(publisher) = event_bus::publisher_from_env(topic)? else { bail!("BILLING_SINK is 'event_bus' but EVENT_BUS is not set") };
+            Billing::event_bus(publisher)
+        }
+        other => bail!("Unknown BILLING_SINK '{}', expected 'file', 'webhook' or 'event_bus'", other),
+    };
+    Ok(Some(billing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Outcome;
+    use futures_util::future::BoxFuture;
+    use futures_util::FutureExt;
+
+    fn completion() -> CompletionInfo {
+        CompletionInfo {
+            request_id: "req-1".to_string(),
+            model: Some("gpt-4o".to_string()),
+            api_key_id: "key_a".to_string(),
+            prompt_tokens: 2_000,
+        }
+    }
+
+    fn summary(outcome: Outcome) -> StreamSummary {
+        StreamSummary { outcome, completion_tokens: 1_000, ttfb: None, duration: Duration::from_millis(1_500), text: None }
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_priced_lines() {
+        let path = std::env::temp_dir().join(format!("billing-{}.jsonl", crate::utils::generate_uuidv4()));
+        let billing = Billing::file(path.to_str().unwrap()).unwrap().with_prices(PriceTable::parse("gpt-4o=0.005/0.015"));
+        let sink = billing.sink.clone().unwrap();
+        for outcome in [Outcome::Ok, Outcome::Cancelled] {
+            let mut record = BillingRecord::new(&completion(), "/v1/chat/completions", &summary(outcome));
+            record.price(&billing.prices);
+            sink.send(&record).await.unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> =
+            std::fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["schema"], SCHEMA);
+        assert_eq!((lines[0]["api_key_id"].as_str(), lines[0]["model"].as_str()), (Some("key_a"), Some("gpt-4o")));
+        assert_eq!((lines[0]["total_tokens"].as_u64(), lines[0]["duration_ms"].as_u64()), (Some(3_000), Some(1_500)));
+        assert!((lines[0]["cost_usd"].as_f64().unwrap() - 0.025).abs() < 1e-9);
+        assert_eq!(lines[1]["outcome"], "cancelled");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    struct Recorder(tokio::sync::mpsc::UnboundedSender<(String, serde_json::Value)>);
+
+    impl Publisher for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn publish<'a>(&'a self, key: &'a str, payload: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.0.send((key.to_string(), serde_json::from_slice(&payload)?))?;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_bus_sink_keys_by_request() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let billing = Billing::event_bus(Arc::new(Recorder(tx)));
+        billing.record(BillingRecord::new(&completion(), "/v1/chat/completions", &summary(Outcome::UpstreamError)));
+        let (key, record) = rx.recv().await.unwrap();
+        assert_eq!(key, "req-1");
+        assert_eq!(record["outcome"], "upstream_error");
+        assert!(record["cost_usd"].is_null());
+
+        // Nothing is sent without a sink
+        Billing::default().record(BillingRecord::new(&completion(), "/v1/chat/completions", &summary(Outcome::Ok)));
+        assert!(!Billing::default().is_enabled());
+    }
+}
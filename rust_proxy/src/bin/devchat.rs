@@ -0,0 +1,454 @@
+// `devchat`: a terminal chat client for smoke-testing deployments. It talks
+// to a running proxy (DEVCHAT_URL, default http://127.0.0.1:$PORT, with
+// DEVCHAT_API_KEY as the bearer token) or, with `--direct`, straight to Dev
+// through DevApiClient, configured from the same environment as the server.
+// Answers stream to stdout and later prompts continue the conversation.
+//
+//   devchat [--direct] [--url URL] [--key KEY] [--model MODEL] [--thread ID] [PROMPT...]
+//
+// With a PROMPT it answers once and exits; otherwise it reads prompts from
+// stdin, where `/model NAME`, `/thread`, `/new` and `/quit` are commands.
+// Through the proxy a conversation is an Assistants API thread; `--thread`
+// resumes one (`thread_` ids) or continues a Dev thread by its id.
+//
+//   devchat bench [-n REQUESTS] [-c CONCURRENCY] [--prompt TEXT] [--mock] [--url URL] [--key KEY] [--model MODEL]
+//
+// fires REQUESTS (100) streaming chat completions at the proxy, CONCURRENCY
+// (10) at a time, and prints time to first token, tokens per second and the
+// error rate; it exits with 1 when any request failed. With `--mock` the
+// proxy runs in-process in front of a built-in Dev stand-in streaming
+// `--mock-tokens` (50) tokens, one per `--mock-interval-ms` (20).
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+
+use rust_proxy::bench::{self, BenchConfig};
+use rust_proxy::config::ServerConfig;
+use rust_proxy::dev_client::{DevApiClient, DevRequestOptions};
+use rust_proxy::sse_parser::{SseEventRecord, SseParser};
+use rust_proxy::sse_processor::{self, process_dev_bytes_stream_unfold, STREAM_ERROR_PREFIX};
+
+const USAGE: &str = "usage: devchat [--direct] [--url URL] [--key KEY] [--model MODEL] [--thread ID] [PROMPT...]
+       devchat bench [-n REQUESTS] [-c CONCURRENCY] [--prompt TEXT] [--mock] [--mock-tokens N] [--mock-interval-ms MS] [--url URL] [--key KEY] [--model MODEL]";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    direct: bool,
+    url: Option<String>,
+    key: Option<String>,
+    model: Option<String>,
+    thread: Option<String>,
+    prompt: Option<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut prompt = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().with_context(|| format!("{} needs a value\n{}", name, USAGE));
+        match arg.as_str() {
+            "--direct" => parsed.direct = true,
+            "--url" => parsed.url = Some(value("--url")?),
+            "--key" => parsed.key = Some(value("--key")?),
+            "--model" | "-m" => parsed.model = Some(value("--model")?),
+            "--thread" | "-t" => parsed.thread = Some(value("--thread")?),
+            "--help" | "-h" => bail!(USAGE),
+            flag if flag.starts_with('-') && prompt.is_empty() => bail!("unknown option {}\n{}", flag, USAGE),
+            _ => prompt.push(arg),
+        }
+    }
+    parsed.prompt = (!prompt.is_empty()).then(|| prompt.join(" "));
+    Ok(parsed)
+}
+
+#[derive(Debug, PartialEq)]
+struct BenchArgs {
+    url: Option<String>,
+    key: Option<String>,
+    model: Option<String>,
+    prompt: String,
+    requests: usize,
+    concurrency: usize,
+    mock: bool,
+    mock_tokens: usize,
+    mock_interval: Duration,
+}
+
+impl Default for BenchArgs {
+    fn default() -> Self {
+        Self {
+            url: None,
+            key: None,
+            model: None,
+            prompt: "Explain what a reverse proxy does in three sentences.".to_string(),
+            requests: 100,
+            concurrency: 10,
+            mock: false,
+            mock_tokens: 50,
+            mock_interval: Duration::from_millis(20),
+        }
+    }
+}
+
+fn parse_bench_args(args: impl IntoIterator<Item = String>) -> Result<BenchArgs> {
+    let mut parsed = BenchArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().with_context(|| format!("{} needs a value\n{}", name, USAGE));
+        let number = |value: String, name: &str| value.parse::<usize>().with_context(|| format!("{} must be a number", name));
+        match arg.as_str() {
+            "--url" => parsed.url = Some(value("--url")?),
+            "--key" => parsed.key = Some(value("--key")?),
+            "--model" | "-m" => parsed.model = Some(value("--model")?),
+            "--prompt" => parsed.prompt = value("--prompt")?,
+            "--requests" | "-n" => parsed.requests = number(value("--requests")?, "--requests")?,
+            "--concurrency" | "-c" => parsed.concurrency = number(value("--concurrency")?, "--concurrency")?,
+            "--mock" => parsed.mock = true,
+            "--mock-tokens" => parsed.mock_tokens = number(value("--mock-tokens")?, "--mock-tokens")?,
+            "--mock-interval-ms" => {
+                parsed.mock_interval = Duration::from_millis(number(value("--mock-interval-ms")?, "--mock-interval-ms")? as u64)
+            }
+            _ => bail!("unexpected argument {}\n{}", arg, USAGE),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Non-empty variable `key`.
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
+fn print_flush(text: &str) {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// Calls `on_event` for each SSE event of `response` until it returns false.
+async fn for_each_event(response: reqwest::Response, mut on_event: impl FnMut(SseEventRecord) -> Result<bool>) -> Result<()> {
+    let mut parser = SseParser::new();
+    let mut bytes = response.bytes_stream();
+    while let Some(chunk) = bytes.next().await {
+        for event in parser.feed(&chunk.context("stream broke")?) {
+            if !on_event(event)? {
+                return Ok(());
+            }
+        }
+    }
+    for event in parser.finish() {
+        if !on_event(event)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A conversation through a running proxy.
+struct ProxyChat {
+    client: reqwest::Client,
+    url: String,
+    key: Option<String>,
+    assistant_id: Option<String>,
+}
+
+impl ProxyChat {
+    async fn post(&self, path: &str, body: Value) -> Result<reqwest::Response> {
+        let mut request = self.client.post(format!("{}{}", self.url, path)).json(&body);
+        if let Some(key) = &self.key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.with_context(|| format!("connecting to {}", self.url))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body: Value = response.json().await.unwrap_or_default();
+            bail!("{} {}: {}", status, path, body["error"]["message"].as_str().unwrap_or("no error message"));
+        }
+        Ok(response)
+    }
+
+    async fn post_json(&self, path: &str, body: Value) -> Result<Value> {
+        Ok(self.post(path, body).await?.json().await?)
+    }
+
+    /// Asks within an Assistants thread, creating the assistant and the
+    /// thread first if needed.
+    async fn ask(&mut self, prompt: &str, model: &str, thread: &mut Option<String>) -> Result<()> {
+        if let Some(dev_thread) = thread.as_deref().filter(|t| !t.starts_with("thread_")) {
+            return self.ask_dev_thread(prompt, model, dev_thread).await;
+        }
+        let assistant_id = match &self.assistant_id {
+            Some(id) => id.clone(),
+            None => {
+                let assistant = self.post_json("/v1/assistants", json!({ "model": model, "name": "devchat" })).await?;
+                let id = assistant["id"].as_str().context("assistant without id")?.to_string();
+                self.assistant_id.insert(id).clone()
+            }
+        };
+        let thread_id = match thread.as_deref() {
+            Some(id) => id.to_string(),
+            None => {
+                let created = self.post_json("/v1/threads", json!({})).await?;
+                thread.insert(created["id"].as_str().context("thread without id")?.to_string()).clone()
+            }
+        };
+        self.post_json(&format!("/v1/threads/{}/messages", thread_id), json!({ "role": "user", "content": prompt })).await?;
+        let run = json!({ "assistant_id": assistant_id, "model": model, "stream": true });
+        let response = self.post(&format!("/v1/threads/{}/runs", thread_id), run).await?;
+        for_each_event(response, |event| {
+            let data: Value = serde_json::from_str(&event.data).unwrap_or_default();
+            match event.event.as_str() {
+                "thread.message.delta" => {
+                    print_flush(data["delta"]["content"][0]["text"]["value"].as_str().unwrap_or_default());
+                    Ok(true)
+                }
+                "thread.run.failed" => bail!("run failed: {}", data["last_error"]["message"].as_str().unwrap_or("unknown error")),
+                "done" => Ok(false),
+                _ => Ok(true),
+            }
+        })
+        .await
+    }
+
+    /// Continues a Dev thread through chat completions.
+    async fn ask_dev_thread(&self, prompt: &str, model: &str, thread_id: &str) -> Result<()> {
+        let body = json!({
+            "model": model,
+            "stream": true,
+            "messages": [{ "role": "user", "content": prompt }],
+            "x_devv": { "thread_id": thread_id },
+        });
+        let response = self.post("/v1/chat/completions", body).await?;
+        for_each_event(response, |event| {
+            if event.data.trim() == "[DONE]" {
+                return Ok(false);
+            }
+            let chunk: Value = serde_json::from_str(&event.data).unwrap_or_default();
+            if let Some(message) = chunk["error"]["message"].as_str() {
+                bail!("stream error: {}", message);
+            }
+            print_flush(chunk["choices"][0]["delta"]["content"].as_str().unwrap_or_default());
+            Ok(true)
+        })
+        .await
+    }
+}
+
+/// Asks Dev directly; the thread id Dev reports is kept for the next prompt.
+async fn ask_direct(client: &DevApiClient, prompt: &str, model: &str, thread: &mut Option<String>) -> Result<()> {
+    let (snapshot_tx, mut snapshot_rx) = mpsc::unbounded_channel();
+    let options = DevRequestOptions {
+        model: Some(model.to_string()),
+        thread_id: thread.clone(),
+        snapshots: vec![snapshot_tx],
+        ..Default::default()
+    };
+    let response = client.send_request(prompt, options.clone()).await.context("Dev rejected the request")?;
+    let mut chunks = std::pin::pin!(process_dev_bytes_stream_unfold(response.bytes_stream(), options, sse_processor::new_completion_id()));
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.context("stream broke")?;
+        let content = chunk.choices.first().and_then(|c| c.delta.content.as_deref()).unwrap_or_default();
+        if let Some(message) = content.strip_prefix(STREAM_ERROR_PREFIX) {
+            bail!("Dev reported an error: {}", message);
+        }
+        print_flush(content);
+    }
+    if let Some(thread_id) = snapshot_rx.try_recv().ok().and_then(|a| a.thread_id) {
+        *thread = Some(thread_id);
+    }
+    Ok(())
+}
+
+enum Backend {
+    Proxy(ProxyChat),
+    Direct(DevApiClient),
+}
+
+struct Session {
+    backend: Backend,
+    model: String,
+    thread: Option<String>,
+}
+
+impl Session {
+    async fn ask(&mut self, prompt: &str) -> Result<()> {
+        match &mut self.backend {
+            Backend::Proxy(proxy) => proxy.ask(prompt, &self.model, &mut self.thread).await?,
+            Backend::Direct(client) => ask_direct(client, prompt, &self.model, &mut self.thread).await?,
+        }
+        print_flush("\n");
+        Ok(())
+    }
+
+    /// Handles a `/` command; false to quit.
+    fn command(&mut self, line: &str) -> bool {
+        let (command, argument) = line.split_once(' ').map(|(c, a)| (c, a.trim())).unwrap_or((line, ""));
+        match command {
+            "/quit" | "/exit" => return false,
+            "/new" => {
+                self.thread = None;
+                eprintln!("Started a new conversation");
+            }
+            "/thread" => eprintln!("Thread: {}", self.thread.as_deref().unwrap_or("(none yet)")),
+            "/model" if !argument.is_empty() => {
+                self.model = argument.to_string();
+                eprintln!("Model: {}", self.model);
+            }
+            "/model" => eprintln!("Model: {}", self.model),
+            _ => eprintln!("Commands: /model [NAME], /thread, /new, /quit"),
+        }
+        true
+    }
+}
+
+/// `--url`, else DEVCHAT_URL, else the local proxy on PORT.
+fn proxy_url(url: Option<String>) -> String {
+    let url = url
+        .or_else(|| env_var("DEVCHAT_URL"))
+        .unwrap_or_else(|| format!("http://127.0.0.1:{}", env_var("PORT").unwrap_or_else(|| "3000".to_string())));
+    url.trim_end_matches('/').to_string()
+}
+
+fn model_or_default(model: Option<String>) -> String {
+    model.or_else(|| env_var("DEVCHAT_MODEL")).unwrap_or_else(|| "gpt-4o".to_string())
+}
+
+/// Runs the benchmark; false when some request failed. `mock_dev` is the
+/// listener of the Dev stand-in, which API_ENDPOINT already points at.
+async fn bench(args: BenchArgs, mock_dev: Option<std::net::TcpListener>) -> Result<bool> {
+    let mut url = proxy_url(args.url);
+    if let Some(listener) = mock_dev {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        tokio::spawn(axum::serve(listener, bench::mock_dev_router(args.mock_tokens, args.mock_interval)).into_future());
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        url = format!("http://{}", proxy.local_addr()?);
+        let app = rust_proxy::app::build_router(DevApiClient::new().context("configuring DevApiClient")?, &ServerConfig::from_env());
+        tokio::spawn(axum::serve(proxy, app).into_future());
+    }
+    let config = BenchConfig {
+        url,
+        api_key: args.key.or_else(|| env_var("DEVCHAT_API_KEY")),
+        model: model_or_default(args.model),
+        prompt: args.prompt,
+        requests: args.requests,
+        concurrency: args.concurrency,
+        timeout: Duration::from_secs(120),
+    };
+    eprintln!("devchat bench: {} requests, {} concurrent, against {}", config.requests, config.concurrency, config.url);
+    let report = bench::run(&config).await;
+    print!("{}", report.summary());
+    Ok(report.errors() == 0)
+}
+
+async fn run(args: Args) -> Result<()> {
+    let backend = if args.direct {
+        Backend::Direct(DevApiClient::new().context("configuring DevApiClient")?)
+    } else {
+        Backend::Proxy(ProxyChat {
+            client: reqwest::Client::new(),
+            url: proxy_url(args.url),
+            key: args.key.or_else(|| env_var("DEVCHAT_API_KEY")),
+            assistant_id: None,
+        })
+    };
+    let model = model_or_default(args.model);
+    let mut session = Session { backend, model, thread: args.thread };
+
+    if let Some(prompt) = args.prompt {
+        return session.ask(&prompt).await;
+    }
+    eprintln!("devchat: model {}; /model NAME switches, /new starts over, /quit exits", session.model);
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        eprint!("> ");
+        let Some(line) = lines.next_line().await? else { break };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('/') {
+            if !session.command(line) {
+                break;
+            }
+            continue;
+        }
+        if let Err(e) = session.ask(line).await {
+            eprintln!("\nerror: {:#}", e);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let _ = dotenvy::dotenv();
+    // Logs go to stderr so answers can be piped
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "warn".into()))
+        .init();
+
+    let mut args = std::env::args().skip(1).peekable();
+    let result = if args.peek().map(String::as_str) == Some("bench") {
+        parse_bench_args(args.skip(1)).and_then(|bench_args| {
+            // The in-process proxy's client reads API_ENDPOINT, so it is
+            // pointed at the Dev stand-in before any other thread exists
+            let mock_dev = match bench_args.mock {
+                true => {
+                    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+                    // SAFETY: no other threads are running yet
+                    unsafe { std::env::set_var("API_ENDPOINT", format!("http://{}/api/v1/chat", listener.local_addr()?)) };
+                    Some(listener)
+                }
+                false => None,
+            };
+            let runtime = tokio::runtime::Runtime::new()?;
+            match runtime.block_on(bench(bench_args, mock_dev))? {
+                true => Ok(()),
+                false => std::process::exit(1),
+            }
+        })
+    } else {
+        parse_args(args).and_then(|args| tokio::runtime::Runtime::new()?.block_on(run(args)))
+    };
+    if let Err(e) = result {
+        eprintln!("devchat: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args("--model gpt-4o-search -t thread_1 what is rust").unwrap();
+        assert_eq!(parsed.model.as_deref(), Some("gpt-4o-search"));
+        assert_eq!(parsed.thread.as_deref(), Some("thread_1"));
+        assert_eq!(parsed.prompt.as_deref(), Some("what is rust"));
+        assert!(args("--direct").unwrap().direct);
+        assert!(args("--model").is_err());
+        assert!(args("--bogus").is_err());
+        // Dashes inside the prompt are text
+        assert_eq!(args("explain -- and -x").unwrap().prompt.as_deref(), Some("explain -- and -x"));
+    }
+
+    #[test]
+    fn test_parse_bench_args() {
+        let parsed = parse_bench_args("-n 20 -c 4 --mock --mock-interval-ms 0".split_whitespace().map(String::from)).unwrap();
+        assert_eq!((parsed.requests, parsed.concurrency, parsed.mock), (20, 4, true));
+        assert_eq!(parsed.mock_interval, Duration::ZERO);
+        assert!(parse_bench_args(["-n".to_string(), "many".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+// `devchat-tui` (feature `tui`): a terminal UI that shows the streamed
+// answer next to the Dev extras: the reasoning trace, the sources and the
+// related questions, each in its own pane. Settings are those of `devchat`
+// (DEVCHAT_URL, DEVCHAT_API_KEY, DEVCHAT_MODEL, `--direct`).
+//
+//   devchat-tui [--direct] [--url URL] [--key KEY] [--model MODEL] [--thread ID]
+//
+// Through the proxy the answer asks for the `x_devv` sources and related
+// questions footers, which are moved into their panes once it ends; the
+// reasoning trace is only available with `--direct`, where every pane comes
+// from Dev's events and later prompts continue the Dev thread. Enter sends,
+// Up/Down/PageUp/PageDown scroll the answer, Esc or Ctrl-C quits.
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::Frame;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use rust_proxy::dev_client::{DevApiClient, DevRequestOptions};
+use rust_proxy::sse_parser::SseParser;
+use rust_proxy::sse_processor::{self, process_dev_bytes_stream_unfold, SseAccumulator, STREAM_ERROR_PREFIX};
+
+const USAGE: &str = "usage: devchat-tui [--direct] [--url URL] [--key KEY] [--model MODEL] [--thread ID]";
+const SOURCES_HEADING: &str = "\n\n**Sources:**\n";
+const RELATED_HEADING: &str = "\n\n**You might also ask:**\n";
+
+/// Non-empty variable `key`.
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// The extras of an answer, for the side panes.
+#[derive(Debug, Default, PartialEq)]
+struct Extras {
+    reasoning: Option<String>,
+    sources: Vec<String>,
+    related: Vec<String>,
+}
+
+impl Extras {
+    fn from_accumulator(accumulator: &SseAccumulator) -> Self {
+        let mut sources: Vec<String> = (1..)
+            .zip(&accumulator.sources)
+            .map(|(number, source)| match (&source.title, &source.url) {
+                (Some(title), Some(url)) => format!("{}. {}\n   {}", number, title, url),
+                (title, url) => format!("{}. {}", number, title.as_deref().or(url.as_deref()).unwrap_or("Untitled")),
+            })
+            .collect();
+        sources.extend(accumulator.github_sources.iter().filter_map(|source| {
+            let repo = source.repo.as_deref()?;
+            Some(match &source.file_path {
+                Some(path) => format!("- {}/{}", repo, path),
+                None => format!("- {}", repo),
+            })
+        }));
+        Self { reasoning: accumulator.reasoning.clone(), sources, related: accumulator.related_questions.clone() }
+    }
+}
+
+/// Splits the proxy's sources and related questions footers off `text`.
+fn split_footers(text: &str) -> (String, Extras) {
+    let mut extras = Extras::default();
+    let mut answer = text;
+    for (heading, is_sources) in [(RELATED_HEADING, false), (SOURCES_HEADING, true)] {
+        let Some(at) = answer.rfind(heading) else { continue };
+        let lines = answer[at + heading.len()..].lines().map(|l| l.trim_start_matches("- ").to_string()).collect();
+        if is_sources {
+            extras.sources = lines;
+        } else {
+            extras.related = lines;
+        }
+        answer = &answer[..at];
+    }
+    (answer.to_string(), extras)
+}
+
+/// What an answer in flight reports to the UI.
+enum Update {
+    Delta(String),
+    /// The answer ended; `extras` is `None` when they are in its footers.
+    Done { thread: Option<String>, extras: Option<Extras> },
+    Failed(String),
+}
+
+#[derive(Clone)]
+enum Backend {
+    Proxy { client: reqwest::Client, url: String, key: Option<String> },
+    Direct(Arc<DevApiClient>),
+}
+
+impl Backend {
+    async fn ask(self, prompt: String, model: String, thread: Option<String>, updates: mpsc::UnboundedSender<Update>) {
+        let result = match self {
+            Backend::Proxy { client, url, key } => ask_proxy(&client, &url, key.as_deref(), &prompt, &model, thread.clone(), &updates).await,
+            Backend::Direct(client) => ask_direct(&client, &prompt, &model, thread.clone(), &updates).await,
+        };
+        let _ = updates.send(match result {
+            Ok((thread_id, extras)) => Update::Done { thread: thread_id.or(thread), extras },
+            Err(e) => Update::Failed(format!("{:#}", e)),
+        });
+    }
+}
+
+async fn ask_proxy(
+    client: &reqwest::Client,
+    url: &str,
+    key: Option<&str>,
+    prompt: &str,
+    model: &str,
+    thread: Option<String>,
+    updates: &mpsc::UnboundedSender<Update>,
+) -> Result<(Option<String>, Option<Extras>)> {
+    let body = json!({
+        "model": model,
+        "stream": true,
+        "messages": [{ "role": "user", "content": prompt }],
+        "x_devv": { "thread_id": thread, "sources_footer": true, "related_questions_footer": true },
+    });
+    let mut request = client.post(format!("{}/v1/chat/completions", url)).json(&body);
+    if let Some(key) = key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await.with_context(|| format!("connecting to {}", url))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body: Value = response.json().await.unwrap_or_default();
+        bail!("{}: {}", status, body["error"]["message"].as_str().unwrap_or("no error message"));
+    }
+    let mut parser = SseParser::new();
+    let mut bytes = response.bytes_stream();
+    while let Some(chunk) = bytes.next().await {
+        for event in parser.feed(&chunk.context("stream broke")?) {
+            if event.data.trim() == "[DONE]" {
+                return Ok((None, None));
+            }
+            let chunk: Value = serde_json::from_str(&event.data).unwrap_or_default();
+            if let Some(message) = chunk["error"]["message"].as_str() {
+                bail!("stream error: {}", message);
+            }
+            if let Some(content) = chunk["choices"][0]["delta"]["content"].as_str() {
+                let _ = updates.send(Update::Delta(content.to_string()));
+            }
+        }
+    }
+    Ok((None, None))
+}
+
+async fn ask_direct(
+    client: &DevApiClient,
+    prompt: &str,
+    model: &str,
+    thread: Option<String>,
+    updates: &mpsc::UnboundedSender<Update>,
+) -> Result<(Option<String>, Option<Extras>)> {
+    let (snapshot_tx, mut snapshot_rx) = mpsc::unbounded_channel();
+    let options = DevRequestOptions { model: Some(model.to_string()), thread_id: thread, snapshots: vec![snapshot_tx], ..Default::default() };
+    let response = client.send_request(prompt, options.clone()).await.context("Dev rejected the request")?;
+    let mut chunks = std::pin::pin!(process_dev_bytes_stream_unfold(response.bytes_stream(), options, sse_processor::new_completion_id()));
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.context("stream broke")?;
+        let content = chunk.choices.first().and_then(|c| c.delta.content.clone()).unwrap_or_default();
+        if let Some(message) = content.strip_prefix(STREAM_ERROR_PREFIX) {
+            bail!("Dev reported an error: {}", message);
+        }
+        let _ = updates.send(Update::Delta(content));
+    }
+    let accumulator = snapshot_rx.try_recv().unwrap_or_default();
+    Ok((accumulator.thread_id.clone(), Some(Extras::from_accumulator(&accumulator))))
+}
+
+struct App {
+    backend: Backend,
+    model: String,
+    thread: Option<String>,
+    input: String,
+    prompt: String,
+    answer: String,
+    extras: Extras,
+    status: String,
+    busy: bool,
+    scroll: u16,
+}
+
+impl App {
+    fn render(&self, frame: &mut Frame) {
+        let [main, input, status] = Layout::vertical([Constraint::Min(5), Constraint::Length(3), Constraint::Length(1)]).areas(frame.area());
+        let [answer, side] = Layout::horizontal([Constraint::Percentage(62), Constraint::Percentage(38)]).areas(main);
+        let [reasoning, sources, related] = Layout::vertical([Constraint::Ratio(1, 3); 3]).areas(side);
+
+        let title = if self.prompt.is_empty() { " Answer ".to_string() } else { format!(" {} ", self.prompt) };
+        frame.render_widget(
+            Paragraph::new(self.answer.as_str()).wrap(Wrap { trim: false }).scroll((self.scroll, 0)).block(Block::bordered().title(title)),
+            answer,
+        );
+        let reasoning_text = match (&self.extras.reasoning, &self.backend) {
+            (Some(text), _) => text.as_str(),
+            (None, Backend::Proxy { .. }) => "(only with --direct)",
+            (None, Backend::Direct(_)) => "",
+        };
+        let pane = |text: String, title: &'static str| {
+            Paragraph::new(text).wrap(Wrap { trim: false }).style(Style::new().fg(Color::Gray)).block(Block::bordered().title(title))
+        };
+        frame.render_widget(pane(reasoning_text.to_string(), " Reasoning "), reasoning);
+        frame.render_widget(pane(self.extras.sources.join("\n"), " Sources "), sources);
+        frame.render_widget(pane(self.extras.related.join("\n"), " Related questions "), related);
+        frame.render_widget(Paragraph::new(self.input.as_str()).block(Block::bordered().title(" Prompt (Enter to send, Esc to quit) ")), input);
+        frame.render_widget(Line::from(self.status.as_str()).reversed(), status);
+    }
+
+    fn set_status(&mut self, state: &str) {
+        self.status = format!(" {} | model {} | thread {}", state, self.model, self.thread.as_deref().unwrap_or("new"));
+    }
+
+    /// Handles a key; false to quit.
+    fn on_key(&mut self, key: KeyEvent, updates: &mpsc::UnboundedSender<Update>) -> bool {
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::Down => self.scroll = self.scroll.saturating_add(1),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::Enter if !self.busy && !self.input.trim().is_empty() => {
+                self.prompt = std::mem::take(&mut self.input).trim().to_string();
+                self.answer.clear();
+                self.extras = Extras::default();
+                self.scroll = 0;
+                self.busy = true;
+                self.set_status("streaming");
+                let ask = self.backend.clone().ask(self.prompt.clone(), self.model.clone(), self.thread.clone(), updates.clone());
+                tokio::spawn(ask);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn on_update(&mut self, update: Update) {
+        match update {
+            Update::Delta(content) => self.answer.push_str(&content),
+            Update::Done { thread, extras } => {
+                self.busy = false;
+                self.thread = thread;
+                self.extras = match extras {
+                    Some(extras) => extras,
+                    None => {
+                        let (answer, extras) = split_footers(&self.answer);
+                        self.answer = answer;
+                        extras
+                    }
+                };
+                self.set_status("done");
+            }
+            Update::Failed(error) => {
+                self.busy = false;
+                self.set_status(&format!("error: {}", error));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    direct: bool,
+    url: Option<String>,
+    key: Option<String>,
+    model: Option<String>,
+    thread: Option<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().with_context(|| format!("{} needs a value\n{}", name, USAGE));
+        match arg.as_str() {
+            "--direct" => parsed.direct = true,
+            "--url" => parsed.url = Some(value("--url")?),
+            "--key" => parsed.key = Some(value("--key")?),
+            "--model" | "-m" => parsed.model = Some(value("--model")?),
+            "--thread" | "-t" => parsed.thread = Some(value("--thread")?),
+            _ => bail!("unexpected argument {}\n{}", arg, USAGE),
+        }
+    }
+    Ok(parsed)
+}
+
+async fn run() -> Result<()> {
+    let Args { direct, url, key, model, thread } = parse_args(std::env::args().skip(1))?;
+    let backend = if direct {
+        Backend::Direct(Arc::new(DevApiClient::new().context("configuring DevApiClient")?))
+    } else {
+        let url = url
+            .or_else(|| env_var("DEVCHAT_URL"))
+            .unwrap_or_else(|| format!("http://127.0.0.1:{}", env_var("PORT").unwrap_or_else(|| "3000".to_string())));
+        Backend::Proxy { client: reqwest::Client::new(), url: url.trim_end_matches('/').to_string(), key: key.or_else(|| env_var("DEVCHAT_API_KEY")) }
+    };
+    let model = model.or_else(|| env_var("DEVCHAT_MODEL")).unwrap_or_else(|| "gpt-4o".to_string());
+    let mut app = App {
+        backend,
+        model,
+        thread,
+        input: String::new(),
+        prompt: String::new(),
+        answer: String::new(),
+        extras: Extras::default(),
+        status: String::new(),
+        busy: false,
+        scroll: 0,
+    };
+    app.set_status("ready");
+
+    // Terminal events are read on their own thread
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if let Event::Key(key) = event
+                && key.kind == KeyEventKind::Press
+                && keys_tx.send(key).is_err()
+            {
+                break;
+            }
+        }
+    });
+    let (updates_tx, mut updates) = mpsc::unbounded_channel();
+
+    let mut terminal = ratatui::init();
+    let result = async {
+        loop {
+            terminal.draw(|frame| app.render(frame))?;
+            tokio::select! {
+                Some(key) = keys.recv() => if !app.on_key(key, &updates_tx) { break },
+                Some(update) = updates.recv() => app.on_update(update),
+            }
+        }
+        Ok(())
+    }
+    .await;
+    ratatui::restore();
+    result
+}
+
+#[tokio::main]
+async fn main() {
+    let _ = dotenvy::dotenv();
+    if let Err(e) = run().await {
+        eprintln!("devchat-tui: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_footers() {
+        let text = "Rust is a language.\n\n**Sources:**\n1. [Book](https://doc.rust-lang.org/book)\n\n**You might also ask:**\n- What is Cargo?\n- What is a crate?";
+        let (answer, extras) = split_footers(text);
+        assert_eq!(answer, "Rust is a language.");
+        assert_eq!(extras.sources, ["1. [Book](https://doc.rust-lang.org/book)"]);
+        assert_eq!(extras.related, ["What is Cargo?", "What is a crate?"]);
+        assert_eq!(split_footers("Plain"), ("Plain".to_string(), Extras::default()));
+    }
+}
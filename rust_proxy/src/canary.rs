@@ -0,0 +1,112 @@
+// Canary routing for the request signer. Dev's signing scheme is reverse
+// engineered, so a new build of the WASM module is first given a slice of
+// traffic: CANARY_PERCENT of requests are signed by the module at
+// CANARY_WASM_PATH, the rest by the stable one. The variant is derived from
+// the request id, so a retried request keeps its variant, and stream
+// outcomes are recorded per variant (see `Metrics`).
+
+use anyhow::Result;
+use http::HeaderName;
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+
+use crate::config::env_or;
+use crate::utils;
+use crate::wasm_signer::WasmSigner;
+
+/// Response header naming the variant that served a canary request.
+pub const X_SIGNER_VARIANT: HeaderName = HeaderName::from_static("x-signer-variant");
+
+/// Which code path handled a request, used as the `variant` metrics label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    #[default]
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Canary => "canary",
+        }
+    }
+}
+
+pub struct Canary {
+    percent: f64,
+    signer: WasmSigner,
+}
+
+static CANARY: Lazy<Option<Canary>> = Lazy::new(|| {
+    Canary::from_env().unwrap_or_else(|e| {
+        error!("Failed to load canary signer, canary routing disabled: {:#}", e);
+        None
+    })
+});
+
+impl Canary {
+    pub fn new(percent: f64, signer: WasmSigner) -> Self {
+        Self { percent: percent.clamp(0.0, 100.0), signer }
+    }
+
+    /// `None` unless both CANARY_WASM_PATH and a positive CANARY_PERCENT are
+    /// set.
+    fn from_env() -> Result<Option<Self>> {
+        let path = std::env::var("CANARY_WASM_PATH").unwrap_or_default();
+        let percent: f64 = env_or("CANARY_PERCENT", 0.0);
+        if path.is_empty() || percent <= 0.0 {
+            return Ok(None);
+        }
+        let canary = Self::new(percent, WasmSigner::from_file(&path)?);
+        info!(path, percent = canary.percent, "Canary signer loaded");
+        Ok(Some(canary))
+    }
+
+    /// The process-wide canary, loaded on first use.
+    pub fn get_instance() -> Option<&'static Self> {
+        CANARY.as_ref()
+    }
+
+    pub fn variant_for(&self, request_id: &str) -> Variant {
+        if bucket(request_id) < self.percent {
+            Variant::Canary
+        } else {
+            Variant::Stable
+        }
+    }
+
+    pub fn signer(&self) -> &WasmSigner {
+        &self.signer
+    }
+}
+
+/// Stable position of `key` in [0, 100), in steps of 0.01.
+fn bucket(key: &str) -> f64 {
+    let hash = utils::sha256_hex(key.as_bytes());
+    let value = u64::from_str_radix(&hash[..16], 16).unwrap_or_default();
+    (value % 10_000) as f64 / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_is_stable_and_spread() {
+        assert_eq!(bucket("req-1"), bucket("req-1"));
+        let below = (0..1000).filter(|i| bucket(&format!("req-{}", i)) < 10.0).count();
+        assert!((50..150).contains(&below), "{} of 1000 below 10%", below);
+    }
+
+    #[test]
+    #[cfg(feature = "wasm-signer")]
+    fn test_variant_for_percent_bounds() {
+        let all = Canary::new(100.0, WasmSigner::from_file("./sign_bg.wasm").unwrap());
+        assert_eq!(all.variant_for("req-1"), Variant::Canary);
+        let none = Canary::new(0.0, WasmSigner::from_file("./sign_bg.wasm").unwrap());
+        assert_eq!(none.variant_for("req-1"), Variant::Stable);
+    }
+}
@@ -0,0 +1,150 @@
+// Capture of raw Dev responses, so parsing failures that are hard to
+// reproduce can be analyzed and turned into test fixtures. With CAPTURE_DIR
+// set, CAPTURE_PERCENT (0) of chat requests are captured, as are requests
+// sending `X-Capture-Upstream: true` if CAPTURE_HEADER allows it (off, since
+// any key holder could fill the disk). Each choice's Dev bytes are written
+// unchanged to `{CAPTURE_DIR}/{unix_ms}-{request_id}-{choice}.sse`, up to
+// CAPTURE_MAX_BYTES (10 MiB) per file; a re-issued stream appends to the
+// file of its choice.
+
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+
+/// Request header asking for the Dev response to be captured.
+pub const X_CAPTURE_UPSTREAM: HeaderName = HeaderName::from_static("x-capture-upstream");
+
+/// Receives the Dev bytes of one choice.
+pub type CaptureSink = mpsc::UnboundedSender<Bytes>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    dir: Option<PathBuf>,
+    percent: f64,
+    header: bool,
+    max_bytes: usize,
+}
+
+impl Capture {
+    pub fn new(dir: PathBuf, percent: f64, header: bool, max_bytes: usize) -> Self {
+        Self { dir: Some(dir), percent: percent.clamp(0.0, 100.0), header, max_bytes }
+    }
+
+    pub fn from_env() -> Self {
+        let Some(dir) = std::env::var("CAPTURE_DIR").ok().filter(|v| !v.trim().is_empty()) else {
+            return Self::default();
+        };
+        let capture = Self::new(
+            PathBuf::from(dir),
+            env_or("CAPTURE_PERCENT", 0.0),
+            env_or("CAPTURE_HEADER", false),
+            env_or("CAPTURE_MAX_BYTES", 10 << 20),
+        );
+        info!(dir = ?capture.dir, percent = capture.percent, header = capture.header, "Capturing Dev responses");
+        capture
+    }
+
+    /// Whether to capture the request with these headers.
+    pub fn wants(&self, headers: &HeaderMap) -> bool {
+        if self.dir.is_none() {
+            return false;
+        }
+        let asked = headers
+            .get(X_CAPTURE_UPSTREAM)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"));
+        (self.header && asked) || (uuid::Uuid::new_v4().as_u128() % 10_000) as f64 / 100.0 < self.percent
+    }
+
+    /// A sink writing choice `index` of `request_id` to its own file, in the
+    /// background.
+    pub fn sink(&self, request_id: &str, index: u32) -> Option<CaptureSink> {
+        let dir = self.dir.clone()?;
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+        let path = dir.join(format!("{}-{}-{}.sse", millis, file_safe(request_id), index));
+        let max_bytes = self.max_bytes;
+        let (tx, mut rx) = mpsc::unbounded_channel::<Bytes>();
+        tokio::spawn(async move {
+            let opened = async {
+                tokio::fs::create_dir_all(&dir).await?;
+                tokio::fs::File::create(&path).await
+            };
+            let mut file = match opened.await {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!(path = %path.display(), "Failed to create capture file: {}", e);
+                    return;
+                }
+            };
+            info!(path = %path.display(), "Capturing Dev response");
+            let mut written = 0;
+            while let Some(bytes) = rx.recv().await {
+                let room = max_bytes.saturating_sub(written).min(bytes.len());
+                if let Err(e) = file.write_all(&bytes[..room]).await {
+                    warn!(path = %path.display(), "Failed to write capture file: {}", e);
+                    return;
+                }
+                written += room;
+                if room < bytes.len() {
+                    warn!(path = %path.display(), max_bytes, "Capture file reached CAPTURE_MAX_BYTES, truncated");
+                    break;
+                }
+            }
+            let _ = file.flush().await;
+        });
+        Some(tx)
+    }
+}
+
+/// `id` with everything but letters, digits, '-' and '_' replaced, shortened
+/// to 64 characters.
+fn file_safe(id: &str) -> String {
+    id.chars().take(64).map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wants() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_CAPTURE_UPSTREAM, "true".parse().unwrap());
+        let dir = std::env::temp_dir();
+        assert!(!Capture::default().wants(&headers));
+        assert!(Capture::new(dir.clone(), 0.0, true, 1024).wants(&headers));
+        assert!(!Capture::new(dir.clone(), 0.0, false, 1024).wants(&headers));
+        assert!(Capture::new(dir, 100.0, false, 1024).wants(&HeaderMap::new()));
+        assert_eq!(file_safe("../req 1"), "___req_1");
+    }
+
+    #[tokio::test]
+    async fn test_sink_writes_raw_bytes_up_to_the_cap() {
+        let dir = std::env::temp_dir().join(format!("capture-test-{}", uuid::Uuid::new_v4()));
+        let sink = Capture::new(dir.clone(), 0.0, false, 12).sink("req/1", 2).unwrap();
+        sink.send(Bytes::from_static(b"data: Hel")).unwrap();
+        sink.send(Bytes::from_static(b"lo\n\ndata: more")).unwrap();
+        drop(sink);
+
+        // The writer finishes once the sink is gone
+        let path = loop {
+            let entry = tokio::fs::read_dir(&dir).await.ok();
+            if let Some(mut entries) = entry
+                && let Ok(Some(entry)) = entries.next_entry().await
+                && tokio::fs::read(entry.path()).await.is_ok_and(|c| c.len() == 12)
+            {
+                break entry.path();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert!(path.file_name().unwrap().to_str().unwrap().ends_with("-req_1-2.sse"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"data: Hello\n");
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
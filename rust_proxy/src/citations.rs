@@ -0,0 +1,205 @@
+// Dev's inline citation markers (`[[1]]`, `[citation:1]`) in the answer
+// text. Plain chat UIs show them as noise, so CITATION_MARKERS can strip
+// them, rewrite them as `[1]`, or as markdown links to the cited source;
+// `keep` (the default) passes them through. This is the `citations` stage
+// of the chunk transformers: only the streamed deltas change, the
+// accumulator still collects the raw text and the sources.
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use std::str::FromStr;
+
+use crate::sse_processor::{AnswerExtras, ChatCompletionChunk, DevSource};
+use crate::transform::{content_chunk, ChoiceContext, ChunkTransformer};
+
+static MARKER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"[ \t]*(?:\[\[(\d+)\]\]|\[\[?citation:\s*(\d+)\]\]?)").expect("valid citation pattern")
+});
+
+/// The start of what may still become a marker: a trailing `[` or `[[`
+/// followed by at most a few marker characters.
+static PARTIAL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[ \t]*\[\[?[a-z:\s\d]{0,16}\]?$").expect("valid partial citation pattern"));
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CitationMode {
+    /// Markers are sent as Dev wrote them.
+    #[default]
+    Keep,
+    /// Markers are removed, with the space before them.
+    Strip,
+    /// `[1]`.
+    Number,
+    /// `[[1]](url)` when source 1 has a url, `[1]` otherwise.
+    Link,
+}
+
+impl FromStr for CitationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "strip" | "remove" => Ok(Self::Strip),
+            "number" => Ok(Self::Number),
+            "link" => Ok(Self::Link),
+            other => Err(format!("unknown citation mode '{}', expected keep, strip, number or link", other)),
+        }
+    }
+}
+
+/// Rewrites the markers of one answer as its deltas arrive; a marker split
+/// across deltas is held back until it is complete.
+#[derive(Debug, Clone, Default)]
+pub struct CitationRewriter {
+    mode: CitationMode,
+    pending: String,
+}
+
+impl CitationRewriter {
+    pub fn new(mode: CitationMode) -> Self {
+        Self { mode, pending: String::new() }
+    }
+
+    /// The text of `delta` that can be sent now, markers rewritten.
+    pub fn rewrite(&mut self, delta: &str, sources: &[DevSource]) -> String {
+        if self.mode == CitationMode::Keep {
+            return delta.to_string();
+        }
+        self.pending.push_str(delta);
+        let hold = PARTIAL.find(&self.pending).map_or(self.pending.len(), |m| m.start());
+        let ready: String = self.pending.drain(..hold).collect();
+        self.replace(&ready, sources)
+    }
+
+    /// Whatever was held back, at the end of the answer.
+    pub fn finish(&mut self, sources: &[DevSource]) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.replace(&rest, sources)
+    }
+
+    fn replace(&self, text: &str, sources: &[DevSource]) -> String {
+        MARKER
+            .replace_all(text, |caps: &Captures| {
+                let number = caps.get(1).or_else(|| caps.get(2)).map_or("", |m| m.as_str());
+                let space = &caps[0][..caps[0].find('[').unwrap_or(0)];
+                match self.mode {
+                    CitationMode::Keep => caps[0].to_string(),
+                    CitationMode::Strip => String::new(),
+                    CitationMode::Number => format!("{}[{}]", space, number),
+                    CitationMode::Link => {
+                        let url = number
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|n| sources.get(n.checked_sub(1)?))
+                            .and_then(|source| source.url.as_deref());
+                        match url {
+                            Some(url) => format!("{}[[{}]]({})", space, number, url),
+                            None => format!("{}[{}]", space, number),
+                        }
+                    }
+                }
+            })
+            .into_owned()
+    }
+}
+
+/// The `citations` stage of a choice, unless its markers are kept.
+pub fn transformer(context: &ChoiceContext) -> Option<CitationTransformer> {
+    (context.citations != CitationMode::Keep).then(|| CitationTransformer {
+        rewriter: CitationRewriter::new(context.citations),
+        extras: context.extras.clone(),
+        template: None,
+    })
+}
+
+/// Rewrites one choice's markers, linking them to the sources Dev sent.
+pub struct CitationTransformer {
+    rewriter: CitationRewriter,
+    extras: AnswerExtras,
+    /// A content chunk of the choice, the shape of the held text's chunk.
+    template: Option<ChatCompletionChunk>,
+}
+
+impl ChunkTransformer for CitationTransformer {
+    fn transform(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        let delta: String = chunk.choices.iter().filter_map(|c| c.delta.content.as_deref()).collect();
+        if delta.is_empty() {
+            return Some(chunk);
+        }
+        self.template.get_or_insert_with(|| chunk.clone());
+        let text = self.rewriter.rewrite(&delta, &self.extras.lock().sources);
+        let finishes = chunk.choices.iter().any(|c| c.finish_reason.is_some());
+        if text.is_empty() && !finishes {
+            return None;
+        }
+        for choice in &mut chunk.choices {
+            choice.delta.content = (!text.is_empty()).then(|| text.clone());
+        }
+        Some(chunk)
+    }
+
+    fn finalize(&mut self, finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+        let held = self.rewriter.finish(&self.extras.lock().sources);
+        let like = finishing.or(self.template.as_ref())?;
+        (!held.is_empty()).then(|| content_chunk(like, held))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_client::DevRequestOptions;
+    use crate::sse_processor::process_dev_bytes_stream_unfold;
+    use crate::transform::TransformChain;
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    fn sources() -> Vec<DevSource> {
+        serde_json::from_value(serde_json::json!([{"title": "Docs", "url": "https://doc.rust-lang.org"}])).unwrap()
+    }
+
+    fn rewrite_all(mode: CitationMode, deltas: &[&str]) -> String {
+        let mut rewriter = CitationRewriter::new(mode);
+        let mut out: String = deltas.iter().map(|d| rewriter.rewrite(d, &sources())).collect();
+        out += &rewriter.finish(&sources());
+        out
+    }
+
+    #[test]
+    fn test_markers_split_across_deltas() {
+        let deltas = ["Use `std::fs` [[", "1]] or tokio [cita", "tion:2]. Index v[i] stays."];
+        assert_eq!(rewrite_all(CitationMode::Strip, &deltas), "Use `std::fs` or tokio. Index v[i] stays.");
+        assert_eq!(rewrite_all(CitationMode::Number, &deltas), "Use `std::fs` [1] or tokio [2]. Index v[i] stays.");
+        assert_eq!(
+            rewrite_all(CitationMode::Link, &deltas),
+            "Use `std::fs` [[1]](https://doc.rust-lang.org) or tokio [2]. Index v[i] stays."
+        );
+        assert_eq!(rewrite_all(CitationMode::Keep, &deltas), deltas.concat());
+    }
+
+    #[test]
+    fn test_held_text_is_released() {
+        let mut rewriter = CitationRewriter::new(CitationMode::Strip);
+        assert_eq!(rewriter.rewrite("see arr[", &[]), "see arr");
+        assert_eq!(rewriter.rewrite("0] here", &[]), "[0] here");
+        assert_eq!(rewriter.rewrite(" and [[", &[]), " and");
+        assert_eq!(rewriter.finish(&[]), " [[");
+        assert_eq!("Remove".parse::<CitationMode>(), Ok(CitationMode::Strip));
+    }
+
+    #[tokio::test]
+    async fn test_markers_link_to_the_sources_of_the_stream() {
+        let bytes = futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(Bytes::from(
+            "event: sources\ndata: [{\"title\": \"Book\", \"url\": \"https://doc.rust-lang.org/book\"}]\n\n\
+             event: c\ndata: Ownership [[\n\nevent: c\ndata: 1]] matters[[2\n\n",
+        ))]);
+        let mut options = DevRequestOptions { citations: CitationMode::Link, ..Default::default() };
+        let context = ChoiceContext::attach(&mut options);
+        let chunks = process_dev_bytes_stream_unfold(bytes, options, "chatcmpl-1".to_string());
+        let chunks: Vec<_> = TransformChain::request_options().apply(chunks, &context).map(|c| c.unwrap()).collect().await;
+        let text: String = chunks.iter().filter_map(|c| c.choices[0].delta.content.clone()).collect();
+        assert_eq!(text, "Ownership [[1]](https://doc.rust-lang.org/book) matters[[2");
+        assert_eq!(chunks.last().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+}
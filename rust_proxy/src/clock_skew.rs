@@ -0,0 +1,120 @@
+// Clock skew compensation for signature timestamps. Dev rejects signatures
+// whose timestamp is too far from its own clock, so a drifting host clock
+// turns into a stream of auth errors. Each upstream response's `Date` header
+// is compared with the local clock; the difference becomes the offset added
+// to signature timestamps when the response was a timestamp rejection, or
+// when it moved by more than CLOCK_SKEW_TOLERANCE_SECS.
+
+use http::{header, HeaderMap, StatusCode};
+use regex::Regex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::config::env_or;
+
+/// Matches error bodies that complain about the request timestamp.
+const DEFAULT_REJECTION_PATTERN: &str = r"(?i)time\s*stamp|expired|clock";
+
+#[derive(Debug)]
+pub struct ClockSkew {
+    offset_secs: AtomicI64,
+    tolerance_secs: i64,
+    rejection: Regex,
+}
+
+impl ClockSkew {
+    pub fn new(tolerance_secs: i64, rejection: Regex) -> Self {
+        Self { offset_secs: AtomicI64::new(0), tolerance_secs, rejection }
+    }
+
+    /// CLOCK_SKEW_TOLERANCE_SECS (10) and TIMESTAMP_REJECTION_PATTERN.
+    pub fn from_env() -> Self {
+        let pattern = std::env::var("TIMESTAMP_REJECTION_PATTERN").ok().filter(|p| !p.is_empty());
+        let rejection = pattern
+            .and_then(|p| {
+                Regex::new(&p).inspect_err(|e| warn!("Invalid TIMESTAMP_REJECTION_PATTERN, using the default: {}", e)).ok()
+            })
+            .unwrap_or_else(|| Regex::new(DEFAULT_REJECTION_PATTERN).expect("valid default pattern"));
+        Self::new(env_or("CLOCK_SKEW_TOLERANCE_SECS", 10), rejection)
+    }
+
+    /// Seconds added to the local clock for signature timestamps.
+    pub fn offset_secs(&self) -> i64 {
+        self.offset_secs.load(Ordering::Relaxed)
+    }
+
+    /// Corrected Unix time in seconds.
+    pub fn now_secs(&self) -> u64 {
+        local_secs().saturating_add_signed(self.offset_secs())
+    }
+
+    /// Whether an upstream error looks like a rejected signature timestamp.
+    pub fn is_rejection(&self, status: StatusCode, body: &str) -> bool {
+        matches!(status, StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+            && self.rejection.is_match(body)
+    }
+
+    /// Measures the skew from the `Date` header of a response and adopts it
+    /// as described above. Returns the new offset when it changed.
+    pub fn observe(&self, headers: &HeaderMap, rejected: bool) -> Option<i64> {
+        let date = headers.get(header::DATE)?.to_str().ok()?;
+        let server = httpdate::parse_http_date(date).ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        self.apply(server - local_secs() as i64, rejected)
+    }
+
+    fn apply(&self, skew: i64, rejected: bool) -> Option<i64> {
+        let offset = self.offset_secs();
+        if skew == offset || (!rejected && (skew - offset).abs() <= self.tolerance_secs) {
+            debug!(skew, offset, "Upstream clock skew within tolerance");
+            return None;
+        }
+        self.offset_secs.store(skew, Ordering::Relaxed);
+        warn!(skew, previous = offset, rejected, "Adjusting signature timestamps for upstream clock skew");
+        Some(skew)
+    }
+}
+
+fn local_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn skew() -> ClockSkew {
+        ClockSkew::new(10, Regex::new(DEFAULT_REJECTION_PATTERN).unwrap())
+    }
+
+    #[test]
+    fn test_small_skew_is_only_applied_after_rejection() {
+        let clock = skew();
+        assert_eq!(clock.apply(3, false), None);
+        assert_eq!(clock.offset_secs(), 0);
+        assert_eq!(clock.apply(3, true), Some(3));
+        assert_eq!(clock.apply(60, false), Some(60));
+        assert_eq!(clock.apply(62, false), None);
+    }
+
+    #[test]
+    fn test_observe_date_header() {
+        let clock = skew();
+        let ahead = SystemTime::now() + Duration::from_secs(120);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::DATE, httpdate::fmt_http_date(ahead).parse().unwrap());
+        let offset = clock.observe(&headers, false).unwrap();
+        assert!((119..=121).contains(&offset));
+        assert!(clock.now_secs() >= local_secs() + 119);
+        assert_eq!(clock.observe(&HeaderMap::new(), true), None);
+    }
+
+    #[test]
+    fn test_rejection_detection() {
+        let clock = skew();
+        assert!(clock.is_rejection(StatusCode::UNAUTHORIZED, r#"{"msg":"Timestamp expired"}"#));
+        assert!(!clock.is_rejection(StatusCode::UNAUTHORIZED, r#"{"msg":"invalid sid"}"#));
+        assert!(!clock.is_rejection(StatusCode::INTERNAL_SERVER_ERROR, "timestamp"));
+    }
+}
@@ -0,0 +1,157 @@
+// Coalescing of content deltas. Dev can send an event per character, which
+// becomes thousands of tiny SSE events; with COALESCE_INTERVAL_MS set,
+// adjacent content chunks of a choice are merged into one that goes out
+// after that many milliseconds, or sooner once it holds COALESCE_MAX_BYTES
+// (1024). The first content chunk is sent at once, so the time to first
+// byte does not change; chunks that finish the choice flush what is held.
+
+use anyhow::Result;
+use futures_util::stream::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::env_or;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::{ChunkStream, StreamTransformer};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Coalescing {
+    pub interval: Duration,
+    pub max_bytes: usize,
+}
+
+impl Default for Coalescing {
+    fn default() -> Self {
+        Self { interval: Duration::ZERO, max_bytes: 1024 }
+    }
+}
+
+impl Coalescing {
+    pub fn from_env() -> Self {
+        Self {
+            interval: Duration::from_millis(env_or("COALESCE_INTERVAL_MS", 0)),
+            max_bytes: env_or("COALESCE_MAX_BYTES", 1024),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// `chunks` of one choice with adjacent content deltas merged.
+    pub fn coalesce<S>(self, chunks: S) -> impl Stream<Item = Result<ChatCompletionChunk>> + Send + 'static
+    where
+        S: Stream<Item = Result<ChatCompletionChunk>> + Send + Unpin + 'static,
+    {
+        struct State<S> {
+            chunks: S,
+            config: Coalescing,
+            held: Option<(ChatCompletionChunk, Instant)>,
+            ready: VecDeque<Result<ChatCompletionChunk>>,
+            first_sent: bool,
+            done: bool,
+        }
+
+        let state = State { chunks, config: self, held: None, ready: VecDeque::new(), first_sent: false, done: false };
+        futures_util::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(item) = state.ready.pop_front() {
+                    return Some((item, state));
+                }
+                if state.done {
+                    return None;
+                }
+                let next = match &state.held {
+                    Some((_, deadline)) => tokio::select! {
+                        next = state.chunks.next() => Some(next),
+                        _ = tokio::time::sleep_until(*deadline) => None,
+                    },
+                    None => Some(state.chunks.next().await),
+                };
+                match next {
+                    // The held content is due
+                    None => state.ready.extend(state.held.take().map(|(chunk, _)| Ok(chunk))),
+                    Some(Some(Ok(chunk))) if state.first_sent && content_only(&chunk) => {
+                        match &mut state.held {
+                            Some((held, _)) => append(held, chunk),
+                            None => state.held = Some((chunk, Instant::now() + state.config.interval)),
+                        }
+                        if state.held.as_ref().is_some_and(|(held, _)| content_len(held) >= state.config.max_bytes) {
+                            state.ready.extend(state.held.take().map(|(chunk, _)| Ok(chunk)));
+                        }
+                    }
+                    Some(Some(item)) => {
+                        state.first_sent |= item.as_ref().is_ok_and(content_only);
+                        state.ready.extend(state.held.take().map(|(chunk, _)| Ok(chunk)));
+                        state.ready.push_back(item);
+                    }
+                    Some(None) => {
+                        state.ready.extend(state.held.take().map(|(chunk, _)| Ok(chunk)));
+                        state.done = true;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// A chunk that only adds content to its choice.
+fn content_only(chunk: &ChatCompletionChunk) -> bool {
+    matches!(chunk.choices.as_slice(), [choice] if choice.finish_reason.is_none() && choice.delta.content.is_some())
+}
+
+fn content_len(chunk: &ChatCompletionChunk) -> usize {
+    chunk.choices.iter().filter_map(|c| c.delta.content.as_ref()).map(String::len).sum()
+}
+
+fn append(held: &mut ChatCompletionChunk, chunk: ChatCompletionChunk) {
+    if let (Some(held), Some(choice)) = (held.choices.first_mut(), chunk.choices.into_iter().next()) {
+        held.delta.content.get_or_insert_with(String::new).push_str(&choice.delta.content.unwrap_or_default());
+    }
+}
+
+/// The `coalesce` stage, see `transform`.
+impl StreamTransformer for Coalescing {
+    fn wrap(&self, chunks: ChunkStream) -> ChunkStream {
+        self.coalesce(chunks).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    fn contents(chunks: Vec<Result<ChatCompletionChunk>>) -> Vec<String> {
+        chunks.into_iter().map(|c| c.unwrap().choices[0].delta.content.clone().unwrap_or_default()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_adjacent_deltas_are_merged() {
+        let coalescing = Coalescing { interval: Duration::from_secs(60), max_bytes: 4 };
+        let chunks = futures_util::stream::iter(
+            ["H", "e", "l", "l", "o", " ", "w"].into_iter().map(|c| Ok(test_chunk(Some(c), None))).chain([Ok(test_chunk(None, Some("stop")))]),
+        );
+        let out: Vec<_> = coalescing.coalesce(chunks).collect().await;
+        // The first goes out alone, then up to 4 bytes, the rest with the finish
+        assert_eq!(contents(out), ["H", "ello", " w", ""]);
+    }
+
+    #[tokio::test]
+    async fn test_held_delta_is_flushed_after_the_interval() {
+        let coalescing = Coalescing { interval: Duration::from_millis(20), max_bytes: 1024 };
+        let slow = futures_util::stream::iter([Ok(test_chunk(Some("a"), None)), Ok(test_chunk(Some("b"), None)), Ok(test_chunk(Some("c"), None))])
+            .chain(futures_util::stream::once(async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok(test_chunk(Some("d"), None))
+            }))
+            .boxed();
+        let mut out = Box::pin(coalescing.coalesce(slow));
+        assert_eq!(contents(vec![out.next().await.unwrap()]), ["a"]);
+        let started = Instant::now();
+        assert_eq!(contents(vec![out.next().await.unwrap()]), ["bc"]);
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!(contents(vec![out.next().await.unwrap()]), ["d"]);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::auth::ApiKeyId;
+use crate::error::ApiError;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+/// Request header a client can use to mark itself as batch traffic.
+pub const X_PRIORITY: &str = "x-priority";
+
+/// Admission class of a chat request. When every stream slot is taken,
+/// queued interactive requests are admitted before batch ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Interactive,
+    Batch,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Interactive => "interactive",
+            Self::Batch => "batch",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "interactive" => Ok(Self::Interactive),
+            "batch" => Ok(Self::Batch),
+            other => Err(format!("unknown priority '{}', expected 'interactive' or 'batch'", other)),
+        }
+    }
+}
+
+type Waiter = oneshot::Sender<StreamPermit>;
+
+// Waiters of one priority class, served weighted round robin by API key: the
+// key at the front of the rotation gets up to `weight` admissions, then moves
+// to the back. A key flooding the queue therefore only delays itself.
+#[derive(Debug, Default)]
+struct FairQueue {
+    rotation: VecDeque<String>,
+    waiters: HashMap<String, VecDeque<Waiter>>,
+    // Admissions left in the current turn of the front key; 0 = new turn
+    credits: usize,
+}
+
+impl FairQueue {
+    fn push(&mut self, key: &str, waiter: Waiter) {
+        let queue = self.waiters.entry(key.to_string()).or_default();
+        if queue.is_empty() {
+            self.rotation.push_back(key.to_string());
+        }
+        queue.push_back(waiter);
+    }
+
+    fn pop(&mut self, weights: &HashMap<String, usize>) -> Option<Waiter> {
+        let key = self.rotation.front()?.clone();
+        if self.credits == 0 {
+            self.credits = weights.get(&key).copied().unwrap_or(1).max(1);
+        }
+        self.credits -= 1;
+        let queue = self.waiters.get_mut(&key)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            self.waiters.remove(&key);
+            self.rotation.pop_front();
+            self.credits = 0;
+        } else if self.credits == 0 {
+            self.rotation.rotate_left(1);
+        }
+        waiter
+    }
+
+    fn purge_closed(&mut self) {
+        self.waiters.retain(|_, queue| {
+            queue.retain(|waiter| !waiter.is_closed());
+            !queue.is_empty()
+        });
+        let front = self.rotation.front().cloned();
+        self.rotation.retain(|key| self.waiters.contains_key(key));
+        if self.rotation.front() != front.as_ref() {
+            self.credits = 0;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.waiters.values().map(VecDeque::len).sum()
+    }
+}
+
+// Free slots plus the requests waiting for one, one fair queue per priority
+// class.
+#[derive(Debug)]
+struct Scheduler {
+    available: usize,
+    queues: [FairQueue; 2],
+    weights: HashMap<String, usize>,
+}
+
+impl Scheduler {
+    /// Forgets waiters that gave up (timed out or disconnected).
+    fn purge_closed(&mut self) {
+        for queue in &mut self.queues {
+            queue.purge_closed();
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.queues.iter().map(FairQueue::len).sum()
+    }
+
+    fn next_waiter(&mut self) -> Option<Waiter> {
+        let Self { queues, weights, .. } = self;
+        queues.iter_mut().find_map(|queue| queue.pop(weights))
+    }
+}
+
+/// A stream slot; dropping it hands the slot to the next queued request.
+#[derive(Debug)]
+pub struct StreamPermit {
+    scheduler: Option<Arc<Mutex<Scheduler>>>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let Some(shared) = self.scheduler.take() else { return };
+        let mut scheduler = shared.lock().unwrap();
+        while let Some(waiter) = scheduler.next_waiter() {
+            match waiter.send(StreamPermit { scheduler: Some(shared.clone()) }) {
+                Ok(()) => return,
+                // The waiter is gone; disarm the permit so it is not released twice
+                Err(mut permit) => permit.scheduler = None,
+            }
+        }
+        scheduler.available += 1;
+    }
+}
+
+/// Caps the number of chat streams in flight at once. A permit is held for
+/// the whole lifetime of the response body, not just until the handler
+/// returns, so long SSE streams are counted correctly. With a queue
+/// configured, requests over the cap wait for a slot by priority instead of
+/// being rejected right away.
+#[derive(Debug, Clone)]
+pub struct StreamLimiter {
+    scheduler: Arc<Mutex<Scheduler>>,
+    max_streams: usize,
+    queue_size: usize,
+    queue_timeout: Duration,
+    batch_keys: Arc<HashSet<String>>,
+}
+
+impl StreamLimiter {
+    pub fn new(max_streams: usize) -> Self {
+        Self {
+            scheduler: Arc::new(Mutex::new(Scheduler {
+                available: max_streams,
+                queues: Default::default(),
+                weights: HashMap::new(),
+            })),
+            max_streams,
+            queue_size: 0,
+            queue_timeout: Duration::ZERO,
+            batch_keys: Arc::default(),
+        }
+    }
+
+    /// Lets up to `size` requests wait at most `timeout` for a slot.
+    pub fn with_queue(mut self, size: usize, timeout: Duration) -> Self {
+        self.queue_size = size;
+        self.queue_timeout = timeout;
+        self
+    }
+
+    /// API key ids (`key_...`) whose requests are always batch priority.
+    pub fn with_batch_keys<I: IntoIterator<Item = String>>(mut self, key_ids: I) -> Self {
+        self.batch_keys = Arc::new(key_ids.into_iter().collect());
+        self
+    }
+
+    /// Relative share of queued admissions per API key id (default 1).
+    pub fn with_key_weights(self, weights: HashMap<String, usize>) -> Self {
+        self.scheduler.lock().unwrap().weights = weights;
+        self
+    }
+
+    pub fn max_streams(&self) -> usize {
+        self.max_streams
+    }
+
+    /// Number of streams currently holding a permit.
+    pub fn in_flight(&self) -> usize {
+        self.max_streams - self.scheduler.lock().unwrap().available
+    }
+
+    /// Priority of `request`: batch for configured batch keys, otherwise
+    /// whatever `X-Priority` asks for (interactive by default).
+    pub fn priority_for(&self, request: &Request) -> Priority {
+        let is_batch_key = request
+            .extensions()
+            .get::<ApiKeyId>()
+            .is_some_and(|id| self.batch_keys.contains(id.as_str()));
+        if is_batch_key {
+            return Priority::Batch;
+        }
+        request
+            .headers()
+            .get(X_PRIORITY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().inspect_err(|e| debug!("Ignoring X-Priority: {}", e)).ok())
+            .unwrap_or(Priority::Interactive)
+    }
+
+    /// Takes a free slot, or queues for one under `key_id` when the queue
+    /// has room.
+    pub async fn acquire(&self, priority: Priority, key_id: &str) -> Result<StreamPermit, ApiError> {
+        let waiter = {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            scheduler.purge_closed();
+            if scheduler.available > 0 && scheduler.queued() == 0 {
+                scheduler.available -= 1;
+                return Ok(StreamPermit { scheduler: Some(self.scheduler.clone()) });
+            }
+            if scheduler.queued() >= self.queue_size {
+                warn!(max_streams = self.max_streams, queued = scheduler.queued(), "Concurrency limit reached, shedding request");
+                return Err(ApiError::overloaded());
+            }
+            let (sender, receiver) = oneshot::channel();
+            scheduler.queues[priority as usize].push(key_id, sender);
+            receiver
+        };
+
+        let started = Instant::now();
+        match tokio::time::timeout(self.queue_timeout, waiter).await {
+            Ok(Ok(permit)) => {
+                debug!(priority = priority.as_str(), waited_ms = started.elapsed().as_millis() as u64, "Admitted from queue");
+                Ok(permit)
+            }
+            _ => {
+                warn!(priority = priority.as_str(), timeout = ?self.queue_timeout, "Timed out waiting for a stream slot");
+                Err(ApiError::overloaded())
+            }
+        }
+    }
+}
+
+/// Admission middleware: waits for (or is refused) a stream slot, then ties
+/// the permit to the response body. Refusals are 503s.
+pub async fn shed_load(State(limiter): State<StreamLimiter>, request: Request, next: Next) -> Response {
+    let priority = limiter.priority_for(&request);
+    let key_id = request.extensions().get::<ApiKeyId>().cloned().unwrap_or_else(ApiKeyId::anonymous);
+    let permit = match limiter.acquire(priority, key_id.as_str()).await {
+        Ok(permit) => permit,
+        Err(e) => return e.into_response(),
+    };
+    debug!(in_flight = limiter.in_flight(), priority = priority.as_str(), "Acquired stream permit");
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let body_stream = body.into_data_stream().map(move |frame| {
+        // Keep the permit alive until the body is fully sent or dropped
+        let _permit = &permit;
+        frame
+    });
+    Response::from_parts(parts, Body::from_stream(body_stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    fn app(limiter: StreamLimiter) -> Router {
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(limiter, shed_load))
+    }
+
+    async fn wait_until_queued(limiter: &StreamLimiter, count: usize) {
+        while limiter.scheduler.lock().unwrap().queued() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permit_held_until_body_dropped() {
+        let limiter = StreamLimiter::new(1);
+        let response = app(limiter.clone())
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(limiter.in_flight(), 1);
+
+        // A second request is shed while the first body is still alive
+        let shed = app(limiter.clone())
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(response);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_interactive_admitted_before_batch() {
+        let limiter = StreamLimiter::new(1).with_queue(10, Duration::from_secs(5));
+        let running = limiter.acquire(Priority::Interactive, "k").await.unwrap();
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        for (queued, priority) in [Priority::Batch, Priority::Interactive].into_iter().enumerate() {
+            let (waiter, order_tx) = (limiter.clone(), order_tx.clone());
+            tokio::spawn(async move {
+                let _permit = waiter.acquire(priority, "k").await.unwrap();
+                order_tx.send(priority).unwrap();
+            });
+            // Queue them in a known order
+            wait_until_queued(&limiter, queued + 1).await;
+        }
+
+        drop(running);
+        assert_eq!(order_rx.recv().await, Some(Priority::Interactive));
+        assert_eq!(order_rx.recv().await, Some(Priority::Batch));
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_full_and_timeout_are_rejected() {
+        let limiter = StreamLimiter::new(1).with_queue(1, Duration::from_millis(20));
+        let _running = limiter.acquire(Priority::Interactive, "k").await.unwrap();
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(Priority::Interactive, "k").await.map(|_| ()) }
+        });
+        wait_until_queued(&limiter, 1).await;
+        let full = limiter.acquire(Priority::Interactive, "k").await.unwrap_err();
+        assert_eq!(full.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(queued.await.unwrap().is_err());
+        assert_eq!(limiter.in_flight(), 1);
+    }
+
+    #[test]
+    fn test_fair_queue_round_robin_by_weight() {
+        let mut queue = FairQueue::default();
+        let mut receivers = Vec::new();
+        for key in ["a", "a", "a", "a", "b", "b", "c"] {
+            let (sender, receiver) = oneshot::channel();
+            queue.push(key, sender);
+            receivers.push((key, receiver));
+        }
+        let weights = HashMap::from([("a".to_string(), 2)]);
+        let mut order = Vec::new();
+        while let Some(waiter) = queue.pop(&weights) {
+            waiter.send(StreamPermit { scheduler: None }).unwrap();
+            let index = receivers.iter_mut().position(|(_, r)| r.try_recv().is_ok()).unwrap();
+            order.push(receivers[index].0);
+        }
+        assert_eq!(order, ["a", "a", "b", "c", "a", "a", "b"]);
+    }
+
+    #[test]
+    fn test_purge_drops_departed_keys() {
+        let mut queue = FairQueue::default();
+        let (gone, receiver) = oneshot::channel();
+        drop(receiver);
+        queue.push("gone", gone);
+        let (waiting, _receiver) = oneshot::channel();
+        queue.push("waiting", waiting);
+        queue.purge_closed();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.rotation, ["waiting"]);
+    }
+
+    #[test]
+    fn test_priority_from_key_and_header() {
+        let limiter = StreamLimiter::new(1).with_batch_keys(vec!["key_batch".to_string()]);
+        let mut request = Request::get("/").header(X_PRIORITY, "batch").body(Body::empty()).unwrap();
+        assert_eq!(limiter.priority_for(&request), Priority::Batch);
+
+        let mut interactive = Request::get("/").header(X_PRIORITY, "interactive").body(Body::empty()).unwrap();
+        interactive.extensions_mut().insert(ApiKeyId("key_batch".to_string()));
+        assert_eq!(limiter.priority_for(&interactive), Priority::Batch);
+
+        request.headers_mut().insert(X_PRIORITY, "urgent".parse().unwrap());
+        assert_eq!(limiter.priority_for(&request), Priority::Interactive);
+    }
+}
@@ -0,0 +1,260 @@
+use http::{HeaderName, HeaderValue, Method};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+use tracing::{info, warn};
+
+/// Reads `key` from the environment and parses it, falling back to `default`
+/// when the variable is unset or malformed.
+pub fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(key) {
+        Ok(raw) => match raw.trim().parse::<T>() {
+            Ok(value) => value,
+            Err(e) => {
+                warn!(key, value = %raw, error = %e, "Invalid value for environment variable, using default");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Splits a comma separated environment value into trimmed, non-empty items.
+pub fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn env_list(key: &str, default: &str) -> Vec<String> {
+    parse_list(&env::var(key).unwrap_or_else(|_| default.to_string()))
+}
+
+/// Parses `name=weight` pairs such as `key_ab12=3,key_cd34=1`; malformed
+/// entries are skipped with a warning.
+fn parse_weights(raw: &str) -> HashMap<String, usize> {
+    parse_list(raw)
+        .into_iter()
+        .filter_map(|item| {
+            let parsed = item
+                .split_once('=')
+                .and_then(|(name, weight)| Some((name.trim().to_string(), weight.trim().parse().ok()?)));
+            if parsed.is_none() {
+                warn!(entry = %item, "Ignoring malformed weight entry");
+            }
+            parsed
+        })
+        .collect()
+}
+
+// Server-level settings for the HTTP layer, read from environment variables
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Largest accepted request body; bigger prompts are rejected with 413.
+    pub max_request_body_bytes: usize,
+    /// Timeout for ordinary (non-streaming) routes.
+    pub request_timeout: Duration,
+    /// Timeout for the streaming chat route until the response starts,
+    /// which includes signing and waiting for the Dev backend to answer.
+    pub stream_timeout: Duration,
+    /// Maximum number of chat streams in flight; excess requests are queued
+    /// or get 503.
+    pub max_concurrent_streams: usize,
+    /// Requests allowed to wait for a stream slot (0 = reject right away).
+    pub stream_queue_size: usize,
+    /// Longest a queued request waits before it gets 503.
+    pub stream_queue_timeout: Duration,
+    /// API key ids whose requests are queued as batch priority.
+    pub batch_api_key_ids: Vec<String>,
+    /// Share of queued admissions per API key id, from `key_id=weight` pairs
+    /// (keys not listed weigh 1).
+    pub stream_key_weights: HashMap<String, usize>,
+    pub cors: CorsConfig,
+    /// Serve on TCP (`PORT`); can be turned off when only a socket is wanted.
+    pub listen_tcp: bool,
+    /// Optional Unix domain socket path, served in addition to TCP.
+    pub unix_socket_path: Option<String>,
+    /// Permission bits for the socket file, given in octal (e.g. "660").
+    pub unix_socket_mode: Option<u32>,
+    /// UDP port for the experimental HTTP/3 listener (feature `http3`).
+    pub http3_port: Option<u16>,
+    /// PEM certificate chain and key used by the HTTP/3 listener.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// How long a `/readyz` upstream probe result is reused.
+    pub readiness_cache_ttl: Duration,
+    /// Timeout for a single upstream reachability probe.
+    pub readiness_probe_timeout: Duration,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let config = Self {
+            max_request_body_bytes: env_or("MAX_REQUEST_BODY_BYTES", 1024 * 1024),
+            request_timeout: Duration::from_secs(env_or("REQUEST_TIMEOUT_SECS", 30)),
+            stream_timeout: Duration::from_secs(env_or("STREAM_TIMEOUT_SECS", 120)),
+            max_concurrent_streams: env_or("MAX_CONCURRENT_STREAMS", 64),
+            stream_queue_size: env_or("STREAM_QUEUE_SIZE", 0),
+            stream_queue_timeout: Duration::from_secs(env_or("STREAM_QUEUE_TIMEOUT_SECS", 10)),
+            batch_api_key_ids: env_list("BATCH_API_KEY_IDS", ""),
+            stream_key_weights: parse_weights(&env::var("STREAM_KEY_WEIGHTS").unwrap_or_default()),
+            cors: CorsConfig::from_env(),
+            listen_tcp: env_or("LISTEN_TCP", true),
+            unix_socket_path: env::var("UNIX_SOCKET_PATH").ok().filter(|v| !v.is_empty()),
+            unix_socket_mode: env::var("UNIX_SOCKET_MODE").ok().and_then(|v| {
+                u32::from_str_radix(v.trim(), 8)
+                    .inspect_err(|e| warn!(value = %v, error = %e, "Invalid UNIX_SOCKET_MODE, ignoring"))
+                    .ok()
+            }),
+            http3_port: env::var("HTTP3_PORT").ok().and_then(|v| v.trim().parse().ok()),
+            tls_cert_path: env::var("TLS_CERT_PATH").ok().filter(|v| !v.is_empty()),
+            tls_key_path: env::var("TLS_KEY_PATH").ok().filter(|v| !v.is_empty()),
+            readiness_cache_ttl: Duration::from_secs(env_or("READINESS_CACHE_SECS", 10)),
+            readiness_probe_timeout: Duration::from_secs(env_or("READINESS_PROBE_TIMEOUT_SECS", 3)),
+        };
+        info!(?config, "Server configuration loaded");
+        config
+    }
+}
+
+// Cross-origin settings so browser-based UIs can call the proxy directly.
+// A single "*" entry means "any".
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            allowed_origins: env_list("CORS_ALLOWED_ORIGINS", "*"),
+            allowed_headers: env_list("CORS_ALLOWED_HEADERS", "authorization, content-type, accept, x-request-id, x-priority"),
+            allowed_methods: env_list("CORS_ALLOWED_METHODS", "GET, POST, OPTIONS"),
+            max_age: Duration::from_secs(env_or("CORS_MAX_AGE_SECS", 600)),
+        }
+    }
+
+    /// Builds the tower-http layer. Credentials are never allowed, so "*"
+    /// can be used for every list; `text/event-stream` responses need no
+    /// extra exposed headers for EventSource/fetch streaming to work.
+    pub fn layer(&self) -> CorsLayer {
+        let origins = if is_wildcard(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.allowed_origins.iter().filter_map(|origin| {
+                HeaderValue::from_str(origin)
+                    .inspect_err(|e| warn!(origin, error = %e, "Ignoring invalid CORS origin"))
+                    .ok()
+            }))
+        };
+        let headers = if is_wildcard(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(self.allowed_headers.iter().filter_map(|name| {
+                HeaderName::from_str(name)
+                    .inspect_err(|e| warn!(header = name, error = %e, "Ignoring invalid CORS header"))
+                    .ok()
+            }))
+        };
+        let methods = if is_wildcard(&self.allowed_methods) {
+            AllowMethods::any()
+        } else {
+            AllowMethods::list(self.allowed_methods.iter().filter_map(|method| {
+                Method::from_str(&method.to_ascii_uppercase())
+                    .inspect_err(|e| warn!(method, error = %e, "Ignoring invalid CORS method"))
+                    .ok()
+            }))
+        };
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_headers(headers)
+            .allow_methods(methods)
+            // Let browser clients read the correlation id, upstream routing
+            // and token quota
+            .expose_headers([
+                crate::request_id::X_REQUEST_ID,
+                crate::failover::X_UPSTREAM,
+                crate::failover::X_FAILOVER_REASON,
+                crate::canary::X_SIGNER_VARIANT,
+                crate::key_store::X_QUOTA_REMAINING_TOKENS_DAY,
+                crate::key_store::X_QUOTA_REMAINING_TOKENS_MONTH,
+            ])
+            .max_age(self.max_age)
+    }
+}
+
+fn is_wildcard(items: &[String]) -> bool {
+    items.iter().any(|item| item == "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::post, Router};
+    use http::{header, Request};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_parse_weights() {
+        let weights = parse_weights("key_a=3, key_b = 1, broken, key_c=x");
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights["key_a"], 3);
+        assert_eq!(weights["key_b"], 1);
+    }
+
+    fn cors(origins: &str) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: parse_list(origins),
+            allowed_headers: parse_list("authorization, content-type"),
+            allowed_methods: parse_list("post"),
+            max_age: Duration::from_secs(60),
+        }
+    }
+
+    async fn preflight(config: CorsConfig, origin: &str) -> http::Response<Body> {
+        let app = Router::new()
+            .route("/v1/chat/completions", post(|| async { "ok" }))
+            .layer(config.layer());
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/v1/chat/completions")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_list_trims_and_skips_empty() {
+        assert_eq!(parse_list(" a, b ,,c "), vec!["a", "b", "c"]);
+        assert!(parse_list("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cors_wildcard_origin() {
+        let response = preflight(cors("*"), "https://chat.example.com").await;
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn test_cors_origin_list() {
+        let config = cors("https://chat.example.com, https://ui.example.com");
+        let allowed = preflight(config.clone(), "https://ui.example.com").await;
+        assert_eq!(allowed.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://ui.example.com");
+        let denied = preflight(config, "https://evil.example.com").await;
+        assert!(denied.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
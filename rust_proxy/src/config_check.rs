@@ -0,0 +1,318 @@
+// Startup validation of the environment: every problem at once, in one
+// report, instead of the first failure deep inside some constructor.
+//
+// Errors are settings the proxy cannot do its job without: the Dev
+// credentials (API_ENDPOINT, DEVICE_ID, SID; the latter two unless
+// SECRETS_PROVIDER supplies them), URLs that do not parse and files that do
+// not exist. Warnings are malformed numbers, flags and JSON tables, which
+// the proxy would otherwise ignore in favor of their defaults. `bootstrap`
+// logs the report at startup and exits when it has errors.
+//
+// `bootstrap --check-config` goes further without binding a port: it also
+// initializes the signer and builds the Dev client, prints the report and
+// exits with 1 on errors, 0 otherwise (for CI and deployment pipelines).
+
+use std::fmt;
+use tracing::{error, warn};
+
+use crate::config::ServerConfig;
+use crate::dev_client::DevApiClient;
+use crate::signer::Signer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub key: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// What `check` initialized successfully.
+    pub passed: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    /// A non-negative integer.
+    Count,
+    /// A decimal number.
+    Number,
+    /// `true` or `false`.
+    Flag,
+    Url,
+    /// Comma separated URLs.
+    Urls,
+    Json,
+    /// The path of an existing file.
+    File,
+}
+
+/// Credentials every Dev request needs.
+const CREDENTIALS: [&str; 2] = ["DEVICE_ID", "SID"];
+
+const FORMATS: &[(&str, Format)] = &[
+    ("PORT", Format::Count),
+    ("HTTP3_PORT", Format::Count),
+    ("OS_TYPE", Format::Count),
+    ("MAX_REQUEST_BODY_BYTES", Format::Count),
+    ("REQUEST_TIMEOUT_SECS", Format::Count),
+    ("STREAM_TIMEOUT_SECS", Format::Count),
+    ("MAX_CONCURRENT_STREAMS", Format::Count),
+    ("STREAM_QUEUE_SIZE", Format::Count),
+    ("STREAM_QUEUE_TIMEOUT_SECS", Format::Count),
+    ("CORS_MAX_AGE_SECS", Format::Count),
+    ("READINESS_CACHE_SECS", Format::Count),
+    ("READINESS_PROBE_TIMEOUT_SECS", Format::Count),
+    ("SELF_TEST_TIMEOUT_SECS", Format::Count),
+    ("UPSTREAM_ERROR_BURST_WINDOW_SECS", Format::Count),
+    ("UPSTREAM_ERROR_BURST_THRESHOLD", Format::Count),
+    ("USAGE_FLUSH_SECS", Format::Count),
+    ("DEV_MAX_CONCURRENT_STREAMS", Format::Count),
+    ("DEV_STREAM_QUEUE_TIMEOUT_SECS", Format::Count),
+    ("CIRCUIT_FAILURE_THRESHOLD", Format::Count),
+    ("CIRCUIT_OPEN_SECS", Format::Count),
+    ("HEDGE_AFTER_MS", Format::Count),
+    ("WASM_POOL_SIZE", Format::Count),
+    ("WASM_WATCH_SECS", Format::Count),
+    ("SIGNATURE_CACHE_SECS", Format::Count),
+    ("CLOCK_SKEW_TOLERANCE_SECS", Format::Count),
+    ("SECRETS_REFRESH_SECS", Format::Count),
+    ("DEV_POOL_MAX_IDLE_PER_HOST", Format::Count),
+    ("DEV_POOL_IDLE_TIMEOUT_SECS", Format::Count),
+    ("DEV_TCP_KEEPALIVE_SECS", Format::Count),
+    ("DEV_CONNECT_TIMEOUT_SECS", Format::Count),
+    ("SSE_REPLAY_EVENTS", Format::Count),
+    ("SSE_REPLAY_LINGER_SECS", Format::Count),
+    ("STATE_STORE_POLL_MS", Format::Count),
+    ("STREAM_RETRY_ATTEMPTS", Format::Count),
+    ("CHAT_MAX_N", Format::Count),
+    ("FILE_MAX_BYTES", Format::Count),
+    ("FILES_MAX_TOTAL_BYTES", Format::Count),
+    ("MODERATION_OUTPUT_CHARS", Format::Count),
+    ("OUTPUT_REDACT_HOLDBACK", Format::Count),
+    ("COALESCE_INTERVAL_MS", Format::Count),
+    ("COALESCE_MAX_BYTES", Format::Count),
+    ("ACCUMULATOR_MAX_TEXT_BYTES", Format::Count),
+    ("ACCUMULATOR_MAX_REASONING_BYTES", Format::Count),
+    ("ACCUMULATOR_MAX_RELATED_BYTES", Format::Count),
+    ("ACCUMULATOR_MAX_ACTIONS", Format::Count),
+    ("WEBHOOK_TIMEOUT_SECS", Format::Count),
+    ("HISTORY_RETENTION_DAYS", Format::Count),
+    ("HISTORY_MAX_ENTRIES", Format::Count),
+    ("EMBEDDINGS_TIMEOUT_SECS", Format::Count),
+    ("ASSISTANTS_MAX_OBJECTS", Format::Count),
+    ("CAPTURE_MAX_BYTES", Format::Count),
+    ("LOG_WARN_BURST", Format::Count),
+    ("LOG_WARN_WINDOW_SECS", Format::Count),
+    ("SLOW_TTFB_MS", Format::Count),
+    ("SLOW_TOTAL_MS", Format::Count),
+    ("JWT_JWKS_REFRESH_SECS", Format::Count),
+    ("OIDC_SESSION_SECS", Format::Count),
+    ("MAX_RESPONSE_BYTES", Format::Count),
+    ("WASM_PLUGIN_FUEL", Format::Count),
+    ("SCRIPT_MAX_OPERATIONS", Format::Count),
+    ("CANARY_PERCENT", Format::Number),
+    ("CAPTURE_PERCENT", Format::Number),
+    ("PACING_RATE", Format::Number),
+    ("LISTEN_TCP", Format::Flag),
+    ("DEV_CA_EXCLUSIVE", Format::Flag),
+    ("SELF_TEST", Format::Flag),
+    ("AUDIT_LOG", Format::Flag),
+    ("AUDIT_REDACT_BUILTINS", Format::Flag),
+    ("DEV_COOKIES", Format::Flag),
+    ("LANGUAGE_DETECT", Format::Flag),
+    ("MODERATION_INPUT", Format::Flag),
+    ("MODERATION_OUTPUT", Format::Flag),
+    ("MODERATION_FAIL_CLOSED", Format::Flag),
+    ("SOURCES_FOOTER", Format::Flag),
+    ("RELATED_QUESTIONS_FOOTER", Format::Flag),
+    ("STATELESS_STREAMING", Format::Flag),
+    ("WEBHOOK_INCLUDE_TEXT", Format::Flag),
+    ("CAPTURE_HEADER", Format::Flag),
+    ("DEDUP_INFLIGHT", Format::Flag),
+    ("API_ENDPOINT", Format::Url),
+    ("FALLBACK_API_ENDPOINT", Format::Url),
+    ("DEV_THREADS_URL", Format::Url),
+    ("DEV_REFRESH_URL", Format::Url),
+    ("DEV_PROXY", Format::Url),
+    ("WASM_URL", Format::Url),
+    ("VAULT_ADDR", Format::Url),
+    ("MODERATION_API_URL", Format::Url),
+    ("EMBEDDINGS_URL", Format::Url),
+    ("JWT_JWKS_URL", Format::Url),
+    ("OIDC_ISSUER", Format::Url),
+    ("OIDC_REDIRECT_URL", Format::Url),
+    ("BILLING_WEBHOOK_URL", Format::Url),
+    ("STATE_STORE_URL", Format::Url),
+    ("WEBHOOK_URLS", Format::Urls),
+    ("MODEL_MODES", Format::Json),
+    ("MODEL_FALLBACKS", Format::Json),
+    ("SYSTEM_PROMPTS", Format::Json),
+    ("PROMPT_TEMPLATES", Format::Json),
+    ("OUTPUT_REDACT_PATTERNS", Format::Json),
+    ("JWT_SCOPE_MODELS", Format::Json),
+    ("DEV_CLIENT_CERT", Format::File),
+    ("DEV_CLIENT_KEY", Format::File),
+    ("DEV_CA_CERTS", Format::File),
+    ("TLS_CERT_PATH", Format::File),
+    ("TLS_KEY_PATH", Format::File),
+    ("CANARY_WASM_PATH", Format::File),
+    ("SCRIPT_FILE", Format::File),
+];
+
+impl Format {
+    /// What is wrong with `value`, if anything, and how bad it is.
+    fn check(self, value: &str) -> Option<(Severity, String)> {
+        let invalid = |expected: &str| format!("'{}' is not {}", value, expected);
+        match self {
+            Self::Count => value.parse::<u64>().is_err().then(|| (Severity::Warning, invalid("a non-negative integer"))),
+            Self::Number => value.parse::<f64>().is_err().then(|| (Severity::Warning, invalid("a number"))),
+            Self::Flag => value.parse::<bool>().is_err().then(|| (Severity::Warning, invalid("'true' or 'false'"))),
+            Self::Url => reqwest::Url::parse(value).err().map(|e| (Severity::Error, format!("{} ({})", invalid("a valid URL"), e))),
+            Self::Urls => value.split(',').map(str::trim).filter(|url| !url.is_empty()).find_map(|url| Self::Url.check(url)),
+            Self::Json => serde_json::from_str::<serde_json::Value>(value).err().map(|e| (Severity::Warning, format!("invalid JSON ({})", e))),
+            Self::File => (!std::path::Path::new(value).is_file()).then(|| (Severity::Error, format!("file '{}' does not exist", value))),
+        }
+    }
+
+    /// What the proxy does with a malformed value.
+    fn consequence(self) -> &'static str {
+        match self {
+            Self::Json => "the setting is ignored",
+            Self::Url | Self::Urls => "requests to it will fail",
+            Self::File => "loading it will fail",
+            _ => "the default is used",
+        }
+    }
+}
+
+/// Checks the process environment.
+pub fn validate() -> Report {
+    validate_with(|key| std::env::var(key).ok())
+}
+
+/// `validate`, then what `--check-config` initializes: the signer (loading
+/// and test-signing its module), the server settings and the Dev client.
+pub fn check() -> Report {
+    let mut report = validate();
+    let mut component = |key, result: anyhow::Result<String>| match result {
+        Ok(summary) => report.passed.push(summary),
+        Err(e) => report.problems.push(Problem { key, severity: Severity::Error, message: format!("{:#}", e) }),
+    };
+    component("SIGNER", Signer::from_env().map(|signer| match signer {
+        Signer::Wasm(wasm) => {
+            let module = wasm.module_info();
+            format!("signer: wasm, {} (sha256 {})", module.source, module.sha256)
+        }
+        #[allow(unreachable_patterns)]
+        other => format!("signer: {}", other.kind()),
+    }));
+    let server = ServerConfig::from_env();
+    component("LISTEN_TCP", match (server.listen_tcp, &server.unix_socket_path) {
+        (false, None) => Err(anyhow::anyhow!("no listener configured: set LISTEN_TCP=true or UNIX_SOCKET_PATH")),
+        _ => Ok("server settings: loaded".to_string()),
+    });
+    component("API_ENDPOINT", DevApiClient::new().map(|_| "dev client: built".to_string()));
+    report
+}
+
+fn validate_with(var: impl Fn(&str) -> Option<String>) -> Report {
+    let var = |key: &str| var(key).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    let mut report = Report::default();
+    let mut problem = |key, severity, message| report.problems.push(Problem { key, severity, message });
+
+    if var("API_ENDPOINT").is_none() {
+        problem("API_ENDPOINT", Severity::Error, "missing: the Dev chat endpoint URL is required".to_string());
+    }
+    if var("SECRETS_PROVIDER").is_none() {
+        for key in CREDENTIALS.into_iter().filter(|key| var(key).is_none()) {
+            problem(key, Severity::Error, "missing: required unless SECRETS_PROVIDER supplies it".to_string());
+        }
+    }
+    for &(key, format) in FORMATS {
+        if let Some((severity, message)) = var(key).and_then(|value| format.check(&value)) {
+            problem(key, severity, format!("{}; {}", message, format.consequence()));
+        }
+    }
+    report
+}
+
+impl Report {
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+
+    /// Logs each problem on its own line.
+    pub fn log(&self) {
+        for problem in &self.problems {
+            match problem.severity {
+                Severity::Error => error!(key = problem.key, "Invalid configuration: {}", problem.message),
+                Severity::Warning => warn!(key = problem.key, "Questionable configuration: {}", problem.message),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for passed in &self.passed {
+            writeln!(f, "{:<7} {}", "ok", passed)?;
+        }
+        for problem in &self.problems {
+            let severity = match problem.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            writeln!(f, "{:<7} {}: {}", severity, problem.key, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn validate(vars: &[(&str, &str)]) -> Report {
+        let vars: HashMap<_, _> = vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        validate_with(|key| vars.get(key).cloned())
+    }
+
+    fn keys(report: &Report, severity: Severity) -> Vec<&'static str> {
+        report.problems.iter().filter(|p| p.severity == severity).map(|p| p.key).collect()
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let report = validate(&[
+            ("API_ENDPOINT", "dev.example/api"),
+            ("SID", " "),
+            ("PORT", "80a"),
+            ("LISTEN_TCP", "yes"),
+            ("MODEL_MODES", "{"),
+            ("WEBHOOK_URLS", "https://hooks.example/a, nope"),
+            ("TLS_CERT_PATH", "/nonexistent/cert.pem"),
+        ]);
+        assert!(report.has_errors());
+        assert_eq!(keys(&report, Severity::Error), ["DEVICE_ID", "SID", "API_ENDPOINT", "WEBHOOK_URLS", "TLS_CERT_PATH"]);
+        assert_eq!(keys(&report, Severity::Warning), ["PORT", "LISTEN_TCP", "MODEL_MODES"]);
+        assert!(report.to_string().contains("warning PORT: '80a' is not a non-negative integer; the default is used"));
+    }
+
+    #[test]
+    fn test_valid_configuration() {
+        let endpoint = ("API_ENDPOINT", "https://dev.example/api/v1/chat");
+        let report = validate(&[endpoint, ("SECRETS_PROVIDER", "vault"), ("CANARY_PERCENT", "2.5"), ("DEV_COOKIES", "false")]);
+        assert!(report.problems.is_empty(), "{}", report);
+        assert!(!validate(&[endpoint, ("DEVICE_ID", "d"), ("SID", "s")]).has_errors());
+    }
+}
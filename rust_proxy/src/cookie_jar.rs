@@ -0,0 +1,137 @@
+// Cookies for the Dev backend, which sets session cookies next to the signed
+// headers. The jar captures Set-Cookie from every upstream response and
+// replays matching cookies on later requests. With DEV_COOKIE_JAR_PATH the
+// jar (session cookies included) is loaded at startup and saved whenever a
+// response changes it, so a restart keeps the session. DEV_COOKIES=false
+// turns cookie handling off.
+
+use anyhow::{Context, Result};
+use cookie_store::CookieStore;
+use http::HeaderValue;
+use reqwest::Url;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing::{debug, info, warn};
+
+pub struct CookieJar {
+    store: RwLock<CookieStore>,
+    path: Option<PathBuf>,
+}
+
+impl CookieJar {
+    /// An empty jar, saved to `path` when given.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { store: RwLock::new(CookieStore::default()), path }
+    }
+
+    /// Loads the jar saved at `path`; a missing file gives an empty jar.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let store = match std::fs::File::open(&path) {
+            Ok(file) => cookie_store::serde::json::load_all(BufReader::new(file))
+                .map_err(|e| anyhow::anyhow!(e))
+                .with_context(|| format!("Failed to parse cookie jar '{}'", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => CookieStore::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read cookie jar '{}'", path.display())),
+        };
+        info!(path = %path.display(), cookies = store.iter_unexpired().count(), "Loaded upstream cookie jar");
+        Ok(Self { store: RwLock::new(store), path: Some(path) })
+    }
+
+    /// DEV_COOKIES (true) and DEV_COOKIE_JAR_PATH, the latter optionally
+    /// overridden with `prefix` since each account has its own session.
+    pub fn from_env(prefix: &str) -> Result<Option<Self>> {
+        if !crate::config::env_or("DEV_COOKIES", true) {
+            return Ok(None);
+        }
+        let path = std::env::var(format!("{}DEV_COOKIE_JAR_PATH", prefix))
+            .ok()
+            .filter(|v| !v.is_empty())
+            .or_else(|| std::env::var("DEV_COOKIE_JAR_PATH").ok().filter(|v| !v.is_empty()));
+        match path {
+            Some(path) => Self::load(path.into()).map(Some),
+            None => Ok(Some(Self::new(None))),
+        }
+    }
+
+    /// Number of unexpired cookies.
+    pub fn len(&self) -> usize {
+        self.store.read().expect("cookie jar lock poisoned").iter_unexpired().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the jar to its file through a temporary file, so a crash never
+    /// leaves a truncated jar behind.
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp).with_context(|| format!("Failed to create '{}'", tmp.display()))?;
+        cookie_store::serde::json::save_incl_expired_and_nonpersistent(
+            &self.store.read().expect("cookie jar lock poisoned"),
+            &mut file,
+        )
+        .map_err(|e| anyhow::anyhow!(e))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace '{}'", path.display()))
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies: Vec<_> = cookie_headers
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| cookie::Cookie::parse(value.to_string()).ok())
+            .collect();
+        if cookies.is_empty() {
+            return;
+        }
+        debug!(count = cookies.len(), %url, "Storing upstream cookies");
+        self.store.write().expect("cookie jar lock poisoned").store_response_cookies(cookies.into_iter(), url);
+        if let Err(e) = self.save() {
+            warn!("Failed to save upstream cookie jar: {:#}", e);
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let store = self.store.read().expect("cookie jar lock poisoned");
+        let header = store
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        (!header.is_empty()).then(|| HeaderValue::from_str(&header).ok()).flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::cookie::CookieStore as _;
+
+    #[test]
+    fn test_captures_and_replays_cookies() {
+        let jar = CookieJar::new(None);
+        let url = Url::parse("https://api.dev.example/chat").unwrap();
+        let set = [HeaderValue::from_static("session=abc; Path=/"), HeaderValue::from_static("lang=en")];
+        jar.set_cookies(&mut set.iter(), &url);
+        assert_eq!(jar.len(), 2);
+        let header = jar.cookies(&url).unwrap();
+        assert!(header.to_str().unwrap().contains("session=abc"));
+        assert!(jar.cookies(&Url::parse("https://other.example/").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_jar_survives_reload() {
+        let path = std::env::temp_dir().join(format!("cookie_jar_test_{}.json", std::process::id()));
+        let url = Url::parse("https://api.dev.example/chat").unwrap();
+        let jar = CookieJar::load(path.clone()).unwrap();
+        assert!(jar.is_empty());
+        jar.set_cookies(&mut [HeaderValue::from_static("session=abc; Path=/")].iter(), &url);
+
+        let reloaded = CookieJar::load(path.clone()).unwrap();
+        assert_eq!(reloaded.cookies(&url).unwrap(), "session=abc");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+// Embedded admin dashboard for single-instance deployments: `/admin` serves a
+// self-contained HTML page that polls `/admin/summary` (metrics, active
+// streams, recent errors) and the public `/healthz` / `/readyz` probes. The
+// page itself holds no data; it uses the OIDC session cookie when there is
+// one, or asks for the admin token and sends it as a bearer token, so
+// everything it shows stays behind `require_admin`.
+
+use axum::extract::State;
+use axum::response::Html;
+use axum::Json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error_reporting::ErrorReporter;
+use crate::metrics::Metrics;
+use crate::streams::StreamRegistry;
+
+const DASHBOARD_HTML: &str = include_str!("assets/dashboard.html");
+
+/// `GET /admin`
+pub async fn dashboard_handler() -> Html<&'static str> {
+    Html(DASHBOARD_HTML)
+}
+
+/// `GET /admin/summary` (admin)
+#[utoipa::path(get, path = "/admin/summary", tag = "admin", security(("admin_token" = [])), responses(
+    (status = 200, description = "Per-model metrics, active streams and recent errors", body = Object),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn summary_handler(
+    State(metrics): State<Arc<Metrics>>,
+    State(streams): State<StreamRegistry>,
+    State(reporter): State<Arc<ErrorReporter>>,
+) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "object": "admin.summary",
+        "generated_at": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        "models": metrics.summary(),
+        "streams": streams.list(),
+        "recent_errors": reporter.recent(),
+    }))
+}
@@ -1,10 +1,26 @@
-use crate::{utils, wasm_signer::WasmSigner};
+use crate::{files, secrets, signer::Signer, utils};
+use crate::files::Attachment;
+use crate::citations::CitationMode;
+use crate::event_bus::AccumulatorSink;
+use crate::footers::Footers;
+use crate::sse_processor::AnswerExtras;
+use crate::cookie_jar::CookieJar;
+use crate::session_refresh::SessionRefresher;
+use crate::upstream_pool::{self, PoolConfig};
+use crate::canary::{Canary, Variant};
+use crate::clock_skew::ClockSkew;
+use crate::signature_cache::{SignatureCache, Signed};
 use anyhow::{anyhow, Context, Result};
-// use bytes::Bytes;
+use bytes::Bytes;
+use futures_util::stream::{Stream, StreamExt};
 use http::HeaderMap;
-use reqwest::{Client, Response};
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Response};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tracing::{debug, instrument, info, error, warn};
 // use crate::sse_processor::SseAccumulator;
 // use futures_util::stream::{Stream, TryStreamExt};
@@ -33,6 +49,31 @@ pub struct DevRequestOptions {
     pub thread_id: Option<String>,
     pub plugin_action: Option<String>,
     pub programming_language: Option<String>, 
+    /// Correlation id forwarded to Dev as `X-Request-Id`; not part of the body.
+    #[serde(skip)]
+    pub request_id: Option<String>,
+    /// Signer to use; `Canary` falls back to stable when no canary is loaded.
+    #[serde(skip)]
+    pub variant: Variant,
+    /// Uploaded files sent ahead of the prompt; Dev has no attachment field.
+    #[serde(skip)]
+    pub attachments: Vec<Attachment>,
+    /// Further keys for the body's `extra` object, sent verbatim.
+    #[serde(skip)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+    /// How the `citations` stage rewrites citation markers in the answer.
+    #[serde(skip)]
+    pub citations: CitationMode,
+    /// Content the `footers` stage appends when the answer ends.
+    #[serde(skip)]
+    pub footers: Footers,
+    /// Where the stream processor publishes the sources and related
+    /// questions, see `transform::ChoiceContext`.
+    #[serde(skip)]
+    pub extras: Option<AnswerExtras>,
+    /// Receive the accumulator once the answer's stream ended.
+    #[serde(skip)]
+    pub snapshots: Vec<AccumulatorSink>,
 }
 
 // Structure for the "extra" field in the request body
@@ -52,6 +93,8 @@ pub struct ExtraPayload {
     language: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     programming_language: Option<String>,
+    #[serde(flatten)]
+    passthrough: serde_json::Map<String, serde_json::Value>,
 }
 
 // Structure for the main request body sent to Dev API
@@ -64,14 +107,403 @@ pub struct DevRequestBody {
     extra: ExtraPayload,
 }
 
+/// Content codings advertised to the Dev backend via `Accept-Encoding`.
+/// reqwest decompresses the body stream transparently, keyed on the
+/// response `Content-Encoding`, for every coding enabled here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AcceptEncoding {
+    pub gzip: bool,
+    pub brotli: bool,
+    pub deflate: bool,
+}
+
+impl AcceptEncoding {
+    /// Parses a comma separated list such as "gzip, br". "identity" (or an
+    /// empty value) disables compression entirely.
+    pub fn parse(value: &str) -> Self {
+        let mut accept = Self::default();
+        for coding in value.split(',').map(|c| c.trim().to_ascii_lowercase()) {
+            match coding.as_str() {
+                "gzip" => accept.gzip = true,
+                "br" => accept.brotli = true,
+                "deflate" => accept.deflate = true,
+                "identity" | "" => {}
+                other => warn!(coding = other, "Ignoring unsupported upstream content coding"),
+            }
+        }
+        accept
+    }
+}
+
+/// TLS material for reaching the Dev backend through gateways that require
+/// mutual TLS or are signed by a private CA. All paths point to PEM files.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTlsConfig {
+    /// Client certificate chain presented to the upstream.
+    pub client_cert_path: Option<String>,
+    /// PKCS#8 private key matching `client_cert_path`.
+    pub client_key_path: Option<String>,
+    /// Extra root certificates (each file may hold a bundle).
+    pub ca_cert_paths: Vec<String>,
+    /// Trust only `ca_cert_paths`, not the system roots.
+    pub ca_exclusive: bool,
+}
+
+impl UpstreamTlsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            client_cert_path: env::var("DEV_CLIENT_CERT").ok().filter(|v| !v.is_empty()),
+            client_key_path: env::var("DEV_CLIENT_KEY").ok().filter(|v| !v.is_empty()),
+            ca_cert_paths: env::var("DEV_CA_CERTS")
+                .map(|v| crate::config::parse_list(&v))
+                .unwrap_or_default(),
+            ca_exclusive: crate::config::env_or("DEV_CA_EXCLUSIVE", false),
+        }
+    }
+
+    /// Adds the configured identity and root certificates to `builder`.
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        for path in &self.ca_cert_paths {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate file '{}'", path))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Failed to parse CA certificates in '{}'", path))?;
+            info!(path, count = certs.len(), "Adding upstream root certificates");
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.ca_exclusive {
+            if self.ca_cert_paths.is_empty() {
+                return Err(anyhow!("DEV_CA_EXCLUSIVE is set but no DEV_CA_CERTS were given"));
+            }
+            builder = builder.tls_built_in_root_certs(false);
+        }
+
+        match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = std::fs::read(cert_path)
+                    .with_context(|| format!("Failed to read client certificate '{}'", cert_path))?;
+                let key = std::fs::read(key_path)
+                    .with_context(|| format!("Failed to read client key '{}'", key_path))?;
+                let identity = Identity::from_pkcs8_pem(&cert, &key)
+                    .context("Failed to build client identity from certificate and key")?;
+                info!(cert_path, "Using client certificate for upstream mTLS");
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => return Err(anyhow!("DEV_CLIENT_CERT and DEV_CLIENT_KEY must be set together")),
+        }
+        Ok(builder)
+    }
+}
+
+/// Egress proxy for upstream traffic. Without DEV_PROXY, reqwest already
+/// honors HTTPS_PROXY / HTTP_PROXY / ALL_PROXY and NO_PROXY.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UpstreamProxyConfig {
+    /// Proxy settings from the standard environment variables.
+    #[default]
+    System,
+    /// Connect directly, ignoring the proxy environment variables.
+    Disabled,
+    /// `http://`, `https://`, `socks5://` or `socks5h://` URL (credentials
+    /// may be embedded), bypassed for hosts in `no_proxy`.
+    Url { url: String, no_proxy: Option<String> },
+}
+
+impl UpstreamProxyConfig {
+    /// DEV_PROXY (a proxy URL, or `none` / `off`) and DEV_NO_PROXY, which
+    /// falls back to NO_PROXY.
+    pub fn from_env() -> Self {
+        let value = |key: &str| env::var(key).ok().filter(|v| !v.trim().is_empty());
+        match value("DEV_PROXY").as_deref().map(str::trim) {
+            None => Self::System,
+            Some("none" | "off") => Self::Disabled,
+            Some(url) => Self::Url {
+                url: url.to_string(),
+                no_proxy: value("DEV_NO_PROXY").or_else(|| value("NO_PROXY")),
+            },
+        }
+    }
+
+    pub fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        match self {
+            Self::System => Ok(builder),
+            Self::Disabled => {
+                info!("Upstream proxy disabled");
+                Ok(builder.no_proxy())
+            }
+            Self::Url { url, no_proxy } => {
+                let proxy = reqwest::Proxy::all(url.as_str())
+                    .with_context(|| format!("Invalid DEV_PROXY '{}'", utils::redact_url_credentials(url)))?
+                    .no_proxy(no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+                info!(proxy = %utils::redact_url_credentials(url), no_proxy, "Routing upstream traffic through proxy");
+                Ok(builder.proxy(proxy))
+            }
+        }
+    }
+}
+
+/// Static host resolution for upstream hosts (DEV_RESOLVE), for when DNS
+/// for the backend is blocked or to pin a known-good regional endpoint.
+/// Entries are `host=ip`; repeat a host to give it several addresses. The
+/// port always comes from the request URL.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UpstreamResolveConfig {
+    pub hosts: Vec<(String, Vec<IpAddr>)>,
+}
+
+impl UpstreamResolveConfig {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut hosts: Vec<(String, Vec<IpAddr>)> = Vec::new();
+        for entry in crate::config::parse_list(raw) {
+            let (host, ip) = entry
+                .split_once('=')
+                .with_context(|| format!("DEV_RESOLVE entry '{}' is not host=ip", entry))?;
+            let ip: IpAddr = ip.trim().trim_start_matches('[').trim_end_matches(']').parse()
+                .with_context(|| format!("Invalid IP address in DEV_RESOLVE entry '{}'", entry))?;
+            let host = host.trim().to_ascii_lowercase();
+            match hosts.iter_mut().find(|(h, _)| *h == host) {
+                Some((_, ips)) => ips.push(ip),
+                None => hosts.push((host, vec![ip])),
+            }
+        }
+        Ok(Self { hosts })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        Self::parse(&env::var("DEV_RESOLVE").unwrap_or_default())
+    }
+
+    pub fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        for (host, ips) in &self.hosts {
+            info!(host, ?ips, "Resolving upstream host statically");
+            let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        builder
+    }
+}
+
+/// Returned (inside `anyhow::Error`) when Dev answers with a non-success
+/// status, so callers can react to the status with `downcast_ref`.
+#[derive(Debug)]
+pub struct UpstreamStatusError {
+    pub status: http::StatusCode,
+    pub body: String,
+    /// Dev's `Retry-After`, in seconds.
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for UpstreamStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Dev API Error ({}): {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for UpstreamStatusError {}
+
+/// A `Retry-After` header in seconds, either given as such or as the time
+/// until an HTTP date.
+fn retry_after(headers: &http::HeaderMap) -> Option<u64> {
+    let value = headers.get(http::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    let until = httpdate::parse_http_date(value).ok()?;
+    Some(until.duration_since(std::time::SystemTime::now()).map(|d| d.as_secs_f64().ceil() as u64).unwrap_or(0))
+}
+
+/// A 401 from `DevApiClient::execute`, before the session refresh; callers
+/// of `send_request` only ever see the inner `UpstreamStatusError`.
+#[derive(Debug)]
+struct ExpiredSession {
+    sid: String,
+    error: UpstreamStatusError,
+}
+
+impl std::fmt::Display for ExpiredSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for ExpiredSession {}
+
+/// Returned (inside `anyhow::Error`) when no upstream stream slot became
+/// free within the queue timeout.
+#[derive(Debug)]
+pub struct UpstreamQueueTimeout {
+    pub max_streams: usize,
+    pub waited: Duration,
+}
+
+impl std::fmt::Display for UpstreamQueueTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "All {} upstream stream slots stayed busy for {:?}", self.max_streams, self.waited)
+    }
+}
+
+impl std::error::Error for UpstreamQueueTimeout {}
+
+/// Caps simultaneous Dev streams for one credential, since Dev throttles
+/// accounts that hold too many SSE connections open. Requests over the cap
+/// wait in FIFO order for up to `queue_timeout`.
+#[derive(Debug, Clone)]
+pub struct UpstreamLimiter {
+    semaphore: Arc<Semaphore>,
+    max_streams: usize,
+    queue_timeout: Duration,
+}
+
+impl UpstreamLimiter {
+    pub fn new(max_streams: usize, queue_timeout: Duration) -> Self {
+        Self { semaphore: Arc::new(Semaphore::new(max_streams)), max_streams, queue_timeout }
+    }
+
+    /// DEV_MAX_CONCURRENT_STREAMS (0 or unset = unlimited) and
+    /// DEV_STREAM_QUEUE_TIMEOUT_SECS, each optionally overridden with `prefix`.
+    pub fn from_env(prefix: &str) -> Option<Self> {
+        let max_streams = crate::config::env_or(&prefixed_key(prefix, "DEV_MAX_CONCURRENT_STREAMS"), 0usize);
+        (max_streams > 0).then(|| {
+            let timeout = crate::config::env_or(&prefixed_key(prefix, "DEV_STREAM_QUEUE_TIMEOUT_SECS"), 30);
+            Self::new(max_streams, Duration::from_secs(timeout))
+        })
+    }
+
+    /// Number of upstream streams currently open.
+    pub fn in_flight(&self) -> usize {
+        self.max_streams - self.semaphore.available_permits()
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let started = Instant::now();
+        match tokio::time::timeout(self.queue_timeout, self.semaphore.clone().acquire_owned()).await {
+            Ok(permit) => {
+                let permit = permit.context("upstream limiter closed")?;
+                debug!(waited_ms = started.elapsed().as_millis() as u64, in_flight = self.in_flight(), "Acquired upstream slot");
+                Ok(permit)
+            }
+            Err(_) => {
+                warn!(max_streams = self.max_streams, "Timed out waiting for an upstream stream slot");
+                Err(UpstreamQueueTimeout { max_streams: self.max_streams, waited: started.elapsed() }.into())
+            }
+        }
+    }
+}
+
+/// A successful Dev response. Holds the upstream slot (if limited) until
+/// the body stream is dropped; derefs to the `reqwest::Response`.
+pub struct UpstreamResponse {
+    response: Response,
+    permit: Option<OwnedSemaphorePermit>,
+    first_chunk: Option<Bytes>,
+}
+
+impl UpstreamResponse {
+    /// Waits for the first body chunk, which `bytes_stream` still yields.
+    pub async fn peek_first_chunk(&mut self) -> reqwest::Result<()> {
+        if self.first_chunk.is_none() {
+            self.first_chunk = self.response.chunk().await?;
+        }
+        Ok(())
+    }
+
+    pub fn bytes_stream(self) -> impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static {
+        let permit = self.permit;
+        let first_chunk = futures_util::stream::iter(self.first_chunk.map(Ok));
+        first_chunk.chain(self.response.bytes_stream()).map(move |chunk| {
+            // Keep the slot until the stream is finished or dropped
+            let _permit = &permit;
+            chunk
+        })
+    }
+}
+
+impl Deref for UpstreamResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Response {
+        &self.response
+    }
+}
+
+impl std::fmt::Debug for UpstreamResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.response.fmt(f)
+    }
+}
+
+/// `{prefix}{key}` when that variable is set, otherwise `key`, so a prefixed
+/// client only needs to override the settings that differ.
+fn prefixed_key(prefix: &str, key: &str) -> String {
+    prefixed_key_in(prefix, key, |name| env::var(name).ok())
+}
+
+/// `prefixed_key` over the variables `lookup` finds.
+fn prefixed_key_in(prefix: &str, key: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let prefixed = format!("{}{}", prefix, key);
+    if !prefix.is_empty() && lookup(&prefixed).is_some_and(|v| !v.is_empty()) {
+        prefixed
+    } else {
+        key.to_string()
+    }
+}
+
+/// Dev account credentials sent with every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevCredentials {
+    pub device_id: String,
+    pub sid: String,
+}
+
+/// Credentials of one client, replaceable at runtime (e.g. by a secrets
+/// provider refresh) without rebuilding the client.
+#[derive(Debug, Clone)]
+pub struct CredentialStore(Arc<RwLock<Arc<DevCredentials>>>);
+
+impl CredentialStore {
+    pub fn new(credentials: DevCredentials) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(credentials))))
+    }
+
+    /// Snapshot used for one request, so signature and headers agree.
+    pub fn get(&self) -> Arc<DevCredentials> {
+        self.0.read().expect("credential lock poisoned").clone()
+    }
+
+    /// Replaces the credentials; returns whether they changed.
+    pub fn set(&self, credentials: DevCredentials) -> bool {
+        let mut current = self.0.write().expect("credential lock poisoned");
+        if **current == credentials {
+            return false;
+        }
+        *current = Arc::new(credentials);
+        true
+    }
+}
+
+/// `<origin of the chat endpoint>/api/v1/threads`.
+fn default_threads_url(api_endpoint: &str) -> String {
+    match reqwest::Url::parse(api_endpoint) {
+        Ok(url) => format!("{}/api/v1/threads", url.origin().ascii_serialization()),
+        Err(_) => format!("{}/threads", api_endpoint.trim_end_matches('/')),
+    }
+}
+
 pub struct DevApiClient {
     client: Client,
-    wasm_signer: &'static WasmSigner,
+    signer: Signer,
+    canary: Option<&'static Canary>,
     // Add fields for configuration
     api_endpoint: String,
-    device_id: String,
+    threads_url: String,
+    credentials: CredentialStore,
     os_type: String,
-    sid: String,
+    upstream_limiter: Option<UpstreamLimiter>,
+    clock: Arc<ClockSkew>,
+    refresher: Option<Arc<SessionRefresher>>,
 }
 
 // Manually implement Clone
@@ -79,50 +511,190 @@ impl Clone for DevApiClient {
     fn clone(&self) -> Self {
         Self {
             client: self.client.clone(),
-            wasm_signer: self.wasm_signer, // &'static is Copy
+            signer: self.signer, // Copy
+            canary: self.canary,
             // Clone the new fields
             api_endpoint: self.api_endpoint.clone(),
-            device_id: self.device_id.clone(),
+            threads_url: self.threads_url.clone(),
+            credentials: self.credentials.clone(),
             os_type: self.os_type.clone(),
-            sid: self.sid.clone(),
+            upstream_limiter: self.upstream_limiter.clone(),
+            clock: self.clock.clone(),
+            refresher: self.refresher.clone(),
         }
     }
 }
 
 impl DevApiClient {
     pub fn new() -> Result<Self> {
+        Self::from_env_prefixed("")
+    }
+
+    /// Builds a client for another Dev endpoint/account: API_ENDPOINT,
+    /// DEVICE_ID, OS_TYPE, SID and the upstream stream limit are read with
+    /// `prefix` first (e.g. `FALLBACK_SID`), as is the cookie jar file; TLS
+    /// and encoding are shared. DEV_THREADS_URL (the API_ENDPOINT origin with
+    /// `/api/v1/threads`) is where the thread endpoints live.
+    /// With SECRETS_PROVIDER set, DEVICE_ID and SID are then kept up to date
+    /// from the secrets backend. A 401 renews the session through
+    /// DEV_REFRESH_URL or, without one, the secrets backend.
+    pub fn from_env_prefixed(prefix: &str) -> Result<Self> {
         // Read configuration from environment variables with defaults
-        let api_endpoint = env::var("API_ENDPOINT")
+        let api_endpoint = env::var(prefixed_key(prefix, "API_ENDPOINT"))
             .unwrap_or_else(|_| "https://xxx".to_string());
-        let device_id = env::var("DEVICE_ID")
+        let device_id = env::var(prefixed_key(prefix, "DEVICE_ID"))
             .unwrap_or_else(|_| "xxxx".to_string());
-        let os_type = env::var("OS_TYPE")
+        let os_type = env::var(prefixed_key(prefix, "OS_TYPE"))
             .unwrap_or_else(|_| "3".to_string());
-        let sid = env::var("SID")
+        let sid = env::var(prefixed_key(prefix, "SID"))
         .unwrap_or_else(|_|"sid".to_string());
-            
-        info!(api_endpoint, device_id, os_type, "DevApiClient configured");
+        let threads_url = env::var(prefixed_key(prefix, "DEV_THREADS_URL"))
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| default_threads_url(&api_endpoint));
+        let accept_encoding = AcceptEncoding::parse(
+            &env::var("DEV_ACCEPT_ENCODING").unwrap_or_else(|_| "gzip, br".to_string()),
+        );
+
+        info!(prefix, api_endpoint, device_id, os_type, ?accept_encoding, "DevApiClient configured");
         // debug!("api_endpoint: {}", api_endpoint);
         // debug!("device_id: {}", device_id);
         // debug!("os_type: {}", os_type);
         // debug!("sid: {}", sid);
 
-        let client = Client::builder().build()
+        let tls_config = UpstreamTlsConfig::from_env();
+        let proxy_config = UpstreamProxyConfig::from_env();
+        let resolve_config = UpstreamResolveConfig::from_env()?;
+        let pool_config = PoolConfig::from_env();
+
+        let mut client_builder = pool_config.apply(Client::builder())
+            .gzip(accept_encoding.gzip)
+            .brotli(accept_encoding.brotli)
+            .deflate(accept_encoding.deflate);
+        if let Some(jar) = CookieJar::from_env(prefix).context("Failed to set up the upstream cookie jar")? {
+            client_builder = client_builder.cookie_provider(Arc::new(jar));
+        }
+        let client_builder = resolve_config.apply(proxy_config.apply(client_builder)?);
+        let client = tls_config.apply(client_builder)
+            .context("Failed to apply upstream TLS configuration")?
+            .build()
             .context("Failed to build reqwest client")?;
-        let wasm_signer = WasmSigner::get_instance()
-            .context("Failed to get WasmSigner instance")?; // Propagate error if init failed
-            
+        let signer = Signer::from_env()
+            .context("Failed to initialize the request signer")?; // Propagate error if init failed
+        let canary = Canary::get_instance();
+        let upstream_limiter = UpstreamLimiter::from_env(prefix);
+        if let Some(limiter) = &upstream_limiter {
+            info!(max_streams = limiter.max_streams, queue_timeout = ?limiter.queue_timeout, "Upstream streams are limited");
+        }
+
+        let credentials = CredentialStore::new(DevCredentials { device_id, sid });
+        let provider = secrets::provider_from_env().context("Failed to configure the secrets provider")?;
+        if let Some(provider) = &provider {
+            secrets::spawn_refresh(provider.clone(), prefix.to_string(), credentials.clone());
+        }
+        let refresh_url = env::var(prefixed_key(prefix, "DEV_REFRESH_URL")).ok().filter(|v| !v.is_empty());
+        let refresher = match (refresh_url, provider) {
+            (Some(url), _) => {
+                info!(prefix, url, "Dev sessions are refreshed through the refresh endpoint");
+                Some(SessionRefresher::endpoint(client.clone(), url, credentials.clone()))
+            }
+            (None, Some(provider)) => Some(SessionRefresher::secrets(provider, prefix.to_string(), credentials.clone())),
+            (None, None) => None,
+        };
+
         Ok(Self {
             client,
-            wasm_signer,
+            signer,
+            canary,
             // Store the configuration
             api_endpoint,
-            device_id,
+            threads_url,
+            credentials,
             os_type,
-            sid,
+            upstream_limiter,
+            clock: Arc::new(ClockSkew::from_env()),
+            refresher: refresher.map(Arc::new),
         })
     }
 
+    /// Canary variant for a request; always stable without a canary signer.
+    pub fn variant_for(&self, request_id: &str) -> Variant {
+        self.canary.map_or(Variant::Stable, |canary| canary.variant_for(request_id))
+    }
+
+    /// Signs `content` with the given nonce and timestamp, using the canary
+    /// signer for `Variant::Canary` when one is loaded.
+    pub fn sign(
+        &self,
+        credentials: &DevCredentials,
+        content: &str,
+        nonce: &str,
+        timestamp: &str,
+        variant: Variant,
+    ) -> Result<Signed> {
+        let signer = match (variant, self.canary) {
+            (Variant::Canary, Some(canary)) => Signer::Wasm(canary.signer()),
+            _ => self.signer,
+        };
+        debug!(timestamp, nonce, variant = variant.as_str(), signer = signer.kind(), "Calling signer...");
+        let signature = signer.sign(
+            nonce,
+            timestamp,
+            &credentials.device_id, // Pass configured device_id
+            content,
+        ).context("Failed to get signature")?;
+        debug!(signature, "Signature received");
+        Ok(Signed { nonce: nonce.to_string(), timestamp: timestamp.to_string(), signature })
+    }
+
+    /// The headers Dev authenticates a request with.
+    pub fn signed_headers(
+        &self,
+        credentials: &DevCredentials,
+        signed: &Signed,
+        request_id: Option<&str>,
+    ) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::CONTENT_TYPE, "application/json".parse()?);
+        // Use the current device_id and self.os_type
+        headers.insert("device-id", credentials.device_id.parse()?);
+        headers.insert("os-type", self.os_type.parse()?);
+        headers.insert("nonce", signed.nonce.parse()?);
+        headers.insert("timestamp", signed.timestamp.parse()?);
+        headers.insert("sign", signed.signature.parse()?);
+        headers.insert("sid", credentials.sid.parse()?);
+        if let Some(request_id) = request_id {
+            headers.insert(crate::request_id::X_REQUEST_ID, request_id.parse()?);
+        }
+        Ok(headers)
+    }
+
+    /// Signer kind for `variant`, for diagnostics.
+    pub fn signer_kind(&self, variant: Variant) -> &'static str {
+        match (variant, self.canary) {
+            (Variant::Canary, Some(_)) => "wasm-canary",
+            _ => self.signer.kind(),
+        }
+    }
+
+    pub fn credentials(&self) -> &CredentialStore {
+        &self.credentials
+    }
+
+    /// Skew-corrected clock used for signature timestamps.
+    pub fn clock(&self) -> &ClockSkew {
+        &self.clock
+    }
+
+    pub fn api_endpoint(&self) -> &str {
+        &self.api_endpoint
+    }
+
+    /// Base URL of the Dev thread endpoints, without a trailing slash.
+    pub fn threads_url(&self) -> &str {
+        self.threads_url.trim_end_matches('/')
+    }
+
     #[instrument(skip(self, content, options), fields(content_len = content.len()))]
     pub fn build_request_params(
         &self,
@@ -130,44 +702,29 @@ impl DevApiClient {
         options: &DevRequestOptions,
     ) -> Result<BuiltRequestParams> {
         debug!("Building request parameters using configured endpoint...");
+        let content = &*files::with_attachments(content, &options.attachments);
 
-        // 1. Timestamp and Nonce
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_secs()
-            .to_string();
-        let nonce = utils::generate_uuidv4();
-        debug!(timestamp, nonce, "Generated timestamp and nonce");
-
-        // 2. Device ID (Use configured value)
-        debug!(device_id = %self.device_id, "Using configured device ID");
-
-        // 3. WASM Signature
-        debug!("Calling WASM signer...");
-        let signature = self.wasm_signer.sign(
-            &nonce,
-            &timestamp,
-            &self.device_id, // Pass configured device_id
-            content,
-        ).context("Failed to get signature from WASM")?;
-        debug!(signature, "Signature received from WASM");
+        // 1. Device ID and session (current credentials)
+        let credentials = self.credentials.get();
+        debug!(device_id = %credentials.device_id, "Using configured device ID");
 
-        // 4. Build Headers
-        let mut headers = HeaderMap::new();
-        headers.insert(http::header::CONTENT_TYPE, "application/json".parse()?);
-        // Use self.device_id and self.os_type
-        headers.insert("device-id", self.device_id.parse()?);
-        headers.insert("os-type", self.os_type.parse()?);
-        headers.insert("nonce", nonce.parse()?);
-        headers.insert("timestamp", timestamp.parse()?);
-        headers.insert("sign", signature.parse()?);
-        headers.insert("sid", self.sid.parse()?);
+        // 2. Timestamp, nonce and signature, reused from the signature cache
+        //    for a repeated payload when it is enabled
+        let now = self.clock.now_secs();
+        let sign = || self.sign(&credentials, content, &utils::generate_uuidv4(), &now.to_string(), options.variant);
+        let signed = match SignatureCache::get_instance() {
+            Some(cache) => {
+                let key = SignatureCache::key(options.variant.as_str(), &credentials.device_id, content);
+                cache.get_or_sign(&key, now, sign)?
+            }
+            None => sign()?,
+        };
 
+        // 3. Build Headers
+        let headers = self.signed_headers(&credentials, &signed, options.request_id.as_deref())?;
         debug!(?headers, "Constructed headers");
 
-        println!("headers: {:?}", headers);
-
-        // 5. Build Body
+        // 4. Build Body
         let extra_payload = ExtraPayload {
             search_mode: options.search_mode.clone(),
             model: options.model.clone(),
@@ -176,6 +733,7 @@ impl DevApiClient {
             plugin_action: options.plugin_action.clone(),
             language: options.language.clone(),
             programming_language: options.programming_language.clone(),
+            passthrough: options.extra.clone(),
         };
 
         let request_body = DevRequestBody {
@@ -201,14 +759,44 @@ impl DevApiClient {
         &self,
         content: &str,
         options: DevRequestOptions, 
-    ) -> Result<Response> {
+    ) -> Result<UpstreamResponse> {
         debug!("Preparing to send request to Dev API...");
 
-        // 1. Build parameters
-        let params = self.build_request_params(content, &options)
+        // 0. Wait for an upstream slot before signing, so the signature is fresh
+        let permit = match &self.upstream_limiter {
+            Some(limiter) => Some(limiter.acquire().await?),
+            None => None,
+        };
+
+        // 1. Send; an expired session is renewed and the request retried once
+        let mut response = self.execute(content, &options).await;
+        if let (Err(e), Some(refresher)) = (&response, &self.refresher)
+            && let Some(rejected_sid) = e.downcast_ref::<ExpiredSession>().map(|s| s.sid.clone())
+            && refresher.refresh(&rejected_sid, &self.os_type).await
+        {
+            info!("Retrying Dev API request with the refreshed session");
+            response = self.execute(content, &options).await;
+        }
+        let response = response.map_err(|e| match e.downcast::<ExpiredSession>() {
+            Ok(expired) => expired.error.into(),
+            Err(e) => e,
+        })?;
+
+        // If success, return the response
+        self.clock.observe(response.headers(), false);
+        info!("Dev API request successful, returning response.");
+        Ok(UpstreamResponse { response, permit, first_chunk: None })
+    }
+
+    /// One signed attempt. Non-success statuses become `UpstreamStatusError`,
+    /// wrapped in `ExpiredSession` for a 401 that a new session may fix.
+    async fn execute(&self, content: &str, options: &DevRequestOptions) -> Result<Response> {
+        // Build parameters
+        let params = self.build_request_params(content, options)
             .context("Failed to build request parameters")?;
+        let sid = params.headers.get("sid").and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
 
-        // 2. Build reqwest request
+        // Build reqwest request
         let request = self.client
             .post(&params.url)
             .headers(params.headers)
@@ -218,23 +806,211 @@ impl DevApiClient {
 
         debug!(url = %params.url, "Sending request...");
 
-        // 3. Send request and get response
+        // Send request and get response
+        upstream_pool::REQUESTS.inc();
         let response = self.client.execute(request).await
             .context("Failed to execute request to Dev API")?;
 
         debug!(status = %response.status(), "Received response status");
 
+        // reqwest strips Content-Encoding once it has decoded the body, so a
+        // remaining header means a coding we did not enable is coming through.
+        if let Some(encoding) = response.headers().get(http::header::CONTENT_ENCODING) {
+            warn!(?encoding, "Dev API response uses a content coding that is not being decoded");
+        }
+
+        self.check_status(response, sid).await
+    }
+
+    /// `response` if it is a success. Otherwise an `UpstreamStatusError`,
+    /// wrapped in `ExpiredSession` for a 401 that a new session may fix.
+    async fn check_status(&self, response: Response, sid: String) -> Result<Response> {
         // Check status: If not success, consume response to get error and return Err
         if !response.status().is_success() {
              let status = response.status();
+             let headers = response.headers().clone();
              let error_body = response.text().await
                 .unwrap_or_else(|e| format!("Failed to read error body: {}", e));
              error!(%status, error_body, "Dev API returned non-success status");
-             return Err(anyhow!("Dev API Error ({}): {}", status, error_body)); // Return Err directly
+             // Correct the timestamps of later requests if ours was rejected
+             let timestamp_rejected = self.clock.is_rejection(status, &error_body);
+             self.clock.observe(&headers, timestamp_rejected);
+             let error = UpstreamStatusError { status, body: error_body, retry_after: retry_after(&headers) };
+             if status == http::StatusCode::UNAUTHORIZED && !timestamp_rejected {
+                 return Err(ExpiredSession { sid, error }.into());
+             }
+             return Err(error.into()); // Return Err directly
         }
-        
-        // If success, return the response
-        info!("Dev API request successful, returning response.");
         Ok(response)
     }
-} 
\ No newline at end of file
+
+    /// A signed request to another Dev API of this account, such as the
+    /// thread endpoints. The signature covers `body` (empty for GET and
+    /// DELETE) like it covers a chat body; an expired session is renewed and
+    /// the request retried once.
+    #[instrument(skip(self, body))]
+    pub async fn send_api_request(
+        &self,
+        method: http::Method,
+        url: &str,
+        body: Option<String>,
+        request_id: Option<&str>,
+    ) -> Result<Response> {
+        let mut response = self.execute_api(&method, url, body.as_deref(), request_id).await;
+        if let (Err(e), Some(refresher)) = (&response, &self.refresher)
+            && let Some(rejected_sid) = e.downcast_ref::<ExpiredSession>().map(|s| s.sid.clone())
+            && refresher.refresh(&rejected_sid, &self.os_type).await
+        {
+            info!("Retrying Dev API request with the refreshed session");
+            response = self.execute_api(&method, url, body.as_deref(), request_id).await;
+        }
+        let response = response.map_err(|e| match e.downcast::<ExpiredSession>() {
+            Ok(expired) => expired.error.into(),
+            Err(e) => e,
+        })?;
+        self.clock.observe(response.headers(), false);
+        Ok(response)
+    }
+
+    async fn execute_api(&self, method: &http::Method, url: &str, body: Option<&str>, request_id: Option<&str>) -> Result<Response> {
+        let credentials = self.credentials.get();
+        let now = self.clock.now_secs();
+        let signed = self.sign(&credentials, body.unwrap_or_default(), &utils::generate_uuidv4(), &now.to_string(), Variant::Stable)?;
+        let headers = self.signed_headers(&credentials, &signed, request_id)?;
+        let mut request = self.client.request(method.clone(), url).headers(headers);
+        if let Some(body) = body {
+            request = request.body(body.to_string());
+        }
+        debug!(%method, url, "Sending Dev API request...");
+        upstream_pool::REQUESTS.inc();
+        let response = request.send().await.with_context(|| format!("Failed to execute {} {}", method, url))?;
+        self.check_status(response, credentials.sid.clone()).await
+    }
+
+    /// Cheap reachability check: an unsigned HEAD request to the configured
+    /// endpoint. Any HTTP status proves DNS, TCP and TLS are working; only
+    /// transport failures are reported as errors.
+    #[instrument(skip(self))]
+    pub async fn probe(&self, timeout: Duration) -> Result<http::StatusCode> {
+        let response = self.client
+            .head(&self.api_endpoint)
+            .timeout(timeout)
+            .send()
+            .await
+            .with_context(|| format!("Dev API endpoint '{}' is unreachable", self.api_endpoint))?;
+        debug!(status = %response.status(), "Upstream probe answered");
+        Ok(response.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_config_requires_cert_and_key_together() {
+        let config = UpstreamTlsConfig {
+            client_cert_path: Some("client.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(config.apply(Client::builder()).is_err());
+    }
+
+    #[test]
+    fn test_tls_config_exclusive_requires_ca() {
+        let config = UpstreamTlsConfig { ca_exclusive: true, ..Default::default() };
+        assert!(config.apply(Client::builder()).is_err());
+    }
+
+    #[test]
+    fn test_tls_config_missing_ca_file_is_reported() {
+        let config = UpstreamTlsConfig {
+            ca_cert_paths: vec!["/nonexistent/ca.pem".to_string()],
+            ..Default::default()
+        };
+        let err = config.apply(Client::builder()).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(http::header::RETRY_AFTER, " 12 ".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(12));
+        let date = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(90));
+        headers.insert(http::header::RETRY_AFTER, date.parse().unwrap());
+        assert!((89..=90).contains(&retry_after(&headers).unwrap()));
+        headers.insert(http::header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_limiter_queues_then_times_out() {
+        let limiter = UpstreamLimiter::new(1, Duration::from_millis(20));
+        let held = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+        let err = limiter.acquire().await.unwrap_err();
+        assert!(err.downcast_ref::<UpstreamQueueTimeout>().is_some());
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        drop(held);
+        assert!(waiter.await.unwrap());
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_proxy_config_accepts_socks_and_rejects_garbage() {
+        let socks = UpstreamProxyConfig::Url { url: "socks5h://user:pw@127.0.0.1:1080".to_string(), no_proxy: None };
+        assert!(socks.apply(Client::builder()).is_ok());
+        let bad = UpstreamProxyConfig::Url { url: "not a url".to_string(), no_proxy: Some("localhost".to_string()) };
+        assert!(bad.apply(Client::builder()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_config_groups_hosts() {
+        let config = UpstreamResolveConfig::parse("Api.Dev.example=203.0.113.7, api.dev.example=[2001:db8::1],b=10.0.0.1").unwrap();
+        assert_eq!(config.hosts.len(), 2);
+        assert_eq!(config.hosts[0].0, "api.dev.example");
+        assert_eq!(config.hosts[0].1, vec!["203.0.113.7".parse::<IpAddr>().unwrap(), "2001:db8::1".parse().unwrap()]);
+        assert!(UpstreamResolveConfig::parse("api.dev.example").is_err());
+        assert!(UpstreamResolveConfig::parse("api.dev.example=nope").is_err());
+        assert_eq!(UpstreamResolveConfig::parse("").unwrap(), UpstreamResolveConfig::default());
+    }
+
+    #[test]
+    fn test_prefixed_key_falls_back_to_plain() {
+        let lookup = |name: &str| (name == "FALLBACK_SID" || name == "SID").then(|| "x".to_string());
+        assert_eq!(prefixed_key_in("FALLBACK_", "SID", lookup), "FALLBACK_SID");
+        assert_eq!(prefixed_key_in("FALLBACK_", "DEVICE_ID", lookup), "DEVICE_ID");
+        assert_eq!(prefixed_key_in("", "SID", lookup), "SID");
+    }
+
+    #[test]
+    fn test_accept_encoding_parse_list() {
+        let accept = AcceptEncoding::parse("gzip, BR");
+        assert_eq!(accept, AcceptEncoding { gzip: true, brotli: true, deflate: false });
+    }
+
+    #[test]
+    fn test_accept_encoding_parse_identity_disables_all() {
+        assert_eq!(AcceptEncoding::parse("identity"), AcceptEncoding::default());
+        assert_eq!(AcceptEncoding::parse(""), AcceptEncoding::default());
+    }
+
+    #[test]
+    fn test_accept_encoding_parse_ignores_unknown() {
+        let accept = AcceptEncoding::parse("zstd, deflate");
+        assert_eq!(accept, AcceptEncoding { gzip: false, brotli: false, deflate: true });
+    }
+
+    #[test]
+    fn test_default_threads_url() {
+        assert_eq!(default_threads_url("https://api.example.com/api/v1/stream/chat"), "https://api.example.com/api/v1/threads");
+        assert_eq!(default_threads_url("not a url/"), "not a url/threads");
+    }
+}
@@ -0,0 +1,175 @@
+// Translation of Dev errors into OpenAI errors, so clients can tell an
+// expired upstream session from a blocked prompt without parsing messages.
+// The body of a non-success Dev response, or the data of an `error` event,
+// is read as JSON (`{"error": {"message", "code"}}`, `{"error": "..."}`,
+// `{"message", "code"}`, ...) or as plain text, and its code, message and
+// status are matched against the failures Dev is known to report.
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::sse_processor::ChunkError;
+
+/// Longest message passed on to clients, in characters.
+const MAX_MESSAGE_CHARS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevErrorKind {
+    /// Dev no longer accepts our session or token.
+    AuthExpired,
+    /// The Dev account ran out of credits.
+    QuotaExceeded,
+    /// Dev refused the prompt or the answer.
+    ContentBlocked,
+    /// The requested model does not exist or is switched off.
+    ModelUnavailable,
+    RateLimited,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevError {
+    pub kind: DevErrorKind,
+    pub message: String,
+}
+
+impl DevError {
+    /// Classifies a Dev error body; `status` is that of the response, if
+    /// the error did not arrive mid-stream.
+    pub fn parse(status: Option<StatusCode>, body: &str) -> Self {
+        let json: Option<Value> = serde_json::from_str(body.trim()).ok();
+        let field = |paths: &[&[&str]]| {
+            let json = json.as_ref()?;
+            paths.iter().find_map(|path| {
+                let value = path.iter().try_fold(json, |value, key| value.get(key))?;
+                match value {
+                    Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+                    Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                }
+            })
+        };
+        let message = match &json {
+            Some(Value::String(s)) => Some(s.trim().to_string()),
+            Some(_) => field(&[&["error", "message"], &["error"], &["message"], &["msg"], &["detail"]]),
+            None => Some(body.trim().to_string()).filter(|b| !b.is_empty()),
+        };
+        let code = field(&[&["error", "code"], &["code"], &["error", "type"], &["type"]]).unwrap_or_default();
+        let message = message
+            .or_else(|| status.and_then(|s| s.canonical_reason()).map(str::to_string))
+            .unwrap_or_else(|| "The backend service returned an error".to_string());
+        let kind = classify(status, &format!("{} {}", code, message).to_lowercase());
+        Self { kind, message: message.chars().take(MAX_MESSAGE_CHARS).collect() }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            DevErrorKind::AuthExpired => StatusCode::UNAUTHORIZED,
+            DevErrorKind::QuotaExceeded | DevErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            DevErrorKind::ContentBlocked => StatusCode::BAD_REQUEST,
+            DevErrorKind::ModelUnavailable => StatusCode::NOT_FOUND,
+            DevErrorKind::Other => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn error_type(&self) -> &'static str {
+        match self.kind {
+            DevErrorKind::AuthExpired => "authentication_error",
+            DevErrorKind::QuotaExceeded => "insufficient_quota",
+            DevErrorKind::RateLimited => "requests",
+            DevErrorKind::ContentBlocked | DevErrorKind::ModelUnavailable => "invalid_request_error",
+            DevErrorKind::Other => "server_error",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            DevErrorKind::AuthExpired => "upstream_auth_expired",
+            DevErrorKind::QuotaExceeded => "insufficient_quota",
+            DevErrorKind::RateLimited => "rate_limit_exceeded",
+            DevErrorKind::ContentBlocked => "content_policy_violation",
+            DevErrorKind::ModelUnavailable => "model_not_found",
+            DevErrorKind::Other => "upstream_error",
+        }
+    }
+
+    /// The error response for a failed Dev request; `retry_after` is Dev's,
+    /// for rate limiting.
+    pub fn to_api_error(&self, retry_after: Option<u64>) -> ApiError {
+        if self.kind == DevErrorKind::RateLimited {
+            return ApiError::upstream_rate_limited(retry_after);
+        }
+        ApiError::new(self.status(), self.error_type(), self.message.clone()).with_code(self.code())
+    }
+
+    /// The error object of the chunk ending a stream on this error.
+    pub fn to_chunk_error(&self) -> ChunkError {
+        ChunkError { message: self.message.clone(), error_type: self.error_type(), code: self.code() }
+    }
+}
+
+/// The kind of error `text` (code and message, lowercase) describes.
+fn classify(status: Option<StatusCode>, text: &str) -> DevErrorKind {
+    let any = |words: &[&str]| words.iter().any(|w| text.contains(w));
+    if any(&["quota", "credit", "insufficient balance", "insufficient_quota", "usage limit", "subscription"]) {
+        DevErrorKind::QuotaExceeded
+    } else if any(&["content policy", "content_policy", "blocked", "moderation", "sensitive", "inappropriate", "safety"]) {
+        DevErrorKind::ContentBlocked
+    } else if text.contains("model")
+        && any(&["not found", "not_found", "unavailable", "not available", "not supported", "unsupported", "does not exist"])
+    {
+        DevErrorKind::ModelUnavailable
+    } else if status == Some(StatusCode::UNAUTHORIZED)
+        || any(&["expired", "unauthorized", "unauthenticated", "not logged in", "login required", "invalid token"])
+    {
+        DevErrorKind::AuthExpired
+    } else if status == Some(StatusCode::TOO_MANY_REQUESTS) || any(&["rate limit", "rate_limit", "too many requests"]) {
+        DevErrorKind::RateLimited
+    } else {
+        DevErrorKind::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_known_cases() {
+        let cases = [
+            (Some(StatusCode::UNAUTHORIZED), r#"{"error": "Unauthorized"}"#, DevErrorKind::AuthExpired),
+            (Some(StatusCode::FORBIDDEN), r#"{"code": "TOKEN_EXPIRED", "message": "Please log in again"}"#, DevErrorKind::AuthExpired),
+            (Some(StatusCode::TOO_MANY_REQUESTS), r#"{"error": {"message": "Daily quota exceeded"}}"#, DevErrorKind::QuotaExceeded),
+            (Some(StatusCode::TOO_MANY_REQUESTS), "slow down", DevErrorKind::RateLimited),
+            (None, "Your message was blocked by our content policy", DevErrorKind::ContentBlocked),
+            (Some(StatusCode::BAD_REQUEST), r#"{"msg": "model claude-9 is not available"}"#, DevErrorKind::ModelUnavailable),
+            (Some(StatusCode::INTERNAL_SERVER_ERROR), "", DevErrorKind::Other),
+            (Some(StatusCode::PAYMENT_REQUIRED), "Insufficient balance", DevErrorKind::QuotaExceeded),
+            // Not about money: clients would retry a quota error
+            (Some(StatusCode::BAD_REQUEST), "insufficient context length", DevErrorKind::Other),
+            (Some(StatusCode::FORBIDDEN), "insufficient permissions", DevErrorKind::Other),
+        ];
+        for (status, body, kind) in cases {
+            assert_eq!(DevError::parse(status, body).kind, kind, "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(DevError::parse(None, r#"{"error": {"message": " Daily quota exceeded "}}"#).message, "Daily quota exceeded");
+        assert_eq!(DevError::parse(None, r#""plain json string""#).message, "plain json string");
+        assert_eq!(DevError::parse(Some(StatusCode::BAD_GATEWAY), "").message, "Bad Gateway");
+        assert_eq!(DevError::parse(None, &"x".repeat(1000)).message.len(), MAX_MESSAGE_CHARS);
+    }
+
+    #[test]
+    fn test_api_error() {
+        let error = DevError::parse(Some(StatusCode::PAYMENT_REQUIRED), "insufficient credits").to_api_error(None);
+        assert_eq!((error.status, error.error_type, error.code), (StatusCode::TOO_MANY_REQUESTS, "insufficient_quota", Some("insufficient_quota")));
+        let error = DevError::parse(Some(StatusCode::TOO_MANY_REQUESTS), "").to_api_error(Some(9));
+        assert_eq!((error.code, error.retry_after), (Some("rate_limit_exceeded"), Some(9)));
+        let chunk_error = DevError::parse(None, "request blocked").to_chunk_error();
+        assert_eq!((chunk_error.error_type, chunk_error.code), ("invalid_request_error", "content_policy_violation"));
+    }
+}
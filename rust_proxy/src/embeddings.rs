@@ -0,0 +1,132 @@
+// `POST /v1/embeddings`. Dev has no embeddings, so SDKs that probe for them
+// get a 501 OpenAI error saying so, unless EMBEDDINGS_URL names an
+// OpenAI-compatible embeddings endpoint: requests are then forwarded there
+// unchanged, with EMBEDDINGS_API_KEY as the bearer token, and its response is
+// returned as is.
+
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use http::{header, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Default)]
+pub struct Embeddings {
+    client: reqwest::Client,
+    url: Option<String>,
+    api_key: Option<String>,
+    timeout: Duration,
+}
+
+impl Embeddings {
+    pub fn new(url: Option<String>, api_key: Option<String>) -> Self {
+        Self { client: reqwest::Client::new(), url, api_key, timeout: Duration::from_secs(30) }
+    }
+
+    pub fn from_env() -> Self {
+        let url = std::env::var("EMBEDDINGS_URL").ok().filter(|v| !v.trim().is_empty());
+        let api_key = std::env::var("EMBEDDINGS_API_KEY").ok().filter(|v| !v.is_empty());
+        if let Some(url) = &url {
+            info!(url, "Forwarding embeddings requests");
+        }
+        Self { timeout: Duration::from_secs(env_or("EMBEDDINGS_TIMEOUT_SECS", 30)), ..Self::new(url, api_key) }
+    }
+}
+
+/// `POST /v1/embeddings`
+#[utoipa::path(post, path = "/v1/embeddings", tag = "embeddings", security(("api_key" = [])),
+    request_body(content = Object, description = "An OpenAI embeddings request, forwarded as is"),
+    responses(
+        (status = 200, description = "The embeddings backend's response", body = Object),
+        (status = 501, description = "No embeddings backend is configured", body = ErrorBody),
+        (status = 502, description = "The embeddings backend failed", body = ErrorBody),
+    ),
+)]
+pub async fn embeddings_handler(State(embeddings): State<Arc<Embeddings>>, body: Bytes) -> Response {
+    let Some(url) = &embeddings.url else {
+        return ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "invalid_request_error",
+            "Embeddings are not available: this proxy serves chat completions only",
+        )
+        .with_code("unsupported_endpoint")
+        .into_response();
+    };
+    let mut request = embeddings
+        .client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .timeout(embeddings.timeout)
+        .body(body);
+    if let Some(api_key) = &embeddings.api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(url, "Embeddings request failed: {}", e);
+            return ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "Failed to contact the embeddings backend")
+                .into_response();
+        }
+    };
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .cloned()
+        .unwrap_or_else(|| header::HeaderValue::from_static("application/json"));
+    match response.bytes().await {
+        Ok(body) => (status, [(header::CONTENT_TYPE, content_type)], Body::from(body)).into_response(),
+        Err(e) => {
+            warn!(url, "Failed to read the embeddings response: {}", e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "Failed to read the embeddings response").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    async fn call(embeddings: Embeddings) -> (StatusCode, serde_json::Value) {
+        let app = Router::new().route("/v1/embeddings", post(embeddings_handler)).with_state(Arc::new(embeddings));
+        let request = http::Request::post("/v1/embeddings").body(Body::from(r#"{"model":"m","input":"hi"}"#)).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_without_backend() {
+        let (status, body) = call(Embeddings::default()).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(body["error"]["code"], "unsupported_endpoint");
+    }
+
+    #[tokio::test]
+    async fn test_forwards_to_backend() {
+        let backend = Router::new().route(
+            "/embeddings",
+            post(|headers: http::HeaderMap, body: String| async move {
+                let auth = headers.get(header::AUTHORIZATION).unwrap().to_str().unwrap().to_string();
+                axum::Json(serde_json::json!({ "object": "list", "auth": auth, "request": body }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/embeddings", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, backend).await });
+
+        let (status, body) = call(Embeddings::new(Some(url), Some("sk-test".to_string()))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["auth"], "Bearer sk-test");
+        assert_eq!(body["request"], r#"{"model":"m","input":"hi"}"#);
+    }
+}
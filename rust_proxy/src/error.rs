@@ -0,0 +1,207 @@
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::{header, StatusCode};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// An error rendered in the OpenAI error envelope:
+/// `{"error": {"message", "type", "param", "code"}}`.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    pub error_type: &'static str,
+    pub code: Option<&'static str>,
+    /// The request field the error is about.
+    pub param: Option<&'static str>,
+    /// Sent as a `Retry-After` header (seconds) when set.
+    pub retry_after: Option<u64>,
+}
+
+/// Backoff asked of clients when Dev rate limits without a `Retry-After`.
+const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
+
+/// OpenAI error envelope, as documented in the OpenAPI spec.
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ErrorDetail<'a> {
+    message: &'a str,
+    #[serde(rename = "type")]
+    error_type: &'a str,
+    param: Option<&'a str>,
+    code: Option<&'a str>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error_type: &'static str, message: impl Into<String>) -> Self {
+        Self { status, message: message.into(), error_type, code: None, param: None, retry_after: None }
+    }
+
+    /// 400 `invalid_request_error` about the request field `param`.
+    pub fn invalid_param(param: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request_error", message).with_param(param)
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_param(mut self, param: &'static str) -> Self {
+        self.param = Some(param);
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn payload_too_large() -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "invalid_request_error",
+            "Request body exceeds the maximum allowed size",
+        )
+        .with_code("request_too_large")
+    }
+
+    pub fn request_timeout() -> Self {
+        Self::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "timeout_error",
+            "Request timed out before a response could be produced",
+        )
+        .with_code("request_timeout")
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "invalid_request_error", message).with_code("invalid_api_key")
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "permission_error", message).with_code("forbidden")
+    }
+
+    /// 429 for Dev rate limiting us, asking clients to back off for
+    /// `retry_after` seconds, or a default if Dev did not say.
+    pub fn upstream_rate_limited(retry_after: Option<u64>) -> Self {
+        Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "requests",
+            "The backend service is rate limiting requests, please retry later",
+        )
+        .with_code("rate_limit_exceeded")
+        .with_retry_after(retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECS))
+    }
+
+    pub fn overloaded() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "server_error",
+            "The proxy is handling too many concurrent streams, please retry shortly",
+        )
+        .with_code("server_overloaded")
+        .with_retry_after(1)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                message: &self.message,
+                error_type: self.error_type,
+                param: self.param,
+                code: self.code,
+            },
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(seconds) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+/// Response mapper that turns the plain-text/empty 413 and 408 responses
+/// produced by tower-http layers and axum extractors into OpenAI errors.
+pub async fn openai_error_for_status(response: Response) -> Response {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json {
+        return response;
+    }
+    match response.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::payload_too_large().into_response(),
+        StatusCode::REQUEST_TIMEOUT => ApiError::request_timeout().into_response(),
+        _ => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_error_envelope() {
+        let response = ApiError::payload_too_large().into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let json = body_json(response).await;
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "request_too_large");
+        assert!(json["error"]["param"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_api_error_param() {
+        let response = ApiError::invalid_param("n", "n must be at least 1").with_code("invalid_n").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let json = body_json(response).await;
+        assert_eq!(json["error"]["param"], "n");
+        assert_eq!(json["error"]["code"], "invalid_n");
+    }
+
+    #[tokio::test]
+    async fn test_api_error_retry_after_header() {
+        let response = ApiError::overloaded().into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_rate_limited() {
+        let response = ApiError::upstream_rate_limited(Some(30)).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        assert_eq!(body_json(response).await["error"]["code"], "rate_limit_exceeded");
+        let response = ApiError::upstream_rate_limited(None).into_response();
+        assert_eq!(response.headers()[header::RETRY_AFTER], DEFAULT_RETRY_AFTER_SECS.to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn test_openai_error_for_status_rewrites_plain_timeout() {
+        let plain = (StatusCode::REQUEST_TIMEOUT, "").into_response();
+        let response = openai_error_for_status(plain).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(body_json(response).await["error"]["code"], "request_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_openai_error_for_status_keeps_other_responses() {
+        let ok = (StatusCode::OK, "pong").into_response();
+        let response = openai_error_for_status(ok).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
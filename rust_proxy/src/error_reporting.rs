@@ -0,0 +1,220 @@
+// Error reporting for failures that need attention beyond local logs:
+// upstream 5xx/transport failure bursts and stream-processing errors (panics
+// are captured by the Sentry panic integration). Reports are always logged
+// on the `error_report` target; with the `sentry` feature and SENTRY_DSN set
+// they are also sent to Sentry with the request context attached. The most
+// recent errors are kept in memory for the admin dashboard.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::config::env_or;
+
+/// Tracing target used for error reports.
+pub const TARGET: &str = "error_report";
+
+/// Number of errors kept for `ErrorReporter::recent`.
+const RECENT_ERRORS: usize = 50;
+
+/// Request details attached to every report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReportContext<'a> {
+    pub request_id: &'a str,
+    pub model: Option<&'a str>,
+    pub route: &'a str,
+}
+
+// Fixed window counting upstream failures; reports once per window when the
+// threshold is reached so a flapping backend does not flood the reporter.
+#[derive(Debug)]
+struct BurstWindow {
+    window: Duration,
+    threshold: u32,
+    started: Instant,
+    failures: u32,
+}
+
+impl BurstWindow {
+    fn new(window: Duration, threshold: u32) -> Self {
+        Self { window, threshold, started: Instant::now(), failures: 0 }
+    }
+
+    /// Counts one failure at `now`; returns the failure count when this call
+    /// crosses the threshold.
+    fn record(&mut self, now: Instant) -> Option<u32> {
+        if now.duration_since(self.started) >= self.window {
+            self.started = now;
+            self.failures = 0;
+        }
+        self.failures += 1;
+        (self.failures == self.threshold).then_some(self.failures)
+    }
+}
+
+/// One upstream or stream error, as listed on the admin dashboard.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    pub at: u64,
+    pub kind: &'static str,
+    pub request_id: String,
+    pub model: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct ErrorReporter {
+    upstream_failures: Mutex<BurstWindow>,
+    recent: Mutex<VecDeque<RecentError>>,
+}
+
+impl ErrorReporter {
+    pub fn new(burst_window: Duration, burst_threshold: u32) -> Self {
+        Self {
+            upstream_failures: Mutex::new(BurstWindow::new(burst_window, burst_threshold.max(1))),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            Duration::from_secs(env_or("UPSTREAM_ERROR_BURST_WINDOW_SECS", 60)),
+            env_or("UPSTREAM_ERROR_BURST_THRESHOLD", 5),
+        )
+    }
+
+    /// Records an upstream 5xx (`status`) or transport failure (`None`).
+    pub fn upstream_failure(&self, status: Option<http::StatusCode>, detail: &str, ctx: ReportContext<'_>) {
+        if status.is_some_and(|s| !s.is_server_error()) {
+            return;
+        }
+        self.remember("upstream_failure", detail, ctx);
+        let (failures, window) = {
+            let mut burst = self.upstream_failures.lock().unwrap();
+            match burst.record(Instant::now()) {
+                Some(failures) => (failures, burst.window),
+                None => return,
+            }
+        };
+        let message = format!("Upstream failure burst: {} failures within {:?}, latest: {}", failures, window, detail);
+        error!(
+            target: TARGET,
+            request_id = ctx.request_id,
+            model = ctx.model.unwrap_or("-"),
+            route = ctx.route,
+            status = status.map(|s| s.as_u16()),
+            "{}", message
+        );
+        send(&message, "upstream_burst", ctx);
+    }
+
+    /// Reports an error raised while turning the Dev stream into chunks.
+    pub fn stream_error(&self, detail: &str, ctx: ReportContext<'_>) {
+        self.remember("stream_error", detail, ctx);
+        let message = format!("Stream processing error: {}", detail);
+        error!(
+            target: TARGET,
+            request_id = ctx.request_id,
+            model = ctx.model.unwrap_or("-"),
+            route = ctx.route,
+            "{}", message
+        );
+        send(&message, "stream_error", ctx);
+    }
+
+    /// The latest errors, newest first.
+    pub fn recent(&self) -> Vec<RecentError> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    fn remember(&self, kind: &'static str, detail: &str, ctx: ReportContext<'_>) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back(RecentError {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            kind,
+            request_id: ctx.request_id.to_string(),
+            model: ctx.model.map(String::from),
+            message: detail.to_string(),
+        });
+    }
+}
+
+#[cfg(feature = "sentry")]
+fn send(message: &str, kind: &str, ctx: ReportContext<'_>) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("kind", kind);
+            scope.set_tag("request_id", ctx.request_id);
+            scope.set_tag("route", ctx.route);
+            if let Some(model) = ctx.model {
+                scope.set_tag("model", model);
+            }
+        },
+        || sentry::capture_message(message, sentry::Level::Error),
+    );
+}
+
+#[cfg(not(feature = "sentry"))]
+fn send(_message: &str, _kind: &str, _ctx: ReportContext<'_>) {}
+
+/// Initializes the Sentry client from SENTRY_DSN (plus optional
+/// SENTRY_ENVIRONMENT). Keep the guard alive for the life of the process so
+/// queued events are flushed on exit.
+#[cfg(feature = "sentry")]
+pub fn init_sentry() -> Option<sentry::ClientInitGuard> {
+    let dsn = std::env::var("SENTRY_DSN").ok().filter(|v| !v.is_empty())?;
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: std::env::var("SENTRY_ENVIRONMENT").ok().map(Into::into),
+            ..Default::default()
+        },
+    ));
+    tracing::info!(enabled = guard.is_enabled(), "Sentry error reporting initialized");
+    Some(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_reports_once_at_threshold() {
+        let start = Instant::now();
+        let mut burst = BurstWindow { window: Duration::from_secs(60), threshold: 3, started: start, failures: 0 };
+        assert_eq!(burst.record(start), None);
+        assert_eq!(burst.record(start), None);
+        assert_eq!(burst.record(start), Some(3));
+        assert_eq!(burst.record(start), None);
+    }
+
+    #[test]
+    fn test_recent_errors_are_capped_newest_first() {
+        let reporter = ErrorReporter::new(Duration::from_secs(60), 1000);
+        let ctx = ReportContext { request_id: "req", model: None, route: "/" };
+        reporter.upstream_failure(Some(http::StatusCode::BAD_REQUEST), "ignored", ctx);
+        for i in 0..RECENT_ERRORS + 2 {
+            reporter.stream_error(&format!("error {}", i), ctx);
+        }
+        let recent = reporter.recent();
+        assert_eq!(recent.len(), RECENT_ERRORS);
+        assert_eq!(recent[0].message, format!("error {}", RECENT_ERRORS + 1));
+        assert_eq!(recent[0].kind, "stream_error");
+    }
+
+    #[test]
+    fn test_burst_window_resets() {
+        let start = Instant::now();
+        let mut burst = BurstWindow { window: Duration::from_secs(60), threshold: 2, started: start, failures: 0 };
+        assert_eq!(burst.record(start), None);
+        let later = start + Duration::from_secs(61);
+        assert_eq!(burst.record(later), None);
+        assert_eq!(burst.record(later), Some(2));
+    }
+}
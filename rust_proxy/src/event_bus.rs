@@ -0,0 +1,284 @@
+// Publishing of completed conversations to an event bus, so analytics
+// pipelines can consume them without sitting on the HTTP path. With
+// EVENT_BUS set to `nats` or `kafka`, every answer whose Dev stream ended
+// becomes one JSON message on EVENT_BUS_TOPIC (`chat.completions`): the
+// request, its model, key and choice index, and the accumulator snapshot
+// (text, sources, related questions, thread ids). EVENT_BUS_URL is the NATS
+// server or the Kafka bootstrap servers; the request id is the Kafka key.
+//
+// Each backend needs its cargo feature (`nats`, `kafka`); without it the
+// setting is ignored with a warning. Messages are published in the
+// background and dropped when the bus is unavailable.
+
+use anyhow::{bail, Result};
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::sse_processor::SseAccumulator;
+use crate::webhooks::CompletionInfo;
+
+/// Published messages by `result` (ok / error), registered by `Metrics`.
+pub static MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("event_bus_messages_total", "Completed conversations published to the event bus"), &["result"])
+        .expect("valid counter")
+});
+
+/// Receives the accumulator of an answer once its Dev stream ended.
+pub type AccumulatorSink = mpsc::UnboundedSender<SseAccumulator>;
+
+/// A message broker completed conversations are sent to.
+pub trait Publisher: Send + Sync {
+    /// Name for logs.
+    fn name(&self) -> &'static str;
+
+    fn publish<'a>(&'a self, key: &'a str, payload: Vec<u8>) -> BoxFuture<'a, Result<()>>;
+}
+
+#[derive(Debug, Serialize)]
+struct Message<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    completion: &'a CompletionInfo,
+    index: u32,
+    accumulator: &'a SseAccumulator,
+}
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    publisher: Option<Arc<dyn Publisher>>,
+}
+
+impl EventBus {
+    pub fn new(publisher: Arc<dyn Publisher>) -> Self {
+        Self { publisher: Some(publisher) }
+    }
+
+    pub fn from_env() -> Self {
+        let topic = std::env::var("EVENT_BUS_TOPIC").ok().filter(|v| !v.trim().is_empty()).unwrap_or_else(|| "chat.completions".to_string());
+        match publisher_from_env(topic) {
+            Ok(Some(publisher)) => {
+                info!(bus = publisher.name(), "Publishing completed conversations to the event bus");
+                Self::new(publisher)
+            }
+            Ok(None) => Self::default(),
+            Err(e) => {
+                warn!("Event bus disabled: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.publisher.is_some()
+    }
+
+    /// A sink for choice `index` of `completion`; what it receives is
+    /// published in the background.
+    pub fn sink(&self, completion: CompletionInfo, index: u32) -> Option<AccumulatorSink> {
+        let publisher = self.publisher.clone()?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<SseAccumulator>();
+        tokio::spawn(async move {
+            while let Some(accumulator) = rx.recv().await {
+                let message = Message {
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+                    completion: &completion,
+                    index,
+                    accumulator: &accumulator,
+                };
+                let payload = match serde_json::to_vec(&message) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Failed to serialize event bus message: {}", e);
+                        continue;
+                    }
+                };
+                match publisher.publish(&completion.request_id, payload).await {
+                    Ok(()) => {
+                        debug!(bus = publisher.name(), request_id = %completion.request_id, index, "Published completed conversation");
+                        MESSAGES.with_label_values(&["ok"]).inc();
+                    }
+                    Err(e) => {
+                        warn!(bus = publisher.name(), request_id = %completion.request_id, "Failed to publish completed conversation: {:#}", e);
+                        MESSAGES.with_label_values(&["error"]).inc();
+                    }
+                }
+            }
+        });
+        Some(tx)
+    }
+}
+
+/// A publisher to `topic` on the EVENT_BUS broker; `None` when unset.
+pub fn publisher_from_env(topic: String) -> Result<Option<Arc<dyn Publisher>>> {
+    let bus = std::env::var("EVENT_BUS").unwrap_or_default().trim().to_ascii_lowercase();
+    if bus.is_empty() {
+        return Ok(None);
+    }
+    let url = std::env::var("EVENT_BUS_URL").ok().filter(|v| !v.trim().is_empty());
+    let Some(url) = url else {
+        bail!("EVENT_BUS is '{}' but EVENT_BUS_URL is not set", bus);
+    };
+    match bus.as_str() {
+        "nats" => nats::publisher(url, topic),
+        "kafka" => kafka::publisher(url, topic),
+        other => bail!("Unknown EVENT_BUS '{}', expected 'nats' or 'kafka'", other),
+    }
+    .map(Some)
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::*;
+    use anyhow::Context;
+    use futures_util::FutureExt;
+    use tokio::sync::OnceCell;
+
+    /// Connects on first use, so startup does not wait for the server.
+    struct NatsPublisher {
+        url: String,
+        subject: String,
+        client: OnceCell<async_nats::Client>,
+    }
+
+    impl Publisher for NatsPublisher {
+        fn name(&self) -> &'static str {
+            "nats"
+        }
+
+        fn publish<'a>(&'a self, _key: &'a str, payload: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let client = self
+                    .client
+                    .get_or_try_init(|| async_nats::connect(self.url.as_str()))
+                    .await
+                    .with_context(|| format!("connecting to {}", self.url))?;
+                client.publish(self.subject.clone(), payload.into()).await?;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    pub(super) fn publisher(url: String, subject: String) -> Result<Arc<dyn Publisher>> {
+        Ok(Arc::new(NatsPublisher { url, subject, client: OnceCell::new() }))
+    }
+}
+
+#[cfg(not(feature = "nats"))]
+mod nats {
+    use super::*;
+
+    pub(super) fn publisher(_url: String, _subject: String) -> Result<Arc<dyn Publisher>> {
+        bail!("EVENT_BUS is 'nats' but this build lacks the `nats` feature")
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::*;
+    use anyhow::anyhow;
+    use futures_util::FutureExt;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::ClientConfig;
+    use std::time::Duration;
+
+    const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct KafkaPublisher {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl Publisher for KafkaPublisher {
+        fn name(&self) -> &'static str {
+            "kafka"
+        }
+
+        fn publish<'a>(&'a self, key: &'a str, payload: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let record = FutureRecord::to(&self.topic).key(key).payload(&payload);
+                self.producer.send(record, QUEUE_TIMEOUT).await.map_err(|(e, _)| anyhow!(e))?;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    pub(super) fn publisher(brokers: String, topic: String) -> Result<Arc<dyn Publisher>> {
+        let producer = ClientConfig::new().set("bootstrap.servers", &brokers).create()?;
+        Ok(Arc::new(KafkaPublisher { producer, topic }))
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+mod kafka {
+    use super::*;
+
+    pub(super) fn publisher(_brokers: String, _topic: String) -> Result<Arc<dyn Publisher>> {
+        bail!("EVENT_BUS is 'kafka' but this build lacks the `kafka` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        messages: Mutex<Vec<(String, serde_json::Value)>>,
+        notify: tokio::sync::Notify,
+    }
+
+    impl Publisher for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn publish<'a>(&'a self, key: &'a str, payload: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.messages.lock().unwrap().push((key.to_string(), serde_json::from_slice(&payload)?));
+                self.notify.notify_one();
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_published() {
+        let recorder = Arc::new(Recorder::default());
+        let bus = EventBus::new(recorder.clone());
+        let completion = CompletionInfo {
+            request_id: "req-1".to_string(),
+            model: Some("gpt-4o".to_string()),
+            api_key_id: "key_a".to_string(),
+            prompt_tokens: 3,
+        };
+        let sink = bus.sink(completion, 1).unwrap();
+        let mut accumulator = SseAccumulator::default();
+        accumulator.text = "Hello".to_string();
+        accumulator.thread_id = Some("t-1".to_string());
+        sink.send(accumulator).unwrap();
+        recorder.notify.notified().await;
+
+        let (key, message) = recorder.messages.lock().unwrap().pop().unwrap();
+        assert_eq!(key, "req-1");
+        assert_eq!((message["request_id"].as_str(), message["index"].as_u64()), (Some("req-1"), Some(1)));
+        assert_eq!(message["accumulator"]["text"], "Hello");
+        assert_eq!(message["accumulator"]["threadId"], "t-1");
+        assert!(EventBus::default().sink(CompletionInfo {
+            request_id: String::new(),
+            model: None,
+            api_key_id: String::new(),
+            prompt_tokens: 0,
+        }, 0).is_none());
+    }
+}
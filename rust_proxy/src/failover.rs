@@ -0,0 +1,500 @@
+// Routing between the primary Dev account and an optional fallback (another
+// Dev endpoint/account configured with FALLBACK_* variables). A circuit
+// breaker stops sending to a primary that keeps failing; while it is open,
+// or when a primary request fails, requests go to the fallback. With
+// HEDGE_AFTER_MS set, a primary that has not sent its first byte in time is
+// raced against a duplicate request to the fallback and the slower one is
+// cancelled. Which upstream served a request is reported in the `X-Upstream`
+// response header, and a switch also sets `X-Failover-Reason`.
+
+use anyhow::Result;
+use futures_util::future::{BoxFuture, FutureExt};
+use http::{HeaderName, HeaderValue, StatusCode};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::canary::Variant;
+use crate::config::env_or;
+use crate::dev_client::{DevApiClient, DevRequestOptions, UpstreamQueueTimeout, UpstreamResponse, UpstreamStatusError};
+
+pub const X_UPSTREAM: HeaderName = HeaderName::from_static("x-upstream");
+pub const X_FAILOVER_REASON: HeaderName = HeaderName::from_static("x-failover-reason");
+
+/// Returned (inside `anyhow::Error`) when the breaker is open and there is
+/// no fallback to route to.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The Dev backend is failing; requests are paused for {}s", self.retry_after.as_secs().max(1))
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // One trial request is in flight; another is allowed if it never reports
+    HalfOpen { since: Instant },
+}
+
+/// Consecutive-failure circuit breaker: opens after `threshold` failures in
+/// a row, lets a single trial request through after `open_for`, and closes
+/// again on the first success. A threshold of 0 disables it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    threshold: u32,
+    open_for: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        Self { state: Mutex::new(BreakerState::Closed { failures: 0 }), threshold, open_for }
+    }
+
+    /// Whether a request may go to the guarded upstream at `now`.
+    pub fn allow(&self, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { since: until } if now < until => false,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                *state = BreakerState::HalfOpen { since: now + self.open_for };
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { failures: 0 }) {
+            info!("Upstream recovered, closing circuit breaker");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => self.threshold,
+        };
+        *state = if failures >= self.threshold {
+            warn!(failures, open_for = ?self.open_for, "Opening upstream circuit breaker");
+            BreakerState::Open { until: now + self.open_for }
+        } else {
+            BreakerState::Closed { failures }
+        };
+    }
+
+    /// Time until a trial request is allowed, if the breaker is open.
+    pub fn retry_after(&self, now: Instant) -> Option<Duration> {
+        match *self.state.lock().unwrap() {
+            BreakerState::Open { until } | BreakerState::HalfOpen { since: until } if now < until => Some(until - now),
+            _ => None,
+        }
+    }
+}
+
+/// Which upstream served a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamKind {
+    Primary,
+    Fallback,
+}
+
+impl UpstreamKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Fallback => "fallback",
+        }
+    }
+}
+
+/// Why a request was sent to the fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverReason {
+    PrimaryError,
+    CircuitOpen,
+    /// The hedge request produced a first byte before the primary.
+    Hedged,
+}
+
+impl FailoverReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PrimaryError => "primary_error",
+            Self::CircuitOpen => "circuit_open",
+            Self::Hedged => "hedged",
+        }
+    }
+}
+
+/// A started upstream stream and how it was routed.
+#[derive(Debug)]
+pub struct Routed {
+    pub response: UpstreamResponse,
+    pub upstream: UpstreamKind,
+    pub failover: Option<FailoverReason>,
+    /// The primary failure that caused a failover, for reporting.
+    pub primary_error: Option<anyhow::Error>,
+}
+
+impl Routed {
+    fn primary(response: UpstreamResponse) -> Self {
+        Self { response, upstream: UpstreamKind::Primary, failover: None, primary_error: None }
+    }
+
+    fn fallback(response: UpstreamResponse, reason: FailoverReason, primary_error: Option<anyhow::Error>) -> Self {
+        Self { response, upstream: UpstreamKind::Fallback, failover: Some(reason), primary_error }
+    }
+
+    /// `X-Upstream` and, after a switch, `X-Failover-Reason`.
+    pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let mut headers = vec![(X_UPSTREAM, HeaderValue::from_static(self.upstream.as_str()))];
+        if let Some(reason) = self.failover {
+            headers.push((X_FAILOVER_REASON, HeaderValue::from_static(reason.as_str())));
+        }
+        headers
+    }
+}
+
+/// Whether an error says the primary itself is unhealthy (counts towards
+/// the breaker): transport failures and 5xx.
+fn is_upstream_fault(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<UpstreamStatusError>() {
+        Some(e) => e.status.is_server_error(),
+        None => error.downcast_ref::<UpstreamQueueTimeout>().is_none(),
+    }
+}
+
+/// Whether the fallback might succeed where the primary failed. Request
+/// errors (400, 413, ...) would fail the same way on any account.
+fn should_fail_over(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<UpstreamStatusError>() {
+        Some(e) => {
+            e.status.is_server_error()
+                || matches!(e.status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS)
+        }
+        None => true,
+    }
+}
+
+/// How a hedged race ended.
+#[derive(Debug)]
+enum HedgeOutcome<T> {
+    /// The primary settled before the hedge was due; no hedge was sent.
+    Primary(Result<T>),
+    /// The primary answered first; the hedge was cancelled.
+    PrimaryWon(T),
+    /// The hedge answered first; the primary was cancelled.
+    HedgeWon(T),
+    /// The primary failed after the hedge was sent; the hedge's result.
+    PrimaryFailed(anyhow::Error, Result<T>),
+    /// The hedge failed; the primary's result.
+    HedgeFailed(anyhow::Error, Result<T>),
+}
+
+/// Runs `primary`, and if it has not finished after `delay` also `hedge()`,
+/// returning whichever succeeds first. The losing future is dropped.
+async fn race<'a, T>(
+    primary: impl Future<Output = Result<T>>,
+    hedge: impl FnOnce() -> BoxFuture<'a, Result<T>>,
+    delay: Duration,
+) -> HedgeOutcome<T> {
+    let mut primary = std::pin::pin!(primary);
+    tokio::select! {
+        result = &mut primary => return HedgeOutcome::Primary(result),
+        _ = tokio::time::sleep(delay) => {}
+    }
+    debug!(?delay, "Primary has not answered yet, sending hedge request");
+    let mut hedge = hedge();
+    tokio::select! {
+        result = &mut primary => match result {
+            Ok(response) => HedgeOutcome::PrimaryWon(response),
+            Err(e) => HedgeOutcome::PrimaryFailed(e, hedge.await),
+        },
+        result = &mut hedge => match result {
+            Ok(response) => HedgeOutcome::HedgeWon(response),
+            Err(e) => HedgeOutcome::HedgeFailed(e, primary.await),
+        },
+    }
+}
+
+/// Sends a request and waits for the first body byte.
+async fn first_byte(client: DevApiClient, content: String, options: DevRequestOptions) -> Result<UpstreamResponse> {
+    let mut response = client.send_request(&content, options).await?;
+    response.peek_first_chunk().await?;
+    Ok(response)
+}
+
+pub struct Upstreams {
+    primary: DevApiClient,
+    fallback: Option<DevApiClient>,
+    breaker: CircuitBreaker,
+    hedge_after: Option<Duration>,
+    stream_retries: u32,
+    max_choices: u32,
+}
+
+impl Upstreams {
+    pub fn new(primary: DevApiClient, fallback: Option<DevApiClient>, breaker: CircuitBreaker) -> Self {
+        Self { primary, fallback, breaker, hedge_after: None, stream_retries: 0, max_choices: 1 }
+    }
+
+    /// Re-issue a request whose stream fails midway up to `attempts` times.
+    pub fn with_stream_retries(mut self, attempts: u32) -> Self {
+        self.stream_retries = attempts;
+        self
+    }
+
+    /// The primary account's client, for requests that are not chats.
+    pub fn primary(&self) -> &DevApiClient {
+        &self.primary
+    }
+
+    pub fn stream_retries(&self) -> u32 {
+        self.stream_retries
+    }
+
+    /// Largest `n` a chat request may ask for; each choice is a separate
+    /// upstream request.
+    pub fn with_max_choices(mut self, max: u32) -> Self {
+        self.max_choices = max;
+        self
+    }
+
+    pub fn max_choices(&self) -> u32 {
+        self.max_choices
+    }
+
+    /// Hedge to the fallback when the primary has sent no byte after `delay`.
+    pub fn with_hedging(mut self, delay: Option<Duration>) -> Self {
+        self.hedge_after = delay;
+        self
+    }
+
+    /// The fallback is configured when FALLBACK_API_ENDPOINT is set; the
+    /// breaker by CIRCUIT_FAILURE_THRESHOLD (5) and CIRCUIT_OPEN_SECS (30);
+    /// mid-stream retries by STREAM_RETRY_ATTEMPTS (0); the choices per
+    /// request by CHAT_MAX_N (8).
+    pub fn from_env(primary: DevApiClient) -> Self {
+        let fallback = std::env::var("FALLBACK_API_ENDPOINT")
+            .is_ok_and(|v| !v.is_empty())
+            .then(|| DevApiClient::from_env_prefixed("FALLBACK_"))
+            .and_then(|client| {
+                client.inspect_err(|e| error!("Failed to create fallback DevApiClient, failover disabled: {:#}", e)).ok()
+            });
+        let breaker = CircuitBreaker::new(
+            env_or("CIRCUIT_FAILURE_THRESHOLD", 5),
+            Duration::from_secs(env_or("CIRCUIT_OPEN_SECS", 30)),
+        );
+        let hedge_after = Some(env_or("HEDGE_AFTER_MS", 0u64)).filter(|ms| *ms > 0).map(Duration::from_millis);
+        if hedge_after.is_some() && fallback.is_none() {
+            warn!("HEDGE_AFTER_MS is set but there is no fallback upstream to hedge to");
+        }
+        let stream_retries = env_or("STREAM_RETRY_ATTEMPTS", 0);
+        let max_choices = env_or("CHAT_MAX_N", 8);
+        info!(fallback = fallback.is_some(), threshold = breaker.threshold, ?hedge_after, stream_retries, max_choices, "Upstream routing configured");
+        Self::new(primary, fallback, breaker)
+            .with_hedging(hedge_after)
+            .with_stream_retries(stream_retries)
+            .with_max_choices(max_choices)
+    }
+
+    /// The client for `kind`, if configured.
+    pub fn client(&self, kind: UpstreamKind) -> Option<&DevApiClient> {
+        match kind {
+            UpstreamKind::Primary => Some(&self.primary),
+            UpstreamKind::Fallback => self.fallback.as_ref(),
+        }
+    }
+
+    /// Signer variant for a request, decided once so the primary and the
+    /// fallback use the same one.
+    pub fn variant_for(&self, request_id: &str) -> Variant {
+        self.primary.variant_for(request_id)
+    }
+
+    /// Sends to the primary unless its breaker is open, failing over to the
+    /// fallback when one is configured.
+    pub async fn send(&self, content: &str, options: DevRequestOptions) -> Result<Routed> {
+        let now = Instant::now();
+        if !self.breaker.allow(now) {
+            let Some(fallback) = &self.fallback else {
+                let retry_after = self.breaker.retry_after(now).unwrap_or_default();
+                return Err(CircuitOpen { retry_after }.into());
+            };
+            let response = fallback.send_request(content, options).await?;
+            return Ok(Routed::fallback(response, FailoverReason::CircuitOpen, None));
+        }
+
+        if let (Some(fallback), Some(delay)) = (&self.fallback, self.hedge_after) {
+            return self.send_hedged(fallback, delay, content, options).await;
+        }
+        let result = self.primary.send_request(content, options.clone()).await;
+        match self.settle_primary(result) {
+            Ok(response) => Ok(Routed::primary(response)),
+            Err(e) => self.fail_over(e, content, options).await,
+        }
+    }
+
+    async fn send_hedged(
+        &self,
+        fallback: &DevApiClient,
+        delay: Duration,
+        content: &str,
+        options: DevRequestOptions,
+    ) -> Result<Routed> {
+        let primary = first_byte(self.primary.clone(), content.to_string(), options.clone());
+        let hedge = || first_byte(fallback.clone(), content.to_string(), options.clone()).boxed();
+        match race(primary, hedge, delay).await {
+            HedgeOutcome::Primary(result) => match self.settle_primary(result) {
+                Ok(response) => Ok(Routed::primary(response)),
+                Err(e) => self.fail_over(e, content, options).await,
+            },
+            HedgeOutcome::PrimaryWon(response) => {
+                self.breaker.record_success();
+                Ok(Routed::primary(response))
+            }
+            HedgeOutcome::HedgeWon(response) => {
+                info!(?delay, "Hedge request answered first, cancelled the primary");
+                Ok(Routed::fallback(response, FailoverReason::Hedged, None))
+            }
+            HedgeOutcome::PrimaryFailed(e, hedge) => {
+                let e = self.settle_primary(Err(e)).unwrap_err();
+                Ok(Routed::fallback(hedge?, FailoverReason::PrimaryError, Some(e)))
+            }
+            HedgeOutcome::HedgeFailed(hedge_error, primary) => {
+                warn!("Hedge request failed: {:#}", hedge_error);
+                self.settle_primary(primary).map(Routed::primary)
+            }
+        }
+    }
+
+    /// Feeds a primary result into the breaker.
+    fn settle_primary(&self, result: Result<UpstreamResponse>) -> Result<UpstreamResponse> {
+        match &result {
+            Ok(_) => self.breaker.record_success(),
+            Err(e) if is_upstream_fault(e) => self.breaker.record_failure(Instant::now()),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Retries a failed primary request on the fallback, when that can help.
+    async fn fail_over(&self, primary_error: anyhow::Error, content: &str, options: DevRequestOptions) -> Result<Routed> {
+        let Some(fallback) = self.fallback.as_ref().filter(|_| should_fail_over(&primary_error)) else {
+            return Err(primary_error);
+        };
+        warn!("Primary upstream failed, failing over: {:#}", primary_error);
+        let response = fallback.send_request(content, options).await?;
+        Ok(Routed::fallback(response, FailoverReason::PrimaryError, Some(primary_error)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_after_threshold_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let start = Instant::now();
+        breaker.record_failure(start);
+        assert!(breaker.allow(start));
+        breaker.record_failure(start);
+        assert!(!breaker.allow(start));
+        assert_eq!(breaker.retry_after(start), Some(Duration::from_secs(30)));
+
+        // One trial after the open period, then closed on success
+        let later = start + Duration::from_secs(31);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+        breaker.record_success();
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn test_failed_trial_reopens() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(start);
+        }
+        let later = start + Duration::from_secs(11);
+        assert!(breaker.allow(later));
+        breaker.record_failure(later);
+        assert!(!breaker.allow(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_zero_threshold_disables_breaker() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(10));
+        let now = Instant::now();
+        for _ in 0..10 {
+            breaker.record_failure(now);
+        }
+        assert!(breaker.allow(now));
+    }
+
+    async fn delayed(ms: u64, result: Result<u32>) -> Result<u32> {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        result
+    }
+
+    fn hedge(ms: u64, result: Result<u32>) -> impl FnOnce() -> BoxFuture<'static, Result<u32>> {
+        move || delayed(ms, result).boxed()
+    }
+
+    #[tokio::test]
+    async fn test_race_fast_primary_sends_no_hedge() {
+        let never = || -> BoxFuture<'static, Result<u32>> { panic!("hedge sent") };
+        let outcome = race(delayed(0, Ok(1)), never, Duration::from_millis(200)).await;
+        assert!(matches!(outcome, HedgeOutcome::Primary(Ok(1))));
+    }
+
+    #[tokio::test]
+    async fn test_race_hedge_wins_over_slow_primary() {
+        let outcome = race(delayed(500, Ok(1)), hedge(0, Ok(2)), Duration::from_millis(10)).await;
+        assert!(matches!(outcome, HedgeOutcome::HedgeWon(2)));
+    }
+
+    #[tokio::test]
+    async fn test_race_falls_back_to_the_other_on_failure() {
+        let outcome = race(delayed(30, Ok(1)), hedge(0, Err(anyhow::anyhow!("down"))), Duration::from_millis(10)).await;
+        assert!(matches!(outcome, HedgeOutcome::HedgeFailed(_, Ok(1))));
+        let outcome = race(delayed(20, Err(anyhow::anyhow!("down"))), hedge(200, Ok(2)), Duration::from_millis(10)).await;
+        assert!(matches!(outcome, HedgeOutcome::PrimaryFailed(_, Ok(2))));
+    }
+
+    #[test]
+    fn test_failover_policy() {
+        let status = |status| anyhow::Error::from(UpstreamStatusError { status, body: String::new(), retry_after: None });
+        assert!(should_fail_over(&status(StatusCode::BAD_GATEWAY)));
+        assert!(should_fail_over(&status(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!should_fail_over(&status(StatusCode::BAD_REQUEST)));
+        assert!(should_fail_over(&anyhow::anyhow!("connection reset")));
+
+        assert!(is_upstream_fault(&status(StatusCode::BAD_GATEWAY)));
+        assert!(!is_upstream_fault(&status(StatusCode::TOO_MANY_REQUESTS)));
+        let busy = UpstreamQueueTimeout { max_streams: 1, waited: Duration::ZERO };
+        assert!(!is_upstream_fault(&busy.into()));
+    }
+}
@@ -0,0 +1,313 @@
+// Uploaded files for the OpenAI files flow. `POST /v1/files` stores a text
+// document (code, markdown, logs, ...) and a chat message refers to it with a
+// `{"type": "file", "file": {"file_id": ...}}` content part. The chat handler
+// resolves the references into `DevRequestOptions::attachments`; Dev has no
+// attachment field, so `DevApiClient` sends them as fenced blocks ahead of
+// the prompt. Files live in memory, visible only to the API key that
+// uploaded them, up to FILE_MAX_BYTES each and FILES_MAX_TOTAL_BYTES in all.
+
+use axum::extract::{Multipart, Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use http::StatusCode;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::auth::ApiKeyId;
+use crate::config::env_or;
+use crate::error::ApiError;
+use crate::models::OpenAiMessage;
+use crate::utils;
+
+/// A file's text as sent along with a chat request.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub text: Arc<str>,
+}
+
+/// `content` preceded by each attachment in a code fence longer than any
+/// backtick run inside it.
+pub fn with_attachments<'a>(content: &'a str, attachments: &[Attachment]) -> Cow<'a, str> {
+    if attachments.is_empty() {
+        return Cow::Borrowed(content);
+    }
+    let mut prompt = String::new();
+    for attachment in attachments {
+        let longest_run = attachment.text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        let _ = write!(prompt, "File: {}\n{}\n{}\n{}\n\n", attachment.filename, fence, attachment.text.trim_end(), fence);
+    }
+    prompt.push_str(content);
+    Cow::Owned(prompt)
+}
+
+/// OpenAI file object.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct FileObject {
+    #[schema(example = "file-0c8f6d1e2b3a4f5e8d7c6b5a49382716")]
+    pub id: String,
+    #[schema(example = "file")]
+    pub object: &'static str,
+    pub bytes: usize,
+    pub created_at: u64,
+    pub filename: String,
+    #[schema(example = "user_data")]
+    pub purpose: String,
+}
+
+struct StoredFile {
+    owner: String,
+    object: FileObject,
+    text: Arc<str>,
+}
+
+#[derive(Default)]
+struct Files {
+    by_id: HashMap<String, StoredFile>,
+    total_bytes: usize,
+}
+
+#[derive(Clone)]
+pub struct FileStore {
+    files: Arc<Mutex<Files>>,
+    max_file_bytes: usize,
+    max_total_bytes: usize,
+}
+
+impl FileStore {
+    pub fn new(max_file_bytes: usize, max_total_bytes: usize) -> Self {
+        Self { files: Arc::default(), max_file_bytes, max_total_bytes }
+    }
+
+    /// FILE_MAX_BYTES (512 KiB) and FILES_MAX_TOTAL_BYTES (64 MiB).
+    pub fn from_env() -> Self {
+        Self::new(env_or("FILE_MAX_BYTES", 512 * 1024), env_or("FILES_MAX_TOTAL_BYTES", 64 * 1024 * 1024))
+    }
+
+    /// Stores `data` for `owner`; only UTF-8 text is accepted.
+    pub fn insert(&self, owner: &str, filename: String, purpose: String, data: Vec<u8>) -> Result<FileObject, ApiError> {
+        if data.len() > self.max_file_bytes {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "invalid_request_error",
+                format!("Files are limited to {} bytes", self.max_file_bytes),
+            )
+            .with_param("file")
+            .with_code("file_too_large"));
+        }
+        let text = String::from_utf8(data).map_err(|_| {
+            ApiError::invalid_param("file", "Only UTF-8 text files are supported").with_code("unsupported_file")
+        })?;
+        let mut files = self.files.lock().unwrap();
+        if files.total_bytes + text.len() > self.max_total_bytes {
+            return Err(ApiError::new(StatusCode::INSUFFICIENT_STORAGE, "server_error", "File storage is full")
+                .with_code("file_storage_full"));
+        }
+        let object = FileObject {
+            id: format!("file-{}", utils::generate_uuidv4().replace('-', "")),
+            object: "file",
+            bytes: text.len(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            filename,
+            purpose,
+        };
+        files.total_bytes += text.len();
+        files.by_id.insert(object.id.clone(), StoredFile { owner: owner.to_string(), object: object.clone(), text: text.into() });
+        info!(id = object.id, bytes = object.bytes, "Stored uploaded file");
+        Ok(object)
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Option<FileObject> {
+        let files = self.files.lock().unwrap();
+        files.by_id.get(id).filter(|f| f.owner == owner).map(|f| f.object.clone())
+    }
+
+    /// Files of `owner`, oldest first.
+    pub fn list(&self, owner: &str) -> Vec<FileObject> {
+        let files = self.files.lock().unwrap();
+        let mut list: Vec<FileObject> =
+            files.by_id.values().filter(|f| f.owner == owner).map(|f| f.object.clone()).collect();
+        list.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        list
+    }
+
+    pub fn delete(&self, owner: &str, id: &str) -> bool {
+        let mut files = self.files.lock().unwrap();
+        match files.by_id.get(id) {
+            Some(file) if file.owner == owner => {
+                let bytes = file.object.bytes;
+                files.by_id.remove(id);
+                files.total_bytes -= bytes;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The files `messages` refer to, each once, in order of appearance.
+    pub fn attachments(&self, owner: &str, messages: &[OpenAiMessage]) -> Result<Vec<Attachment>, ApiError> {
+        let files = self.files.lock().unwrap();
+        let mut attachments: Vec<Attachment> = Vec::new();
+        let mut seen = Vec::new();
+        for file_id in messages.iter().flat_map(|m| m.content.file_ids()) {
+            let Some(file_id) = file_id else {
+                return Err(ApiError::invalid_param("messages", "Files must be uploaded to /v1/files and referenced by file_id")
+                    .with_code("unsupported_file"));
+            };
+            if seen.contains(&file_id) {
+                continue;
+            }
+            let file = files.by_id.get(file_id).filter(|f| f.owner == owner).ok_or_else(|| not_found(file_id))?;
+            seen.push(file_id);
+            attachments.push(Attachment { filename: file.object.filename.clone(), text: file.text.clone() });
+        }
+        Ok(attachments)
+    }
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No such file: '{}'", id)).with_code("file_not_found")
+}
+
+/// `POST /v1/files`, a multipart form with `file` and `purpose`.
+#[utoipa::path(post, path = "/v1/files", tag = "files", security(("api_key" = [])),
+    request_body(content = String, content_type = "multipart/form-data", description = "`file` (UTF-8 text) and optional `purpose`"),
+    responses(
+        (status = 200, body = FileObject),
+        (status = 400, description = "Missing or non-text file", body = ErrorBody),
+        (status = 413, description = "File larger than FILE_MAX_BYTES", body = ErrorBody),
+        (status = 507, description = "FILES_MAX_TOTAL_BYTES reached", body = ErrorBody),
+    ),
+)]
+pub async fn upload_file_handler(
+    State(files): State<FileStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    mut multipart: Multipart,
+) -> Response {
+    let mut upload = None;
+    let mut purpose = "user_data".to_string();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return ApiError::invalid_param("file", e.body_text()).into_response(),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let filename = field.file_name().unwrap_or("upload.txt").to_string();
+        let data = match field.bytes().await {
+            Ok(data) => data,
+            Err(e) => return ApiError::invalid_param("file", e.body_text()).into_response(),
+        };
+        match name.as_str() {
+            "file" => upload = Some((filename, data.to_vec())),
+            "purpose" => purpose = String::from_utf8_lossy(&data).into_owned(),
+            _ => {}
+        }
+    }
+    let Some((filename, data)) = upload else {
+        return ApiError::invalid_param("file", "The form has no 'file' field").into_response();
+    };
+    match files.insert(api_key_id.as_str(), filename, purpose, data) {
+        Ok(object) => Json(object).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /v1/files`
+#[utoipa::path(get, path = "/v1/files", tag = "files", security(("api_key" = [])), responses(
+    (status = 200, description = "`{\"object\": \"list\", \"data\": [FileObject]}`", body = Object),
+))]
+pub async fn list_files_handler(State(files): State<FileStore>, Extension(api_key_id): Extension<ApiKeyId>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "object": "list", "data": files.list(api_key_id.as_str()) }))
+}
+
+/// `GET /v1/files/{id}`
+#[utoipa::path(get, path = "/v1/files/{id}", tag = "files", security(("api_key" = [])),
+    params(("id" = String, Path, description = "File id")),
+    responses((status = 200, body = FileObject), (status = 404, body = ErrorBody)),
+)]
+pub async fn get_file_handler(
+    State(files): State<FileStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    match files.get(api_key_id.as_str(), &id) {
+        Some(object) => Json(object).into_response(),
+        None => not_found(&id).into_response(),
+    }
+}
+
+/// `DELETE /v1/files/{id}`
+#[utoipa::path(delete, path = "/v1/files/{id}", tag = "files", security(("api_key" = [])),
+    params(("id" = String, Path, description = "File id")),
+    responses(
+        (status = 200, body = Object, example = json!({"id": "file-0c8f", "object": "file", "deleted": true})),
+        (status = 404, body = ErrorBody),
+    ),
+)]
+pub async fn delete_file_handler(
+    State(files): State<FileStore>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    if !files.delete(api_key_id.as_str(), &id) {
+        return not_found(&id).into_response();
+    }
+    Json(serde_json::json!({ "id": id, "object": "file", "deleted": true })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(json: serde_json::Value) -> Vec<OpenAiMessage> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_store_is_per_owner_and_bounded() {
+        let store = FileStore::new(16, 24);
+        let file = store.insert("key_a", "a.rs".to_string(), "user_data".to_string(), b"fn main() {}".to_vec()).unwrap();
+        assert_eq!(file.bytes, 12);
+        assert!(store.get("key_b", &file.id).is_none());
+        assert_eq!(store.list("key_a").len(), 1);
+
+        let too_big = store.insert("key_a", "b".to_string(), String::new(), vec![b'x'; 17]).unwrap_err();
+        assert_eq!(too_big.code, Some("file_too_large"));
+        let full = store.insert("key_a", "c".to_string(), String::new(), vec![b'x'; 13]).unwrap_err();
+        assert_eq!(full.code, Some("file_storage_full"));
+        let binary = store.insert("key_a", "d".to_string(), String::new(), vec![0xff, 0xfe]).unwrap_err();
+        assert_eq!(binary.code, Some("unsupported_file"));
+
+        assert!(!store.delete("key_b", &file.id));
+        assert!(store.delete("key_a", &file.id));
+        assert!(store.insert("key_a", "c".to_string(), String::new(), vec![b'x'; 13]).is_ok());
+    }
+
+    #[test]
+    fn test_attachments_from_file_parts() {
+        let store = FileStore::new(1024, 1024);
+        let file = store.insert("key_a", "notes.md".to_string(), "user_data".to_string(), b"```sh\nls\n```\n".to_vec()).unwrap();
+        let part = serde_json::json!({"type": "file", "file": {"file_id": file.id}});
+        let msgs = messages(serde_json::json!([
+            {"content": [part]},
+            {"content": [{"type": "text", "text": "explain"}, part]},
+        ]));
+        let attachments = store.attachments("key_a", &msgs).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(
+            with_attachments("explain", &attachments),
+            "File: notes.md\n````\n```sh\nls\n```\n````\n\nexplain"
+        );
+        assert_eq!(store.attachments("key_b", &msgs).unwrap_err().code, Some("file_not_found"));
+        let inline = messages(serde_json::json!([{"content": [{"type": "file", "file": {"file_data": "aGk="}}]}]));
+        assert_eq!(store.attachments("key_a", &inline).unwrap_err().code, Some("unsupported_file"));
+        assert_eq!(with_attachments("hi", &[]), "hi");
+    }
+}
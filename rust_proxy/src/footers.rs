@@ -0,0 +1,136 @@
+// Footers: extra content appended when an answer ends normally, for clients
+// that only show message content. SOURCES_FOOTER adds a markdown "Sources"
+// list of the web and GitHub sources, numbered like the citation markers;
+// RELATED_QUESTIONS_FOOTER a "You might also ask" list. Requests can turn
+// them on or off with `x_devv.sources_footer` and
+// `x_devv.related_questions_footer`. This is the `footers` stage of the
+// chunk transformers; the footers go out as one content chunk ahead of the
+// one finishing the answer.
+
+use crate::config;
+use crate::sse_processor::{AnswerExtras, ChatCompletionChunk, Extras};
+use crate::transform::{content_chunk, ChoiceContext, ChunkTransformer};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Footers {
+    /// The "Sources" list (SOURCES_FOOTER, or `x_devv.sources_footer`).
+    pub sources: bool,
+    /// The related questions (RELATED_QUESTIONS_FOOTER, or
+    /// `x_devv.related_questions_footer`).
+    pub related_questions: bool,
+}
+
+impl Footers {
+    pub fn from_env() -> Self {
+        Self {
+            sources: config::env_or("SOURCES_FOOTER", false),
+            related_questions: config::env_or("RELATED_QUESTIONS_FOOTER", false),
+        }
+    }
+}
+
+/// A markdown "Sources" list of the web and GitHub sources, numbered like
+/// the citation markers.
+pub fn sources_footer(extras: &Extras) -> Option<String> {
+    let mut lines = Vec::new();
+    for (number, source) in (1..).zip(&extras.sources) {
+        let title = source.title.as_deref().or(source.url.as_deref()).unwrap_or("Untitled");
+        lines.push(match &source.url {
+            Some(url) => format!("{}. [{}]({})", number, title, url),
+            None => format!("{}. {}", number, title),
+        });
+    }
+    for source in &extras.github_sources {
+        let Some(repo) = &source.repo else { continue };
+        lines.push(match &source.file_path {
+            Some(path) => format!("- [{}/{}](https://github.com/{}/blob/HEAD/{})", repo, path, repo, path),
+            None => format!("- [{}](https://github.com/{})", repo, repo),
+        });
+    }
+    (!lines.is_empty()).then(|| format!("\n\n**Sources:**\n{}", lines.join("\n")))
+}
+
+/// A "You might also ask" list of the related questions.
+pub fn related_questions_footer(extras: &Extras) -> Option<String> {
+    let lines: Vec<String> = extras.related_questions.iter().map(|q| format!("- {}", q)).collect();
+    (!lines.is_empty()).then(|| format!("\n\n**You might also ask:**\n{}", lines.join("\n")))
+}
+
+/// The `footers` stage of a choice, if its request asked for any.
+pub fn transformer(context: &ChoiceContext) -> Option<FooterTransformer> {
+    let Footers { sources, related_questions } = context.footers;
+    (sources || related_questions).then(|| FooterTransformer { footers: context.footers, extras: context.extras.clone() })
+}
+
+/// Ends one choice's answer with its footers.
+pub struct FooterTransformer {
+    footers: Footers,
+    extras: AnswerExtras,
+}
+
+impl ChunkTransformer for FooterTransformer {
+    fn transform(&mut self, chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        Some(chunk)
+    }
+
+    fn finalize(&mut self, finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+        // Only an answer Dev completed: not a failed, cut off or filtered one
+        let finishing = finishing.filter(|chunk| {
+            chunk.error.is_none() && chunk.choices.iter().all(|c| c.finish_reason.as_deref() == Some("stop"))
+        })?;
+        let extras = self.extras.lock();
+        let sources = self.footers.sources.then(|| sources_footer(&extras)).flatten();
+        let related = self.footers.related_questions.then(|| related_questions_footer(&extras)).flatten();
+        let content: String = sources.into_iter().chain(related).collect();
+        (!content.is_empty()).then(|| content_chunk(finishing, content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_client::DevRequestOptions;
+    use crate::sse_processor::process_dev_bytes_stream_unfold;
+    use crate::transform::TransformChain;
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    async fn answer(events: &'static str, footers: Footers) -> Vec<ChatCompletionChunk> {
+        let bytes = futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(Bytes::from(events))]);
+        let mut options = DevRequestOptions { footers, ..Default::default() };
+        let context = ChoiceContext::attach(&mut options);
+        let chunks = process_dev_bytes_stream_unfold(bytes, options, "chatcmpl-1".to_string());
+        TransformChain::request_options().apply(chunks, &context).map(|c| c.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_sources_footer_ends_the_answer() {
+        let events = "event: sources\ndata: [{\"title\": \"Book\", \"url\": \"https://doc.rust-lang.org/book\"}, {\"url\": \"https://crates.io\"}]\n\n\
+                      event: repoSources\ndata: [{\"repo\": \"rust-lang/rust\", \"filePath\": \"README.md\"}]\n\n\
+                      event: c\ndata: Answer.\n\n";
+        let chunks = answer(events, Footers { sources: true, ..Default::default() }).await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[1].choices[0].delta.content.as_deref(),
+            Some("\n\n**Sources:**\n1. [Book](https://doc.rust-lang.org/book)\n2. [https://crates.io](https://crates.io)\n\
+                  - [rust-lang/rust/README.md](https://github.com/rust-lang/rust/blob/HEAD/README.md)")
+        );
+        assert_eq!(chunks[2].choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(sources_footer(&Extras::default()), None);
+    }
+
+    #[tokio::test]
+    async fn test_related_questions_footer() {
+        let events = "event: c\ndata: Answer.\n\nevent: rlq\ndata: What is a lifetime?\n\nevent: q\ndata: What is Box?\n\n";
+        let footers = Footers { sources: true, related_questions: true };
+        let chunks = answer(events, footers).await;
+        assert_eq!(
+            chunks[1].choices[0].delta.content.as_deref(),
+            Some("\n\n**You might also ask:**\n- What is a lifetime?\n- What is Box?")
+        );
+        assert_eq!(chunks.len(), 3);
+        // A failed answer ends without them
+        let failed = answer("event: rlq\ndata: What is Box?\n\nevent: error\ndata: boom\n\n", footers).await;
+        assert_eq!(failed.len(), 1);
+    }
+}
@@ -0,0 +1,214 @@
+// Kubernetes-style probes. `/healthz` only proves the process is serving
+// HTTP; `/readyz` also checks the request signer and that the Dev upstream can be
+// reached. Upstream probe results are cached so frequent kubelet polling does
+// not turn into a steady stream of upstream requests.
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::{routing::get, Json, Router};
+use http::StatusCode;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::config::ServerConfig;
+use crate::dev_client::DevApiClient;
+use crate::signer::Signer;
+
+/// Outcome of a single dependency check.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, ToSchema)]
+pub struct CheckResult {
+    #[schema(example = "ok")]
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl CheckResult {
+    pub fn ok() -> Self {
+        Self { status: "ok", detail: None }
+    }
+
+    pub fn fail(detail: impl Into<String>) -> Self {
+        Self { status: "fail", detail: Some(detail.into()) }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UpstreamCheck {
+    #[serde(flatten)]
+    result: CheckResult,
+    /// Age of the (possibly cached) probe result.
+    #[schema(value_type = u64)]
+    age_ms: u128,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ReadinessChecks {
+    wasm_signer: CheckResult,
+    upstream: UpstreamCheck,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ReadinessReport {
+    #[schema(example = "ready")]
+    status: &'static str,
+    checks: ReadinessChecks,
+}
+
+/// Remembers the last upstream probe for `ttl`. The lock is held while a
+/// probe runs, so concurrent readiness requests share a single probe.
+#[derive(Debug, Clone)]
+pub struct ProbeCache {
+    ttl: Duration,
+    last: Arc<Mutex<Option<(Instant, CheckResult)>>>,
+}
+
+impl ProbeCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, last: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Returns the cached result and its age, running `probe` when the cache
+    /// is empty or stale.
+    pub async fn get_or_probe<F, Fut>(&self, probe: F) -> (CheckResult, Duration)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = CheckResult>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, result)) = last.as_ref() {
+            let age = checked_at.elapsed();
+            if age < self.ttl {
+                return (result.clone(), age);
+            }
+        }
+        let result = probe().await;
+        *last = Some((Instant::now(), result.clone()));
+        (result, Duration::ZERO)
+    }
+}
+
+#[derive(Clone)]
+struct HealthState {
+    dev_client: DevApiClient,
+    probe_cache: ProbeCache,
+    probe_timeout: Duration,
+    started_at: Instant,
+}
+
+/// Routes for `/healthz` and `/readyz`, merged into the main router.
+pub fn router(dev_client: DevApiClient, server_config: &ServerConfig) -> Router {
+    let state = HealthState {
+        dev_client,
+        probe_cache: ProbeCache::new(server_config.readiness_cache_ttl),
+        probe_timeout: server_config.readiness_probe_timeout,
+        started_at: Instant::now(),
+    };
+    Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .with_state(state)
+}
+
+/// Liveness: the process is serving HTTP.
+#[utoipa::path(get, path = "/healthz", tag = "health", responses(
+    (status = 200, description = "Status and uptime", body = Object, example = json!({"status": "ok", "uptime_secs": 42})),
+))]
+async fn healthz_handler(State(state): State<HealthState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status": "ok",
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+    }))
+}
+
+/// Readiness: the request signer (WASM unless SIGNER=native) loads and the Dev upstream is reachable.
+#[utoipa::path(get, path = "/readyz", tag = "health", responses(
+    (status = 200, description = "Ready", body = ReadinessReport),
+    (status = 503, description = "A dependency check failed", body = ReadinessReport),
+))]
+async fn readyz_handler(State(state): State<HealthState>) -> Response {
+    let wasm_signer = match Signer::from_env() {
+        Ok(_) => CheckResult::ok(),
+        Err(e) => CheckResult::fail(e.to_string()),
+    };
+
+    let (upstream, age) = state.probe_cache.get_or_probe(|| async {
+        match state.dev_client.probe(state.probe_timeout).await {
+            Ok(status) => {
+                debug!(%status, "Upstream readiness probe succeeded");
+                CheckResult::ok()
+            }
+            Err(e) => {
+                warn!("Upstream readiness probe failed: {:#}", e);
+                CheckResult::fail(format!("{:#}", e))
+            }
+        }
+    }).await;
+
+    let report = readiness_report(wasm_signer, upstream, age);
+    let status = if report.status == "ready" { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report)).into_response()
+}
+
+fn readiness_report(wasm_signer: CheckResult, upstream: CheckResult, age: Duration) -> ReadinessReport {
+    let ready = wasm_signer.is_ok() && upstream.is_ok();
+    ReadinessReport {
+        status: if ready { "ready" } else { "not_ready" },
+        checks: ReadinessChecks {
+            wasm_signer,
+            upstream: UpstreamCheck { result: upstream, age_ms: age.as_millis() },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_probe_cache_reuses_fresh_result() {
+        let cache = ProbeCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        for _ in 0..3 {
+            let (result, _) = cache.get_or_probe(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                CheckResult::ok()
+            }).await;
+            assert!(result.is_ok());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_probe_cache_reprobes_when_stale() {
+        let cache = ProbeCache::new(Duration::ZERO);
+        cache.get_or_probe(|| async { CheckResult::fail("down") }).await;
+        let (result, age) = cache.get_or_probe(|| async { CheckResult::ok() }).await;
+        assert!(result.is_ok());
+        assert_eq!(age, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_readiness_report_fails_on_any_check() {
+        let report = readiness_report(CheckResult::ok(), CheckResult::fail("timeout"), Duration::from_millis(5));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["status"], "not_ready");
+        assert_eq!(json["checks"]["wasm_signer"]["status"], "ok");
+        assert_eq!(json["checks"]["upstream"]["status"], "fail");
+        assert_eq!(json["checks"]["upstream"]["detail"], "timeout");
+        assert_eq!(json["checks"]["upstream"]["age_ms"], 5);
+
+        let report = readiness_report(CheckResult::ok(), CheckResult::ok(), Duration::ZERO);
+        assert_eq!(report.status, "ready");
+    }
+}
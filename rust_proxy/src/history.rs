@@ -0,0 +1,351 @@
+// Conversation history in SQLite, so a small team can look up what the proxy
+// answered. With HISTORY_DB set to a database path, every answer whose Dev
+// stream ended is stored with its prompt, the final text, sources and Dev
+// thread id; `GET /admin/history` searches it. Entries older than
+// HISTORY_RETENTION_DAYS (30) are deleted, and only the newest
+// HISTORY_MAX_ENTRIES are kept when that is set (0 keeps all of them);
+// retention runs at startup and hourly. With STATELESS_STREAMING the answer
+// text is not collected, so only the prompt and ids are stored.
+
+use anyhow::{Context, Result};
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::StatusCode;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::env_or;
+use crate::error::ApiError;
+use crate::event_bus::AccumulatorSink;
+use crate::sse_processor::SseAccumulator;
+use crate::webhooks::CompletionInfo;
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created INTEGER NOT NULL,
+        request_id TEXT NOT NULL,
+        choice_index INTEGER NOT NULL,
+        api_key_id TEXT NOT NULL,
+        model TEXT,
+        prompt TEXT NOT NULL,
+        answer TEXT NOT NULL,
+        sources TEXT NOT NULL,
+        github_sources TEXT NOT NULL,
+        thread_id TEXT,
+        error TEXT
+    );
+    CREATE INDEX IF NOT EXISTS conversations_created ON conversations (created);
+    CREATE INDEX IF NOT EXISTS conversations_request ON conversations (request_id);
+";
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// One stored answer, as returned by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub created: u64,
+    pub request_id: String,
+    pub index: u32,
+    pub api_key_id: String,
+    pub model: Option<String>,
+    pub prompt: String,
+    pub answer: String,
+    pub sources: serde_json::Value,
+    pub github_sources: serde_json::Value,
+    pub thread_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// 0 keeps entries regardless of age.
+    pub days: u64,
+    /// 0 keeps any number of entries.
+    pub max_entries: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self { days: 30, max_entries: 0 }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct History {
+    db: Option<Arc<Mutex<Connection>>>,
+    retention: Retention,
+}
+
+impl History {
+    /// Opens (or creates) the database at `path`.
+    pub fn open(path: impl AsRef<Path>, retention: Retention) -> Result<Self> {
+        let path = path.as_ref();
+        let connection = Connection::open(path).with_context(|| format!("open {}", path.display()))?;
+        connection.execute_batch(SCHEMA).context("create history schema")?;
+        Ok(Self { db: Some(Arc::new(Mutex::new(connection))), retention })
+    }
+
+    pub fn from_env() -> Self {
+        let Some(path) = std::env::var("HISTORY_DB").ok().filter(|v| !v.trim().is_empty()) else {
+            return Self::default();
+        };
+        let retention = Retention {
+            days: env_or("HISTORY_RETENTION_DAYS", 30),
+            max_entries: env_or("HISTORY_MAX_ENTRIES", 0),
+        };
+        match Self::open(&path, retention) {
+            Ok(history) => {
+                info!(path, days = retention.days, max_entries = retention.max_entries, "Recording conversation history");
+                history
+            }
+            Err(e) => {
+                warn!(path, "Conversation history disabled: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.db.is_some()
+    }
+
+    fn connection(&self) -> Option<std::sync::MutexGuard<'_, Connection>> {
+        self.db.as_ref().map(|db| db.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// A sink for choice `index` of `completion`, asked with `prompt`; the
+    /// accumulator it receives is stored in the background.
+    pub fn sink(&self, completion: CompletionInfo, prompt: String, index: u32) -> Option<AccumulatorSink> {
+        self.db.as_ref()?;
+        let history = self.clone();
+        let (tx, mut rx) = mpsc::unbounded_channel::<SseAccumulator>();
+        tokio::spawn(async move {
+            while let Some(accumulator) = rx.recv().await {
+                let (history, completion, prompt) = (history.clone(), completion.clone(), prompt.clone());
+                let stored = tokio::task::spawn_blocking(move || {
+                    history.insert(&completion, &prompt, index, &accumulator, now_secs())
+                })
+                .await;
+                match stored {
+                    Ok(Ok(())) => debug!(index, "Stored answer in conversation history"),
+                    Ok(Err(e)) => warn!("Failed to store answer in conversation history: {:#}", e),
+                    Err(e) => warn!("Conversation history task failed: {}", e),
+                }
+            }
+        });
+        Some(tx)
+    }
+
+    fn insert(&self, completion: &CompletionInfo, prompt: &str, index: u32, accumulator: &SseAccumulator, created: u64) -> Result<()> {
+        let Some(db) = self.connection() else { return Ok(()) };
+        db.execute(
+            "INSERT INTO conversations (created, request_id, choice_index, api_key_id, model, prompt, answer, sources,
+                github_sources, thread_id, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                created,
+                completion.request_id,
+                index,
+                completion.api_key_id,
+                completion.model,
+                prompt,
+                accumulator.text,
+                serde_json::to_string(&accumulator.sources)?,
+                serde_json::to_string(&accumulator.github_sources)?,
+                accumulator.thread_id,
+                accumulator.error,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes what the retention settings no longer keep; returns how many
+    /// entries went.
+    pub fn prune(&self) -> Result<usize> {
+        let Some(db) = self.connection() else { return Ok(0) };
+        let mut deleted = 0;
+        if self.retention.days > 0 {
+            let cutoff = now_secs().saturating_sub(self.retention.days * 86_400);
+            deleted += db.execute("DELETE FROM conversations WHERE created < ?1", params![cutoff])?;
+        }
+        if self.retention.max_entries > 0 {
+            deleted += db.execute(
+                "DELETE FROM conversations WHERE id <= (SELECT id FROM conversations ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+                params![self.retention.max_entries],
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    /// Applies retention now, then hourly while the process runs.
+    pub fn spawn_retention(self) {
+        if !self.is_enabled() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let history = self.clone();
+                match tokio::task::spawn_blocking(move || history.prune()).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(deleted)) => info!(deleted, "Pruned conversation history"),
+                    Ok(Err(e)) => warn!("Failed to prune conversation history: {:#}", e),
+                    Err(e) => warn!("Conversation history retention task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Newest entries first, filtered by `query`.
+    pub fn search(&self, query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+        let Some(db) = self.connection() else { return Ok(Vec::new()) };
+        let pattern = query.q.as_deref().filter(|q| !q.is_empty()).map(|q| {
+            format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+        });
+        let mut statement = db.prepare(
+            "SELECT id, created, request_id, choice_index, api_key_id, model, prompt, answer, sources, github_sources,
+                thread_id, error
+             FROM conversations
+             WHERE (?1 IS NULL OR prompt LIKE ?1 ESCAPE '\\' OR answer LIKE ?1 ESCAPE '\\')
+               AND (?2 IS NULL OR api_key_id = ?2)
+               AND (?3 IS NULL OR thread_id = ?3)
+               AND (?4 IS NULL OR request_id = ?4)
+               AND (?5 IS NULL OR created >= ?5)
+             ORDER BY id DESC
+             LIMIT ?6",
+        )?;
+        let json = |raw: String| serde_json::from_str(&raw).unwrap_or(serde_json::Value::Null);
+        let rows = statement.query_map(
+            params![pattern, query.api_key_id, query.thread_id, query.request_id, query.since, query.limit()],
+            |row| {
+                Ok(HistoryEntry {
+                    id: row.get(0)?,
+                    created: row.get(1)?,
+                    request_id: row.get(2)?,
+                    index: row.get(3)?,
+                    api_key_id: row.get(4)?,
+                    model: row.get(5)?,
+                    prompt: row.get(6)?,
+                    answer: row.get(7)?,
+                    sources: json(row.get(8)?),
+                    github_sources: json(row.get(9)?),
+                    thread_id: row.get(10)?,
+                    error: row.get(11)?,
+                })
+            },
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Text the prompt or the answer contains.
+    pub q: Option<String>,
+    pub api_key_id: Option<String>,
+    pub thread_id: Option<String>,
+    pub request_id: Option<String>,
+    /// Only entries created at or after this Unix time.
+    pub since: Option<u64>,
+    /// At most this many entries (50, up to 500).
+    pub limit: Option<u32>,
+}
+
+impl HistoryQuery {
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(50).clamp(1, 500)
+    }
+}
+
+/// `GET /admin/history` (admin): stored answers, newest first.
+#[utoipa::path(get, path = "/admin/history", tag = "admin", params(HistoryQuery), security(("admin_token" = [])), responses(
+    (status = 200, description = "Matching entries, newest first", body = Object),
+    (status = 404, description = "History is disabled", body = ErrorBody),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn history_handler(State(history): State<History>, Query(query): Query<HistoryQuery>) -> Response {
+    if !history.is_enabled() {
+        return ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "Conversation history is disabled")
+            .with_code("history_disabled")
+            .into_response();
+    }
+    match tokio::task::spawn_blocking(move || history.search(&query)).await {
+        Ok(Ok(entries)) => Json(serde_json::json!({ "object": "list", "data": entries })).into_response(),
+        Ok(Err(e)) => {
+            warn!("Failed to search conversation history: {:#}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to search conversation history")
+                .into_response()
+        }
+        Err(e) => {
+            warn!("Conversation history search task failed: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to search conversation history")
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(request_id: &str) -> CompletionInfo {
+        CompletionInfo {
+            request_id: request_id.to_string(),
+            model: Some("gpt-4o".to_string()),
+            api_key_id: "key_a".to_string(),
+            prompt_tokens: 3,
+        }
+    }
+
+    fn answer(text: &str, thread_id: &str) -> SseAccumulator {
+        let mut accumulator = SseAccumulator::default();
+        accumulator.text = text.to_string();
+        accumulator.thread_id = Some(thread_id.to_string());
+        accumulator.sources = serde_json::from_value(serde_json::json!([{"title": "Docs", "url": "https://doc.rust-lang.org"}])).unwrap();
+        accumulator
+    }
+
+    #[test]
+    fn test_record_and_search() {
+        let history = History::open(":memory:", Retention::default()).unwrap();
+        history.insert(&completion("req-1"), "How do I read a file?", 0, &answer("Use std::fs::read_to_string.", "t-1"), now_secs()).unwrap();
+        history.insert(&completion("req-2"), "What is 100% CPU?", 0, &answer("All cores busy.", "t-2"), now_secs()).unwrap();
+
+        let found = history.search(&HistoryQuery { q: Some("read_to".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].request_id.as_str(), found[0].thread_id.as_deref()), ("req-1", Some("t-1")));
+        assert_eq!(found[0].sources[0]["url"], "https://doc.rust-lang.org");
+        // LIKE wildcards in the query are literal
+        let found = history.search(&HistoryQuery { q: Some("100%".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(found.iter().map(|e| e.request_id.as_str()).collect::<Vec<_>>(), ["req-2"]);
+        let all = history.search(&HistoryQuery::default()).unwrap();
+        assert_eq!(all.iter().map(|e| e.request_id.as_str()).collect::<Vec<_>>(), ["req-2", "req-1"]);
+    }
+
+    #[test]
+    fn test_retention() {
+        let history = History::open(":memory:", Retention { days: 7, max_entries: 2 }).unwrap();
+        let old = now_secs() - 8 * 86_400;
+        history.insert(&completion("old"), "q", 0, &answer("a", "t"), old).unwrap();
+        for id in ["a", "b", "c"] {
+            history.insert(&completion(id), "q", 0, &answer("a", "t"), now_secs()).unwrap();
+        }
+        assert_eq!(history.prune().unwrap(), 2);
+        let kept = history.search(&HistoryQuery::default()).unwrap();
+        assert_eq!(kept.iter().map(|e| e.request_id.as_str()).collect::<Vec<_>>(), ["c", "b"]);
+    }
+}
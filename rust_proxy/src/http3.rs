@@ -0,0 +1,127 @@
+// Experimental HTTP/3 (QUIC) listener, enabled with the `http3` feature.
+//
+// Requests are decoded with h3, handed to the same axum `Router` that serves
+// TCP, and response bodies (including SSE streams) are forwarded frame by
+// frame as they are produced.
+
+use anyhow::{anyhow, Context, Result};
+use crate::error::ApiError;
+use axum::body::Body;
+use axum::response::IntoResponse;
+use axum::Router;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::stream::StreamExt;
+use h3::server::RequestResolver;
+use http::Response;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// Creates a QUIC endpoint bound to `addr`, using a PEM certificate chain and
+/// private key. QUIC mandates TLS 1.3, so these are required.
+pub fn bind_endpoint(addr: SocketAddr, cert_path: &str, key_path: &str) -> Result<quinn::Endpoint> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .with_context(|| format!("Failed to open TLS certificate '{}'", cert_path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse TLS certificate '{}'", cert_path))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to load TLS private key '{}'", key_path))?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .context("Failed to select TLS 1.3 for QUIC")?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate/key pair")?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let quic_crypto = QuicServerConfig::try_from(tls)
+        .map_err(|e| anyhow!("TLS configuration unusable for QUIC: {}", e))?;
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_crypto));
+    let endpoint = quinn::Endpoint::server(server_config, addr)
+        .with_context(|| format!("Failed to bind QUIC endpoint on {}", addr))?;
+    info!("listening for HTTP/3 on udp {}", addr);
+    Ok(endpoint)
+}
+
+/// Accept loop for QUIC connections. Request bodies are buffered up to
+/// `max_body_bytes` (chat requests are small JSON documents) before routing.
+pub async fn serve_h3(endpoint: quinn::Endpoint, app: Router, max_body_bytes: usize) -> Result<()> {
+    while let Some(incoming) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("QUIC handshake failed: {}", e);
+                    return;
+                }
+            };
+            let remote = connection.remote_address();
+            let mut h3_conn = match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(%remote, "HTTP/3 connection setup failed: {}", e);
+                    return;
+                }
+            };
+            loop {
+                match h3_conn.accept().await {
+                    Ok(Some(resolver)) => {
+                        let app = app.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_request(resolver, app, max_body_bytes).await {
+                                debug!(%remote, "HTTP/3 request ended with error: {:#}", e);
+                            }
+                        });
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!(%remote, "HTTP/3 connection closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    app: Router,
+    max_body_bytes: usize,
+) -> Result<()> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, mut recv) = stream.split();
+
+    let mut body = BytesMut::new();
+    let mut too_large = false;
+    while let Some(mut chunk) = recv.recv_data().await? {
+        if body.len() + chunk.remaining() > max_body_bytes {
+            too_large = true;
+            break;
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    let response = if too_large {
+        ApiError::payload_too_large().into_response()
+    } else {
+        let request = request.map(|()| Body::from(body.freeze()));
+        app.oneshot(request).await.map_err(|e| anyhow!("router error: {}", e))?
+    };
+    let (parts, body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    let mut frames = body.into_data_stream();
+    while let Some(frame) = frames.next().await {
+        send.send_data(frame?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
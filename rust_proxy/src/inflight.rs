@@ -0,0 +1,238 @@
+// Deduplication of identical in-flight chat requests. With DEDUP_INFLIGHT,
+// a request with the same key, prompt, Dev options and `n` as one whose
+// stream is still running does not go to Dev: it gets the events of that
+// stream from the start, then the live rest, and the response says
+// `X-Deduplicated: true`. The first stream is driven by a background task
+// while anyone reads it, so the original client going away does not end
+// it for the others. A stream can only be joined until it ends. Streams
+// live in the state store, so identical requests on different replicas
+// share them too.
+
+use futures_util::stream::{Stream, StreamExt};
+use http::HeaderName;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::env_or;
+use crate::dev_client::DevRequestOptions;
+use crate::replay::{EventLog, EventPayload};
+use crate::request_policy::Answer;
+use crate::state_store::StateStore;
+
+/// Response header of a request answered by another's stream.
+pub const X_DEDUPLICATED: HeaderName = HeaderName::from_static("x-deduplicated");
+
+/// What makes two chat requests of `owner` identical: the prompt sent to
+/// Dev, the Dev options, the number of choices and how the answer is shaped.
+pub fn fingerprint(owner: &str, prompt: &str, n: u32, options: &DevRequestOptions, answer: &Answer) -> String {
+    let mut hasher = Sha256::new();
+    let body = serde_json::to_string(options).unwrap_or_default();
+    let shape = format!("{}\0{:?}\0{:?}\0{:?}", n, options.citations, options.footers, answer);
+    for part in [owner, prompt, &body, &shape] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    for attachment in &options.attachments {
+        hasher.update(attachment.filename.as_bytes());
+        hasher.update([0]);
+        hasher.update(attachment.text.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Events a shared stream keeps, enough for any answer.
+const MAX_EVENTS: usize = 100_000;
+
+/// How long a claim outlives its leader's last check-in.
+const CLAIM_TTL: Duration = Duration::from_secs(5);
+
+/// Running chat streams by fingerprint, in the state store: the fingerprint
+/// claims the id of the stream answering it, whose events any replica can
+/// read.
+#[derive(Clone, Debug)]
+pub struct InFlight {
+    enabled: bool,
+    store: StateStore,
+    idle: Duration,
+}
+
+impl InFlight {
+    pub fn new(enabled: bool, store: StateStore) -> Self {
+        Self { enabled, store, idle: Duration::from_secs(10) }
+    }
+
+    /// How long a stream runs on without anyone reading it.
+    pub fn with_idle(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    pub fn from_env(store: &StateStore) -> Self {
+        let inflight = Self::new(env_or("DEDUP_INFLIGHT", false), store.clone());
+        if inflight.enabled {
+            info!("Identical in-flight chat requests share one Dev stream");
+        }
+        inflight
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn claim_key(key: &str) -> String {
+        format!("inflight:{}", key)
+    }
+
+    fn log(&self, id: &str) -> EventLog {
+        EventLog::new(self.store.clone(), format!("inflight:stream:{}", id), MAX_EVENTS, self.idle)
+    }
+
+    /// The events of the running stream with this fingerprint, if any.
+    pub async fn join(&self, key: &str) -> Option<impl Stream<Item = EventPayload> + Send + 'static> {
+        let joined = async {
+            let Some(id) = self.store.get(&Self::claim_key(key)).await? else { return Ok(None) };
+            let log = self.log(&id);
+            // Counts as reading before the leader can decide nobody does
+            Ok::<_, anyhow::Error>(log.attach().await?.then_some(log))
+        };
+        match joined.await {
+            Ok(log) => Some(log?.read(0).map(|(_, event)| event)),
+            Err(e) => {
+                warn!("Could not look up in-flight chat streams: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Drives `events` in a background task that others with the same
+    /// fingerprint can join; returns the stream for the client that started
+    /// it. The task stops early once nobody reads.
+    pub async fn lead(
+        &self,
+        key: String,
+        events: impl Stream<Item = EventPayload> + Send + 'static,
+    ) -> impl Stream<Item = EventPayload> + Send + 'static {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let log = self.log(&id);
+        let claim = Self::claim_key(&key);
+        let claimed = async {
+            log.start().await?;
+            self.store.set_new(&claim, id.clone(), Some(CLAIM_TTL)).await
+        };
+        let mut claimed = match claimed.await {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                warn!("Chat stream will not be shared: {:#}", e);
+                false
+            }
+        };
+        let reader = log.clone().read(0).map(|(_, event)| event);
+
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            let release = || async {
+                if let Err(e) = store.remove_if(&claim, id.clone()).await {
+                    warn!("Could not release a shared chat stream: {:#}", e);
+                }
+            };
+            let mut events = Box::pin(events);
+            let mut check = tokio::time::interval(log.tick());
+            loop {
+                tokio::select! {
+                    event = events.next() => match event {
+                        Some(event) => if let Err(e) = log.push(event).await {
+                            warn!("Could not share a chat stream event, ending the stream: {:#}", e);
+                            break;
+                        },
+                        None => break,
+                    },
+                    _ = check.tick() => match log.check_in().await {
+                        Ok(true) if claimed => {
+                            if let Err(e) = store.expire(&claim, CLAIM_TTL).await {
+                                warn!("Could not keep a shared chat stream joinable: {:#}", e);
+                            }
+                        }
+                        Ok(true) => {}
+                        Ok(false) => {
+                            // Nobody can join once the claim is gone, but
+                            // someone may have just before
+                            if claimed {
+                                release().await;
+                                claimed = false;
+                            }
+                            if !log.check_in().await.unwrap_or(false) {
+                                debug!("Nobody reads the shared chat stream any more, stopping it");
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("Could not check in a shared chat stream: {:#}", e),
+                    },
+                }
+            }
+            if claimed {
+                release().await;
+            }
+            if let Err(e) = log.finish().await {
+                warn!("Could not end a shared chat stream: {:#}", e);
+            }
+        });
+        reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow_payloads(n: usize) -> impl Stream<Item = EventPayload> + Send + 'static {
+        futures_util::stream::iter(1..=n).then(|i| async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            (None, format!("chunk{}", i))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_joined_stream_gets_every_event() {
+        let inflight = InFlight::new(true, StateStore::default());
+        let first = inflight.lead("key".to_string(), slow_payloads(3)).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let second = inflight.join("key").await.unwrap();
+        assert!(inflight.join("other").await.is_none());
+        let (first, second): (Vec<_>, Vec<_>) = tokio::join!(first.collect(), second.collect());
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, second);
+        // Finished streams cannot be joined
+        assert!(inflight.join("key").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_stops_without_readers() {
+        let inflight = InFlight::new(true, StateStore::default()).with_idle(Duration::from_millis(100));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        // Ends only when dropped by the driver
+        let events = futures_util::stream::pending::<EventPayload>().chain(futures_util::stream::once(async move {
+            let _tx = tx;
+            (None, String::new())
+        }));
+        drop(inflight.lead("key".to_string(), events).await);
+        tokio::time::timeout(Duration::from_secs(1), rx).await.unwrap().unwrap_err();
+        assert!(inflight.join("key").await.is_none());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let options = DevRequestOptions { model: Some("gpt-4o".to_string()), ..Default::default() };
+        let answer = Answer::default();
+        let key = fingerprint("key_a", "hi", 1, &options, &answer);
+        assert_eq!(key, fingerprint("key_a", "hi", 1, &options, &answer));
+        assert_ne!(key, fingerprint("key_b", "hi", 1, &options, &answer));
+        assert_ne!(key, fingerprint("key_a", "hi", 2, &options, &answer));
+        let search = DevRequestOptions { search_mode: Some("web".to_string()), ..options.clone() };
+        assert_ne!(key, fingerprint("key_a", "hi", 1, &search, &answer));
+        // The request id is not part of it
+        let other_id = DevRequestOptions { request_id: Some("req-2".to_string()), ..options };
+        assert_eq!(key, fingerprint("key_a", "hi", 1, &other_id, &answer));
+    }
+}
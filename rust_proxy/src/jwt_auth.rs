@@ -0,0 +1,341 @@
+// JWT bearer tokens from an identity provider, accepted next to API keys.
+// With JWT_JWKS_URL (or JWT_SECRET, for HS256 tokens) set, a bearer token
+// shaped like a JWT is verified against the provider's keys instead of
+// being looked up as a key: its signature, `exp` and `nbf`, `iss` when
+// JWT_ISSUER is set and `aud` when JWT_AUDIENCE (comma separated) is. The
+// JWKS is cached for JWT_JWKS_REFRESH_SECS (3600) and fetched again early
+// when a token names a key id it does not have. The client is known as
+// `jwt_<sub>` in logs and usage reports.
+//
+// JWT_SCOPE_MODELS maps scopes (the `scope` or `scp` claim) to the models a
+// token with them may use, e.g. `{"chat": ["gpt-4o"], "chat:all": ["*"]}`;
+// when it is set, tokens are limited to the models of their scopes.
+
+use anyhow::{Context, Result};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::auth::{ApiKeyId, ModelAccess};
+use crate::config::{env_or, parse_list};
+use crate::error::ApiError;
+
+/// Shortest time between two fetches of the JWKS for an unknown key id.
+const MIN_REFETCH: Duration = Duration::from_secs(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// Where signing keys come from.
+enum Keys {
+    Secret(DecodingKey),
+    Jwks { url: String, refresh: Duration, client: reqwest::Client, cached: RwLock<Option<(JwkSet, Instant)>> },
+}
+
+struct Config {
+    keys: Keys,
+    issuer: Option<String>,
+    audience: Vec<String>,
+    scope_models: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scp: Option<Value>,
+}
+
+impl Claims {
+    fn scopes(&self) -> BTreeSet<&str> {
+        let mut scopes: BTreeSet<&str> = self.scope.as_deref().unwrap_or_default().split_whitespace().collect();
+        match &self.scp {
+            Some(Value::String(scp)) => scopes.extend(scp.split_whitespace()),
+            Some(Value::Array(scp)) => scopes.extend(scp.iter().filter_map(Value::as_str)),
+            _ => {}
+        }
+        scopes
+    }
+}
+
+/// Whether `token` looks like a compact JWT rather than an API key.
+pub fn is_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+#[derive(Clone, Default)]
+pub struct JwtAuth {
+    config: Option<Arc<Config>>,
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth").field("enabled", &self.is_enabled()).finish()
+    }
+}
+
+impl JwtAuth {
+    fn new(keys: Keys) -> Self {
+        Self { config: Some(Arc::new(Config { keys, issuer: None, audience: Vec::new(), scope_models: None })) }
+    }
+
+    /// Verifies HS256/384/512 tokens signed with `secret`.
+    pub fn with_secret(secret: &[u8]) -> Self {
+        Self::new(Keys::Secret(DecodingKey::from_secret(secret)))
+    }
+
+    /// Verifies tokens with the keys published at `url`.
+    pub fn with_jwks(url: impl Into<String>, refresh: Duration) -> Self {
+        let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build().unwrap_or_default();
+        Self::new(Keys::Jwks { url: url.into(), refresh, client, cached: RwLock::new(None) })
+    }
+
+    fn configure(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        if let Some(config) = self.config.as_mut().and_then(Arc::get_mut) {
+            configure(config);
+        }
+        self
+    }
+
+    pub fn issuer(self, issuer: impl Into<String>) -> Self {
+        let issuer = issuer.into();
+        self.configure(|c| c.issuer = Some(issuer))
+    }
+
+    pub fn audience(self, audience: Vec<String>) -> Self {
+        self.configure(|c| c.audience = audience)
+    }
+
+    pub fn scope_models(self, scope_models: HashMap<String, Vec<String>>) -> Self {
+        self.configure(|c| c.scope_models = Some(scope_models))
+    }
+
+    pub fn from_env() -> Self {
+        let var = |key| std::env::var(key).ok().filter(|v: &String| !v.trim().is_empty());
+        let auth = match (var("JWT_JWKS_URL"), var("JWT_SECRET")) {
+            (Some(url), _) => Self::with_jwks(url.trim(), Duration::from_secs(env_or("JWT_JWKS_REFRESH_SECS", 3600))),
+            (None, Some(secret)) => Self::with_secret(secret.as_bytes()),
+            (None, None) => return Self::default(),
+        };
+        let mut auth = auth.audience(parse_list(&var("JWT_AUDIENCE").unwrap_or_default()));
+        if let Some(issuer) = var("JWT_ISSUER") {
+            auth = auth.issuer(issuer.trim());
+        }
+        if let Some(raw) = var("JWT_SCOPE_MODELS") {
+            match serde_json::from_str(&raw) {
+                Ok(scope_models) => auth = auth.scope_models(scope_models),
+                Err(e) => warn!("Ignoring JWT_SCOPE_MODELS, expected a JSON object of model lists: {}", e),
+            }
+        }
+        if let Some(config) = &auth.config {
+            info!(
+                issuer = config.issuer,
+                audience = ?config.audience,
+                jwks = matches!(config.keys, Keys::Jwks { .. }),
+                scope_models = config.scope_models.is_some(),
+                "JWT bearer tokens accepted"
+            );
+        }
+        auth
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// The client a valid token identifies, and the models it may use.
+    pub async fn verify(&self, token: &str) -> Result<(ApiKeyId, Option<ModelAccess>), ApiError> {
+        let Some(config) = &self.config else {
+            return Err(ApiError::unauthorized("Incorrect API key provided."));
+        };
+        let claims: Claims = self.verify_claims(token).await?;
+        let access = config.scope_models.as_ref().and_then(|scope_models| {
+            let models: Vec<String> =
+                claims.scopes().into_iter().filter_map(|scope| scope_models.get(scope)).flatten().cloned().collect();
+            ModelAccess::restrict(models, "token scopes")
+        });
+        debug!(sub = claims.sub, "Verified bearer token");
+        Ok((ApiKeyId(format!("jwt_{}", claims.sub)), access))
+    }
+
+    /// The claims of a token with a valid signature, `exp`, `sub` and, as
+    /// configured, `iss` and `aud`.
+    pub async fn verify_claims<T: DeserializeOwned>(&self, token: &str) -> Result<T, ApiError> {
+        let Some(config) = &self.config else {
+            return Err(ApiError::unauthorized("Incorrect API key provided."));
+        };
+        let invalid = |reason: &str| {
+            warn!(reason, "Invalid bearer token");
+            ApiError::unauthorized(format!("Invalid bearer token: {}", reason)).with_code("invalid_token")
+        };
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid("malformed token"))?;
+        let key = match &config.keys {
+            Keys::Secret(key) if HMAC_ALGORITHMS.contains(&header.alg) => key.clone(),
+            Keys::Secret(_) => return Err(invalid("unsupported algorithm")),
+            Keys::Jwks { .. } if HMAC_ALGORITHMS.contains(&header.alg) => return Err(invalid("unsupported algorithm")),
+            Keys::Jwks { .. } => config.jwk(header.kid.as_deref()).await.map_err(|e| {
+                warn!("Cannot verify bearer tokens: {:#}", e);
+                ApiError::new(http::StatusCode::SERVICE_UNAVAILABLE, "server_error", "The identity provider's keys are unavailable")
+                    .with_code("jwks_unavailable")
+            })?
+            .ok_or_else(|| invalid("unknown signing key"))?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        validation.validate_aud = !config.audience.is_empty();
+        if validation.validate_aud {
+            validation.set_audience(&config.audience);
+        }
+        let claims = jsonwebtoken::decode::<T>(token, &key, &validation)
+            .map_err(|e| {
+                invalid(match e.kind() {
+                    ErrorKind::ExpiredSignature => "the token has expired",
+                    ErrorKind::ImmatureSignature => "the token is not valid yet",
+                    ErrorKind::InvalidIssuer => "unexpected issuer",
+                    ErrorKind::InvalidAudience => "unexpected audience",
+                    ErrorKind::InvalidSignature => "bad signature",
+                    ErrorKind::MissingRequiredClaim(_) => "missing claims",
+                    _ => "malformed token",
+                })
+            })?
+            .claims;
+        Ok(claims)
+    }
+}
+
+impl Config {
+    /// The JWKS key for `kid`, fetching the set when it is stale or lacks it.
+    async fn jwk(&self, kid: Option<&str>) -> Result<Option<DecodingKey>> {
+        let Keys::Jwks { url, refresh, client, cached } = &self.keys else { return Ok(None) };
+        let find = |set: &JwkSet| match kid {
+            Some(kid) => set.find(kid).cloned(),
+            None => set.keys.first().cloned(),
+        };
+        let stale = {
+            let cached = cached.read().await;
+            match &*cached {
+                Some((set, fetched)) => match find(set) {
+                    Some(jwk) if fetched.elapsed() < *refresh => return Ok(Some(DecodingKey::from_jwk(&jwk)?)),
+                    // A key the provider may have added since: fetch again,
+                    // but not for every token naming an unknown key
+                    None if fetched.elapsed() < MIN_REFETCH => return Ok(None),
+                    _ => true,
+                },
+                None => true,
+            }
+        };
+        if stale {
+            let set: JwkSet = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("fetch {}", url))?
+                .json()
+                .await
+                .with_context(|| format!("parse the JWKS at {}", url))?;
+            debug!(keys = set.keys.len(), "Fetched the JWKS");
+            *cached.write().await = Some((set, Instant::now()));
+        }
+        let cached = cached.read().await;
+        let jwk = cached.as_ref().and_then(|(set, _)| find(set));
+        Ok(jwk.map(|jwk| DecodingKey::from_jwk(&jwk)).transpose()?)
+    }
+
+    #[cfg(test)]
+    async fn set_jwks(&self, set: JwkSet) {
+        if let Keys::Jwks { cached, .. } = &self.keys {
+            *cached.write().await = Some((set, Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn token(claims: Value, alg: Algorithm, kid: Option<&str>) -> String {
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(alg) };
+        encode(&header, &claims, &EncodingKey::from_secret(b"s3cret")).unwrap()
+    }
+
+    fn exp(offset: i64) -> i64 {
+        jsonwebtoken::get_current_timestamp() as i64 + offset
+    }
+
+    #[tokio::test]
+    async fn test_verify_claims() {
+        let auth = JwtAuth::with_secret(b"s3cret").issuer("https://idp.example").audience(vec!["proxy".to_string()]);
+        let valid = json!({"sub": "alice", "exp": exp(600), "iss": "https://idp.example", "aud": "proxy"});
+        let (id, access) = auth.verify(&token(valid.clone(), Algorithm::HS256, None)).await.unwrap();
+        assert_eq!(id.as_str(), "jwt_alice");
+        assert!(access.is_none());
+
+        let mut expired = valid.clone();
+        expired["exp"] = exp(-600).into();
+        let mut other_audience = valid.clone();
+        other_audience["aud"] = "elsewhere".into();
+        for (claims, reason) in [(expired, "expired"), (other_audience, "audience")] {
+            let error = auth.verify(&token(claims, Algorithm::HS256, None)).await.unwrap_err();
+            assert_eq!(error.status, http::StatusCode::UNAUTHORIZED);
+            assert!(error.message.contains(reason), "{}", error.message);
+        }
+        let forged = encode(&Header::default(), &valid, &EncodingKey::from_secret(b"other")).unwrap();
+        assert!(auth.verify(&forged).await.unwrap_err().message.contains("signature"));
+    }
+
+    #[tokio::test]
+    async fn test_scopes_map_to_models() {
+        let scope_models = HashMap::from([
+            ("chat".to_string(), vec!["gpt-4o".to_string()]),
+            ("chat:all".to_string(), vec!["*".to_string()]),
+        ]);
+        let auth = JwtAuth::with_secret(b"s3cret").scope_models(scope_models);
+        let verify = |claims| {
+            let auth = auth.clone();
+            async move { auth.verify(&token(claims, Algorithm::HS256, None)).await.unwrap().1 }
+        };
+        let access = verify(json!({"sub": "a", "exp": exp(600), "scope": "openid chat"})).await.unwrap();
+        assert!(access.check(Some("gpt-4o")).is_ok());
+        assert_eq!(access.check(Some("gpt-4o-search")).unwrap_err().status, http::StatusCode::FORBIDDEN);
+        assert!(verify(json!({"sub": "a", "exp": exp(600), "scp": ["chat", "chat:all"]})).await.is_none());
+        // No mapped scope: no model
+        assert!(verify(json!({"sub": "a", "exp": exp(600)})).await.unwrap().check(Some("gpt-4o")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jwks_keys_by_kid() {
+        let auth = JwtAuth::with_jwks("http://127.0.0.1:9/jwks", Duration::from_secs(3600));
+        // An `oct` key stands in for the provider's public keys
+        let jwks: JwkSet = serde_json::from_value(json!({"keys": [
+            {"kty": "oct", "kid": "k1", "alg": "HS256", "k": "czNjcmV0"}
+        ]}))
+        .unwrap();
+        auth.config.as_ref().unwrap().set_jwks(jwks).await;
+        let claims = json!({"sub": "bob", "exp": exp(600)});
+        // HMAC tokens are refused when keys come from a JWKS
+        let error = auth.verify(&token(claims, Algorithm::HS256, Some("k1"))).await.unwrap_err();
+        assert!(error.message.contains("algorithm"), "{}", error.message);
+        let key = auth.config.as_ref().unwrap().jwk(Some("k1")).await.unwrap();
+        assert!(key.is_some());
+        // Unknown key ids within a minute of the last fetch do not refetch
+        assert!(auth.config.as_ref().unwrap().jwk(Some("k2")).await.unwrap().is_none());
+        assert!(is_jwt(&token(json!({"sub": "x"}), Algorithm::HS256, None)));
+        assert!(!is_jwt("sk-one"));
+    }
+}
@@ -0,0 +1,503 @@
+// Client API keys managed at runtime, in SQLite. With KEYS_DB set to a
+// database path, admins create, label, reconfigure and revoke keys through
+// `/admin/keys`, alongside the static ALLOWED_API_KEYS. A key's secret is
+// shown once, when it is created; the store keeps its SHA-256, and the key
+// goes by the same `key_...` id as a static key in logs and usage reports.
+// Active keys are held in memory, reloaded after every change, so
+// authenticating a request never waits on the database.
+//
+// Per-key settings: `requests_per_minute` caps the requests a key can make
+// in each clock minute, counted in the state store so the cap holds across
+// replicas; beyond it requests get a 429 `rate_limit_exceeded`.
+// `models` restricts a key to those model names (`*` for any); requests for
+// others get a 403 `model_not_allowed`, and `/v1/models` lists only them.
+// `tokens_per_day` / `tokens_per_month` budget the prompt and completion
+// tokens the usage accounting counts per UTC day and month; a key out of
+// either gets a 429 `insufficient_quota`, and responses to the key carry the
+// tokens left in `x-quota-remaining-tokens-day` / `-month`.
+
+use anyhow::{Context, Result};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::{HeaderName, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::auth::key_id;
+use crate::error::ApiError;
+use crate::state_store::StateStore;
+use crate::usage::PeriodTokens;
+use crate::utils;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+pub const X_QUOTA_REMAINING_TOKENS_DAY: HeaderName = HeaderName::from_static("x-quota-remaining-tokens-day");
+pub const X_QUOTA_REMAINING_TOKENS_MONTH: HeaderName = HeaderName::from_static("x-quota-remaining-tokens-month");
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        key_hash TEXT NOT NULL UNIQUE,
+        label TEXT,
+        created INTEGER NOT NULL,
+        revoked INTEGER,
+        settings TEXT NOT NULL
+    );
+";
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// What a key may do.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KeySettings {
+    /// Requests per minute; no limit when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    /// The model names the key may use; any when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub models: Option<Vec<String>>,
+    /// Prompt plus completion tokens per UTC day; no budget when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_per_day: Option<u64>,
+    /// Prompt plus completion tokens per UTC month; no budget when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_per_month: Option<u64>,
+}
+
+impl KeySettings {
+    /// What is left of the key's token budgets after `used`, none without
+    /// budgets.
+    pub fn remaining_tokens(&self, used: &PeriodTokens) -> Option<TokenQuota> {
+        if self.tokens_per_day.is_none() && self.tokens_per_month.is_none() {
+            return None;
+        }
+        Some(TokenQuota {
+            daily: self.tokens_per_day.map(|budget| budget.saturating_sub(used.day_tokens)),
+            monthly: self.tokens_per_month.map(|budget| budget.saturating_sub(used.month_tokens)),
+        })
+    }
+}
+
+/// The tokens left of a key's budgets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenQuota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+impl TokenQuota {
+    pub fn is_exhausted(&self) -> bool {
+        self.daily == Some(0) || self.monthly == Some(0)
+    }
+
+    /// The remaining-quota response headers.
+    pub fn headers(&self) -> impl Iterator<Item = (HeaderName, u64)> {
+        [(X_QUOTA_REMAINING_TOKENS_DAY, self.daily), (X_QUOTA_REMAINING_TOKENS_MONTH, self.monthly)]
+            .into_iter()
+            .filter_map(|(name, remaining)| Some((name, remaining?)))
+    }
+}
+
+/// A stored key, without its secret.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StoredKey {
+    pub id: String,
+    pub object: &'static str,
+    pub label: Option<String>,
+    pub created: u64,
+    /// When the key was revoked.
+    pub revoked: Option<u64>,
+    pub settings: KeySettings,
+}
+
+impl StoredKey {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let settings: String = row.get(4)?;
+        Ok(Self {
+            id: row.get(0)?,
+            object: "api_key",
+            label: row.get(1)?,
+            created: row.get(2)?,
+            revoked: row.get(3)?,
+            settings: serde_json::from_str(&settings).unwrap_or_default(),
+        })
+    }
+}
+
+/// `POST /admin/keys`
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateKey {
+    pub label: Option<String>,
+    #[serde(default)]
+    pub settings: KeySettings,
+}
+
+/// `PATCH /admin/keys/{id}`: the fields given replace the stored ones.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateKey {
+    pub label: Option<String>,
+    pub settings: Option<KeySettings>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    db: Option<Arc<Mutex<Connection>>>,
+    /// Active keys by the SHA-256 of their secret.
+    active: Arc<RwLock<HashMap<String, StoredKey>>>,
+    /// Where the requests of each key's current rate window are counted.
+    rates: StateStore,
+}
+
+impl KeyStore {
+    /// Opens (or creates) the database at `path`.
+    pub fn open(path: impl AsRef<FsPath>) -> Result<Self> {
+        let path = path.as_ref();
+        let connection = Connection::open(path).with_context(|| format!("open {}", path.display()))?;
+        connection.execute_batch(SCHEMA).context("create key store schema")?;
+        let store = Self { db: Some(Arc::new(Mutex::new(connection))), ..Default::default() };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Counts rate windows in `rates` instead of this process.
+    pub fn with_rates(mut self, rates: StateStore) -> Self {
+        self.rates = rates;
+        self
+    }
+
+    pub fn from_env(rates: &StateStore) -> Self {
+        let Some(path) = std::env::var("KEYS_DB").ok().filter(|v| !v.trim().is_empty()) else {
+            return Self::default();
+        };
+        match Self::open(&path) {
+            Ok(store) => {
+                info!(path, active = store.active.read().unwrap().len(), "API key store enabled");
+                store.with_rates(rates.clone())
+            }
+            Err(e) => {
+                warn!(path, "API key store disabled: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.db.is_some()
+    }
+
+    fn connection(&self) -> Option<std::sync::MutexGuard<'_, Connection>> {
+        self.db.as_ref().map(|db| db.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Reads the active keys into memory.
+    fn reload(&self) -> Result<()> {
+        let Some(db) = self.connection() else { return Ok(()) };
+        let mut statement =
+            db.prepare("SELECT id, label, created, revoked, settings, key_hash FROM api_keys WHERE revoked IS NULL")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(5)?, StoredKey::from_row(row)?)))?;
+        let active = rows.collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        *self.active.write().unwrap() = active;
+        Ok(())
+    }
+
+    /// The active key whose secret is `token`.
+    pub fn lookup(&self, token: &str) -> Option<StoredKey> {
+        self.active.read().unwrap().get(&utils::sha256_hex(token.as_bytes())).cloned()
+    }
+
+    /// Creates a key; returns it with its secret, which is not stored.
+    pub fn create(&self, request: CreateKey) -> Result<(StoredKey, String)> {
+        let secret = format!("sk-{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let key = StoredKey {
+            id: key_id(&secret),
+            object: "api_key",
+            label: request.label,
+            created: now_secs(),
+            revoked: None,
+            settings: request.settings,
+        };
+        if let Some(db) = self.connection() {
+            db.execute(
+                "INSERT INTO api_keys (id, key_hash, label, created, settings) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![key.id, utils::sha256_hex(secret.as_bytes()), key.label, key.created, serde_json::to_string(&key.settings)?],
+            )?;
+        }
+        self.reload()?;
+        Ok((key, secret))
+    }
+
+    /// All keys, revoked ones included, newest first.
+    pub fn list(&self) -> Result<Vec<StoredKey>> {
+        let Some(db) = self.connection() else { return Ok(Vec::new()) };
+        let mut statement =
+            db.prepare("SELECT id, label, created, revoked, settings FROM api_keys ORDER BY created DESC, id")?;
+        let rows = statement.query_map([], StoredKey::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<StoredKey>> {
+        let Some(db) = self.connection() else { return Ok(None) };
+        let key = db
+            .query_row("SELECT id, label, created, revoked, settings FROM api_keys WHERE id = ?1", params![id], StoredKey::from_row)
+            .optional()?;
+        Ok(key)
+    }
+
+    pub fn update(&self, id: &str, request: UpdateKey) -> Result<Option<StoredKey>> {
+        if let Some(db) = self.connection() {
+            if let Some(label) = &request.label {
+                db.execute("UPDATE api_keys SET label = ?2 WHERE id = ?1", params![id, label])?;
+            }
+            if let Some(settings) = &request.settings {
+                db.execute("UPDATE api_keys SET settings = ?2 WHERE id = ?1", params![id, serde_json::to_string(settings)?])?;
+            }
+        }
+        self.reload()?;
+        self.get(id)
+    }
+
+    /// Revokes a key for good; revoking it again keeps the first time.
+    pub fn revoke(&self, id: &str) -> Result<Option<StoredKey>> {
+        if let Some(db) = self.connection() {
+            db.execute("UPDATE api_keys SET revoked = ?2 WHERE id = ?1 AND revoked IS NULL", params![id, now_secs()])?;
+        }
+        self.reload()?;
+        self.get(id)
+    }
+
+    /// Counts a request of `key` at `now` (Unix seconds) against its rate
+    /// limit; the error says in how many seconds the window resets. Requests
+    /// go through when the state store fails.
+    pub async fn check_rate(&self, key: &StoredKey, now: u64) -> Result<(), u64> {
+        let Some(limit) = key.settings.requests_per_minute else { return Ok(()) };
+        let window = RATE_WINDOW.as_secs();
+        let counter = format!("ratelimit:{}:{}", key.id, now / window);
+        match self.rates.add(&counter, 1, Some(RATE_WINDOW)).await {
+            Ok(count) if count > i64::from(limit) => Err((window - now % window).max(1)),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(api_key_id = key.id, "Could not count a request against the rate limit: {:#}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn disabled() -> Response {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "The API key store is disabled; set KEYS_DB to enable it.")
+        .with_code("key_store_disabled")
+        .into_response()
+}
+
+fn not_found(id: &str) -> Response {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No API key '{}'", id))
+        .with_code("key_not_found")
+        .into_response()
+}
+
+/// Runs a store operation off the async runtime.
+async fn run<T: Send + 'static>(
+    store: KeyStore,
+    operation: impl FnOnce(KeyStore) -> Result<T> + Send + 'static,
+) -> Result<T, Response> {
+    let failed = |message: String| {
+        warn!("API key store operation failed: {}", message);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "The API key store operation failed").into_response()
+    };
+    match tokio::task::spawn_blocking(move || operation(store)).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(failed(format!("{:#}", e))),
+        Err(e) => Err(failed(e.to_string())),
+    }
+}
+
+/// `GET /admin/keys` (admin): every stored key, newest first.
+#[utoipa::path(get, path = "/admin/keys", tag = "admin", security(("admin_token" = [])), responses(
+    (status = 200, description = "`{\"object\": \"list\", \"data\": [key]}`", body = Object),
+    (status = 404, description = "The key store is disabled", body = ErrorBody),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn list_keys_handler(State(store): State<KeyStore>) -> Response {
+    if !store.is_enabled() {
+        return disabled();
+    }
+    match run(store, |store| store.list()).await {
+        Ok(keys) => Json(serde_json::json!({ "object": "list", "data": keys })).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `POST /admin/keys` (admin): the response carries the secret, once.
+#[utoipa::path(post, path = "/admin/keys", tag = "admin", security(("admin_token" = [])), request_body = CreateKey,
+    responses(
+        (status = 201, description = "The key, with its secret in `key`", body = Object),
+        (status = 404, description = "The key store is disabled", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn create_key_handler(State(store): State<KeyStore>, Json(request): Json<CreateKey>) -> Response {
+    if !store.is_enabled() {
+        return disabled();
+    }
+    match run(store, |store| store.create(request)).await {
+        Ok((key, secret)) => {
+            info!(id = key.id, label = key.label, "API key created");
+            let mut body = serde_json::to_value(&key).unwrap_or_default();
+            body["key"] = secret.into();
+            (StatusCode::CREATED, Json(body)).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+/// `GET /admin/keys/{id}` (admin)
+#[utoipa::path(get, path = "/admin/keys/{id}", tag = "admin", security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Key id")),
+    responses(
+        (status = 200, description = "The key", body = StoredKey),
+        (status = 404, description = "No such key, or the key store is disabled", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn get_key_handler(State(store): State<KeyStore>, Path(id): Path<String>) -> Response {
+    if !store.is_enabled() {
+        return disabled();
+    }
+    let lookup = id.clone();
+    match run(store, move |store| store.get(&lookup)).await {
+        Ok(Some(key)) => Json(key).into_response(),
+        Ok(None) => not_found(&id),
+        Err(response) => response,
+    }
+}
+
+/// `PATCH /admin/keys/{id}` (admin): relabels a key or replaces its settings.
+#[utoipa::path(patch, path = "/admin/keys/{id}", tag = "admin", security(("admin_token" = [])), request_body = UpdateKey,
+    params(("id" = String, Path, description = "Key id")),
+    responses(
+        (status = 200, description = "The updated key", body = StoredKey),
+        (status = 404, description = "No such key, or the key store is disabled", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn update_key_handler(State(store): State<KeyStore>, Path(id): Path<String>, Json(request): Json<UpdateKey>) -> Response {
+    if !store.is_enabled() {
+        return disabled();
+    }
+    let lookup = id.clone();
+    match run(store, move |store| store.update(&lookup, request)).await {
+        Ok(Some(key)) => {
+            info!(id = key.id, "API key updated");
+            Json(key).into_response()
+        }
+        Ok(None) => not_found(&id),
+        Err(response) => response,
+    }
+}
+
+/// `DELETE /admin/keys/{id}` (admin): revokes the key; it stays listed.
+#[utoipa::path(delete, path = "/admin/keys/{id}", tag = "admin", security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Key id")),
+    responses(
+        (status = 200, description = "The revoked key", body = StoredKey),
+        (status = 404, description = "No such key, or the key store is disabled", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn revoke_key_handler(State(store): State<KeyStore>, Path(id): Path<String>) -> Response {
+    if !store.is_enabled() {
+        return disabled();
+    }
+    let lookup = id.clone();
+    match run(store, move |store| store.revoke(&lookup)).await {
+        Ok(Some(key)) => {
+            warn!(id = key.id, "API key revoked");
+            Json(key).into_response()
+        }
+        Ok(None) => not_found(&id),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_lookup_update_revoke() {
+        let store = KeyStore::open(":memory:").unwrap();
+        let (key, secret) = store.create(CreateKey { label: Some("ci".to_string()), ..Default::default() }).unwrap();
+        assert!(secret.starts_with("sk-"));
+        assert_eq!(key.id, key_id(&secret));
+        assert_eq!(store.lookup(&secret).unwrap().label.as_deref(), Some("ci"));
+        assert!(store.lookup("sk-other").is_none());
+
+        let settings = KeySettings {
+            requests_per_minute: Some(5),
+            models: Some(vec!["gpt-4o".to_string()]),
+            tokens_per_day: Some(1_000),
+            ..Default::default()
+        };
+        let updated = store.update(&key.id, UpdateKey { settings: Some(settings.clone()), ..Default::default() }).unwrap().unwrap();
+        assert_eq!((updated.label.as_deref(), &updated.settings), (Some("ci"), &settings));
+        assert_eq!(store.lookup(&secret).unwrap().settings, settings);
+        assert!(store.update("key_missing", UpdateKey::default()).unwrap().is_none());
+
+        let revoked = store.revoke(&key.id).unwrap().unwrap();
+        assert!(revoked.revoked.is_some());
+        assert!(store.lookup(&secret).is_none());
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_minute() {
+        let store = KeyStore::default();
+        let key = StoredKey {
+            id: "key_a".to_string(),
+            object: "api_key",
+            label: None,
+            created: 0,
+            revoked: None,
+            settings: KeySettings { requests_per_minute: Some(2), ..Default::default() },
+        };
+        let start = 1_700_000_040;
+        assert_eq!(store.check_rate(&key, start).await, Ok(()));
+        assert_eq!(store.check_rate(&key, start + 10).await, Ok(()));
+        assert_eq!(store.check_rate(&key, start + 20).await, Err(40));
+        assert_eq!(store.check_rate(&key, start + 60).await, Ok(()));
+        let unlimited = StoredKey { settings: KeySettings::default(), ..key };
+        for _ in 0..100 {
+            assert_eq!(store.check_rate(&unlimited, start).await, Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_remaining_tokens() {
+        let used = PeriodTokens { day_tokens: 40, month_tokens: 900, ..Default::default() };
+        assert_eq!(KeySettings::default().remaining_tokens(&used), None);
+
+        let settings = KeySettings { tokens_per_day: Some(100), tokens_per_month: Some(1_000), ..Default::default() };
+        let quota = settings.remaining_tokens(&used).unwrap();
+        assert_eq!(quota, TokenQuota { daily: Some(60), monthly: Some(100) });
+        assert!(!quota.is_exhausted());
+        assert_eq!(quota.headers().collect::<Vec<_>>(), [(X_QUOTA_REMAINING_TOKENS_DAY, 60), (X_QUOTA_REMAINING_TOKENS_MONTH, 100)]);
+
+        let monthly = KeySettings { tokens_per_month: Some(500), ..Default::default() };
+        let quota = monthly.remaining_tokens(&used).unwrap();
+        assert_eq!(quota, TokenQuota { daily: None, monthly: Some(0) });
+        assert!(quota.is_exhausted());
+        assert_eq!(quota.headers().count(), 1);
+    }
+}
@@ -0,0 +1,131 @@
+// Answer language for Dev. Unless the request names one (`x_devv.language`,
+// or the model's mode), it comes from the `Accept-Language` header, then
+// from the script the prompt is written in (LANGUAGE_DETECT, on by default),
+// and finally DEFAULT_LANGUAGE ("All", which lets Dev decide).
+
+use http::{header, HeaderMap};
+use tracing::debug;
+
+use crate::config::env_or;
+
+/// Share of a prompt's letters that must be in one script to call it.
+const SCRIPT_SHARE: f64 = 0.3;
+
+#[derive(Debug, Clone)]
+pub struct LanguageSelector {
+    pub default: String,
+    pub detect: bool,
+}
+
+impl LanguageSelector {
+    /// DEFAULT_LANGUAGE (All) and LANGUAGE_DETECT (true).
+    pub fn from_env() -> Self {
+        Self { default: env_or("DEFAULT_LANGUAGE", "All".to_string()), detect: env_or("LANGUAGE_DETECT", true) }
+    }
+
+    /// The language for a request that did not name one.
+    pub fn select(&self, headers: &HeaderMap, prompt: &str) -> String {
+        let (language, source) = match accept_language(headers) {
+            Some(language) => (language, "header"),
+            None => match self.detect.then(|| detect(prompt)).flatten() {
+                Some(language) => (language.to_string(), "prompt"),
+                None => (self.default.clone(), "default"),
+            },
+        };
+        debug!(language, source, "Selected answer language");
+        language
+    }
+}
+
+/// The primary subtag of the preferred `Accept-Language` entry (`zh` for
+/// `zh-CN,en;q=0.8`); `*` counts as no preference.
+pub fn accept_language(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::ACCEPT_LANGUAGE)?.to_str().ok()?;
+    let mut best: Option<(&str, f32)> = None;
+    for entry in value.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if tag.is_empty() || tag == "*" || quality <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, q)| quality > q) {
+            best = Some((tag, quality));
+        }
+    }
+    let (tag, _) = best?;
+    let primary = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+    (primary.len() >= 2 && primary.chars().all(|c| c.is_ascii_alphabetic())).then_some(primary)
+}
+
+/// The language of `prompt` judged by its script, for scripts that name a
+/// language well enough. Latin text gives `None`.
+pub fn detect(prompt: &str) -> Option<&'static str> {
+    let (mut letters, mut han, mut kana, mut hangul, mut cyrillic, mut arabic) = (0usize, 0, 0, 0, 0, 0);
+    for c in prompt.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match c as u32 {
+            0x4E00..=0x9FFF | 0x3400..=0x4DBF => han += 1,
+            0x3040..=0x30FF => kana += 1,
+            0xAC00..=0xD7AF | 0x1100..=0x11FF => hangul += 1,
+            0x0400..=0x04FF => cyrillic += 1,
+            0x0600..=0x06FF => arabic += 1,
+            _ => {}
+        }
+    }
+    let share = |count: usize| letters > 0 && count as f64 / letters as f64 >= SCRIPT_SHARE;
+    if kana > 0 && share(kana + han) {
+        Some("ja")
+    } else if share(han) {
+        Some("zh")
+    } else if share(hangul) {
+        Some("ko")
+    } else if share(cyrillic) {
+        Some("ru")
+    } else if share(arabic) {
+        Some("ar")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(accept_language: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, accept_language.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_accept_language_preference() {
+        assert_eq!(accept_language(&headers("zh-CN,zh;q=0.9,en;q=0.8")).as_deref(), Some("zh"));
+        assert_eq!(accept_language(&headers("en;q=0.5, de-DE;q=0.7")).as_deref(), Some("de"));
+        assert_eq!(accept_language(&headers("*")), None);
+        assert_eq!(accept_language(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_detect_by_script() {
+        assert_eq!(detect("如何在 Rust 中读取文件？"), Some("zh"));
+        assert_eq!(detect("ファイルを読む方法は？"), Some("ja"));
+        assert_eq!(detect("Как прочитать файл?"), Some("ru"));
+        assert_eq!(detect("How do I read a file?"), None);
+        assert_eq!(detect("fn main() { println!(\"你\"); }"), None);
+    }
+
+    #[test]
+    fn test_select_order() {
+        let selector = LanguageSelector { default: "All".to_string(), detect: true };
+        assert_eq!(selector.select(&headers("en-US"), "如何读取文件"), "en");
+        assert_eq!(selector.select(&HeaderMap::new(), "如何读取文件"), "zh");
+        assert_eq!(selector.select(&HeaderMap::new(), "read a file"), "All");
+        let no_detect = LanguageSelector { detect: false, ..selector };
+        assert_eq!(no_detect.select(&HeaderMap::new(), "如何读取文件"), "All");
+    }
+}
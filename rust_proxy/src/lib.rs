@@ -1,5 +1,77 @@
 pub mod wasm_signer;
+#[cfg(feature = "native-signer")]
+pub mod native_signer;
+pub mod signer;
+pub mod signature_cache;
+pub mod clock_skew;
+pub mod secrets;
+pub mod session_refresh;
+pub mod cookie_jar;
+pub mod upstream_pool;
+pub mod state_store;
+pub mod replay;
+pub mod canary;
 pub mod utils;
 pub mod dev_client;
+pub mod dev_errors;
+pub mod failover;
 pub mod sse_processor;
-pub mod models;
\ No newline at end of file
+pub mod sse_parser;
+pub mod citations;
+pub mod footers;
+pub mod coalesce;
+pub mod inflight;
+pub mod pacing;
+pub mod models;
+pub mod request_policy;
+pub mod model_modes;
+pub mod model_catalog;
+pub mod language;
+pub mod system_prompt;
+pub mod prompt_template;
+pub mod moderation;
+pub mod output_filter;
+pub mod output_cap;
+pub mod transform;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm_plugin;
+pub mod scripting;
+pub mod files;
+pub mod threads;
+pub mod assistants;
+pub mod embeddings;
+pub mod config;
+pub mod config_check;
+pub mod error;
+pub mod concurrency;
+pub mod health;
+pub mod access_log;
+pub mod log_sampling;
+pub mod audit;
+pub mod error_reporting;
+pub mod metrics;
+pub mod tokenizer;
+pub mod auth;
+pub mod key_store;
+pub mod jwt_auth;
+pub mod oidc;
+pub mod usage;
+pub mod webhooks;
+pub mod slow_requests;
+pub mod event_bus;
+pub mod billing;
+pub mod history;
+pub mod capture;
+pub mod streams;
+pub mod dashboard;
+pub mod openapi;
+pub mod request_id;
+pub mod self_test;
+pub mod bench;
+#[cfg(unix)]
+pub mod listener;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod service;
+pub mod app;
+pub mod telemetry;
@@ -0,0 +1,93 @@
+use anyhow::{bail, Context, Result};
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder as ConnBuilder;
+use hyper_util::service::TowerToHyperService;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tracing::{debug, error, info, warn};
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket file left
+/// behind by a previous run, and applies `mode` (e.g. 0o660) if given so a
+/// fronting nginx/caddy user can connect. Anything at `path` that is not a
+/// socket is left alone and the bind fails.
+pub fn bind_unix(path: &str, mode: Option<u32>) -> Result<UnixListener> {
+    let socket_path = Path::new(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            bail!("'{}' exists and is not a socket; refusing to replace it", path);
+        }
+        debug!(path, "Removing stale unix socket");
+        std::fs::remove_file(socket_path)
+            .with_context(|| format!("Failed to remove existing socket file '{}'", path))?;
+    }
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("Failed to bind unix socket '{}'", path))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions on '{}'", path))?;
+    }
+    info!(path, "listening on unix socket");
+    Ok(listener)
+}
+
+/// Accept loop for a Unix socket. axum::serve only accepts TCP listeners,
+/// so each connection is driven by hyper-util directly. Like axum::serve,
+/// accept errors never end the loop: a connection aborted before it was
+/// accepted is skipped, anything else (e.g. EMFILE) is retried after a pause.
+pub async fn serve_unix(listener: UnixListener, app: Router) -> Result<()> {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _addr)) => socket,
+            Err(e) => {
+                handle_accept_error(e).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = ConnBuilder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                warn!("Unix socket connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_accept_error(e: std::io::Error) {
+    use std::io::ErrorKind;
+    if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset) {
+        debug!("Unix socket connection failed before it was accepted: {}", e);
+        return;
+    }
+    error!("Failed to accept unix socket connection: {}", e);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_unix_replaces_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("rust_proxy_listener_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("proxy.sock");
+        let socket = socket.to_str().unwrap();
+        drop(bind_unix(socket, None).unwrap());
+        // The socket file left behind is replaced on the next bind
+        assert!(bind_unix(socket, Some(0o660)).is_ok());
+
+        let file = dir.join("not-a-socket");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(bind_unix(file.to_str().unwrap(), None).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
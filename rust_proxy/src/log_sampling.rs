@@ -0,0 +1,142 @@
+// Sampling of warnings that repeat per line of a Dev stream, such as
+// malformed events, so an upstream incident does not flood the logs. Each
+// warning class logs at most LOG_WARN_BURST (10) warnings per
+// LOG_WARN_WINDOW_SECS (60); the rest are counted in
+// `log_warnings_suppressed_total` and summarized once per window with one
+// warning per class. LOG_WARN_BURST=0 logs every warning.
+
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use std::collections::HashMap;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::env_or;
+
+/// Suppressed warnings by `class`, registered by `Metrics`.
+pub static SUPPRESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("log_warnings_suppressed_total", "Warnings dropped by log sampling"), &["class"])
+        .expect("valid counter")
+});
+
+static SAMPLER: Lazy<Sampler> =
+    Lazy::new(|| Sampler::new(env_or("LOG_WARN_BURST", 10), Duration::from_secs(env_or("LOG_WARN_WINDOW_SECS", 60).max(1))));
+
+/// Whether a warning of `class` should be logged; counts it as suppressed
+/// otherwise.
+pub fn allow(class: &'static str) -> bool {
+    SAMPLER.allow(class, Instant::now())
+}
+
+/// Starts logging the per-window summaries of suppressed warnings, once per
+/// process.
+pub fn spawn_summaries() {
+    static STARTED: Once = Once::new();
+    if SAMPLER.burst == 0 {
+        return;
+    }
+    STARTED.call_once(|| {
+        tokio::spawn(async {
+            let mut ticker = tokio::time::interval(SAMPLER.window);
+            loop {
+                ticker.tick().await;
+                SAMPLER.flush(Instant::now());
+            }
+        });
+    });
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    logged: u64,
+    suppressed: u64,
+}
+
+#[derive(Debug)]
+struct Sampler {
+    burst: u64,
+    window: Duration,
+    classes: Mutex<HashMap<&'static str, Window>>,
+}
+
+impl Sampler {
+    fn new(burst: u64, window: Duration) -> Self {
+        Self { burst, window, classes: Mutex::new(HashMap::new()) }
+    }
+
+    fn allow(&self, class: &'static str, now: Instant) -> bool {
+        if self.burst == 0 {
+            return true;
+        }
+        let mut classes = self.classes.lock().unwrap();
+        let window = classes.entry(class).or_insert(Window { started: now, logged: 0, suppressed: 0 });
+        if now.duration_since(window.started) >= self.window {
+            self.summarize(class, window);
+            *window = Window { started: now, logged: 0, suppressed: 0 };
+        }
+        if window.logged < self.burst {
+            window.logged += 1;
+            true
+        } else {
+            window.suppressed += 1;
+            SUPPRESSED.with_label_values(&[class]).inc();
+            false
+        }
+    }
+
+    /// Summarizes and closes the windows that are over; returns the classes
+    /// that had warnings suppressed, with their counts.
+    fn flush(&self, now: Instant) -> Vec<(&'static str, u64)> {
+        let mut summarized = Vec::new();
+        self.classes.lock().unwrap().retain(|class, window| {
+            if now.duration_since(window.started) < self.window {
+                return true;
+            }
+            if window.suppressed > 0 {
+                self.summarize(class, window);
+                summarized.push((*class, window.suppressed));
+            }
+            false
+        });
+        summarized
+    }
+
+    fn summarize(&self, class: &str, window: &Window) {
+        if window.suppressed > 0 {
+            warn!(
+                class,
+                logged = window.logged,
+                suppressed = window.suppressed,
+                window_secs = self.window.as_secs(),
+                "Suppressed repeated warnings"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_per_window_and_summary() {
+        let sampler = Sampler::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let allowed: Vec<bool> = (0..5).map(|_| sampler.allow("test_json", start)).collect();
+        assert_eq!(allowed, [true, true, false, false, false]);
+        assert!(sampler.allow("test_other", start));
+
+        // Nothing is summarized before the window is over
+        assert!(sampler.flush(start + Duration::from_secs(30)).is_empty());
+        assert_eq!(sampler.flush(start + Duration::from_secs(60)), [("test_json", 3)]);
+        assert!(sampler.allow("test_json", start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_zero_burst_logs_everything() {
+        let sampler = Sampler::new(0, Duration::from_secs(60));
+        assert!((0..100).all(|_| sampler.allow("test_json", Instant::now())));
+    }
+}
@@ -1,28 +1,13 @@
-mod wasm_signer;
-mod utils;
-mod dev_client;
-mod sse_processor;
-mod models;
-
-use axum::{routing::{get, post}, Router, Json};
-use axum::response::{IntoResponse, Response};
-use axum::response::sse::{Event as SseEvent, Sse};
-use futures_util::stream::StreamExt;
-use http::StatusCode;
-use std::convert::Infallible;
+#[cfg(not(feature = "lambda"))]
 use std::net::SocketAddr;
-use std::time::Duration;
-use tower_http::trace::TraceLayer;
-use tracing::{info, warn, error, debug, instrument};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{info, error};
 
-// --- Add dotenvy import ---
-use dotenvy;
-
-// Import necessary items from our modules
-use dev_client::{DevApiClient, DevRequestOptions};
-use sse_processor::process_dev_bytes_stream_unfold;
-use models::OpenAiChatRequest; // Moved struct definition
+// Import necessary items from the library crate
+use rust_proxy::{app, config_check, self_test, signer, telemetry, tokenizer};
+#[cfg(not(feature = "lambda"))]
+use rust_proxy::listener;
+use rust_proxy::config::ServerConfig;
+use rust_proxy::dev_client::DevApiClient;
 
 #[tokio::main]
 async fn main() {
@@ -38,144 +23,149 @@ async fn main() {
     }
 
     // --- Initialize tracing (logging) AFTER loading env vars ---
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            // Now std::env::var will see variables loaded from .env files
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "bootstrap=debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-        
-    // Now we can use tracing macros like info!, debug!, etc.
-    info!("Tracing initialized.");
-
-    // Ensure WASM is loaded early (optional but good for catching init errors)
-    if let Err(e) = wasm_signer::WasmSigner::get_instance() {
-        tracing::error!("Fatal: Failed to initialize WASM Signer: {}", e);
-        // In a real app, you might panic or exit here
-        // return;
-    } else {
-        tracing::info!("WASM Signer initialized successfully (or already initialized).");
+    telemetry::init_tracing();
+
+    // Report panics and error bursts to Sentry when SENTRY_DSN is set
+    #[cfg(feature = "sentry")]
+    let _sentry_guard = rust_proxy::error_reporting::init_sentry();
+
+    // Dry run: check the configuration, signer and Dev client, then exit
+    if std::env::args().any(|arg| arg == "--check-config") {
+        let report = config_check::check();
+        print!("{}", report);
+        if report.has_errors() {
+            println!("Configuration check failed");
+            std::process::exit(1);
+        }
+        println!("Configuration check passed");
+        return;
     }
 
-    // Initialize the Dev API client (panics on failure for simplicity here)
-    let dev_client = DevApiClient::new().expect("Failed to create DevApiClient");
+    // Report every missing or malformed setting at once; errors are fatal
+    let report = config_check::validate();
+    report.log();
+    if report.has_errors() {
+        error!("Fix the configuration errors above and restart");
+        std::process::exit(1);
+    }
 
-    // Build our application with routes
-    let app = Router::new()
-        .route("/api/ping", get(ping_handler))
-        .route("/v1/chat/completions", post(chat_completions_handler))
-        // Add state for the client
-        .with_state(dev_client)
-        // Add tracing layer
-        .layer(TraceLayer::new_for_http());
-
-    // Vercel runs on a specific port internally
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(3000); // Default to 3000 if PORT not set
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("listening on {}", addr);
-
-    // Run the Axum server
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
+    // Ensure the signer (WASM unless SIGNER=native) is loaded early (optional but good for catching init errors)
+    match signer::Signer::from_env() {
+        Err(e) => {
+            tracing::error!("Fatal: Failed to initialize signer: {}", e);
+            // In a real app, you might panic or exit here
+            // return;
+        }
+        Ok(signer) => tracing::info!(signer = signer.kind(), "Signer initialized successfully (or already initialized)."),
+    }
+    // Newer module from WASM_URL, file watch on WASM_PATH
+    signer::start_module_reloading().await;
 
-async fn ping_handler() -> &'static str {
-    info!("Ping handler called");
-    "pong"
-}
+    // Load the tokenizer tables before the first stream needs them
+    tokenizer::warm_up();
 
-#[axum::debug_handler]
-#[instrument(skip(client, req))]
-async fn chat_completions_handler(
-    axum::extract::State(client): axum::extract::State<DevApiClient>,
-    Json(req): Json<OpenAiChatRequest>,
-) -> Response {
-    info!(?req, "Received chat completions request");
-
-    // Extract content and options from the request
-    // For simplicity, concatenate messages or take the last user message
-    let content = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
-    if content.is_empty() {
-        warn!("Request content is empty");
-        return (StatusCode::BAD_REQUEST, "Request messages are empty or missing content").into_response();
+    // Initialize the Dev API client
+    let dev_client = match DevApiClient::new() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Fatal: Failed to create the Dev API client: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let server_config = ServerConfig::from_env();
+
+    // Optional startup canary: exit before accepting traffic if the upstream
+    // round trip (signing, credentials, streaming) is broken.
+    let self_test_config = self_test::SelfTestConfig::from_env_and_args();
+    if self_test_config.enabled {
+        info!("Running startup self-test against the Dev API...");
+        match self_test::run(&dev_client, &self_test_config).await {
+            Ok(content) => info!(first_content = %content, "Startup self-test succeeded"),
+            Err(e) => {
+                error!("Startup self-test failed: {:#}", e);
+                std::process::exit(1);
+            }
+        }
     }
 
-    // Create Dev options from OpenAI request
-    // TODO: Map more fields if necessary (temperature, top_p etc. are not used by Dev?)
-    let dev_options = DevRequestOptions {
-        model: req.model, // Pass model name through
-        // Default language? Or extract from request?
-        language: Some("All".to_string()), // Example default
-        ..Default::default()
-    };
+    // Build our application with routes
+    let app = app::build_router(dev_client, &server_config);
+
+    // On AWS Lambda the runtime API replaces all listeners; responses are
+    // streamed so SSE reaches the client incrementally.
+    #[cfg(feature = "lambda")]
+    {
+        info!("Running as AWS Lambda function with response streaming");
+        if let Err(e) = lambda_http::run_with_streaming_response(app).await {
+            error!("Lambda runtime exited with error: {}", e);
+        }
+    }
 
-    // Use a unique ID for the request stream (e.g., UUID)
-    let request_id = utils::generate_uuidv4();
+    #[cfg(not(feature = "lambda"))]
+    serve(app, &server_config).await;
+}
 
-    // Call the Dev API client to get the Response
-    let dev_response = match client.send_request(&content, dev_options.clone()).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("Failed to send request to Dev API: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to contact backend service: {}", e)).into_response();
-        }
+// Runs the configured TCP / Unix socket / HTTP/3 listeners until one stops.
+#[cfg(not(feature = "lambda"))]
+async fn serve(app: axum::Router, server_config: &ServerConfig) {
+    // Advertise the HTTP/3 endpoint to clients connecting over TCP
+    #[cfg(feature = "http3")]
+    let app = match server_config.http3_port {
+        Some(h3_port) => app.layer(tower_http::set_header::SetResponseHeaderLayer::if_not_present(
+            http::header::ALT_SVC,
+            http::HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", h3_port)).unwrap(),
+        )),
+        None => app,
     };
 
-    debug!("Dev response: {:?}", dev_response);
+    let mut servers = tokio::task::JoinSet::new();
+
+    if server_config.listen_tcp {
+        // Vercel runs on a specific port internally
+        let port = std::env::var("PORT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(3000); // Default to 3000 if PORT not set
 
-    // Check status *after* getting the response object
-    if !dev_response.status().is_success() {
-        let status = dev_response.status();
-        // Try to get body text without consuming response if possible (might not be easy with stream)
-        // For simplicity, we might just return a generic error here or try to read body once
-        error!("Dev API returned non-success status: {}", status);
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Backend service returned status: {}", status)).into_response();
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        info!("listening on {}", addr);
+
+        // Run the Axum server
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let tcp_app = app.clone();
+        servers.spawn(async move { axum::serve(listener, tcp_app).await.map_err(anyhow::Error::from) });
     }
 
-    // Get the byte stream from the response
-    let byte_stream = dev_response.bytes_stream();
-
-    // Process the Dev byte stream into an OpenAI chunk stream
-    let openai_chunk_stream = process_dev_bytes_stream_unfold(byte_stream, dev_options, request_id.clone());
-
-    // Create the SSE response
-    let sse_stream = openai_chunk_stream.map(move |chunk_result| {
-        match chunk_result {
-            Ok(chunk) => {
-                // Serialize the chunk to JSON and create an SSE event
-                match serde_json::to_string(&chunk) {
-                    Ok(json_data) => SseEvent::default().data(json_data),
-                    Err(e) => {
-                        warn!("Failed to serialize OpenAI chunk: {}", e);
-                        // Send an error event (or just close the stream?)
-                        SseEvent::default().event("error").data(format!("{{\"error\": \"Serialization failed: {}\"}}", e))
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Error processing Dev stream chunk: {}", e);
-                // Send an error event
-                 SseEvent::default().event("error").data(format!("{{\"error\": \"{}\"}}", e))
-            }
+    // Optional Unix domain socket for sidecar deployments behind a local reverse proxy
+    if let Some(path) = &server_config.unix_socket_path {
+        let listener = listener::bind_unix(path, server_config.unix_socket_mode)
+            .expect("Failed to bind unix socket");
+        servers.spawn(listener::serve_unix(listener, app.clone()));
+    }
+
+    // Experimental HTTP/3 listener over QUIC
+    #[cfg(feature = "http3")]
+    if let Some(h3_port) = server_config.http3_port {
+        let (Some(cert), Some(key)) = (&server_config.tls_cert_path, &server_config.tls_key_path) else {
+            error!("HTTP3_PORT requires TLS_CERT_PATH and TLS_KEY_PATH");
+            return;
+        };
+        let endpoint = rust_proxy::http3::bind_endpoint(SocketAddr::from(([0, 0, 0, 0], h3_port)), cert, key)
+            .expect("Failed to start HTTP/3 listener");
+        servers.spawn(rust_proxy::http3::serve_h3(endpoint, app.clone(), server_config.max_request_body_bytes));
+    }
+
+    if servers.is_empty() {
+        error!("No listener configured: set LISTEN_TCP=true or UNIX_SOCKET_PATH");
+        return;
+    }
+
+    // Run until any listener stops
+    if let Some(result) = servers.join_next().await {
+        match result {
+            Ok(Ok(())) => info!("Listener shut down"),
+            Ok(Err(e)) => error!("Listener failed: {:#}", e),
+            Err(e) => error!("Listener task panicked: {}", e),
         }
-    });
-
-    // Add a final [DONE] message as per OpenAI spec for streams
-    let done_stream = futures_util::stream::once(async { 
-         SseEvent::default().data("[DONE]")
-     });
-    
-    // Combine the main stream and the [DONE] message
-    // Convert SseEvent into Result<_, Infallible> for Sse::new
-    let combined_stream = sse_stream.map(Ok::<_, Infallible>).chain(done_stream.map(Ok::<_, Infallible>));
-
-    info!("Starting SSE stream response...");
-    Sse::new(combined_stream)
-        .keep_alive(axum::response::sse::KeepAlive::new().interval(Duration::from_secs(15)))
-        .into_response()
-}
\ No newline at end of file
+    }
+}
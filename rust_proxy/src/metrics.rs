@@ -0,0 +1,510 @@
+// Prometheus metrics, rendered at `/metrics` in the text exposition format.
+//
+// Time to first byte (request received -> first content chunk) is the metric
+// that reflects user-perceived Dev backend health; the total stream duration
+// is recorded alongside it, both labeled by model (the catalog's name, or
+// `other` for models it does not know) and outcome. Completion tokens are
+// counted as they stream, giving per-stream throughput and, via rate() on
+// the counter, aggregate tokens/sec. Stream outcomes and TTFB are also
+// recorded by signer variant, to compare a canary with the stable path.
+// Signature cache hits and misses, session refreshes and upstream connection
+// pool activity are counted process-wide.
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use http::{header, StatusCode};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::canary::Variant;
+use crate::sse_processor::{ChatCompletionChunk, STREAM_ERROR_PREFIX};
+use crate::tokenizer;
+
+const TTFB_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0];
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+const TOKENS_PER_SECOND_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 20.0, 40.0, 60.0, 80.0, 100.0, 150.0, 200.0, 400.0];
+
+/// How a chat stream ended, used as the `outcome` label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The stream reached a final chunk.
+    Ok,
+    /// The request to Dev failed before any stream started.
+    UpstreamError,
+    /// The Dev stream failed or reported an error event.
+    StreamError,
+    /// The client went away before the stream finished.
+    Cancelled,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::UpstreamError => "upstream_error",
+            Self::StreamError => "stream_error",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    ttfb_seconds: HistogramVec,
+    stream_duration_seconds: HistogramVec,
+    completion_tokens_total: IntCounterVec,
+    stream_tokens_per_second: HistogramVec,
+    variant_streams_total: IntCounterVec,
+    variant_ttfb_seconds: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("rust_proxy".to_string()), None)
+            .expect("valid metrics prefix");
+        let ttfb_seconds = HistogramVec::new(
+            HistogramOpts::new("chat_ttfb_seconds", "Time from request receipt to the first content chunk")
+                .buckets(TTFB_BUCKETS.to_vec()),
+            &["model", "outcome"],
+        )
+        .expect("valid histogram");
+        let stream_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("chat_stream_duration_seconds", "Total duration of a chat completion stream")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["model", "outcome"],
+        )
+        .expect("valid histogram");
+        let completion_tokens_total = IntCounterVec::new(
+            Opts::new("chat_completion_tokens_total", "Completion tokens streamed to clients"),
+            &["model"],
+        )
+        .expect("valid counter");
+        let stream_tokens_per_second = HistogramVec::new(
+            HistogramOpts::new(
+                "chat_stream_tokens_per_second",
+                "Completion tokens per second of a stream, from first to last content chunk",
+            )
+            .buckets(TOKENS_PER_SECOND_BUCKETS.to_vec()),
+            &["model"],
+        )
+        .expect("valid histogram");
+        let variant_streams_total = IntCounterVec::new(
+            Opts::new("chat_variant_streams_total", "Finished chat streams by signer variant and outcome"),
+            &["variant", "outcome"],
+        )
+        .expect("valid counter");
+        let variant_ttfb_seconds = HistogramVec::new(
+            HistogramOpts::new("chat_variant_ttfb_seconds", "Time to the first content chunk by signer variant")
+                .buckets(TTFB_BUCKETS.to_vec()),
+            &["variant"],
+        )
+        .expect("valid histogram");
+        registry.register(Box::new(ttfb_seconds.clone())).expect("register ttfb histogram");
+        registry.register(Box::new(stream_duration_seconds.clone())).expect("register duration histogram");
+        registry.register(Box::new(completion_tokens_total.clone())).expect("register token counter");
+        registry.register(Box::new(stream_tokens_per_second.clone())).expect("register throughput histogram");
+        registry.register(Box::new(variant_streams_total.clone())).expect("register variant counter");
+        registry.register(Box::new(variant_ttfb_seconds.clone())).expect("register variant ttfb histogram");
+        registry
+            .register(Box::new(crate::signature_cache::LOOKUPS.clone()))
+            .expect("register signature cache counter");
+        registry
+            .register(Box::new(crate::session_refresh::REFRESHES.clone()))
+            .expect("register session refresh counter");
+        registry
+            .register(Box::new(crate::upstream_pool::CONNECTIONS_OPENED.clone()))
+            .expect("register upstream connection counter");
+        registry
+            .register(Box::new(crate::upstream_pool::CONNECT_SECONDS.clone()))
+            .expect("register upstream connect histogram");
+        registry
+            .register(Box::new(crate::upstream_pool::REQUESTS.clone()))
+            .expect("register upstream request counter");
+        registry
+            .register(Box::new(crate::moderation::FLAGGED.clone()))
+            .expect("register moderation counter");
+        registry
+            .register(Box::new(crate::sse_processor::TRUNCATIONS.clone()))
+            .expect("register accumulator truncation counter");
+        registry
+            .register(Box::new(crate::sse_processor::EVENTS.clone()))
+            .expect("register Dev event counter");
+        registry
+            .register(Box::new(crate::webhooks::DELIVERIES.clone()))
+            .expect("register webhook delivery counter");
+        registry
+            .register(Box::new(crate::event_bus::MESSAGES.clone()))
+            .expect("register event bus message counter");
+        registry
+            .register(Box::new(crate::billing::RECORDS.clone()))
+            .expect("register billing record counter");
+        registry
+            .register(Box::new(crate::output_cap::CAPPED.clone()))
+            .expect("register output cap counter");
+        #[cfg(feature = "wasm-plugins")]
+        registry
+            .register(Box::new(crate::wasm_plugin::ERRORS.clone()))
+            .expect("register WASM plugin error counter");
+        registry
+            .register(Box::new(crate::scripting::ERRORS.clone()))
+            .expect("register script error counter");
+        registry
+            .register(Box::new(crate::access_log::REQUEST_BYTES.clone()))
+            .expect("register request size histogram");
+        registry
+            .register(Box::new(crate::access_log::RESPONSE_BYTES.clone()))
+            .expect("register response size histogram");
+        registry
+            .register(Box::new(crate::log_sampling::SUPPRESSED.clone()))
+            .expect("register suppressed warning counter");
+        registry
+            .register(Box::new(crate::slow_requests::SLOW.clone()))
+            .expect("register slow request counter");
+        Self {
+            registry,
+            ttfb_seconds,
+            stream_duration_seconds,
+            completion_tokens_total,
+            stream_tokens_per_second,
+            variant_streams_total,
+            variant_ttfb_seconds,
+        }
+    }
+
+    /// Starts observing one chat request for `model`, a bounded label such
+    /// as `ModelCatalog::metric_label` gives.
+    pub fn observe_stream(self: &Arc<Self>, model: Option<&str>) -> StreamObserver {
+        StreamObserver {
+            metrics: self.clone(),
+            model: model.unwrap_or("unknown").to_string(),
+            variant: Variant::Stable,
+            started: Instant::now(),
+            ttfb: None,
+            outcome: None,
+            completion_tokens: 0,
+            first_content_at: None,
+            last_content_at: None,
+            text: None,
+            finish_hooks: Vec::new(),
+        }
+    }
+
+    /// Per-model totals for the admin dashboard: stream counts by outcome,
+    /// mean TTFB and duration, and completion tokens.
+    pub fn summary(&self) -> Vec<ModelSummary> {
+        let mut models: BTreeMap<String, ModelSummary> = BTreeMap::new();
+        for family in self.registry.gather() {
+            let name = family.get_name().trim_start_matches("rust_proxy_");
+            for metric in family.get_metric() {
+                let label = |key: &str| {
+                    metric.get_label().iter().find(|l| l.get_name() == key).map(|l| l.get_value().to_string())
+                };
+                let Some(model) = label("model") else { continue };
+                let summary = models.entry(model.clone()).or_insert_with(|| ModelSummary { model, ..Default::default() });
+                let histogram = metric.get_histogram();
+                match name {
+                    "chat_stream_duration_seconds" => {
+                        let outcome = label("outcome").unwrap_or_default();
+                        *summary.streams.entry(outcome).or_default() += histogram.get_sample_count();
+                        summary.duration.add(histogram.get_sample_count(), histogram.get_sample_sum());
+                    }
+                    "chat_ttfb_seconds" => summary.ttfb.add(histogram.get_sample_count(), histogram.get_sample_sum()),
+                    "chat_completion_tokens_total" => summary.completion_tokens += metric.get_counter().get_value() as u64,
+                    _ => {}
+                }
+            }
+        }
+        models.into_values().collect()
+    }
+
+    /// Renders every registered metric in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Totals for one model, see `Metrics::summary`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelSummary {
+    pub model: String,
+    /// Finished streams by outcome.
+    pub streams: BTreeMap<String, u64>,
+    pub ttfb: MeanSeconds,
+    pub duration: MeanSeconds,
+    pub completion_tokens: u64,
+}
+
+/// Running mean of a histogram across label sets.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MeanSeconds {
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl MeanSeconds {
+    fn add(&mut self, count: u64, sum: f64) {
+        self.count += count;
+        self.sum += sum;
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// Summary handed to `on_finish` hooks when a stream ends.
+#[derive(Debug, Clone)]
+pub struct StreamSummary {
+    pub outcome: Outcome,
+    pub completion_tokens: u64,
+    /// Until the first content chunk, if there was one.
+    pub ttfb: Option<Duration>,
+    pub duration: Duration,
+    /// The content sent, if `collect_text` was called.
+    pub text: Option<String>,
+}
+
+type FinishHook = Box<dyn FnOnce(&StreamSummary) + Send>;
+
+/// Tracks one chat stream; histograms are recorded when it is dropped, which
+/// also covers clients that disconnect mid-stream.
+pub struct StreamObserver {
+    metrics: Arc<Metrics>,
+    model: String,
+    variant: Variant,
+    started: Instant,
+    ttfb: Option<f64>,
+    outcome: Option<Outcome>,
+    completion_tokens: u64,
+    first_content_at: Option<Instant>,
+    last_content_at: Option<Instant>,
+    text: Option<String>,
+    finish_hooks: Vec<FinishHook>,
+}
+
+impl StreamObserver {
+    /// Inspects a chunk as it is sent to the client.
+    pub fn on_chunk(&mut self, chunk: &anyhow::Result<ChatCompletionChunk>) {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                self.outcome = Some(Outcome::StreamError);
+                return;
+            }
+        };
+        for choice in &chunk.choices {
+            let content = choice.delta.content.as_deref().unwrap_or_default();
+            if content.starts_with(STREAM_ERROR_PREFIX) {
+                self.outcome = Some(Outcome::StreamError);
+                continue;
+            }
+            if !content.is_empty() {
+                self.on_content(content, Instant::now());
+            }
+            if choice.finish_reason.is_some() && self.outcome.is_none() {
+                self.outcome = Some(Outcome::Ok);
+            }
+        }
+    }
+
+    fn on_content(&mut self, content: &str, now: Instant) {
+        if self.ttfb.is_none() {
+            self.ttfb = Some(now.duration_since(self.started).as_secs_f64());
+            self.first_content_at = Some(now);
+        }
+        self.last_content_at = Some(now);
+        if let Some(text) = &mut self.text {
+            text.push_str(content);
+        }
+        let tokens = tokenizer::count_tokens(content) as u64;
+        self.completion_tokens += tokens;
+        self.metrics.completion_tokens_total.with_label_values(&[&self.model]).inc_by(tokens);
+    }
+
+    /// Generation speed; `None` until content arrived over a measurable span.
+    fn tokens_per_second(&self) -> Option<f64> {
+        let generation = self.last_content_at?.duration_since(self.first_content_at?).as_secs_f64();
+        (generation > 0.0 && self.completion_tokens > 0).then(|| self.completion_tokens as f64 / generation)
+    }
+
+    /// Registers a callback run once the stream has ended, e.g. for usage
+    /// accounting.
+    pub fn on_finish(&mut self, hook: impl FnOnce(&StreamSummary) + Send + 'static) {
+        self.finish_hooks.push(Box::new(hook));
+    }
+
+    /// Keeps the content sent, for the `StreamSummary` of finish hooks.
+    pub fn collect_text(&mut self) {
+        self.text.get_or_insert_with(String::new);
+    }
+
+    /// Labels the variant series; stable unless set.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    /// Marks the request as failed before streaming started.
+    pub fn fail(&mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for StreamObserver {
+    fn drop(&mut self) {
+        let summary = StreamSummary {
+            outcome: self.outcome.unwrap_or(Outcome::Cancelled),
+            completion_tokens: self.completion_tokens,
+            ttfb: self.ttfb.map(Duration::from_secs_f64),
+            duration: self.started.elapsed(),
+            text: self.text.take(),
+        };
+        let labels = [self.model.as_str(), summary.outcome.as_str()];
+        let variant = self.variant.as_str();
+        if let Some(ttfb) = self.ttfb {
+            self.metrics.ttfb_seconds.with_label_values(&labels).observe(ttfb);
+            self.metrics.variant_ttfb_seconds.with_label_values(&[variant]).observe(ttfb);
+        }
+        self.metrics.variant_streams_total.with_label_values(&[variant, summary.outcome.as_str()]).inc();
+        self.metrics
+            .stream_duration_seconds
+            .with_label_values(&labels)
+            .observe(summary.duration.as_secs_f64());
+        if let Some(rate) = self.tokens_per_second() {
+            self.metrics.stream_tokens_per_second.with_label_values(&[&self.model]).observe(rate);
+        }
+        for hook in self.finish_hooks.drain(..) {
+            hook(&summary);
+        }
+    }
+}
+
+/// `GET /metrics`
+#[utoipa::path(get, path = "/metrics", tag = "health", security((), ("admin_basic" = []), ("admin_token" = [])), responses(
+    (status = 200, description = "Prometheus text exposition format", content_type = "text/plain", body = String),
+    (status = 401, description = "ADMIN_BASIC_AUTH is set and the admin credentials are missing", body = ErrorBody),
+))]
+pub async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> Response {
+    match metrics.render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            warn!("Failed to encode metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canary::Variant;
+    use crate::sse_processor::test_chunk;
+
+    #[test]
+    fn test_completed_stream_records_ttfb_and_duration() {
+        let metrics = Arc::new(Metrics::new());
+        {
+            let mut observer = metrics.observe_stream(Some("gpt-4o"));
+            observer.on_chunk(&Ok(test_chunk(Some("hi"), None)));
+            observer.on_chunk(&Ok(test_chunk(None, Some("stop"))));
+        }
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"rust_proxy_chat_ttfb_seconds_count{model="gpt-4o",outcome="ok"} 1"#));
+        assert!(text.contains(r#"rust_proxy_chat_stream_duration_seconds_count{model="gpt-4o",outcome="ok"} 1"#));
+    }
+
+    #[test]
+    fn test_dev_error_event_is_stream_error() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_stream(Some("m")).on_chunk(&Ok(test_chunk(Some("[STREAM_ERROR]: boom"), Some("stop"))));
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"rust_proxy_chat_stream_duration_seconds_count{model="m",outcome="stream_error"} 1"#));
+        assert!(!text.contains("chat_ttfb_seconds_count"));
+    }
+
+    #[test]
+    fn test_tokens_are_counted_and_rated() {
+        let metrics = Arc::new(Metrics::new());
+        let mut observer = metrics.observe_stream(Some("m"));
+        let start = observer.started;
+        observer.on_content("hello world", start);
+        observer.on_content("hello world", start + std::time::Duration::from_secs(2));
+        assert_eq!(observer.completion_tokens, 4);
+        assert_eq!(observer.tokens_per_second(), Some(2.0));
+        drop(observer);
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"rust_proxy_chat_completion_tokens_total{model="m"} 4"#));
+        assert!(text.contains(r#"rust_proxy_chat_stream_tokens_per_second_count{model="m"} 1"#));
+    }
+
+    #[test]
+    fn test_finish_hooks_receive_summary() {
+        let metrics = Arc::new(Metrics::new());
+        let seen = Arc::new(std::sync::Mutex::new(None));
+        let mut observer = metrics.observe_stream(Some("m"));
+        let sink = seen.clone();
+        observer.on_finish(move |summary| *sink.lock().unwrap() = Some((summary.outcome, summary.completion_tokens)));
+        observer.on_chunk(&Ok(test_chunk(Some("hello world"), Some("stop"))));
+        drop(observer);
+        assert_eq!(*seen.lock().unwrap(), Some((Outcome::Ok, 2)));
+    }
+
+    #[test]
+    fn test_dropped_stream_is_cancelled() {
+        let metrics = Arc::new(Metrics::new());
+        drop(metrics.observe_stream(None));
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"rust_proxy_chat_stream_duration_seconds_count{model="unknown",outcome="cancelled"} 1"#));
+        assert!(!text.contains("chat_ttfb_seconds_count"));
+    }
+
+    #[test]
+    fn test_summary_groups_by_model() {
+        let metrics = Arc::new(Metrics::new());
+        {
+            let mut observer = metrics.observe_stream(Some("m"));
+            observer.on_chunk(&Ok(test_chunk(Some("hello world"), Some("stop"))));
+        }
+        metrics.observe_stream(Some("m")).fail(Outcome::UpstreamError);
+        let summary = metrics.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].streams.get("ok"), Some(&1));
+        assert_eq!(summary[0].streams.get("upstream_error"), Some(&1));
+        assert_eq!((summary[0].ttfb.count, summary[0].duration.count), (1, 2));
+        assert_eq!(summary[0].completion_tokens, 2);
+    }
+
+    #[test]
+    fn test_outcomes_are_split_by_variant() {
+        let metrics = Arc::new(Metrics::new());
+        let mut canary = metrics.observe_stream(Some("m"));
+        canary.set_variant(Variant::Canary);
+        canary.on_chunk(&Ok(test_chunk(Some("hi"), Some("stop"))));
+        drop(canary);
+        metrics.observe_stream(Some("m")).fail(Outcome::UpstreamError);
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"rust_proxy_chat_variant_streams_total{outcome="ok",variant="canary"} 1"#));
+        assert!(text.contains(r#"rust_proxy_chat_variant_streams_total{outcome="upstream_error",variant="stable"} 1"#));
+        assert!(text.contains(r#"rust_proxy_chat_variant_ttfb_seconds_count{variant="canary"} 1"#));
+    }
+
+    #[test]
+    fn test_upstream_failure_outcome() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_stream(Some("m")).fail(Outcome::UpstreamError);
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"outcome="upstream_error""#));
+    }
+}
@@ -0,0 +1,307 @@
+// The models clients are offered. `GET /v1/models` lists each of MODELS
+// (`gpt-4o`) and its names with the mode suffixes of MODEL_MODES
+// (`gpt-4o-search`, ...). MODELS_DISABLED switches models off while Dev
+// cannot serve them: they disappear from the list and requests for them get
+// a 404 `model_disabled`. It is a comma-separated list, or a JSON object
+// whose values explain why; an entry starting with `-` disables that mode
+// suffix for every model, e.g. `-agent` when Dev's agent mode is broken,
+// and disabling a model also disables its names with a mode suffix.
+//
+// With DEFAULT_MODEL set, chat requests without a model or with one not in
+// the catalog are answered by that model instead. MODEL_FALLBACKS is a JSON
+// object of model -> models to try in order when Dev cannot be reached for
+// it or rejects it (not for rate limits, quotas or blocked prompts), e.g.
+// `{"gpt-4o-agent": ["gpt-4o-search", "gpt-4o"]}`; the response names the
+// model that answered in `X-Model-Fallback`.
+
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use http::{HeaderName, StatusCode};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+use crate::app::AppState;
+use crate::auth::ModelAccess;
+use crate::config::parse_list;
+use crate::dev_client::UpstreamStatusError;
+use crate::dev_errors::{DevError, DevErrorKind};
+use crate::error::ApiError;
+use crate::failover::CircuitOpen;
+use crate::model_modes::ModelModes;
+
+/// Response header naming the fallback model that answered.
+pub const X_MODEL_FALLBACK: HeaderName = HeaderName::from_static("x-model-fallback");
+
+/// Metrics label of the models not in the catalog.
+pub const OTHER_MODEL: &str = "other";
+
+#[derive(Debug, Clone, Default)]
+pub struct ModelCatalog {
+    models: Vec<String>,
+    /// Of MODEL_MODES.
+    suffixes: Vec<String>,
+    /// Model name or `-suffix` -> why it is disabled.
+    disabled: BTreeMap<String, Option<String>>,
+    default_model: Option<String>,
+    fallbacks: HashMap<String, Vec<String>>,
+}
+
+impl ModelCatalog {
+    pub fn new(models: Vec<String>, modes: &ModelModes, disabled: BTreeMap<String, Option<String>>) -> Self {
+        Self {
+            models,
+            suffixes: modes.suffixes().map(str::to_string).collect(),
+            disabled,
+            default_model: None,
+            fallbacks: HashMap::new(),
+        }
+    }
+
+    pub fn with_default_model(mut self, model: impl Into<String>) -> Self {
+        self.default_model = Some(model.into());
+        self
+    }
+
+    pub fn with_fallbacks(mut self, fallbacks: HashMap<String, Vec<String>>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    pub fn from_env(modes: &ModelModes) -> Self {
+        let models = parse_list(&std::env::var("MODELS").unwrap_or_else(|_| "gpt-4o".to_string()));
+        let raw = std::env::var("MODELS_DISABLED").unwrap_or_default();
+        let disabled = if raw.trim_start().starts_with('{') {
+            serde_json::from_str::<BTreeMap<String, String>>(&raw)
+                .inspect_err(|e| warn!("Ignoring MODELS_DISABLED, expected a JSON object of strings: {}", e))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(model, reason)| (model, Some(reason).filter(|r| !r.trim().is_empty())))
+                .collect()
+        } else {
+            parse_list(&raw).into_iter().map(|model| (model, None)).collect()
+        };
+        let mut catalog = Self::new(models, modes, disabled);
+        if let Some(model) = std::env::var("DEFAULT_MODEL").ok().filter(|v| !v.trim().is_empty()) {
+            info!(model, "Requests without a known model use the default model");
+            catalog = catalog.with_default_model(model.trim());
+        }
+        if let Some(raw) = std::env::var("MODEL_FALLBACKS").ok().filter(|v| !v.trim().is_empty()) {
+            match serde_json::from_str::<HashMap<String, Vec<String>>>(&raw) {
+                Ok(fallbacks) => {
+                    info!(models = ?fallbacks.keys().collect::<Vec<_>>(), "Model fallbacks configured");
+                    catalog = catalog.with_fallbacks(fallbacks);
+                }
+                Err(e) => warn!("Ignoring MODEL_FALLBACKS, expected a JSON object of model lists: {}", e),
+            }
+        }
+        if !catalog.disabled.is_empty() {
+            info!(disabled = ?catalog.disabled.keys().collect::<Vec<_>>(), "Some models are disabled");
+        }
+        catalog
+    }
+
+    /// The entry disabling `model`, with its reason.
+    fn disabled_by(&self, model: &str) -> Option<(&str, Option<&str>)> {
+        self.disabled
+            .iter()
+            .find(|(entry, _)| {
+                let mode_off = entry.starts_with('-') && model.len() > entry.len() && model.ends_with(entry.as_str());
+                let base_off = model
+                    .strip_prefix(entry.as_str())
+                    .is_some_and(|suffix| suffix.is_empty() || self.suffixes.iter().any(|s| s == suffix));
+                mode_off || base_off
+            })
+            .map(|(entry, reason)| (entry.as_str(), reason.as_deref()))
+    }
+
+    /// Refuses requests for a disabled model.
+    pub fn check(&self, model: Option<&str>) -> Result<(), ApiError> {
+        let Some(model) = model else { return Ok(()) };
+        let Some((entry, reason)) = self.disabled_by(model) else { return Ok(()) };
+        let mut message = format!("The model '{}' is currently disabled", model);
+        if entry.starts_with('-') {
+            message.push_str(&format!(" (the '{}' mode is off)", entry));
+        }
+        if let Some(reason) = reason {
+            message.push_str(&format!(": {}", reason));
+        }
+        Err(ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", message)
+            .with_code("model_disabled")
+            .with_param("model"))
+    }
+
+    /// Whether `model` is one of the catalog's names, disabled or not.
+    fn knows(&self, model: &str) -> bool {
+        self.models.iter().any(|base| {
+            model.strip_prefix(base.as_str()).is_some_and(|suffix| suffix.is_empty() || self.suffixes.iter().any(|s| s == suffix))
+        })
+    }
+
+    /// The default model, for a request naming no model or an unknown one.
+    pub fn default_for(&self, model: Option<&str>) -> Option<&str> {
+        let default = self.default_model.as_deref()?;
+        match model {
+            Some(model) if model == default || self.knows(model) => None,
+            _ => Some(default),
+        }
+    }
+
+    /// `model` as a metrics label: one of the catalog's names, or `other`,
+    /// so clients cannot create label values (and series) at will.
+    pub fn metric_label<'a>(&'a self, model: &'a str) -> &'a str {
+        if self.knows(model) || self.default_model.as_deref() == Some(model) { model } else { OTHER_MODEL }
+    }
+
+    /// The models to try, in order, when `model` fails; disabled ones are
+    /// skipped.
+    pub fn fallbacks(&self, model: Option<&str>) -> Vec<String> {
+        let Some(chain) = model.and_then(|model| self.fallbacks.get(model)) else { return Vec::new() };
+        chain.iter().filter(|fallback| self.disabled_by(fallback).is_none()).cloned().collect()
+    }
+
+    /// The names clients can use, base models first.
+    pub fn list(&self) -> Vec<String> {
+        let mut names = self.models.clone();
+        for model in &self.models {
+            names.extend(self.suffixes.iter().map(|suffix| format!("{}{}", model, suffix)));
+        }
+        names.retain(|name| self.disabled_by(name).is_none());
+        names
+    }
+}
+
+/// Whether another model might succeed where a request failed: when Dev
+/// was unreachable, failed or rejected the model, but not for limits of
+/// the account or the prompt itself.
+pub fn should_fall_back(error: &anyhow::Error) -> bool {
+    if error.downcast_ref::<CircuitOpen>().is_some() {
+        return false;
+    }
+    match error.downcast_ref::<UpstreamStatusError>() {
+        Some(e) => matches!(DevError::parse(Some(e.status), &e.body).kind, DevErrorKind::ModelUnavailable | DevErrorKind::Other),
+        None => true,
+    }
+}
+
+/// An entry of `/v1/models`.
+#[derive(Debug, Serialize)]
+struct Model {
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+}
+
+impl Model {
+    fn new(id: String) -> Self {
+        Self { id, object: "model", created: 0, owned_by: "devv" }
+    }
+}
+
+#[utoipa::path(get, path = "/v1/models", tag = "models", security(("api_key" = [])), responses(
+    (status = 200, description = "`{\"object\": \"list\", \"data\": [model]}` without disabled models or those the key may not use", body = Object),
+    (status = 401, body = ErrorBody),
+))]
+pub async fn list_models_handler(State(state): State<AppState>, access: Option<Extension<ModelAccess>>) -> Response {
+    let mut names = state.model_catalog.list();
+    if let Some(Extension(access)) = &access {
+        names.retain(|name| access.allows(name));
+    }
+    let data: Vec<Model> = names.into_iter().map(Model::new).collect();
+    Json(serde_json::json!({ "object": "list", "data": data })).into_response()
+}
+
+#[utoipa::path(get, path = "/v1/models/{id}", tag = "models", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Model name")),
+    responses(
+        (status = 200, description = "The model", body = Object),
+        (status = 403, description = "The key may not use the model", body = ErrorBody),
+        (status = 404, description = "Unknown or disabled model", body = ErrorBody),
+    ),
+)]
+pub async fn get_model_handler(
+    State(state): State<AppState>,
+    access: Option<Extension<ModelAccess>>,
+    Path(id): Path<String>,
+) -> Response {
+    if let Some(Extension(access)) = &access
+        && let Err(e) = access.check(Some(&id))
+    {
+        return e.into_response();
+    }
+    if let Err(e) = state.model_catalog.check(Some(&id)) {
+        return e.into_response();
+    }
+    if !state.model_catalog.list().contains(&id) {
+        return ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("The model '{}' does not exist", id))
+            .with_code("model_not_found")
+            .with_param("model")
+            .into_response();
+    }
+    Json(Model::new(id)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> ModelCatalog {
+        let disabled = BTreeMap::from([
+            ("-agent".to_string(), Some("Dev's agent mode is down".to_string())),
+            ("claude-3".to_string(), None),
+        ]);
+        ModelCatalog::new(vec!["gpt-4o".to_string(), "claude-3".to_string()], &ModelModes::builtin(), disabled)
+    }
+
+    #[test]
+    fn test_list_hides_disabled_models() {
+        let names = catalog().list();
+        assert_eq!(names, ["gpt-4o", "gpt-4o-search", "gpt-4o-fast"]);
+    }
+
+    #[test]
+    fn test_metric_label_folds_unknown_models() {
+        let catalog = catalog().with_default_model("gpt-4o-mini");
+        assert_eq!(catalog.metric_label("gpt-4o-search"), "gpt-4o-search");
+        assert_eq!(catalog.metric_label("claude-3"), "claude-3");
+        assert_eq!(catalog.metric_label("gpt-4o-mini"), "gpt-4o-mini");
+        assert_eq!(catalog.metric_label("gpt-4o-\u{1f600}"), OTHER_MODEL);
+        assert_eq!(catalog.metric_label("random-1234"), OTHER_MODEL);
+    }
+
+    #[test]
+    fn test_check() {
+        let catalog = catalog();
+        assert!(catalog.check(None).is_ok());
+        assert!(catalog.check(Some("gpt-4o-search")).is_ok());
+        assert!(catalog.check(Some("claude-3.5")).is_ok());
+        let error = catalog.check(Some("gpt-4o-agent")).unwrap_err();
+        assert_eq!((error.status, error.code), (StatusCode::NOT_FOUND, Some("model_disabled")));
+        assert!(error.message.ends_with("(the '-agent' mode is off): Dev's agent mode is down"), "{}", error.message);
+        assert_eq!(catalog.check(Some("claude-3")).unwrap_err().message, "The model 'claude-3' is currently disabled");
+        assert!(catalog.check(Some("claude-3-search")).is_err());
+    }
+
+    #[test]
+    fn test_default_model_and_fallbacks() {
+        assert_eq!(catalog().default_for(None), None);
+        let fallbacks = HashMap::from([("gpt-4o-agent".to_string(), vec!["claude-3".to_string(), "gpt-4o".to_string()])]);
+        let catalog = catalog().with_default_model("gpt-4o").with_fallbacks(fallbacks);
+        assert_eq!(catalog.default_for(None), Some("gpt-4o"));
+        assert_eq!(catalog.default_for(Some("gpt-5")), Some("gpt-4o"));
+        assert_eq!(catalog.default_for(Some("gpt-4o-search")), None);
+        assert_eq!(catalog.default_for(Some("claude-3")), None);
+        // claude-3 is disabled
+        assert_eq!(catalog.fallbacks(Some("gpt-4o-agent")), ["gpt-4o"]);
+        assert!(catalog.fallbacks(Some("gpt-4o")).is_empty());
+
+        let status = |status, body: &str| anyhow::Error::from(UpstreamStatusError { status, body: body.to_string(), retry_after: None });
+        assert!(should_fall_back(&status(StatusCode::BAD_GATEWAY, "")));
+        assert!(should_fall_back(&status(StatusCode::BAD_REQUEST, "model not supported")));
+        assert!(!should_fall_back(&status(StatusCode::TOO_MANY_REQUESTS, "")));
+        assert!(!should_fall_back(&status(StatusCode::PAYMENT_REQUIRED, "quota exceeded")));
+        assert!(should_fall_back(&anyhow::anyhow!("connection reset")));
+    }
+}
@@ -0,0 +1,103 @@
+// Dev backend modes selected by model name. A model ending in one of the
+// table's suffixes (`devv-search`, `gpt-4o-agent`) gets that suffix's Dev
+// options and is sent to Dev without the suffix. MODEL_MODES replaces the
+// built-in table with a JSON object of suffix -> `DevvOptions`; its `*` entry
+// applies to every model. Options given in the request (`x_devv`) still win.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::models::DevvOptions;
+
+const DEFAULT_KEY: &str = "*";
+
+const BUILTIN: &str = r#"{
+    "-search": {"search_mode": "web"},
+    "-agent": {"is_expert": true},
+    "-fast": {"search_mode": "chat", "is_expert": false}
+}"#;
+
+#[derive(Debug, Clone)]
+pub struct ModelModes {
+    defaults: DevvOptions,
+    /// Longest suffix first, so `-web-search` beats `-search`.
+    suffixes: Vec<(String, DevvOptions)>,
+}
+
+impl ModelModes {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut table: HashMap<String, DevvOptions> = serde_json::from_str(json).context("Invalid model mode table")?;
+        let defaults = table.remove(DEFAULT_KEY).unwrap_or_default();
+        let mut suffixes: Vec<_> = table.into_iter().filter(|(suffix, _)| !suffix.is_empty()).collect();
+        suffixes.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Ok(Self { defaults, suffixes })
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN).expect("valid builtin model modes")
+    }
+
+    /// MODEL_MODES, or the built-in table when unset or invalid.
+    pub fn from_env() -> Self {
+        let modes = match std::env::var("MODEL_MODES").ok().filter(|v| !v.trim().is_empty()) {
+            Some(json) => Self::from_json(&json)
+                .inspect_err(|e| warn!("Ignoring MODEL_MODES, using the built-in table: {:#}", e))
+                .unwrap_or_else(|_| Self::builtin()),
+            None => Self::builtin(),
+        };
+        info!(suffixes = ?modes.suffixes.iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>(), "Model modes configured");
+        modes
+    }
+
+    /// The suffixes selecting a mode, longest first.
+    pub fn suffixes(&self) -> impl Iterator<Item = &str> {
+        self.suffixes.iter().map(|(suffix, _)| suffix.as_str())
+    }
+
+    /// The model name to send to Dev and the options `model` selects.
+    pub fn resolve(&self, model: Option<&str>) -> (Option<String>, DevvOptions) {
+        let Some(model) = model else { return (None, self.defaults.clone()) };
+        for (suffix, options) in &self.suffixes {
+            if let Some(base) = model.strip_suffix(suffix.as_str())
+                && !base.is_empty()
+            {
+                return (Some(base.to_string()), self.defaults.clone().merge(options.clone()));
+            }
+        }
+        (Some(model.to_string()), self.defaults.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_suffixes() {
+        let modes = ModelModes::builtin();
+        let (model, options) = modes.resolve(Some("devv-search"));
+        assert_eq!(model.as_deref(), Some("devv"));
+        assert_eq!(options.search_mode.as_deref(), Some("web"));
+
+        let (model, options) = modes.resolve(Some("gpt-4o-agent"));
+        assert_eq!((model.as_deref(), options.is_expert), (Some("gpt-4o"), Some(true)));
+
+        let (model, options) = modes.resolve(Some("gpt-4o"));
+        assert_eq!((model.as_deref(), options.search_mode), (Some("gpt-4o"), None));
+        assert_eq!(modes.resolve(Some("-fast")).0.as_deref(), Some("-fast"));
+    }
+
+    #[test]
+    fn test_longest_suffix_wins() {
+        let modes = ModelModes::from_json(
+            r#"{"*": {"language": "en"}, "-search": {"search_mode": "web"}, "-code-search": {"search_mode": "github"}}"#,
+        )
+        .unwrap();
+        let (model, options) = modes.resolve(Some("devv-code-search"));
+        assert_eq!((model.as_deref(), options.search_mode.as_deref()), (Some("devv"), Some("github")));
+        assert_eq!(options.language.as_deref(), Some("en"));
+        assert_eq!(modes.resolve(None).1.language.as_deref(), Some("en"));
+        assert!(ModelModes::from_json("[]").is_err());
+    }
+}
@@ -1,22 +1,252 @@
+use http::HeaderMap;
 use serde::Deserialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+use crate::dev_client::DevRequestOptions;
+use crate::error::ApiError;
+
+/// Request header carrying `DevvOptions` as JSON, for clients that cannot
+/// add body fields.
+pub const X_DEVV_OPTIONS: &str = "x-devv-options";
 
 // Structure to deserialize the incoming request body for /v1/chat/completions
-#[derive(Debug, Deserialize)]
+/// Chat completion request. Only `messages`, `model` and `n` are used;
+/// `logprobs` and the OpenAI fields Dev cannot honor are handled per policy,
+/// and the response is always streamed.
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct OpenAiChatRequest {
     // We primarily need messages and model
     pub messages: Vec<OpenAiMessage>,
+    /// Passed through to Dev as the model name; a mode suffix such as
+    /// `-search` (see MODEL_MODES) selects Dev options and is removed.
+    #[schema(example = "gpt-4o")]
     pub model: Option<String>, // Model name might be used for  options
+    /// Number of choices; each is a separate Dev request, streamed with its
+    /// own `choices[].index`.
+    #[schema(example = 1, minimum = 1)]
+    pub n: Option<u32>,
+    /// Dev returns no token probabilities: rejected with 400 or answered
+    /// with `"logprobs": null`, depending on LOGPROBS_MODE.
+    pub logprobs: Option<bool>,
+    /// Treated like `logprobs`.
+    pub top_logprobs: Option<u32>,
+    /// Dev backend options; they override the `X-Devv-Options` header.
+    pub x_devv: Option<DevvOptions>,
     // #[serde(default)] // Default to false if not present
     // pub stream: bool,
-    // We don't necessarily need to deserialize other fields like temperature, top_p etc.
-    // unless we plan to map them to  options, which seems unlikely based on JS analysis.
-    // #[serde(flatten)]
-    // pub extra: std::collections::HashMap<String, serde_json::Value>,
+    /// Every other field. Those Dev cannot honor (temperature, seed, tools,
+    /// ...) are rejected or ignored with a `Warning` header, depending on
+    /// UNSUPPORTED_PARAMS.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Dev-specific request options, beyond what OpenAI fields express.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+pub struct DevvOptions {
+    /// Search scope, e.g. "web" or "chat".
+    pub search_mode: Option<String>,
+    pub is_expert: Option<bool>,
+    /// Answer language, e.g. "en", "zh" or "All".
+    pub language: Option<String>,
+    /// Continue this Dev thread.
+    pub thread_id: Option<String>,
+    pub plugin_action: Option<String>,
+    pub programming_language: Option<String>,
+    /// End the answer with a markdown list of its sources; the default is
+    /// SOURCES_FOOTER. Not sent to Dev.
+    pub sources_footer: Option<bool>,
+    /// End the answer with Dev's related questions; the default is
+    /// RELATED_QUESTIONS_FOOTER. Not sent to Dev.
+    pub related_questions_footer: Option<bool>,
+    /// Other keys (repository filters, ...) go to the Dev request's `extra`
+    /// object verbatim.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl DevvOptions {
+    /// The `X-Devv-Options` header, if present.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, ApiError> {
+        let Some(value) = headers.get(X_DEVV_OPTIONS) else { return Ok(None) };
+        let invalid = |reason: String| {
+            ApiError::new(http::StatusCode::BAD_REQUEST, "invalid_request_error", format!("Invalid X-Devv-Options header: {}", reason))
+                .with_code("invalid_devv_options")
+        };
+        let json = value.to_str().map_err(|e| invalid(e.to_string()))?;
+        serde_json::from_str(json).map(Some).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// `self` with every field `other` sets taking precedence.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.extra.extend(other.extra);
+        Self {
+            search_mode: other.search_mode.or(self.search_mode),
+            is_expert: other.is_expert.or(self.is_expert),
+            language: other.language.or(self.language),
+            thread_id: other.thread_id.or(self.thread_id),
+            plugin_action: other.plugin_action.or(self.plugin_action),
+            programming_language: other.programming_language.or(self.programming_language),
+            sources_footer: other.sources_footer.or(self.sources_footer),
+            related_questions_footer: other.related_questions_footer.or(self.related_questions_footer),
+            extra: self.extra,
+        }
+    }
+
+    /// Sets the fields given here on `options`.
+    pub fn apply(self, options: &mut DevRequestOptions) {
+        let Self {
+            search_mode,
+            is_expert,
+            language,
+            thread_id,
+            plugin_action,
+            programming_language,
+            sources_footer,
+            related_questions_footer,
+            extra,
+        } = self;
+        options.search_mode = search_mode.or(options.search_mode.take());
+        options.is_expert = is_expert.or(options.is_expert);
+        options.language = language.or(options.language.take());
+        options.thread_id = thread_id.or(options.thread_id.take());
+        options.plugin_action = plugin_action.or(options.plugin_action.take());
+        options.programming_language = programming_language.or(options.programming_language.take());
+        options.footers.sources = sources_footer.unwrap_or(options.footers.sources);
+        options.footers.related_questions = related_questions_footer.unwrap_or(options.footers.related_questions);
+        options.extra.extend(extra);
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// A chat message; the content of the last message is sent as the prompt.
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct OpenAiMessage {
-    // pub role: String, // e.g., "user", "system", "assistant"
-    pub content: String,
+    /// "system", "user", "assistant", ...; used by prompt templates.
+    #[schema(example = "user")]
+    pub role: Option<String>,
+    pub content: MessageContent,
     // name, tool_calls, tool_call_id can be ignored for now
+}
+
+impl OpenAiMessage {
+    /// The message as prompt text.
+    pub fn text(&self) -> String {
+        self.content.text()
+    }
+}
+
+/// A plain string, or the content parts newer clients send.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// The `file_id` of each file part; `None` for files given inline.
+    pub fn file_ids(&self) -> Vec<Option<&str>> {
+        match self {
+            Self::Text(_) => Vec::new(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::File { file } => Some(file.file_id.as_deref()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of `image_url` parts.
+    pub fn image_count(&self) -> usize {
+        match self {
+            Self::Text(_) => 0,
+            Self::Parts(parts) => parts.iter().filter(|part| matches!(part, ContentPart::ImageUrl { .. })).count(),
+        }
+    }
+
+    /// The text parts joined by newlines; other parts are left out.
+    pub fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    /// A file uploaded through `/v1/files`, sent along as an attachment.
+    File { file: FileRef },
+    /// Part types this proxy does not know (audio, files, ...).
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FileRef {
+    pub file_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImageUrl {
+    /// An http(s) URL or a `data:` URL.
+    pub url: String,
+    pub detail: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_parts_flatten_to_text() {
+        let req: OpenAiChatRequest = serde_json::from_value(serde_json::json!({"messages": [
+            {"role": "system", "content": "be brief"},
+            {"role": "user", "content": [
+                {"type": "text", "text": "what is"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                {"type": "input_audio", "input_audio": {"data": "", "format": "wav"}},
+                {"type": "text", "text": "in this picture?"},
+            ]},
+        ]}))
+        .unwrap();
+        assert_eq!(req.messages[0].text(), "be brief");
+        assert_eq!(req.messages[1].text(), "what is\nin this picture?");
+        assert!(matches!(&req.messages[1].content, MessageContent::Parts(parts) if parts.len() == 4));
+        assert_eq!(req.messages[1].content.image_count(), 1);
+    }
+
+    #[test]
+    fn test_devv_options_merge_and_apply() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_DEVV_OPTIONS, r#"{"search_mode": "web", "language": "en", "repos": ["a/b"]}"#.parse().unwrap());
+        let from_header = DevvOptions::from_headers(&headers).unwrap().unwrap();
+        let req: OpenAiChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{"content": "hi"}],
+            "x_devv": {"language": "zh", "is_expert": true},
+        }))
+        .unwrap();
+        let mut options = DevRequestOptions { language: Some("All".to_string()), ..Default::default() };
+        from_header.merge(req.x_devv.unwrap()).apply(&mut options);
+        assert_eq!(options.search_mode.as_deref(), Some("web"));
+        assert_eq!(options.language.as_deref(), Some("zh"));
+        assert_eq!(options.is_expert, Some(true));
+        assert_eq!(options.extra["repos"], serde_json::json!(["a/b"]));
+
+        headers.insert(X_DEVV_OPTIONS, "not json".parse().unwrap());
+        assert_eq!(DevvOptions::from_headers(&headers).unwrap_err().code, Some("invalid_devv_options"));
+    }
 } 
\ No newline at end of file
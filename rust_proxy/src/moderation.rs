@@ -0,0 +1,307 @@
+// Content moderation. Moderators check text and name what they found; a
+// prompt they flag is refused with 400 `content_filter` before it reaches
+// Dev (MODERATION_INPUT, on by default), and a stream whose output they flag
+// ends with `finish_reason: "content_filter"` (MODERATION_OUTPUT, off by
+// default). Output is checked as a whole, every MODERATION_OUTPUT_CHARS
+// (0 = every chunk) of new text; the chunk that trips a rule is not sent.
+//
+// The keyword moderator matches MODERATION_KEYWORDS (comma separated, whole
+// words, any case) and MODERATION_REGEX. With MODERATION_API_URL set, text is
+// also sent to an OpenAI-compatible `/v1/moderations` endpoint, using
+// MODERATION_API_KEY; if it cannot be reached the text passes, unless
+// MODERATION_FAIL_CLOSED is set.
+
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{env_or, parse_list};
+use crate::error::ApiError;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::{ChunkStream, StreamTransformer};
+
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Finish reason of a stream cut off by moderation.
+pub const CONTENT_FILTER: &str = "content_filter";
+
+/// Flagged texts by `stage` (input / output) and `moderator`, registered by
+/// `Metrics`.
+pub static FLAGGED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("moderation_flagged_total", "Texts flagged by moderation"), &["stage", "moderator"])
+        .expect("valid counter")
+});
+
+/// A check of text against a content policy.
+pub trait Moderator: Send + Sync {
+    /// Name for logs and metrics.
+    fn name(&self) -> &'static str;
+
+    /// What the text violates, or `None` when it passes.
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+}
+
+/// Keywords and regular expressions.
+pub struct KeywordModerator {
+    rules: Vec<Regex>,
+}
+
+impl KeywordModerator {
+    pub fn new(rules: Vec<Regex>) -> Self {
+        Self { rules }
+    }
+
+    /// Whole-word, case-insensitive rules for `keywords`.
+    pub fn keywords(keywords: &[String]) -> Self {
+        Self::new(
+            keywords
+                .iter()
+                .filter_map(|word| {
+                    RegexBuilder::new(&format!(r"\b{}\b", regex::escape(word))).case_insensitive(true).build().ok()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn with_rule(mut self, rule: Regex) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Moderator for KeywordModerator {
+    fn name(&self) -> &'static str {
+        "keywords"
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        let found = self.rules.iter().find(|rule| rule.is_match(text)).map(|rule| format!("matches /{}/", rule.as_str()));
+        async move { Ok(found) }.boxed()
+    }
+}
+
+/// An OpenAI-compatible moderation endpoint.
+pub struct ApiModerator {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl ApiModerator {
+    pub fn new(url: String, api_key: Option<String>) -> Self {
+        Self { client: reqwest::Client::new(), url, api_key }
+    }
+}
+
+impl Moderator for ApiModerator {
+    fn name(&self) -> &'static str {
+        "api"
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        async move {
+            let mut request = self.client.post(&self.url).json(&serde_json::json!({ "input": text })).timeout(API_TIMEOUT);
+            if let Some(key) = &self.api_key {
+                request = request.bearer_auth(key);
+            }
+            let body: Value = request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .context("Moderation request failed")?
+                .json()
+                .await
+                .context("Invalid moderation response")?;
+            Ok(flagged_categories(&body))
+        }
+        .boxed()
+    }
+}
+
+/// The flagged categories of a `/v1/moderations` response, if it is flagged.
+fn flagged_categories(body: &Value) -> Option<String> {
+    let result = body.pointer("/results/0")?;
+    if !result.get("flagged").and_then(Value::as_bool).unwrap_or(false) {
+        return None;
+    }
+    let categories: Vec<&str> = result
+        .get("categories")
+        .and_then(Value::as_object)
+        .map(|c| c.iter().filter(|(_, v)| v.as_bool() == Some(true)).map(|(k, _)| k.as_str()).collect())
+        .unwrap_or_default();
+    Some(if categories.is_empty() { "flagged".to_string() } else { categories.join(", ") })
+}
+
+/// The configured moderators and where they apply.
+#[derive(Clone, Default)]
+pub struct Moderation {
+    moderators: Vec<Arc<dyn Moderator>>,
+    pub input: bool,
+    pub output: bool,
+    pub output_interval: usize,
+    pub fail_closed: bool,
+}
+
+impl Moderation {
+    pub fn new(moderators: Vec<Arc<dyn Moderator>>) -> Self {
+        Self { moderators, input: true, output: false, output_interval: 0, fail_closed: false }
+    }
+
+    pub fn from_env() -> Self {
+        let mut keywords = KeywordModerator::keywords(&parse_list(&std::env::var("MODERATION_KEYWORDS").unwrap_or_default()));
+        if let Some(pattern) = std::env::var("MODERATION_REGEX").ok().filter(|v| !v.is_empty()) {
+            match Regex::new(&pattern) {
+                Ok(rule) => keywords = keywords.with_rule(rule),
+                Err(e) => warn!(error = %e, "Invalid MODERATION_REGEX, ignoring"),
+            }
+        }
+        let mut moderators: Vec<Arc<dyn Moderator>> = Vec::new();
+        if !keywords.is_empty() {
+            moderators.push(Arc::new(keywords));
+        }
+        if let Some(url) = std::env::var("MODERATION_API_URL").ok().filter(|v| !v.is_empty()) {
+            let key = std::env::var("MODERATION_API_KEY").ok().filter(|v| !v.is_empty());
+            moderators.push(Arc::new(ApiModerator::new(url, key)));
+        }
+        let moderation = Self {
+            moderators,
+            input: env_or("MODERATION_INPUT", true),
+            output: env_or("MODERATION_OUTPUT", false),
+            output_interval: env_or("MODERATION_OUTPUT_CHARS", 0),
+            fail_closed: env_or("MODERATION_FAIL_CLOSED", false),
+        };
+        if !moderation.moderators.is_empty() {
+            info!(
+                moderators = ?moderation.moderators.iter().map(|m| m.name()).collect::<Vec<_>>(),
+                input = moderation.input,
+                output = moderation.output,
+                "Content moderation enabled"
+            );
+        }
+        moderation
+    }
+
+    /// Runs every moderator on `text`; the first finding wins.
+    pub async fn check(&self, stage: &str, text: &str) -> Option<String> {
+        for moderator in &self.moderators {
+            match moderator.check(text).await {
+                Ok(Some(reason)) => {
+                    warn!(stage, moderator = moderator.name(), reason, "Content flagged by moderation");
+                    FLAGGED.with_label_values(&[stage, moderator.name()]).inc();
+                    return Some(reason);
+                }
+                Ok(None) => {}
+                Err(e) if self.fail_closed => {
+                    warn!(stage, moderator = moderator.name(), "Moderation failed, blocking: {:#}", e);
+                    return Some("moderation unavailable".to_string());
+                }
+                Err(e) => warn!(stage, moderator = moderator.name(), "Moderation failed, letting the text pass: {:#}", e),
+            }
+        }
+        None
+    }
+
+    /// 400 `content_filter` if the prompt is flagged.
+    pub async fn check_prompt(&self, prompt: &str) -> Result<(), ApiError> {
+        if !self.input || self.moderators.is_empty() {
+            return Ok(());
+        }
+        match self.check("input", prompt).await {
+            Some(_) => Err(ApiError::invalid_param("messages", "The prompt was blocked by the content filter")
+                .with_code(CONTENT_FILTER)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether streams are moderated at all.
+    pub fn moderates_output(&self) -> bool {
+        self.output && !self.moderators.is_empty()
+    }
+
+    /// `chunks` of one choice, ended with a `content_filter` chunk once the
+    /// text so far is flagged.
+    pub fn filter_output<S>(self, chunks: S) -> impl Stream<Item = Result<ChatCompletionChunk>> + Send + 'static
+    where
+        S: Stream<Item = Result<ChatCompletionChunk>> + Send + Unpin + 'static,
+    {
+        let state = (chunks, self, String::new(), 0usize, false);
+        futures_util::stream::unfold(state, |(mut chunks, moderation, mut text, mut checked, stopped)| async move {
+            if stopped {
+                return None;
+            }
+            let mut item = chunks.next().await?;
+            if let Ok(chunk) = &mut item {
+                let delta: String = chunk.choices.iter().filter_map(|c| c.delta.content.as_deref()).collect();
+                text.push_str(&delta);
+                if !delta.is_empty() && text.len() - checked >= moderation.output_interval {
+                    checked = text.len();
+                    if moderation.check("output", &text).await.is_some() {
+                        for choice in &mut chunk.choices {
+                            choice.delta.content = None;
+                            choice.finish_reason = Some(CONTENT_FILTER.to_string());
+                        }
+                        return Some((item, (chunks, moderation, text, checked, true)));
+                    }
+                }
+            }
+            Some((item, (chunks, moderation, text, checked, false)))
+        })
+    }
+}
+
+/// The `moderate` stage, see `transform`.
+impl StreamTransformer for Moderation {
+    fn wrap(&self, chunks: ChunkStream) -> ChunkStream {
+        self.clone().filter_output(chunks).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    fn keywords(words: &[&str]) -> Moderation {
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        Moderation::new(vec![Arc::new(KeywordModerator::keywords(&words))])
+    }
+
+    #[tokio::test]
+    async fn test_prompt_blocked_by_keyword() {
+        let moderation = keywords(&["forbidden"]);
+        assert!(moderation.check_prompt("this is fine").await.is_ok());
+        assert!(moderation.check_prompt("unforbiddenness is a word").await.is_ok());
+        let error = moderation.check_prompt("a FORBIDDEN topic").await.unwrap_err();
+        assert_eq!(error.code, Some(CONTENT_FILTER));
+    }
+
+    #[tokio::test]
+    async fn test_output_stream_cut_off() {
+        let moderation = Moderation { output: true, ..keywords(&["secret plan"]) };
+        let chunks = futures_util::stream::iter(vec![Ok(test_chunk(Some("the secret "), None)), Ok(test_chunk(Some("plan is "), None)), Ok(test_chunk(Some("hidden"), None))]);
+        let out: Vec<_> = moderation.filter_output(chunks).map(|c| c.unwrap()).collect().await;
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].choices[0].delta.content.as_deref(), Some("the secret "));
+        assert_eq!(out[1].choices[0].delta.content, None);
+        assert_eq!(out[1].choices[0].finish_reason.as_deref(), Some(CONTENT_FILTER));
+    }
+
+    #[test]
+    fn test_flagged_categories() {
+        let body = serde_json::json!({"results": [{"flagged": true, "categories": {"violence": true, "hate": false}}]});
+        assert_eq!(flagged_categories(&body).as_deref(), Some("violence"));
+        assert_eq!(flagged_categories(&serde_json::json!({"results": [{"flagged": false}]})), None);
+    }
+}
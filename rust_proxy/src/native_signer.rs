@@ -0,0 +1,32 @@
+// Native implementation of the signature computed by Dev's `sign_bg.wasm`:
+// the SHA-256 hex digest of nonce, timestamp, device id and request content
+// followed by a salt embedded in the module. Enabled by the `native-signer`
+// feature and selected with SIGNER=native; no WASM runtime is instantiated.
+
+use crate::utils;
+
+/// Salt baked into the current signer module.
+const SIGN_SALT: &str = "a3ca42fca81e3f895c99c87194d87452";
+
+pub fn sign(nonce: &str, timestamp: &str, device_id: &str, query: &str) -> String {
+    let message = [nonce, timestamp, device_id, query, SIGN_SALT].concat();
+    utils::sha256_hex(message.as_bytes())
+}
+
+#[cfg(all(test, feature = "wasm-signer"))]
+mod tests {
+    use super::*;
+    use crate::wasm_signer::WasmSigner;
+
+    #[test]
+    fn test_matches_wasm_signer() {
+        let wasm = WasmSigner::with_pool_size("./sign_bg.wasm", 1).unwrap();
+        for (nonce, timestamp, device_id, query) in [
+            ("nonce", "1700000000", "device", "hello"),
+            ("5f0c7a1e-1b2d-4c8e-9f3a-2d6b8e4f1a7c", "1712345678", "xxxx", "写一个 Rust 函数"),
+            ("", "", "", ""),
+        ] {
+            assert_eq!(sign(nonce, timestamp, device_id, query), wasm.sign(nonce, timestamp, device_id, query).unwrap());
+        }
+    }
+}
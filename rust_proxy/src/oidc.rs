@@ -0,0 +1,389 @@
+// OIDC login for the admin surface, so operators sign in with the company
+// identity provider instead of sharing ADMIN_TOKEN. With OIDC_ISSUER,
+// OIDC_CLIENT_ID, OIDC_CLIENT_SECRET and OIDC_REDIRECT_URL (this proxy's
+// `/admin/oidc/callback`, as registered with the provider) set,
+// `GET /admin/login` runs the authorization-code flow; the callback checks
+// the ID token against the provider's JWKS and its nonce, then starts a
+// session held in an HttpOnly cookie for OIDC_SESSION_SECS (28800). Admin
+// routes accept the session like the admin token. OIDC_ALLOWED_EMAILS
+// (comma separated, `@example.com` for a whole domain) limits who may sign
+// in. Logins in progress and sessions live in the state store, so any
+// replica accepts them; sessions end when they expire or at
+// `GET /admin/logout`.
+
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::auth::AdminAuth;
+use crate::config::{env_or, parse_list};
+use crate::error::ApiError;
+use crate::jwt_auth::JwtAuth;
+use crate::state_store::StateStore;
+
+const SESSION_COOKIE: &str = "rust_proxy_admin";
+/// How long a login may take at the provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const JWKS_REFRESH: Duration = Duration::from_secs(3600);
+
+/// The provider's endpoints, from its discovery document.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug)]
+struct Provider {
+    discovery: Discovery,
+    id_tokens: JwtAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    sub: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+fn random_token() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+#[derive(Debug)]
+pub struct Oidc {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    /// Emails and `@domain`s allowed in; anyone the provider knows when
+    /// empty.
+    allowed: Vec<String>,
+    session_ttl: Duration,
+    client: reqwest::Client,
+    provider: OnceCell<Provider>,
+    /// Nonces of logins in progress by `state`, and the subjects of
+    /// sessions by id.
+    store: StateStore,
+}
+
+impl Oidc {
+    pub fn new(issuer: String, client_id: String, client_secret: String, redirect_url: String) -> Self {
+        Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret,
+            redirect_url,
+            allowed: Vec::new(),
+            session_ttl: Duration::from_secs(28_800),
+            client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build().unwrap_or_default(),
+            provider: OnceCell::new(),
+            store: StateStore::default(),
+        }
+    }
+
+    pub fn with_store(mut self, store: StateStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_allowed(mut self, allowed: Vec<String>) -> Self {
+        self.allowed = allowed.into_iter().map(|a| a.to_lowercase()).collect();
+        self
+    }
+
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = ttl;
+        self
+    }
+
+    pub fn from_env(store: &StateStore) -> Option<Self> {
+        let var = |key| std::env::var(key).ok().map(|v: String| v.trim().to_string()).filter(|v| !v.is_empty());
+        let issuer = var("OIDC_ISSUER")?;
+        let (Some(client_id), Some(client_secret), Some(redirect_url)) =
+            (var("OIDC_CLIENT_ID"), var("OIDC_CLIENT_SECRET"), var("OIDC_REDIRECT_URL"))
+        else {
+            warn!("OIDC_ISSUER is set without OIDC_CLIENT_ID, OIDC_CLIENT_SECRET and OIDC_REDIRECT_URL, OIDC login is disabled");
+            return None;
+        };
+        let oidc = Self::new(issuer, client_id, client_secret, redirect_url)
+            .with_allowed(parse_list(&var("OIDC_ALLOWED_EMAILS").unwrap_or_default()))
+            .with_session_ttl(Duration::from_secs(env_or("OIDC_SESSION_SECS", 28_800)))
+            .with_store(store.clone());
+        info!(issuer = oidc.issuer, allowed = ?oidc.allowed, "OIDC login enabled for the admin API");
+        Some(oidc)
+    }
+
+    /// The provider's endpoints and ID token verifier, discovered once.
+    async fn provider(&self) -> Result<&Provider> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer);
+                let discovery: Discovery = self
+                    .client
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .with_context(|| format!("fetch {}", url))?
+                    .json()
+                    .await
+                    .with_context(|| format!("parse {}", url))?;
+                let id_tokens = JwtAuth::with_jwks(discovery.jwks_uri.clone(), JWKS_REFRESH)
+                    .issuer(discovery.issuer.clone())
+                    .audience(vec![self.client_id.clone()]);
+                Ok(Provider { discovery, id_tokens })
+            })
+            .await
+    }
+
+    /// Where to send the browser to sign in.
+    async fn login_url(&self) -> Result<String> {
+        let provider = self.provider().await?;
+        let (state, nonce) = (random_token(), random_token());
+        let url = reqwest::Url::parse_with_params(&provider.discovery.authorization_endpoint, [
+            ("response_type", "code"),
+            ("client_id", &self.client_id),
+            ("redirect_uri", &self.redirect_url),
+            ("scope", "openid email profile"),
+            ("state", &state),
+            ("nonce", &nonce),
+        ])?;
+        self.store.set(&login_key(&state), nonce, Some(LOGIN_TIMEOUT)).await?;
+        Ok(url.into())
+    }
+
+    /// Finishes a login: exchanges `code` for an ID token and, if it names
+    /// someone allowed in, starts a session; returns its id and who it is.
+    async fn complete(&self, code: &str, state: &str) -> Result<(String, String), ApiError> {
+        let store_error = |e: anyhow::Error| {
+            warn!("OIDC login failed: {:#}", e);
+            ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", "Logins are unavailable, please try again")
+                .with_code("oidc_error")
+        };
+        // Only one callback can take a login
+        let key = login_key(state);
+        let nonce = match self.store.get(&key).await.map_err(store_error)? {
+            Some(nonce) if self.store.remove_if(&key, nonce.clone()).await.map_err(store_error)? => Some(nonce),
+            _ => None,
+        };
+        let Some(nonce) = nonce else {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", "Unknown or expired login, please sign in again")
+                .with_code("invalid_state"));
+        };
+        let provider_error = |e: anyhow::Error| {
+            warn!("OIDC login failed: {:#}", e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "The identity provider could not complete the login")
+                .with_code("oidc_error")
+        };
+        let provider = self.provider().await.map_err(provider_error)?;
+        let id_token = self.exchange(provider, code).await.map_err(provider_error)?;
+        let claims: IdClaims = provider.id_tokens.verify_claims(&id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce.as_str()) {
+            return Err(ApiError::unauthorized("The ID token does not belong to this login").with_code("invalid_nonce"));
+        }
+        let subject = claims.email.clone().filter(|_| claims.email_verified != Some(false)).unwrap_or(claims.sub.clone());
+        if !self.allows(&claims) {
+            warn!(subject, "OIDC login refused for an account that is not allowed");
+            return Err(ApiError::forbidden(format!("{} may not use the admin API", subject)));
+        }
+        let session = random_token();
+        self.store.set(&session_key(&session), subject.clone(), Some(self.session_ttl)).await.map_err(store_error)?;
+        Ok((session, subject))
+    }
+
+    /// The ID token for an authorization code.
+    async fn exchange(&self, provider: &Provider, code: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            id_token: Option<String>,
+        }
+        let response: TokenResponse = self
+            .client
+            .post(&provider.discovery.token_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "authorization_code"), ("code", code), ("redirect_uri", &self.redirect_url)])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("exchange the authorization code")?
+            .json()
+            .await
+            .context("parse the token response")?;
+        response.id_token.ok_or_else(|| anyhow!("the token response has no id_token"))
+    }
+
+    fn allows(&self, claims: &IdClaims) -> bool {
+        if self.allowed.is_empty() {
+            return true;
+        }
+        let Some(email) = claims.email.as_deref().filter(|_| claims.email_verified != Some(false)) else { return false };
+        let email = email.to_lowercase();
+        self.allowed.iter().any(|allowed| if allowed.starts_with('@') { email.ends_with(allowed.as_str()) } else { *allowed == email })
+    }
+
+    /// Who the session cookie in `headers` belongs to, if it is current.
+    pub async fn session(&self, headers: &HeaderMap) -> Option<String> {
+        let id = session_cookie(headers)?;
+        self.store.get(&session_key(&id)).await.unwrap_or_else(|e| {
+            warn!("Cannot look up an admin session: {:#}", e);
+            None
+        })
+    }
+
+    async fn end_session(&self, headers: &HeaderMap) -> Option<String> {
+        let subject = self.session(headers).await?;
+        let id = session_cookie(headers)?;
+        if let Err(e) = self.store.remove(&session_key(&id)).await {
+            warn!("Cannot end an admin session: {:#}", e);
+        }
+        Some(subject)
+    }
+
+    /// The `Set-Cookie` value for `session`; an empty one clears it.
+    fn cookie(&self, session: &str, max_age: Duration) -> HeaderValue {
+        let secure = if self.redirect_url.starts_with("https://") { "; Secure" } else { "" };
+        let value =
+            format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}", SESSION_COOKIE, session, max_age.as_secs(), secure);
+        HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static(""))
+    }
+}
+
+fn login_key(state: &str) -> String {
+    format!("oidc:login:{}", state)
+}
+
+fn session_key(session: &str) -> String {
+    format!("oidc:session:{}", session)
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(cookie::Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|c| c.name() == SESSION_COOKIE)
+        .map(|c| c.value().to_string())
+}
+
+fn disabled() -> Response {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "OIDC login is disabled; set OIDC_ISSUER to enable it.")
+        .with_code("oidc_disabled")
+        .into_response()
+}
+
+/// `GET /admin/login`: redirects to the identity provider.
+pub async fn login_handler(State(admin): State<AdminAuth>) -> Response {
+    let Some(oidc) = admin.oidc() else { return disabled() };
+    match oidc.login_url().await {
+        Ok(url) => Redirect::to(&url).into_response(),
+        Err(e) => {
+            warn!("Cannot start an OIDC login: {:#}", e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "The identity provider is unavailable")
+                .with_code("oidc_error")
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// `GET /admin/oidc/callback`: where the provider sends the browser back.
+pub async fn callback_handler(State(admin): State<AdminAuth>, Query(callback): Query<Callback>) -> Response {
+    let Some(oidc) = admin.oidc() else { return disabled() };
+    let (Some(code), Some(state)) = (callback.code, callback.state) else {
+        let reason = callback.error_description.or(callback.error).unwrap_or_else(|| "no authorization code".to_string());
+        warn!(reason, "OIDC login did not complete");
+        return ApiError::unauthorized(format!("Sign-in failed: {}", reason)).with_code("login_failed").into_response();
+    };
+    match oidc.complete(&code, &state).await {
+        Ok((session, subject)) => {
+            info!(subject, "Administrator signed in");
+            ([(header::SET_COOKIE, oidc.cookie(&session, oidc.session_ttl))], Redirect::to("/admin")).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `GET /admin/logout`: ends the session.
+pub async fn logout_handler(State(admin): State<AdminAuth>, headers: HeaderMap) -> Response {
+    let Some(oidc) = admin.oidc() else { return disabled() };
+    if let Some(subject) = oidc.end_session(&headers).await {
+        info!(subject, "Administrator signed out");
+    }
+    ([(header::SET_COOKIE, oidc.cookie("", Duration::ZERO))], Redirect::to("/admin")).into_response()
+}
+
+/// The login routes.
+pub fn router(admin: AdminAuth) -> Router {
+    Router::new()
+        .route("/admin/login", get(login_handler))
+        .route("/admin/oidc/callback", get(callback_handler))
+        .route("/admin/logout", get(logout_handler))
+        .with_state(admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc() -> Oidc {
+        Oidc::new("https://idp.example/".to_string(), "proxy".to_string(), "secret".to_string(), "https://proxy/cb".to_string())
+            .with_allowed(vec!["ops@example.com".to_string(), "@Admins.example".to_string()])
+    }
+
+    fn claims(email: &str, verified: Option<bool>) -> IdClaims {
+        IdClaims { sub: "1".to_string(), email: Some(email.to_string()), email_verified: verified, nonce: None }
+    }
+
+    #[test]
+    fn test_allowed_emails() {
+        let oidc = oidc();
+        assert_eq!(oidc.issuer, "https://idp.example");
+        assert!(oidc.allows(&claims("ops@example.com", None)));
+        assert!(oidc.allows(&claims("Ann@admins.example", Some(true))));
+        assert!(!oidc.allows(&claims("ann@admins.example", Some(false))));
+        assert!(!oidc.allows(&claims("dev@example.com", Some(true))));
+        let anyone = Oidc::new(String::new(), String::new(), String::new(), String::new());
+        assert!(anyone.allows(&IdClaims { email: None, ..claims("", None) }));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_and_unknown_state() {
+        let oidc = oidc();
+        let ttl = Some(Duration::from_secs(60));
+        oidc.store.set(&session_key("s1"), "ops@example.com".to_string(), ttl).await.unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("theme=dark; rust_proxy_admin=s1"));
+        assert_eq!(oidc.session(&headers).await.as_deref(), Some("ops@example.com"));
+        assert_eq!(oidc.end_session(&headers).await.as_deref(), Some("ops@example.com"));
+        assert!(oidc.session(&headers).await.is_none());
+
+        let error = oidc.complete("code", "never-issued").await.unwrap_err();
+        assert_eq!(error.code, Some("invalid_state"));
+        let cookie = oidc.cookie("s2", Duration::from_secs(60));
+        assert_eq!(cookie, "rust_proxy_admin=s2; Path=/; HttpOnly; SameSite=Lax; Max-Age=60; Secure");
+    }
+}
@@ -0,0 +1,137 @@
+// OpenAPI description of the proxy, generated from the handler and model
+// annotations and served at `/openapi.json`, with a Swagger UI at `/docs`.
+// The UI page loads swagger-ui from a CDN, so the binary embeds only a small
+// HTML shell.
+
+use axum::response::Html;
+use axum::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::error::{ErrorBody, ErrorDetail};
+use crate::health::{CheckResult, ReadinessChecks, ReadinessReport, UpstreamCheck};
+use crate::files::FileObject;
+use crate::key_store::{CreateKey, KeySettings, StoredKey, UpdateKey};
+use crate::models::{ContentPart, DevvOptions, FileRef, ImageUrl, MessageContent, OpenAiChatRequest, OpenAiMessage};
+use crate::canary::Variant;
+use crate::failover::UpstreamKind;
+use crate::signer::{DebugSignRequest, ReloadRequest};
+use crate::sse_processor::{ChatCompletionChunk, ChunkError, Choice, Delta};
+use crate::streams::StreamInfo;
+use crate::wasm_signer::ModuleInfo;
+
+const SWAGGER_UI_HTML: &str = include_str!("assets/swagger.html");
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "rust_proxy",
+        description = "OpenAI-compatible streaming proxy for the Dev chat API.\n\n\
+            Supported request fields: `messages` (the last message is the prompt; files uploaded to \
+            `/v1/files` and referenced by `file` parts go along with it), `model` and `n`. \
+            Other OpenAI fields are rejected or ignored as configured; responses are always streamed.\n\n\
+            Extensions: every response carries `X-Request-Id` (taken from the request or generated).",
+    ),
+    paths(
+        crate::app::chat_completions_handler,
+        crate::model_catalog::list_models_handler,
+        crate::model_catalog::get_model_handler,
+        crate::files::upload_file_handler,
+        crate::files::list_files_handler,
+        crate::files::get_file_handler,
+        crate::files::delete_file_handler,
+        crate::threads::list_threads_handler,
+        crate::threads::get_thread_handler,
+        crate::threads::delete_thread_handler,
+        crate::assistants::create_assistant_handler,
+        crate::assistants::list_assistants_handler,
+        crate::assistants::get_assistant_handler,
+        crate::assistants::delete_assistant_handler,
+        crate::assistants::create_thread_handler,
+        crate::assistants::create_message_handler,
+        crate::assistants::list_messages_handler,
+        crate::assistants::create_run_handler,
+        crate::assistants::get_run_handler,
+        crate::embeddings::embeddings_handler,
+        crate::app::ping_handler,
+        crate::health::healthz_handler,
+        crate::health::readyz_handler,
+        crate::metrics::metrics_handler,
+        crate::usage::usage_handler,
+        crate::history::history_handler,
+        crate::key_store::list_keys_handler,
+        crate::key_store::create_key_handler,
+        crate::key_store::get_key_handler,
+        crate::key_store::update_key_handler,
+        crate::key_store::revoke_key_handler,
+        crate::streams::list_streams_handler,
+        crate::streams::cancel_stream_handler,
+        crate::streams::tail_stream_handler,
+        crate::dashboard::summary_handler,
+        crate::signer::signer_info_handler,
+        crate::signer::reload_signer_handler,
+        crate::signer::debug_sign_handler,
+    ),
+    components(schemas(
+        OpenAiChatRequest, DevvOptions, OpenAiMessage, MessageContent, ContentPart, ImageUrl, FileRef, FileObject, ChatCompletionChunk, ChunkError, Choice, Delta, ErrorBody, ErrorDetail,
+        CheckResult, ReadinessReport, ReadinessChecks, UpstreamCheck, StreamInfo, ModuleInfo, ReloadRequest,
+        DebugSignRequest, Variant, UpstreamKind, StoredKey, KeySettings, CreateKey, UpdateKey,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "chat", description = "OpenAI-compatible chat completions"),
+        (name = "models", description = "The model names chat completions accept"),
+        (name = "files", description = "Text files that chat messages can attach"),
+        (name = "threads", description = "Dev's server-side conversations"),
+        (name = "assistants", description = "A subset of the Assistants API over Dev threads"),
+        (name = "embeddings", description = "Forwarded to EMBEDDINGS_URL when configured"),
+        (name = "health", description = "Probes and metrics"),
+        (name = "admin", description = "Operator API, requires ADMIN_TOKEN or an OIDC login session"),
+    ),
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let bearer = |description: &str| {
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).description(Some(description)).build())
+        };
+        components.add_security_scheme("api_key", bearer("A key from ALLOWED_API_KEYS or the key store, or a JWT from the identity provider (not required when none is set)"));
+        components.add_security_scheme("admin_token", bearer("The ADMIN_TOKEN value"));
+        components.add_security_scheme(
+            "admin_basic",
+            SecurityScheme::Http(
+                HttpBuilder::new().scheme(HttpAuthScheme::Basic).description(Some("A user of ADMIN_BASIC_AUTH")).build(),
+            ),
+        );
+    }
+}
+
+/// `GET /openapi.json`
+pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// `GET /docs`
+pub async fn swagger_ui_handler() -> Html<&'static str> {
+    Html(SWAGGER_UI_HTML)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_documents_routes_and_schemas() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let chat = &spec["paths"]["/v1/chat/completions"]["post"];
+        assert_eq!(chat["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/OpenAiChatRequest");
+        assert!(chat["responses"]["200"]["content"]["text/event-stream"].is_object());
+        assert!(spec["paths"]["/admin/streams/{id}"]["delete"].is_object());
+        assert!(spec["components"]["schemas"]["ErrorBody"].is_object());
+        assert!(spec["components"]["securitySchemes"]["admin_token"].is_object());
+    }
+}
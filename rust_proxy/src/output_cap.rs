@@ -0,0 +1,118 @@
+// Cap on the size of an answer, protecting clients from pathological
+// multi-megabyte output. With MAX_RESPONSE_BYTES set, each choice ends with
+// `finish_reason: "length"` once its content reaches that many bytes (UTF-8):
+// the chunk crossing the cap is cut at a character boundary and the Dev
+// stream is dropped. 0 or unset means no cap.
+
+use once_cell::sync::Lazy;
+use prometheus::IntCounter;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::ChunkTransformer;
+
+/// Finish reason of a choice cut off at the cap.
+pub const LENGTH: &str = "length";
+
+/// Choices cut off at the cap, registered by `Metrics`.
+pub static CAPPED: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("chat_output_capped_total", "Choices ended at MAX_RESPONSE_BYTES").expect("valid counter")
+});
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputCap {
+    max_bytes: Option<usize>,
+}
+
+impl OutputCap {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { max_bytes: (max_bytes > 0).then_some(max_bytes) }
+    }
+
+    pub fn from_env() -> Self {
+        let cap = Self::new(env_or("MAX_RESPONSE_BYTES", 0));
+        if let Some(max_bytes) = cap.max_bytes {
+            info!(max_bytes, "Capping response size");
+        }
+        cap
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some()
+    }
+
+    /// The `cap` stage of a choice, see `transform`.
+    pub fn limiter(self) -> Limiter {
+        Limiter { max_bytes: self.max_bytes.unwrap_or(usize::MAX), sent: 0 }
+    }
+}
+
+/// Counts one choice's content and cuts it at the cap.
+pub struct Limiter {
+    max_bytes: usize,
+    sent: usize,
+}
+
+impl ChunkTransformer for Limiter {
+    fn transform(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        let mut capped = false;
+        for choice in &mut chunk.choices {
+            let Some(content) = &mut choice.delta.content else { continue };
+            let room = self.max_bytes - self.sent;
+            if content.len() <= room {
+                self.sent += content.len();
+                continue;
+            }
+            let end = (0..=room).rev().find(|&i| content.is_char_boundary(i)).unwrap_or_default();
+            content.truncate(end);
+            self.sent += end;
+            if content.is_empty() {
+                choice.delta.content = None;
+            }
+            choice.finish_reason = Some(LENGTH.to_string());
+            capped = true;
+        }
+        if capped {
+            warn!(max_bytes = self.max_bytes, "Response reached MAX_RESPONSE_BYTES, ending the choice");
+            CAPPED.inc();
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+    use crate::transform::{ChoiceContext, TransformChain};
+    use futures_util::StreamExt;
+
+    async fn run(cap: OutputCap, parts: &[&str]) -> Vec<(Option<String>, Option<String>)> {
+        let chunks = futures_util::stream::iter(parts.iter().map(|part| Ok(test_chunk(Some(part), None))).collect::<Vec<_>>());
+        TransformChain::default()
+            .with("cap", move || cap.limiter())
+            .apply(chunks, &ChoiceContext::default())
+            .map(|chunk| {
+                let choice = chunk.unwrap().choices.remove(0);
+                (choice.delta.content, choice.finish_reason)
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_cap_cuts_at_a_char_boundary() {
+        let capped = run(OutputCap::new(6), &["Hello", " wörld", "never sent"]).await;
+        assert_eq!(capped, [(Some("Hello".to_string()), None), (Some(" ".to_string()), Some(LENGTH.to_string()))]);
+    }
+
+    #[tokio::test]
+    async fn test_cap_at_exact_size_ends_on_the_next_content() {
+        let capped = run(OutputCap::new(5), &["Hello", "!", "never sent"]).await;
+        assert_eq!(capped, [(Some("Hello".to_string()), None), (None, Some(LENGTH.to_string()))]);
+        let uncapped = run(OutputCap::default(), &["Hello", "!"]).await;
+        assert_eq!(uncapped.len(), 2);
+        assert!(uncapped.iter().all(|(_, finish_reason)| finish_reason.is_none()));
+    }
+}
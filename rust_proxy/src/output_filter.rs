@@ -0,0 +1,191 @@
+// Redaction of streamed output, so secrets, internal hostnames or banned
+// phrases in an answer never leave the proxy. OUTPUT_REDACT_PATTERNS is a
+// JSON array of regular expressions; their matches in the content deltas
+// are replaced with OUTPUT_REDACT_REPLACEMENT ("[REDACTED]").
+//
+// A match can span chunks, so the last OUTPUT_REDACT_HOLDBACK characters
+// (64) of the text are held back until more arrives or the choice finishes;
+// the holdback must be at least as long as the longest text a pattern can
+// match. Text held back when a stream fails is dropped.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::ChunkTransformer;
+
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+#[derive(Debug, Clone)]
+pub struct OutputFilter {
+    rules: Vec<Regex>,
+    replacement: String,
+    holdback: usize,
+}
+
+impl Default for OutputFilter {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl OutputFilter {
+    pub fn new(rules: Vec<Regex>) -> Self {
+        Self { rules, replacement: DEFAULT_REPLACEMENT.to_string(), holdback: 64 }
+    }
+
+    /// Rules from a JSON array of patterns.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let patterns: Vec<String> = serde_json::from_str(json).context("Expected a JSON array of patterns")?;
+        let rules = patterns
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid redaction pattern '{}'", p)))
+            .collect::<Result<_>>()?;
+        Ok(Self::new(rules))
+    }
+
+    pub fn with_replacement(mut self, replacement: String) -> Self {
+        self.replacement = replacement;
+        self
+    }
+
+    pub fn with_holdback(mut self, holdback: usize) -> Self {
+        self.holdback = holdback;
+        self
+    }
+
+    /// OUTPUT_REDACT_PATTERNS, OUTPUT_REDACT_REPLACEMENT and
+    /// OUTPUT_REDACT_HOLDBACK; invalid patterns disable the filter.
+    pub fn from_env() -> Self {
+        let filter = match std::env::var("OUTPUT_REDACT_PATTERNS").ok().filter(|v| !v.trim().is_empty()) {
+            Some(json) => Self::from_json(&json).unwrap_or_else(|e| {
+                warn!("Ignoring OUTPUT_REDACT_PATTERNS: {:#}", e);
+                Self::default()
+            }),
+            None => Self::default(),
+        };
+        let filter = filter
+            .with_replacement(env_or("OUTPUT_REDACT_REPLACEMENT", DEFAULT_REPLACEMENT.to_string()))
+            .with_holdback(env_or("OUTPUT_REDACT_HOLDBACK", 64));
+        if !filter.is_empty() {
+            info!(rules = filter.rules.len(), holdback = filter.holdback, "Output redaction enabled");
+        }
+        filter
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for rule in &self.rules {
+            if rule.is_match(&text) {
+                text = rule.replace_all(&text, self.replacement.as_str()).into_owned();
+            }
+        }
+        text
+    }
+
+    /// Appends `delta` to `pending` and takes the redacted text that is safe
+    /// to send: everything when `last`, otherwise all but the holdback and
+    /// any match that reaches into it.
+    fn take(&self, pending: &mut String, delta: &str, last: bool) -> String {
+        pending.push_str(delta);
+        let mut cut = if last || self.holdback == 0 {
+            pending.len()
+        } else {
+            pending.char_indices().rev().nth(self.holdback - 1).map_or(0, |(i, _)| i)
+        };
+        // Moving the cut before one match can split another, so repeat
+        // until no match crosses it
+        while let Some(start) = self
+            .rules
+            .iter()
+            .flat_map(|rule| rule.find_iter(pending))
+            .filter(|m| m.start() < cut && m.end() > cut)
+            .map(|m| m.start())
+            .min()
+        {
+            cut = start;
+        }
+        let safe = self.redact(&pending[..cut]);
+        pending.drain(..cut);
+        safe
+    }
+
+    /// The `redact` stage of a choice, see `transform`.
+    pub fn redactor(&self) -> Redactor {
+        Redactor { filter: self.clone(), pending: String::new() }
+    }
+}
+
+/// Redacts one choice's content, holding back what a match may still reach.
+pub struct Redactor {
+    filter: OutputFilter,
+    pending: String,
+}
+
+impl ChunkTransformer for Redactor {
+    fn transform(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        let last = chunk.choices.iter().any(|c| c.finish_reason.is_some());
+        let delta: String = chunk.choices.iter().filter_map(|c| c.delta.content.as_deref()).collect();
+        let safe = self.filter.take(&mut self.pending, &delta, last);
+        if safe.is_empty() && !last {
+            return None;
+        }
+        for choice in &mut chunk.choices {
+            choice.delta.content = (!safe.is_empty()).then(|| safe.clone());
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+    use crate::transform::{ChoiceContext, TransformChain};
+    use futures_util::StreamExt;
+
+    fn filter() -> OutputFilter {
+        OutputFilter::from_json(r#"["\\bsk-[A-Za-z0-9]{8,}", "db\\d+\\.internal\\.corp"]"#).unwrap().with_holdback(12)
+    }
+
+    #[test]
+    fn test_match_split_across_deltas() {
+        let filter = filter();
+        let mut pending = String::new();
+        let mut out = String::new();
+        for delta in ["use the key sk-abc", "defgh1234 on db", "7.internal.corp", " please"] {
+            out += &filter.take(&mut pending, delta, false);
+        }
+        out += &filter.take(&mut pending, "", true);
+        assert_eq!(out, "use the key [REDACTED] on [REDACTED] please");
+    }
+
+    #[test]
+    fn test_holdback_keeps_a_growing_match() {
+        let filter = filter();
+        let mut pending = String::new();
+        assert_eq!(filter.take(&mut pending, "token sk-abcdefgh", false), "token");
+        assert_eq!(filter.take(&mut pending, "ijklmnopqrstuvwxyz done", true), " [REDACTED] done");
+        assert!(OutputFilter::from_json(r#"["("]"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_redacted() {
+        let chunks = futures_util::stream::iter(vec![
+            Ok(test_chunk(Some("connect to db1.inter"), None)),
+            Ok(test_chunk(Some("nal.corp"), None)),
+            Ok(test_chunk(None, Some("stop"))),
+        ]);
+        let chain = TransformChain::default().with("redact", || filter().redactor());
+        let out: Vec<_> = chain.apply(chunks, &ChoiceContext::default()).map(|c| c.unwrap()).collect().await;
+        let text: String = out.iter().filter_map(|c| c.choices[0].delta.content.clone()).collect();
+        assert_eq!(text, "connect to [REDACTED]");
+        assert_eq!(out.last().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+}
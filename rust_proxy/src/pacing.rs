@@ -0,0 +1,156 @@
+// Output pacing. Dev delivers an answer in bursts; with PACING_RATE set,
+// each choice's content is released at that steady rate instead, in
+// PACING_UNIT ("chars", or "tokens" counted like usage) per second, which
+// makes the stream pleasant to watch in demo UIs. A slow backend is never
+// slowed down further: time lost waiting for Dev is not made up later.
+
+use anyhow::Result;
+use futures_util::stream::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::env_or;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::tokenizer;
+use crate::transform::{ChunkStream, StreamTransformer};
+
+/// How often content is released in `chars` mode.
+const TICK: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PacingUnit {
+    #[default]
+    Chars,
+    Tokens,
+}
+
+impl FromStr for PacingUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "chars" | "characters" => Ok(Self::Chars),
+            "tokens" => Ok(Self::Tokens),
+            other => Err(format!("unknown pacing unit '{}', expected chars or tokens", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pacing {
+    /// Units per second; 0 disables pacing.
+    pub rate: f64,
+    pub unit: PacingUnit,
+}
+
+impl Pacing {
+    pub fn from_env() -> Self {
+        Self { rate: env_or("PACING_RATE", 0.0), unit: env_or("PACING_UNIT", PacingUnit::Chars) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// The pieces `content` is released in: words for tokens, otherwise
+    /// what a tick's worth of characters is.
+    fn split(&self, content: &str) -> Vec<String> {
+        match self.unit {
+            PacingUnit::Tokens => content.split_inclusive(char::is_whitespace).map(str::to_string).collect(),
+            PacingUnit::Chars => {
+                let per_tick = ((self.rate * TICK.as_secs_f64()) as usize).max(1);
+                let chars: Vec<char> = content.chars().collect();
+                chars.chunks(per_tick).map(|piece| piece.iter().collect()).collect()
+            }
+        }
+    }
+
+    /// How long releasing `piece` takes at the configured rate.
+    fn duration(&self, piece: &str) -> Duration {
+        let units = match self.unit {
+            PacingUnit::Chars => piece.chars().count(),
+            PacingUnit::Tokens => tokenizer::count_tokens(piece),
+        };
+        Duration::from_secs_f64(units as f64 / self.rate)
+    }
+
+    /// `chunks` of one choice with their content released at the rate.
+    pub fn pace<S>(self, chunks: S) -> impl Stream<Item = Result<ChatCompletionChunk>> + Send + 'static
+    where
+        S: Stream<Item = Result<ChatCompletionChunk>> + Send + Unpin + 'static,
+    {
+        struct State<S> {
+            chunks: S,
+            pacing: Pacing,
+            pieces: VecDeque<ChatCompletionChunk>,
+            release_at: Instant,
+        }
+
+        let state = State { chunks, pacing: self, pieces: VecDeque::new(), release_at: Instant::now() };
+        futures_util::stream::unfold(state, |mut state| async move {
+            if state.pieces.is_empty() {
+                let chunk = match state.chunks.next().await? {
+                    Ok(chunk) if chunk.choices.len() == 1 && chunk.choices[0].delta.content.as_ref().is_some_and(|c| !c.is_empty()) => {
+                        chunk
+                    }
+                    // Anything but content (finish, errors) is not held up
+                    other => return Some((other, state)),
+                };
+                let content = chunk.choices[0].delta.content.clone().unwrap_or_default();
+                for piece in state.pacing.split(&content) {
+                    let mut piece_chunk = chunk.clone();
+                    piece_chunk.choices[0].delta.content = Some(piece);
+                    piece_chunk.choices[0].finish_reason = None;
+                    state.pieces.push_back(piece_chunk);
+                }
+                if let Some(last) = state.pieces.back_mut() {
+                    last.choices[0].finish_reason = chunk.choices[0].finish_reason.clone();
+                }
+                state.release_at = state.release_at.max(Instant::now());
+            }
+            tokio::time::sleep_until(state.release_at).await;
+            let piece = state.pieces.pop_front()?;
+            let content = piece.choices[0].delta.content.as_deref().unwrap_or_default();
+            state.release_at += state.pacing.duration(content);
+            Some((Ok(piece), state))
+        })
+    }
+}
+
+/// The `pace` stage, see `transform`.
+impl StreamTransformer for Pacing {
+    fn wrap(&self, chunks: ChunkStream) -> ChunkStream {
+        self.pace(chunks).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    #[test]
+    fn test_split_by_unit() {
+        let chars = Pacing { rate: 40.0, unit: PacingUnit::Chars };
+        assert_eq!(chars.split("hello"), ["he", "ll", "o"]);
+        assert_eq!(chars.duration("hello"), Duration::from_millis(125));
+        let tokens = Pacing { rate: 10.0, unit: PacingUnit::Tokens };
+        assert_eq!(tokens.split("one two  three"), ["one ", "two ", " ", "three"]);
+        assert_eq!("Tokens".parse::<PacingUnit>(), Ok(PacingUnit::Tokens));
+    }
+
+    #[tokio::test]
+    async fn test_burst_is_spread_out() {
+        let pacing = Pacing { rate: 200.0, unit: PacingUnit::Chars };
+        let chunks = futures_util::stream::iter([Ok(test_chunk(Some(&"x".repeat(40)), None)), Ok(test_chunk(None, Some("stop")))]);
+        let started = Instant::now();
+        let out: Vec<_> = pacing.pace(chunks).map(|c| c.unwrap()).collect().await;
+        // 40 characters at 200 per second, in pieces of 10
+        assert_eq!(out.len(), 5);
+        assert_eq!(out[0].choices[0].delta.content.as_deref(), Some("xxxxxxxxxx"));
+        assert_eq!(out[4].choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+}
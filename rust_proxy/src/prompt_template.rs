@@ -0,0 +1,126 @@
+// Prompt templates: how the OpenAI conversation becomes the single prompt
+// Dev takes. Without a template the prompt is the last message, after the
+// proxy's system prompt. A minijinja template (PROMPT_TEMPLATE, or per model
+// in PROMPT_TEMPLATES, a JSON object of model name -> template) renders it
+// from the whole conversation instead. Templates see:
+//
+// - `messages`: list of `{role, content}`, content flattened to text
+// - `last`: the content of the last message
+// - `system_prompt`: the proxy's system prompt for the request, if any
+// - `model`: the requested model name
+//
+// For example, a transcript of the conversation:
+//
+//   {% if system_prompt %}{{ system_prompt }}\n\n{% endif %}
+//   {% for m in messages %}{{ m.role }}: {{ m.content }}\n{% endfor %}
+
+use anyhow::{Context, Result};
+use minijinja::{context, Environment};
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::models::OpenAiMessage;
+
+const DEFAULT_TEMPLATE: &str = "*";
+
+#[derive(Serialize)]
+struct Message<'a> {
+    role: &'a str,
+    content: String,
+}
+
+pub struct PromptTemplates {
+    env: Environment<'static>,
+}
+
+impl PromptTemplates {
+    /// Templates by model name; `default` applies to the other models.
+    pub fn new(default: Option<String>, by_model: HashMap<String, String>) -> Result<Self> {
+        let mut env = Environment::new();
+        env.set_keep_trailing_newline(true);
+        let templates = default.map(|t| (DEFAULT_TEMPLATE.to_string(), t)).into_iter().chain(by_model);
+        for (name, source) in templates {
+            env.add_template_owned(name.clone(), source)
+                .with_context(|| format!("Invalid prompt template for '{}'", name))?;
+        }
+        Ok(Self { env })
+    }
+
+    /// PROMPT_TEMPLATE and PROMPT_TEMPLATES; invalid templates are ignored.
+    pub fn from_env() -> Self {
+        let default = std::env::var("PROMPT_TEMPLATE").ok().filter(|v| !v.trim().is_empty());
+        let by_model = match std::env::var("PROMPT_TEMPLATES").ok().filter(|v| !v.trim().is_empty()) {
+            Some(json) => serde_json::from_str(&json)
+                .inspect_err(|e| warn!("Ignoring PROMPT_TEMPLATES, it is not a JSON object of strings: {}", e))
+                .unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        let templates = Self::new(default, by_model).unwrap_or_else(|e| {
+            warn!("Prompt templates disabled: {:#}", e);
+            Self { env: Environment::new() }
+        });
+        let names: Vec<_> = templates.env.templates().map(|(name, _)| name).collect();
+        if !names.is_empty() {
+            info!(?names, "Prompt templates loaded");
+        }
+        templates
+    }
+
+    /// The prompt for `messages` rendered with the template for `model`, or
+    /// `None` when no template applies.
+    pub fn render(
+        &self,
+        model: Option<&str>,
+        messages: &[OpenAiMessage],
+        system_prompt: Option<&str>,
+    ) -> Option<Result<String>> {
+        let template = model
+            .and_then(|model| self.env.get_template(model).ok())
+            .or_else(|| self.env.get_template(DEFAULT_TEMPLATE).ok())?;
+        let messages: Vec<Message> = messages
+            .iter()
+            .map(|m| Message { role: m.role.as_deref().unwrap_or("user"), content: m.text() })
+            .collect();
+        let last = messages.last().map(|m| m.content.clone()).unwrap_or_default();
+        let rendered = template
+            .render(context! { messages, last, system_prompt, model })
+            .with_context(|| format!("Failed to render prompt template '{}'", template.name()));
+        Some(rendered.map(|prompt| prompt.trim().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<OpenAiMessage> {
+        serde_json::from_value(serde_json::json!([
+            {"role": "system", "content": "be brief"},
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "hello"},
+            {"role": "user", "content": [{"type": "text", "text": "how are you?"}]},
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_transcript_template() {
+        let transcript = "{% if system_prompt %}{{ system_prompt }}\n\n{% endif %}\
+            {% for m in messages %}{{ m.role }}: {{ m.content }}\n{% endfor %}";
+        let templates = PromptTemplates::new(None, HashMap::from([("gpt-4o".to_string(), transcript.to_string())])).unwrap();
+        let prompt = templates.render(Some("gpt-4o"), &messages(), Some("Be polite.")).unwrap().unwrap();
+        assert_eq!(prompt, "Be polite.\n\nsystem: be brief\nuser: hi\nassistant: hello\nuser: how are you?");
+        assert!(templates.render(Some("other"), &messages(), None).is_none());
+    }
+
+    #[test]
+    fn test_default_template_and_errors() {
+        let templates = PromptTemplates::new(Some("Q: {{ last }}".to_string()), HashMap::new()).unwrap();
+        assert_eq!(templates.render(None, &messages(), None).unwrap().unwrap(), "Q: how are you?");
+
+        assert!(PromptTemplates::new(Some("{% for %}".to_string()), HashMap::new()).is_err());
+        let failing = PromptTemplates::new(Some("{{ last | nosuchfilter }}".to_string()), HashMap::new()).unwrap();
+        assert!(failing.render(None, &messages(), None).unwrap().is_err());
+    }
+}
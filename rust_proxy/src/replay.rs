@@ -0,0 +1,349 @@
+// Resumable chat streams. Every SSE event of a chat completion carries an
+// `id:`. With SSE_REPLAY_EVENTS set, the events of each stream are also kept
+// in the state store, up to that many per request, and the upstream stream
+// is driven by a background task instead of the client connection. A client
+// whose connection dropped repeats the request with the same X-Request-Id
+// and a `Last-Event-ID` header, on any replica; it gets the kept events
+// after that id, then the live rest of the stream. A stream nobody reads is
+// stopped after SSE_REPLAY_LINGER_SECS, and a finished one stays replayable
+// that long.
+
+use anyhow::Result;
+use axum::response::sse::Event as SseEvent;
+use futures_util::stream::{Stream, StreamExt};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::state_store::StateStore;
+
+pub const LAST_EVENT_ID: &str = "last-event-id";
+
+/// An SSE event before numbering: optional `event:` name and the data.
+pub type EventPayload = (Option<&'static str>, String);
+
+/// The `event:` names chat streams use.
+const EVENT_NAMES: &[&str] = &["error"];
+
+/// How long a stream counts as running after its driver last said so; a
+/// driver whose replica went away ends it for the readers after that.
+const RUNNING_TTL: Duration = Duration::from_secs(5);
+
+const RUNNING: &str = "running";
+const DONE: &str = "done";
+
+fn to_sse(id: u64, name: Option<&'static str>, data: &str) -> SseEvent {
+    let event = SseEvent::default().id(id.to_string()).data(data);
+    match name {
+        Some(name) => event.event(name),
+        None => event,
+    }
+}
+
+/// Numbers `events` from 1, without buffering.
+pub fn numbered(events: impl Stream<Item = EventPayload>) -> impl Stream<Item = SseEvent> {
+    events.enumerate().map(|(i, (name, data))| to_sse(i as u64 + 1, name, &data))
+}
+
+/// The `Last-Event-ID` request header, if it holds an event id.
+pub fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers.get(LAST_EVENT_ID)?.to_str().ok()?.trim().parse().ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    data: String,
+}
+
+impl StoredEvent {
+    fn encode((name, data): EventPayload) -> Result<String> {
+        Ok(serde_json::to_string(&StoredEvent { event: name.map(str::to_string), data })?)
+    }
+
+    fn decode(entry: &str) -> Option<EventPayload> {
+        let stored: StoredEvent = serde_json::from_str(entry).ok()?;
+        let name = stored.event.and_then(|name| EVENT_NAMES.iter().find(|known| **known == name).copied());
+        Some((name, stored.data))
+    }
+}
+
+/// The events of one stream in the state store: a driver on one replica
+/// appends them while readers on any replica follow. Besides the log, the
+/// stream has a state (`running` while the driver says so, then `done`)
+/// and a reader heartbeat.
+#[derive(Clone)]
+pub(crate) struct EventLog {
+    store: StateStore,
+    key: String,
+    capacity: usize,
+    /// How long the stream runs without a reader, and stays readable once
+    /// it finished.
+    linger: Duration,
+}
+
+impl EventLog {
+    pub(crate) fn new(store: StateStore, key: String, capacity: usize, linger: Duration) -> Self {
+        Self { store, key, capacity, linger }
+    }
+
+    fn events_key(&self) -> String {
+        format!("{}:events", self.key)
+    }
+
+    fn state_key(&self) -> String {
+        format!("{}:state", self.key)
+    }
+
+    fn readers_key(&self) -> String {
+        format!("{}:read", self.key)
+    }
+
+    /// How often the driver checks in, and readers at most refresh their
+    /// heartbeat.
+    pub(crate) fn tick(&self) -> Duration {
+        (self.linger / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+    }
+
+    /// Marks the stream running, with a reader to come.
+    pub(crate) async fn start(&self) -> Result<()> {
+        self.store.set(&self.state_key(), RUNNING.to_string(), Some(RUNNING_TTL)).await?;
+        self.store.set(&self.readers_key(), String::new(), Some(self.linger)).await
+    }
+
+    /// Counts as a reader; whether the stream is still running.
+    pub(crate) async fn attach(&self) -> Result<bool> {
+        self.store.set(&self.readers_key(), String::new(), Some(self.linger)).await?;
+        Ok(self.store.get(&self.state_key()).await?.as_deref() == Some(RUNNING))
+    }
+
+    /// Whether the stream is running or still readable.
+    pub(crate) async fn exists(&self) -> Result<bool> {
+        Ok(self.store.get(&self.state_key()).await?.is_some())
+    }
+
+    pub(crate) async fn push(&self, event: EventPayload) -> Result<()> {
+        self.store.append(&self.events_key(), StoredEvent::encode(event)?, self.capacity, self.linger).await?;
+        Ok(())
+    }
+
+    /// Keeps the stream running; whether anyone read it within the linger.
+    pub(crate) async fn check_in(&self) -> Result<bool> {
+        self.store.set(&self.state_key(), RUNNING.to_string(), Some(RUNNING_TTL)).await?;
+        self.store.expire(&self.events_key(), self.linger).await?;
+        Ok(self.store.get(&self.readers_key()).await?.is_some())
+    }
+
+    /// Ends the stream for its readers; it stays readable for the linger.
+    pub(crate) async fn finish(&self) -> Result<()> {
+        self.store.set(&self.state_key(), DONE.to_string(), Some(self.linger)).await?;
+        self.store.expire(&self.events_key(), self.linger).await?;
+        self.store.wake(&self.events_key());
+        Ok(())
+    }
+
+    async fn poll(&self, last: u64, heartbeat: &mut Option<Instant>) -> Result<(bool, Vec<(u64, String)>)> {
+        if heartbeat.is_none_or(|at| at.elapsed() >= self.tick()) {
+            self.store.set(&self.readers_key(), String::new(), Some(self.linger)).await?;
+            *heartbeat = Some(Instant::now());
+        }
+        // The state first: once it is `done`, every event is in the log
+        let running = self.store.get(&self.state_key()).await?.as_deref() == Some(RUNNING);
+        Ok((running, self.store.entries(&self.events_key(), last).await?))
+    }
+
+    /// Events after `after`, then live ones until the stream ends.
+    pub(crate) fn read(self, after: u64) -> impl Stream<Item = (u64, EventPayload)> + Send + 'static {
+        let start = (self, after, VecDeque::new(), None);
+        futures_util::stream::unfold(start, |(log, last, mut pending, mut heartbeat)| async move {
+            loop {
+                if let Some((number, event)) = pending.pop_front() {
+                    return Some(((number, event), (log, number, pending, heartbeat)));
+                }
+                let appended = log.store.appended(&log.events_key(), log.tick());
+                let (running, entries) = match log.poll(last, &mut heartbeat).await {
+                    Ok(polled) => polled,
+                    Err(e) => {
+                        warn!("Lost track of a chat stream: {:#}", e);
+                        return None;
+                    }
+                };
+                if let Some((first, _)) = entries.first()
+                    && *first > last + 1
+                {
+                    warn!(from = last + 1, to = first - 1, "The state store no longer holds some events");
+                }
+                if entries.is_empty() {
+                    if !running {
+                        return None;
+                    }
+                    appended.await;
+                    continue;
+                }
+                pending = entries
+                    .into_iter()
+                    .filter_map(|(number, entry)| Some((number, StoredEvent::decode(&entry)?)))
+                    .collect();
+            }
+        })
+    }
+}
+
+/// Resumable streams by owner and request id.
+#[derive(Clone, Debug)]
+pub struct ReplayStore {
+    store: StateStore,
+    capacity: usize,
+    linger: Duration,
+}
+
+impl ReplayStore {
+    /// `capacity` events per stream; 0 disables buffering and resumption.
+    pub fn new(store: StateStore, capacity: usize, linger: Duration) -> Self {
+        Self { store, capacity, linger }
+    }
+
+    /// SSE_REPLAY_EVENTS (0 = off) and SSE_REPLAY_LINGER_SECS (60).
+    pub fn from_env(store: &StateStore) -> Self {
+        let linger = Duration::from_secs(env_or("SSE_REPLAY_LINGER_SECS", 60));
+        let replay = Self::new(store.clone(), env_or("SSE_REPLAY_EVENTS", 0), linger);
+        if replay.enabled() {
+            info!(capacity = replay.capacity, linger = ?replay.linger, "Chat streams are resumable");
+        }
+        replay
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Only the client that started a stream can resume it.
+    fn log(&self, request_id: &str, owner: &str) -> EventLog {
+        let mut hasher = Sha256::new();
+        for part in [owner, request_id] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        let key = format!("replay:{}", hex::encode(hasher.finalize()));
+        EventLog::new(self.store.clone(), key, self.capacity, self.linger)
+    }
+
+    /// Continues the stream of `request_id` after `last_event_id`, if it is
+    /// still kept and was started by `owner`.
+    pub async fn resume(
+        &self,
+        request_id: &str,
+        owner: &str,
+        last_event_id: u64,
+    ) -> Option<impl Stream<Item = SseEvent> + Send + 'static> {
+        let log = self.log(request_id, owner);
+        match log.exists().await {
+            Ok(true) => Some(log.read(last_event_id).map(|(id, (name, data))| to_sse(id, name, &data))),
+            Ok(false) => None,
+            Err(e) => {
+                warn!("Could not look up a resumable chat stream: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Drives `events` in a background task that keeps them for
+    /// `request_id`; returns the stream for the client that started it.
+    pub async fn spawn(
+        &self,
+        request_id: String,
+        owner: String,
+        events: impl Stream<Item = EventPayload> + Send + 'static,
+    ) -> impl Stream<Item = SseEvent> + Send + 'static {
+        let log = self.log(&request_id, &owner);
+        if let Err(e) = log.start().await {
+            warn!(request_id, "Chat stream will not be resumable: {:#}", e);
+        }
+        let reader = log.clone().read(0).map(|(id, (name, data))| to_sse(id, name, &data));
+
+        tokio::spawn(async move {
+            let mut events = Box::pin(events);
+            let mut check = tokio::time::interval(log.tick());
+            loop {
+                tokio::select! {
+                    event = events.next() => match event {
+                        Some(event) => if let Err(e) = log.push(event).await {
+                            warn!(request_id, "Could not keep a chat stream event, ending the stream: {:#}", e);
+                            break;
+                        },
+                        None => break,
+                    },
+                    _ = check.tick() => match log.check_in().await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            info!(request_id, "Stopping a chat stream that was not resumed");
+                            break;
+                        }
+                        Err(e) => warn!(request_id, "Could not check in a chat stream: {:#}", e),
+                    },
+                }
+            }
+            if let Err(e) = log.finish().await {
+                warn!(request_id, "Could not end a chat stream: {:#}", e);
+            }
+            // Drops the upstream stream, which records the stream outcome
+            drop(events);
+        });
+        reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(n: usize) -> impl Stream<Item = EventPayload> + Send + 'static {
+        futures_util::stream::iter((1..=n).map(|i| (None, format!("chunk{}", i))))
+    }
+
+    async fn render(events: impl Stream<Item = SseEvent> + Send + 'static) -> Vec<String> {
+        use axum::response::IntoResponse;
+        let body = axum::response::sse::Sse::new(events.map(Ok::<_, std::convert::Infallible>)).into_response();
+        let bytes = axum::body::to_bytes(body.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap().split("\n\n").filter(|e| !e.is_empty()).map(str::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_numbered_events_carry_ids() {
+        let events = render(numbered(payloads(2))).await;
+        assert_eq!(events, ["id: 1\ndata: chunk1", "id: 2\ndata: chunk2"]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_last_event_id() {
+        let store = ReplayStore::new(StateStore::default(), 16, Duration::from_secs(60));
+        let first = render(store.spawn("req".to_string(), "key".to_string(), payloads(3)).await).await;
+        assert_eq!(first.len(), 3);
+        let resumed = render(store.resume("req", "key", 1).await.unwrap()).await;
+        assert_eq!(resumed, ["id: 2\ndata: chunk2", "id: 3\ndata: chunk3"]);
+        assert!(store.resume("req", "other-key", 1).await.is_none());
+        assert!(store.resume("unknown", "key", 1).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_named_events_survive_the_store() {
+        let store = ReplayStore::new(StateStore::default(), 16, Duration::from_secs(60));
+        let events = futures_util::stream::iter([(Some("error"), "{}".to_string())]);
+        render(store.spawn("req".to_string(), "key".to_string(), events).await).await;
+        let resumed = render(store.resume("req", "key", 0).await.unwrap()).await;
+        assert_eq!(resumed, ["id: 1\ndata: {}\nevent: error"]);
+    }
+
+    #[test]
+    fn test_last_event_id_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), None);
+        headers.insert(LAST_EVENT_ID, "42".parse().unwrap());
+        assert_eq!(last_event_id(&headers), Some(42));
+    }
+}
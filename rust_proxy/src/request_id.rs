@@ -0,0 +1,82 @@
+// Request ID correlation. An incoming `X-Request-Id` is kept (otherwise a
+// UUID is generated), recorded on the request span, forwarded to the Dev
+// backend and echoed back in the response headers. Completion chunks carry
+// their own `chatcmpl-` id, logged next to the request id.
+
+use axum::body::Body;
+use http::{HeaderName, Request};
+use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::Span;
+
+use crate::utils;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Generates request ids with `utils::generate_uuidv4` for requests that
+/// arrive without one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MakeRequestUuid;
+
+impl MakeRequestId for MakeRequestUuid {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        utils::generate_uuidv4().parse().ok().map(RequestId::new)
+    }
+}
+
+/// Returns the request id as a string; ids that are not visible ASCII are
+/// replaced by a fresh UUID so they are safe to log and forward.
+pub fn as_string(request_id: &RequestId) -> String {
+    request_id
+        .header_value()
+        .to_str()
+        .map(String::from)
+        .unwrap_or_else(|_| utils::generate_uuidv4())
+}
+
+/// Span factory for `TraceLayer` that includes the request id, so every log
+/// line emitted while handling a request carries it.
+pub fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = %request_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Extension, Router};
+    use tower::ServiceExt;
+    use tower_http::request_id::{PropagateRequestIdLayer, SetRequestIdLayer};
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|Extension(id): Extension<RequestId>| async move { as_string(&id) }))
+            .layer(PropagateRequestIdLayer::new(X_REQUEST_ID))
+            .layer(SetRequestIdLayer::new(X_REQUEST_ID, MakeRequestUuid))
+    }
+
+    #[tokio::test]
+    async fn test_incoming_request_id_is_echoed() {
+        let request = Request::builder().uri("/").header("x-request-id", "abc-123").body(Body::empty()).unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[&X_REQUEST_ID], "abc-123");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"abc-123");
+    }
+
+    #[tokio::test]
+    async fn test_missing_request_id_is_generated() {
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let id = response.headers()[&X_REQUEST_ID].to_str().unwrap();
+        assert_eq!(id.len(), 36);
+    }
+}
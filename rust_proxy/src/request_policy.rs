@@ -0,0 +1,269 @@
+// OpenAI request fields the Dev backend cannot honor. Instead of dropping
+// them silently, each is either rejected with a 400 naming the field or
+// answered in a fixed, documented way, as configured. Sampling, tool and
+// format parameters (`UNSUPPORTED`) are rejected under
+// UNSUPPORTED_PARAMS=strict and otherwise ignored, listed in a `Warning`
+// response header. A field set to null or its neutral value is no request
+// for anything and passes either way. Image parts in messages are rejected
+// unless IMAGE_INPUTS=drop, which leaves them out of the prompt.
+
+use serde_json::Value;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::error::ApiError;
+use crate::models::OpenAiChatRequest;
+
+/// OpenAI request fields Dev has no equivalent for, with the value that
+/// means "default behavior" where it is not null/false/empty.
+const UNSUPPORTED: &[(&str, Option<f64>)] = &[
+    ("temperature", Some(1.0)),
+    ("top_p", Some(1.0)),
+    ("presence_penalty", Some(0.0)),
+    ("frequency_penalty", Some(0.0)),
+    ("seed", None),
+    ("logit_bias", None),
+    ("stop", None),
+    ("max_tokens", None),
+    ("max_completion_tokens", None),
+    ("response_format", None),
+    ("tools", None),
+    ("tool_choice", None),
+    ("parallel_tool_calls", None),
+    ("functions", None),
+    ("function_call", None),
+];
+
+/// What a request asking for `logprobs`/`top_logprobs` gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogprobsMode {
+    /// 400 `logprobs_unsupported` (default).
+    Reject,
+    /// The completion, with `"logprobs": null` in every choice.
+    Null,
+}
+
+impl FromStr for LogprobsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "null" => Ok(Self::Null),
+            other => Err(format!("unknown logprobs mode '{}', expected reject or null", other)),
+        }
+    }
+}
+
+/// What a request setting an `UNSUPPORTED` field gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamsMode {
+    /// 400 `unsupported_parameter` naming the field.
+    Strict,
+    /// The completion, with the ignored fields in a `Warning` header (default).
+    Lenient,
+}
+
+impl FromStr for ParamsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            other => Err(format!("unknown parameter policy '{}', expected strict or lenient", other)),
+        }
+    }
+}
+
+/// What a request with `image_url` content parts gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImagesMode {
+    /// 400 `images_unsupported` (default).
+    Reject,
+    /// The completion of the text alone, with a `Warning` header.
+    Drop,
+}
+
+impl FromStr for ImagesMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "drop" => Ok(Self::Drop),
+            other => Err(format!("unknown image mode '{}', expected reject or drop", other)),
+        }
+    }
+}
+
+/// How a chat request has to be answered beyond what Dev returns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Answer {
+    /// Every choice carries `"logprobs": null`.
+    pub null_logprobs: bool,
+    /// Fields that were set but are not honored.
+    pub ignored: Vec<&'static str>,
+    /// Image parts left out of the prompt.
+    pub dropped_images: usize,
+}
+
+impl Answer {
+    /// A `Warning` header for each part of the request that was not honored.
+    pub fn warnings(&self) -> Vec<(http::HeaderName, http::HeaderValue)> {
+        let mut texts = Vec::new();
+        if !self.ignored.is_empty() {
+            texts.push(format!("Unsupported parameters ignored: {}", self.ignored.join(", ")));
+        }
+        if self.dropped_images > 0 {
+            texts.push(format!("{} image input(s) dropped, the backend reads text only", self.dropped_images));
+        }
+        texts
+            .into_iter()
+            .filter_map(|text| http::HeaderValue::from_str(&format!("299 rust_proxy \"{}\"", text)).ok())
+            .map(|value| (http::header::WARNING, value))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestPolicy {
+    pub logprobs: LogprobsMode,
+    pub params: ParamsMode,
+    pub images: ImagesMode,
+}
+
+impl RequestPolicy {
+    /// LOGPROBS_MODE (reject), UNSUPPORTED_PARAMS (lenient) and
+    /// IMAGE_INPUTS (reject).
+    pub fn from_env() -> Self {
+        let policy = Self {
+            logprobs: env_or("LOGPROBS_MODE", LogprobsMode::Reject),
+            params: env_or("UNSUPPORTED_PARAMS", ParamsMode::Lenient),
+            images: env_or("IMAGE_INPUTS", ImagesMode::Reject),
+        };
+        info!(?policy, "Request policy configured");
+        policy
+    }
+
+    /// Rejects `req` if it asks for something the policy refuses.
+    pub fn check(&self, req: &OpenAiChatRequest) -> Result<Answer, ApiError> {
+        let mut answer = Answer::default();
+        let wants_logprobs = req.logprobs == Some(true) || req.top_logprobs.is_some_and(|n| n > 0);
+        if wants_logprobs {
+            match self.logprobs {
+                LogprobsMode::Reject => {
+                    let param = if req.logprobs == Some(true) { "logprobs" } else { "top_logprobs" };
+                    return Err(ApiError::invalid_param(param, "logprobs are not supported by this backend")
+                        .with_code("logprobs_unsupported"));
+                }
+                LogprobsMode::Null => answer.null_logprobs = true,
+            }
+        }
+        for &(param, neutral) in UNSUPPORTED {
+            let Some(value) = req.extra.get(param) else { continue };
+            if is_default(value, neutral) {
+                continue;
+            }
+            if self.params == ParamsMode::Strict {
+                return Err(ApiError::invalid_param(param, format!("'{}' is not supported by this backend", param))
+                    .with_code("unsupported_parameter"));
+            }
+            answer.ignored.push(param);
+        }
+        if !answer.ignored.is_empty() {
+            warn!(ignored = ?answer.ignored, "Ignoring unsupported request parameters");
+        }
+        let images: usize = req.messages.iter().map(|m| m.content.image_count()).sum();
+        if images > 0 {
+            if self.images == ImagesMode::Reject {
+                return Err(ApiError::invalid_param("messages", "image inputs are not supported by this backend")
+                    .with_code("images_unsupported"));
+            }
+            warn!(images, "Dropping image inputs");
+            answer.dropped_images = images;
+        }
+        Ok(answer)
+    }
+}
+
+/// Whether `value` asks for nothing beyond the default behavior.
+fn is_default(value: &Value, neutral: Option<f64>) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => true,
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        Value::Number(n) => neutral.is_some_and(|neutral| n.as_f64() == Some(neutral)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(json: serde_json::Value) -> OpenAiChatRequest {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn policy() -> RequestPolicy {
+        RequestPolicy { logprobs: LogprobsMode::Reject, params: ParamsMode::Lenient, images: ImagesMode::Reject }
+    }
+
+    #[test]
+    fn test_logprobs_modes() {
+        let plain = request(serde_json::json!({"messages": [{"content": "hi"}], "logprobs": false}));
+        let asking = request(serde_json::json!({"messages": [{"content": "hi"}], "top_logprobs": 3}));
+
+        let reject = RequestPolicy { logprobs: LogprobsMode::Reject, ..policy() };
+        assert_eq!(reject.check(&plain).unwrap(), Answer::default());
+        let error = reject.check(&asking).unwrap_err();
+        assert_eq!(error.param, Some("top_logprobs"));
+        assert_eq!(error.code, Some("logprobs_unsupported"));
+
+        let null = RequestPolicy { logprobs: LogprobsMode::Null, ..policy() };
+        assert!(null.check(&asking).unwrap().null_logprobs);
+        assert!(!null.check(&plain).unwrap().null_logprobs);
+    }
+
+    #[test]
+    fn test_unsupported_params() {
+        let req = request(serde_json::json!({
+            "messages": [{"content": "hi"}],
+            "temperature": 1,
+            "presence_penalty": 0.5,
+            "seed": 7,
+            "tools": [],
+            "stop": null,
+            "user": "u1",
+        }));
+
+        let answer = policy().check(&req).unwrap();
+        assert_eq!(answer.ignored, ["presence_penalty", "seed"]);
+        let warnings = answer.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, http::header::WARNING);
+        assert_eq!(warnings[0].1, "299 rust_proxy \"Unsupported parameters ignored: presence_penalty, seed\"");
+
+        let strict = RequestPolicy { params: ParamsMode::Strict, ..policy() };
+        let error = strict.check(&req).unwrap_err();
+        assert_eq!(error.param, Some("presence_penalty"));
+        assert_eq!(error.code, Some("unsupported_parameter"));
+        assert!(strict.check(&request(serde_json::json!({"messages": [], "temperature": 1.0}))).is_ok());
+    }
+
+    #[test]
+    fn test_image_inputs() {
+        let req = request(serde_json::json!({"messages": [{"content": [
+            {"type": "text", "text": "describe"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+        ]}]}));
+        let error = policy().check(&req).unwrap_err();
+        assert_eq!(error.code, Some("images_unsupported"));
+
+        let answer = RequestPolicy { images: ImagesMode::Drop, ..policy() }.check(&req).unwrap();
+        assert_eq!(answer.dropped_images, 1);
+        assert_eq!(answer.warnings()[0].1, "299 rust_proxy \"1 image input(s) dropped, the backend reads text only\"");
+    }
+}
@@ -0,0 +1,303 @@
+// Operator scripting hooks: small custom policies (header rewrites, routing
+// tweaks, annotations) kept in config instead of a fork. SCRIPT_FILE is a
+// Rhai script defining any of these functions:
+//
+// - `on_request(req)`: `req` has `model`, `n`, `api_key_id`, `messages`
+//   (`role`, `content`) and `headers` (lower-case names). It may return a
+//   map with `model` (the model to use instead), `headers` (added to the
+//   response) and `reject` (refuses the request with that message, 400).
+// - `on_chunk(chunk)`: `chunk` has `index`, `content` and `finish_reason`;
+//   a map returned replaces those it names, `false` drops the chunk. It runs
+//   as the `script` stage of CHUNK_TRANSFORMS (see `transform`).
+// - `on_finish(summary)`: once a chat stream ended, with `request_id`,
+//   `model`, `api_key_id`, `outcome`, `completion_tokens` and `duration_ms`.
+//
+// `print` and `debug` log on the `script` target. A call may run
+// SCRIPT_MAX_OPERATIONS (100000) operations; a hook that fails is skipped
+// with a warning and counted in `script_errors_total`. Scripting needs the
+// `scripting` cargo feature; without it SCRIPT_FILE is ignored with a warning.
+
+use anyhow::Result;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::metrics::StreamSummary;
+use crate::models::OpenAiChatRequest;
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::ChunkTransformer;
+
+pub const ON_REQUEST: &str = "on_request";
+pub const ON_CHUNK: &str = "on_chunk";
+pub const ON_FINISH: &str = "on_finish";
+
+/// Hook calls that failed, by hook, registered by `Metrics`.
+pub static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("script_errors_total", "Script hook calls that failed, by hook"), &["hook"])
+        .expect("valid counter")
+});
+
+/// A loaded script; hooks take and return JSON-like values.
+trait Runtime: Send + Sync {
+    fn defines(&self, hook: &str) -> bool;
+    fn call(&self, hook: &str, arg: Value) -> Result<Value>;
+}
+
+/// What `on_request` asked for.
+#[derive(Debug, Default)]
+pub struct RequestChanges {
+    pub model: Option<String>,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub reject: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RequestReply {
+    model: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    reject: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ScriptMessage {
+    role: Option<String>,
+    content: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Scripts {
+    runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl Scripts {
+    /// Compiles the script at `path`, with SCRIPT_MAX_OPERATIONS per call.
+    pub fn load(path: &str) -> Result<Self> {
+        let runtime = rhai_runtime::load(path, env_or("SCRIPT_MAX_OPERATIONS", 100_000))?;
+        Ok(Self { runtime: Some(runtime) })
+    }
+
+    pub fn from_env() -> Self {
+        let Some(path) = std::env::var("SCRIPT_FILE").ok().filter(|v| !v.trim().is_empty()) else { return Self::default() };
+        match Self::load(&path) {
+            Ok(scripts) => {
+                let hooks: Vec<_> = [ON_REQUEST, ON_CHUNK, ON_FINISH].into_iter().filter(|hook| scripts.defines(hook)).collect();
+                info!(path, ?hooks, "Script hooks loaded");
+                scripts
+            }
+            Err(e) => {
+                warn!("Ignoring SCRIPT_FILE: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn defines(&self, hook: &str) -> bool {
+        self.runtime.as_ref().is_some_and(|runtime| runtime.defines(hook))
+    }
+
+    /// Calls `hook` if the script defines it; `None` when it does not or failed.
+    fn call(&self, hook: &'static str, arg: Value) -> Option<Value> {
+        let runtime = self.runtime.as_ref().filter(|runtime| runtime.defines(hook))?;
+        runtime
+            .call(hook, arg)
+            .inspect_err(|e| {
+                warn!(hook, "Script hook failed: {:#}", e);
+                ERRORS.with_label_values(&[hook]).inc();
+            })
+            .ok()
+    }
+
+    pub fn on_request(&self, req: &OpenAiChatRequest, api_key_id: &str, headers: &HeaderMap) -> RequestChanges {
+        if !self.defines(ON_REQUEST) {
+            return RequestChanges::default();
+        }
+        let messages: Vec<_> = req.messages.iter().map(|m| ScriptMessage { role: m.role.clone(), content: m.text() }).collect();
+        let headers: BTreeMap<_, _> = headers.iter().filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))).collect();
+        let arg = json!({ "model": req.model, "n": req.n.unwrap_or(1), "api_key_id": api_key_id, "messages": messages, "headers": headers });
+        let reply = match self.call(ON_REQUEST, arg).filter(|reply| !reply.is_null()).map(serde_json::from_value::<RequestReply>) {
+            Some(Ok(reply)) => reply,
+            Some(Err(e)) => {
+                warn!(hook = ON_REQUEST, "Ignoring the script's reply: {}", e);
+                ERRORS.with_label_values(&[ON_REQUEST]).inc();
+                RequestReply::default()
+            }
+            None => RequestReply::default(),
+        };
+        let headers = reply
+            .headers
+            .into_iter()
+            .filter_map(|(name, value)| match (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value)) {
+                (Ok(name), Ok(value)) => Some((name, value)),
+                _ => {
+                    warn!(header = name, "Ignoring an invalid header from the script");
+                    None
+                }
+            })
+            .collect();
+        RequestChanges { model: reply.model, headers, reject: reply.reject }
+    }
+
+    /// The `script` stage of a choice.
+    pub fn transformer(&self) -> ScriptTransformer {
+        ScriptTransformer { scripts: self.clone() }
+    }
+
+    pub fn on_finish(&self, request_id: &str, model: Option<&str>, api_key_id: &str, summary: &StreamSummary) {
+        let arg = json!({
+            "request_id": request_id,
+            "model": model,
+            "api_key_id": api_key_id,
+            "outcome": summary.outcome.as_str(),
+            "completion_tokens": summary.completion_tokens,
+            "duration_ms": summary.duration.as_millis() as u64,
+        });
+        self.call(ON_FINISH, arg);
+    }
+}
+
+/// Runs `on_chunk` on one choice's chunks.
+pub struct ScriptTransformer {
+    scripts: Scripts,
+}
+
+impl ChunkTransformer for ScriptTransformer {
+    fn transform(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        for choice in &mut chunk.choices {
+            let arg = json!({ "index": choice.index, "content": choice.delta.content, "finish_reason": choice.finish_reason });
+            match self.scripts.call(ON_CHUNK, arg) {
+                Some(Value::Bool(false)) => return None,
+                Some(Value::Object(changes)) => {
+                    if let Some(content) = changes.get("content") {
+                        choice.delta.content = content.as_str().map(str::to_string);
+                    }
+                    if let Some(finish_reason) = changes.get("finish_reason") {
+                        choice.finish_reason = finish_reason.as_str().map(str::to_string);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(feature = "scripting")]
+mod rhai_runtime {
+    use super::*;
+    use anyhow::anyhow;
+    use rhai::{Dynamic, Engine, Scope, AST};
+    use tracing::debug;
+
+    struct RhaiRuntime {
+        engine: Engine,
+        ast: AST,
+    }
+
+    impl Runtime for RhaiRuntime {
+        fn defines(&self, hook: &str) -> bool {
+            self.ast.iter_functions().any(|f| f.name == hook)
+        }
+
+        fn call(&self, hook: &str, arg: Value) -> Result<Value> {
+            let arg = rhai::serde::to_dynamic(arg).map_err(|e| anyhow!("{}", e))?;
+            let result: Dynamic = self.engine.call_fn(&mut Scope::new(), &self.ast, hook, (arg,)).map_err(|e| anyhow!("{}", e))?;
+            rhai::serde::from_dynamic(&result).map_err(|e| anyhow!("{}", e))
+        }
+    }
+
+    pub(super) fn compile(source: &str, max_operations: u64) -> Result<Arc<dyn Runtime>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(max_operations);
+        engine.on_print(|text| info!(target: "script", "{}", text));
+        engine.on_debug(|text, _, pos| debug!(target: "script", %pos, "{}", text));
+        let ast = engine.compile(source).map_err(|e| anyhow!("{}", e))?;
+        Ok(Arc::new(RhaiRuntime { engine, ast }))
+    }
+
+    pub(super) fn load(path: &str, max_operations: u64) -> Result<Arc<dyn Runtime>> {
+        let source = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read script '{}': {}", path, e))?;
+        compile(&source, max_operations).map_err(|e| anyhow!("Failed to compile script '{}': {}", path, e))
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod rhai_runtime {
+    use super::*;
+    use anyhow::bail;
+
+    pub(super) fn load(_path: &str, _max_operations: u64) -> Result<Arc<dyn Runtime>> {
+        bail!("SCRIPT_FILE is set but this build lacks the `scripting` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    /// Tags requests for `gpt-4o` and drops chunks saying "drop".
+    struct Policy;
+
+    impl Runtime for Policy {
+        fn defines(&self, hook: &str) -> bool {
+            hook != ON_FINISH
+        }
+
+        fn call(&self, hook: &str, arg: Value) -> Result<Value> {
+            Ok(match hook {
+                ON_REQUEST if arg["model"] == "gpt-4o" => json!({ "model": "gpt-4o-search", "headers": { "x-policy": "tagged", "bad header": "x" } }),
+                ON_REQUEST => json!({ "reject": format!("{} messages", arg["messages"].as_array().unwrap().len()) }),
+                _ if arg["content"] == "drop" => json!(false),
+                _ => json!({ "content": arg["content"].as_str().map(str::to_uppercase) }),
+            })
+        }
+    }
+
+    #[test]
+    fn test_on_request_reroutes_tags_and_rejects() {
+        let scripts = Scripts { runtime: Some(Arc::new(Policy)) };
+        let req = |model: &str| -> OpenAiChatRequest {
+            serde_json::from_value(json!({ "model": model, "messages": [{ "role": "user", "content": "hi" }] })).unwrap()
+        };
+        let changes = scripts.on_request(&req("gpt-4o"), "key_a", &HeaderMap::new());
+        assert_eq!(changes.model.as_deref(), Some("gpt-4o-search"));
+        assert_eq!(changes.headers, [(HeaderName::from_static("x-policy"), HeaderValue::from_static("tagged"))]);
+        assert_eq!(scripts.on_request(&req("other"), "key_a", &HeaderMap::new()).reject.as_deref(), Some("1 messages"));
+        assert!(Scripts::default().on_request(&req("gpt-4o"), "key_a", &HeaderMap::new()).model.is_none());
+    }
+
+    #[test]
+    fn test_on_chunk_rewrites_and_drops() {
+        let mut transformer = Scripts { runtime: Some(Arc::new(Policy)) }.transformer();
+        let out = transformer.transform(test_chunk(Some("hi"), None)).unwrap();
+        assert_eq!(out.choices[0].delta.content.as_deref(), Some("HI"));
+        assert!(transformer.transform(test_chunk(Some("drop"), None)).is_none());
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn test_rhai_hooks() {
+        let script = r#"
+            fn on_request(req) { if req.model == "cheap" { #{ model: "gpt-4o-fast", headers: #{ "x-route": req.api_key_id } } } }
+            fn on_chunk(chunk) { if chunk.content == "secret" { false } else { #{ content: chunk.content + "!" } } }
+            fn spin(x) { loop {} }
+        "#;
+        let scripts = Scripts { runtime: Some(rhai_runtime::compile(script, 10_000).unwrap()) };
+        let req: OpenAiChatRequest = serde_json::from_value(json!({ "model": "cheap", "messages": [{ "content": "hi" }] })).unwrap();
+        let changes = scripts.on_request(&req, "key_a", &HeaderMap::new());
+        assert_eq!(changes.model.as_deref(), Some("gpt-4o-fast"));
+        assert_eq!(changes.headers[0].1, "key_a");
+        let mut transformer = scripts.transformer();
+        assert_eq!(transformer.transform(test_chunk(Some("hi"), None)).unwrap().choices[0].delta.content.as_deref(), Some("hi!"));
+        assert!(transformer.transform(test_chunk(Some("secret"), None)).is_none());
+        assert!(!scripts.defines(ON_FINISH));
+        assert!(scripts.call("spin", json!(1)).is_none());
+    }
+}
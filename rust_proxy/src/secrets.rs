@@ -0,0 +1,300 @@
+// Dev credentials from a secrets backend instead of plaintext env files.
+// SECRETS_PROVIDER selects HashiCorp Vault (`vault`, KV v1 or v2) or AWS
+// Secrets Manager (`aws`). The secret is a flat JSON object using the env
+// variable names, e.g. `{"SID": "...", "DEVICE_ID": "...", "FALLBACK_SID":
+// "..."}`; each client takes its prefixed keys first, like the env. Secrets
+// are fetched at startup and every SECRETS_REFRESH_SECS (300) after that.
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+use crate::config::env_or;
+use crate::dev_client::{CredentialStore, DevCredentials};
+use crate::utils;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A source of secret key/value pairs.
+pub trait SecretsProvider: Send + Sync {
+    /// Name for logs.
+    fn name(&self) -> &'static str;
+
+    fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, String>>>;
+}
+
+/// SECRETS_PROVIDER; `None` when unset.
+pub fn provider_from_env() -> Result<Option<Arc<dyn SecretsProvider>>> {
+    let provider: Arc<dyn SecretsProvider> = match std::env::var("SECRETS_PROVIDER").unwrap_or_default().trim() {
+        "" => return Ok(None),
+        "vault" => Arc::new(VaultProvider::from_env()?),
+        "aws" => Arc::new(AwsSecretsManager::from_env()?),
+        other => bail!("Unknown SECRETS_PROVIDER '{}', expected 'vault' or 'aws'", other),
+    };
+    Ok(Some(provider))
+}
+
+/// Keeps `store` in sync with the secrets: now, then every
+/// SECRETS_REFRESH_SECS. Fetch errors keep the current credentials.
+pub fn spawn_refresh(provider: Arc<dyn SecretsProvider>, prefix: String, store: CredentialStore) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        warn!(provider = provider.name(), "No async runtime, credentials will not be loaded from the secrets provider");
+        return;
+    };
+    let interval = Duration::from_secs(env_or("SECRETS_REFRESH_SECS", 300u64).max(1));
+    info!(provider = provider.name(), prefix, ?interval, "Loading Dev credentials from secrets provider");
+    runtime.spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = refresh_once(provider.as_ref(), &prefix, &store).await {
+                error!(provider = provider.name(), prefix, "Failed to fetch Dev credentials: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Fetches the secrets once and applies them to `store`; returns whether the
+/// credentials changed.
+pub async fn refresh_once(provider: &dyn SecretsProvider, prefix: &str, store: &CredentialStore) -> Result<bool> {
+    let secrets = provider.fetch().await?;
+    let changed = store.set(apply(&store.get(), &secrets, prefix));
+    if changed {
+        info!(provider = provider.name(), prefix, "Dev credentials updated from secrets provider");
+    }
+    Ok(changed)
+}
+
+/// `current` with DEVICE_ID and SID replaced by the secrets that define them.
+fn apply(current: &DevCredentials, secrets: &HashMap<String, String>, prefix: &str) -> DevCredentials {
+    let lookup = |key: &str| {
+        secrets.get(&format!("{}{}", prefix, key)).or_else(|| secrets.get(key)).filter(|v| !v.is_empty()).cloned()
+    };
+    DevCredentials {
+        device_id: lookup("DEVICE_ID").unwrap_or_else(|| current.device_id.clone()),
+        sid: lookup("SID").unwrap_or_else(|| current.sid.clone()),
+    }
+}
+
+/// String-valued fields of a JSON object.
+fn string_map(value: &Value) -> Result<HashMap<String, String>> {
+    let object = value.as_object().ok_or_else(|| anyhow!("secret is not a JSON object"))?;
+    Ok(object.iter().filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string()))).collect())
+}
+
+/// HashiCorp Vault: VAULT_ADDR, VAULT_TOKEN and VAULT_SECRET_PATH (the API
+/// path after `/v1/`, e.g. `secret/data/dev-proxy` for KV v2).
+pub struct VaultProvider {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl VaultProvider {
+    pub fn new(addr: &str, token: String, path: &str) -> Self {
+        let url = format!("{}/v1/{}", addr.trim_end_matches('/'), path.trim_start_matches('/'));
+        Self { client: reqwest::Client::new(), url, token }
+    }
+
+    fn from_env() -> Result<Self> {
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty()).with_context(|| format!("{} is not set", key));
+        Ok(Self::new(&var("VAULT_ADDR")?, var("VAULT_TOKEN")?, &var("VAULT_SECRET_PATH")?))
+    }
+
+    fn parse(body: &Value) -> Result<HashMap<String, String>> {
+        let data = body.get("data").ok_or_else(|| anyhow!("Vault response has no 'data'"))?;
+        // KV v2 nests the secret in data.data, KV v1 returns it as data
+        string_map(data.get("data").filter(|d| d.is_object()).unwrap_or(data))
+    }
+}
+
+impl SecretsProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, String>>> {
+        async move {
+            let body: Value = self
+                .client
+                .get(&self.url)
+                .header("X-Vault-Token", &self.token)
+                .timeout(FETCH_TIMEOUT)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .context("Vault request failed")?
+                .json()
+                .await
+                .context("Vault returned invalid JSON")?;
+            Self::parse(&body)
+        }
+        .boxed()
+    }
+}
+
+/// AWS Secrets Manager `GetSecretValue` for AWS_SECRET_ID, signed with
+/// Signature Version 4 using the standard AWS_REGION, AWS_ACCESS_KEY_ID,
+/// AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN variables (set by Lambda).
+pub struct AwsSecretsManager {
+    client: reqwest::Client,
+    region: String,
+    secret_id: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsSecretsManager {
+    fn from_env() -> Result<Self> {
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        let required = |key: &str| var(key).with_context(|| format!("{} is not set", key));
+        Ok(Self {
+            client: reqwest::Client::new(),
+            region: var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")).context("AWS_REGION is not set")?,
+            secret_id: required("AWS_SECRET_ID")?,
+            access_key_id: required("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+
+    fn host(&self) -> String {
+        format!("secretsmanager.{}.amazonaws.com", self.region)
+    }
+
+    /// Headers for a SigV4-signed `GetSecretValue` call with `body` at
+    /// `now_secs`.
+    fn signed_headers(&self, body: &str, now_secs: u64) -> Vec<(&'static str, String)> {
+        let amz_date = amz_date(now_secs);
+        let date = &amz_date[..8];
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", self.host()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", "secretsmanager.GetSecretValue".to_string()));
+
+        let signed_names = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_names,
+            utils::sha256_hex(body.as_bytes())
+        );
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            utils::sha256_hex(canonical_request.as_bytes())
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, "secretsmanager");
+        let signature = hex::encode(utils::hmac_sha256(&key, string_to_sign.as_bytes()));
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_names, signature
+            ),
+        ));
+        headers
+    }
+}
+
+impl SecretsProvider for AwsSecretsManager {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, String>>> {
+        async move {
+            let body = serde_json::json!({ "SecretId": self.secret_id }).to_string();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let mut request = self.client.post(format!("https://{}/", self.host())).timeout(FETCH_TIMEOUT);
+            for (name, value) in self.signed_headers(&body, now) {
+                if name != "host" {
+                    request = request.header(name, value);
+                }
+            }
+            let response: Value = request
+                .body(body)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .context("Secrets Manager request failed")?
+                .json()
+                .await
+                .context("Secrets Manager returned invalid JSON")?;
+            let secret = response
+                .get("SecretString")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("secret '{}' has no SecretString", self.secret_id))?;
+            string_map(&serde_json::from_str(secret).context("SecretString is not JSON")?)
+        }
+        .boxed()
+    }
+}
+
+/// SigV4 signing key for `date` (YYYYMMDD).
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let k_date = utils::hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    let k_region = utils::hmac_sha256(&k_date, region.as_bytes());
+    let k_service = utils::hmac_sha256(&k_region, service.as_bytes());
+    utils::hmac_sha256(&k_service, b"aws4_request")
+}
+
+/// `YYYYMMDDTHHMMSSZ` for Unix seconds.
+fn amz_date(secs: u64) -> String {
+    let (year, month, day) = utils::civil_date((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_prefers_prefixed_keys() {
+        let current = DevCredentials { device_id: "d0".to_string(), sid: "s0".to_string() };
+        let secrets: HashMap<String, String> =
+            [("SID", "s1"), ("FALLBACK_SID", "s2")].map(|(k, v)| (k.to_string(), v.to_string())).into();
+        assert_eq!(apply(&current, &secrets, ""), DevCredentials { device_id: "d0".to_string(), sid: "s1".to_string() });
+        assert_eq!(apply(&current, &secrets, "FALLBACK_").sid, "s2");
+    }
+
+    #[test]
+    fn test_vault_kv_v1_and_v2() {
+        let v2 = serde_json::json!({"data": {"data": {"SID": "s"}, "metadata": {"version": 3}}});
+        assert_eq!(VaultProvider::parse(&v2).unwrap()["SID"], "s");
+        let v1 = serde_json::json!({"data": {"SID": "s", "DEVICE_ID": "d"}});
+        assert_eq!(VaultProvider::parse(&v1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sigv4_signing_key_and_date() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+        assert_eq!(amz_date(0), "19700101T000000Z");
+        assert_eq!(amz_date(1_709_210_096), "20240229T123456Z");
+    }
+}
@@ -0,0 +1,101 @@
+// Startup canary: signs and sends one tiny request through DevApiClient and
+// waits for streamed content, so broken credentials or a broken signer are
+// found at deploy time rather than on the first real user request.
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::stream::{Stream, StreamExt};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::config::env_or;
+use crate::dev_client::{DevApiClient, DevRequestOptions};
+use crate::sse_processor::{self, process_dev_bytes_stream_unfold, ChatCompletionChunk, STREAM_ERROR_PREFIX};
+
+#[derive(Debug, Clone)]
+pub struct SelfTestConfig {
+    /// Run the canary before serving (`--self-test` or `SELF_TEST=true`).
+    pub enabled: bool,
+    pub prompt: String,
+    /// Deadline for the whole canary, from signing to first content.
+    pub timeout: Duration,
+}
+
+impl SelfTestConfig {
+    pub fn from_env_and_args() -> Self {
+        Self {
+            enabled: std::env::args().any(|arg| arg == "--self-test") || env_or("SELF_TEST", false),
+            prompt: std::env::var("SELF_TEST_PROMPT")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "Reply with the single word: pong".to_string()),
+            timeout: Duration::from_secs(env_or("SELF_TEST_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+/// Runs the canary and returns the first content fragment that streamed back.
+pub async fn run(client: &DevApiClient, config: &SelfTestConfig) -> Result<String> {
+    let started = Instant::now();
+    let content = tokio::time::timeout(config.timeout, canary(client, &config.prompt))
+        .await
+        .map_err(|_| anyhow!("no content received within {:?}; the upstream may be stalling", config.timeout))??;
+    info!(elapsed_ms = started.elapsed().as_millis() as u64, "Self-test passed");
+    Ok(content)
+}
+
+async fn canary(client: &DevApiClient, prompt: &str) -> Result<String> {
+    let options = DevRequestOptions {
+        language: Some("All".to_string()),
+        ..Default::default()
+    };
+    let response = client.send_request(prompt, options.clone()).await
+        .context("canary request was rejected (check API_ENDPOINT, DEVICE_ID, SID and the WASM signer)")?;
+    debug!(status = %response.status(), "Canary request accepted, reading stream");
+
+    let chunks = process_dev_bytes_stream_unfold(response.bytes_stream(), options, sse_processor::new_completion_id());
+    first_content(chunks).await
+}
+
+/// Consumes `chunks` until the first non-empty content delta. A Dev error
+/// event, a transport error or a stream without content is a failure.
+async fn first_content(chunks: impl Stream<Item = Result<ChatCompletionChunk>>) -> Result<String> {
+    let mut chunks = std::pin::pin!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.context("stream broke before any content arrived")?;
+        let Some(content) = chunk.choices.first().and_then(|c| c.delta.content.as_deref()) else {
+            continue;
+        };
+        if let Some(message) = content.strip_prefix(STREAM_ERROR_PREFIX) {
+            bail!("Dev reported an error event: {}", message);
+        }
+        if !content.is_empty() {
+            return Ok(content.to_string());
+        }
+    }
+    bail!("stream ended without any content events (the request may not be signed correctly)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    #[tokio::test]
+    async fn test_first_content_skips_empty_deltas() {
+        let chunks = futures_util::stream::iter(vec![Ok(test_chunk(None, None)), Ok(test_chunk(Some(""), None)), Ok(test_chunk(Some("pong"), None))]);
+        assert_eq!(first_content(chunks).await.unwrap(), "pong");
+    }
+
+    #[tokio::test]
+    async fn test_first_content_reports_dev_error() {
+        let chunks = futures_util::stream::iter(vec![Ok(test_chunk(Some("[STREAM_ERROR]: invalid sign"), None))]);
+        let err = first_content(chunks).await.unwrap_err();
+        assert!(err.to_string().contains("invalid sign"));
+    }
+
+    #[tokio::test]
+    async fn test_first_content_fails_on_empty_stream() {
+        let chunks = futures_util::stream::iter(vec![Ok(test_chunk(None, None))]);
+        assert!(first_content(chunks).await.is_err());
+    }
+}
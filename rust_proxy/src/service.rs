@@ -0,0 +1,177 @@
+// The Dev→OpenAI translation as a `tower::Service`, for Rust applications
+// that embed it instead of going through HTTP. `ChatProxyService` takes an
+// `OpenAiChatRequest` and answers with the stream of its OpenAI chunks: the
+// model's mode, the request's `x_devv` options, `n` choices, the upstream
+// failover and the chunk transformers apply as on `/v1/chat/completions`,
+// with the same errors. What
+// belongs to the HTTP layer (API keys, quotas, system prompts and templates,
+// moderation, SSE framing, replay, webhooks, ...) stays in the router.
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::StreamExt;
+use http::StatusCode;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tracing::{debug, info};
+
+use crate::dev_client::{DevApiClient, DevRequestOptions, UpstreamQueueTimeout, UpstreamStatusError};
+use crate::dev_errors::DevError;
+use crate::error::ApiError;
+use crate::failover::{CircuitOpen, Upstreams};
+use crate::model_modes::ModelModes;
+use crate::models::OpenAiChatRequest;
+use crate::sse_processor;
+use crate::scripting::Scripts;
+use crate::transform::{Builtins, ChoiceContext, TransformChain};
+use crate::utils;
+
+pub use crate::transform::ChunkStream;
+
+#[derive(Clone)]
+pub struct ChatProxyService {
+    upstreams: Arc<Upstreams>,
+    modes: Arc<ModelModes>,
+    transforms: TransformChain,
+}
+
+impl ChatProxyService {
+    /// Sends through `upstreams`, with the built-in model modes and only the
+    /// chunk transformers of the request's citation and footer options.
+    pub fn new(upstreams: Upstreams) -> Self {
+        Self { upstreams: Arc::new(upstreams), modes: Arc::new(ModelModes::builtin()), transforms: TransformChain::request_options() }
+    }
+
+    pub fn with_modes(mut self, modes: ModelModes) -> Self {
+        self.modes = Arc::new(modes);
+        self
+    }
+
+    /// Runs every choice through `transforms`; without the `citations` and
+    /// `footers` stages the request's citation and footer options do nothing.
+    pub fn with_transforms(mut self, transforms: TransformChain) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Upstreams, model modes and chunk transformers configured like the proxy's.
+    pub fn from_env(primary: DevApiClient) -> Self {
+        Self::new(Upstreams::from_env(primary)).with_modes(ModelModes::from_env()).with_transforms(TransformChain::from_env(&Builtins::from_env(&Scripts::from_env(), &Arc::default())))
+    }
+
+    async fn complete(self, mut req: OpenAiChatRequest) -> Result<ChunkStream, ApiError> {
+        let Self { upstreams, modes, transforms } = self;
+        let content = req.messages.last().map(|m| m.text()).unwrap_or_default();
+        if content.is_empty() {
+            return Err(ApiError::invalid_param("messages", "Request messages are empty or missing content"));
+        }
+        let n = req.n.unwrap_or(1);
+        if n == 0 || n > upstreams.max_choices() {
+            return Err(ApiError::invalid_param("n", format!("n must be between 1 and {}", upstreams.max_choices())).with_code("invalid_n"));
+        }
+
+        let request_id = utils::generate_uuidv4();
+        let (dev_model, mode) = modes.resolve(req.model.as_deref());
+        let mut options = DevRequestOptions {
+            model: dev_model,
+            variant: upstreams.variant_for(&request_id),
+            request_id: Some(request_id),
+            ..Default::default()
+        };
+        mode.merge(req.x_devv.take().unwrap_or_default()).apply(&mut options);
+
+        let sends = (0..n).map(|_| upstreams.send(&content, options.clone()));
+        let routed = futures_util::future::try_join_all(sends).await.map_err(|e| upstream_error(&e))?;
+        if let Some(failed) = routed.iter().find(|routed| !routed.response.status().is_success()) {
+            let status = failed.response.status();
+            return Err(ApiError::new(StatusCode::BAD_GATEWAY, "server_error", format!("Backend service returned status: {}", status)));
+        }
+
+        let completion_id = sse_processor::new_completion_id();
+        info!(completion_id, n, "Streaming chat completion");
+        let choices = routed.into_iter().zip(0..).map(|(routed, index)| {
+            debug!(upstream = routed.upstream.as_str(), index, "Dev answered");
+            let mut options = options.clone();
+            let context = ChoiceContext::attach(&mut options);
+            let chunks = sse_processor::process_dev_bytes_stream_unfold(routed.response.bytes_stream(), options, completion_id.clone())
+                .map(move |chunk_result| {
+                    chunk_result.map(|mut chunk| {
+                        for choice in &mut chunk.choices {
+                            choice.index = index;
+                        }
+                        chunk
+                    })
+                });
+            transforms.apply(chunks, &context)
+        });
+        Ok(futures_util::stream::select_all(choices).boxed())
+    }
+}
+
+impl tower::Service<OpenAiChatRequest> for ChatProxyService {
+    type Response = ChunkStream;
+    type Error = ApiError;
+    type Future = BoxFuture<'static, Result<ChunkStream, ApiError>>;
+
+    /// Always ready: upstream capacity is waited for (or refused) per request.
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ApiError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: OpenAiChatRequest) -> Self::Future {
+        Box::pin(self.clone().complete(req))
+    }
+}
+
+/// The OpenAI error for a failed `Upstreams::send`: our own upstream cap and
+/// an open circuit are 503s, Dev failures keep their meaning, anything else
+/// is a 502.
+pub fn upstream_error(e: &anyhow::Error) -> ApiError {
+    if let Some(busy) = e.downcast_ref::<UpstreamQueueTimeout>() {
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", busy.to_string())
+            .with_code("upstream_busy")
+            .with_retry_after(1);
+    }
+    if let Some(open) = e.downcast_ref::<CircuitOpen>() {
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", open.to_string())
+            .with_code("upstream_unavailable")
+            .with_retry_after(open.retry_after.as_secs().max(1));
+    }
+    match e.downcast_ref::<UpstreamStatusError>() {
+        Some(status_error) => DevError::parse(Some(status_error.status), &status_error.body).to_api_error(status_error.retry_after),
+        None => ApiError::new(StatusCode::BAD_GATEWAY, "server_error", format!("Failed to contact backend service: {}", e))
+            .with_code("upstream_unreachable"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failover::CircuitBreaker;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn request(body: serde_json::Value) -> OpenAiChatRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_service_rejects_before_calling_dev() {
+        let upstreams = Upstreams::new(DevApiClient::new().unwrap(), None, CircuitBreaker::new(5, Duration::from_secs(30)));
+        let service = ChatProxyService::new(upstreams.with_max_choices(2));
+
+        let Err(empty) = service.clone().oneshot(request(serde_json::json!({ "messages": [] }))).await else { panic!("empty messages accepted") };
+        assert_eq!((empty.status, empty.param), (StatusCode::BAD_REQUEST, Some("messages")));
+
+        let hi = serde_json::json!({ "messages": [{ "role": "user", "content": "hi" }], "n": 3 });
+        let Err(too_many) = service.oneshot(request(hi)).await else { panic!("n above the limit accepted") };
+        assert_eq!((too_many.status, too_many.code), (StatusCode::BAD_REQUEST, Some("invalid_n")));
+    }
+
+    #[test]
+    fn test_upstream_error_mapping() {
+        let open = upstream_error(&anyhow::Error::new(CircuitOpen { retry_after: Duration::from_millis(200) }));
+        assert_eq!((open.status, open.code, open.retry_after), (StatusCode::SERVICE_UNAVAILABLE, Some("upstream_unavailable"), Some(1)));
+        let unreachable = upstream_error(&anyhow::anyhow!("connection refused"));
+        assert_eq!((unreachable.status, unreachable.code), (StatusCode::BAD_GATEWAY, Some("upstream_unreachable")));
+    }
+}
@@ -0,0 +1,166 @@
+// Recovery from expired Dev sessions. When Dev answers 401 for a reason
+// other than the signature timestamp, `DevApiClient` asks the refresher for
+// a new session and retries the request once. The session comes from the
+// refresh endpoint DEV_REFRESH_URL when set (called with the current
+// device-id/os-type/sid headers; the new sid is read from a `sid` cookie,
+// a `sid` response header or a `sid`/`data.sid`/`token` JSON field), otherwise
+// from re-reading the secrets provider. Concurrent 401s share one refresh.
+
+use anyhow::{anyhow, Context, Result};
+use http::{header, HeaderMap};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::dev_client::{CredentialStore, DevCredentials};
+use crate::secrets::{self, SecretsProvider};
+
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Session refreshes by `result` (refreshed / unchanged / failed),
+/// registered by `Metrics`.
+pub static REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("dev_session_refreshes_total", "Dev session refreshes after a 401 by result"), &["result"])
+        .expect("valid counter")
+});
+
+enum Source {
+    Endpoint { client: reqwest::Client, url: String },
+    Secrets { provider: Arc<dyn SecretsProvider>, prefix: String },
+}
+
+pub struct SessionRefresher {
+    source: Source,
+    store: CredentialStore,
+    /// Serializes refreshes so a burst of 401s triggers only one.
+    in_progress: tokio::sync::Mutex<()>,
+}
+
+impl SessionRefresher {
+    /// Refreshes through `url` with the upstream client.
+    pub fn endpoint(client: reqwest::Client, url: String, store: CredentialStore) -> Self {
+        Self { source: Source::Endpoint { client, url }, store, in_progress: Default::default() }
+    }
+
+    /// Refreshes by fetching the secrets again.
+    pub fn secrets(provider: Arc<dyn SecretsProvider>, prefix: String, store: CredentialStore) -> Self {
+        Self { source: Source::Secrets { provider, prefix }, store, in_progress: Default::default() }
+    }
+
+    /// Gets a new session for the one that was rejected (`rejected_sid`).
+    /// Returns whether the stored credentials now differ from it, i.e.
+    /// whether a retry can help.
+    pub async fn refresh(&self, rejected_sid: &str, os_type: &str) -> bool {
+        let _guard = self.in_progress.lock().await;
+        // Another request may have refreshed while we waited
+        if self.store.get().sid != rejected_sid {
+            REFRESHES.with_label_values(&["refreshed"]).inc();
+            return true;
+        }
+        let result = match &self.source {
+            Source::Endpoint { client, url } => self.refresh_from_endpoint(client, url, os_type).await,
+            Source::Secrets { provider, prefix } => secrets::refresh_once(provider.as_ref(), prefix, &self.store).await,
+        };
+        let (label, changed) = match result {
+            Ok(true) => {
+                info!("Dev session refreshed after 401");
+                ("refreshed", true)
+            }
+            Ok(false) => {
+                warn!("Dev session refresh returned the rejected session");
+                ("unchanged", false)
+            }
+            Err(e) => {
+                warn!("Dev session refresh failed: {:#}", e);
+                ("failed", false)
+            }
+        };
+        REFRESHES.with_label_values(&[label]).inc();
+        changed
+    }
+
+    async fn refresh_from_endpoint(&self, client: &reqwest::Client, url: &str, os_type: &str) -> Result<bool> {
+        let current = self.store.get();
+        let response = client
+            .post(url)
+            .header("device-id", &current.device_id)
+            .header("os-type", os_type)
+            .header("sid", &current.sid)
+            .timeout(REFRESH_TIMEOUT)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("Dev session refresh request failed")?;
+        let headers = response.headers().clone();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        let sid = sid_from_response(&headers, &body).ok_or_else(|| anyhow!("refresh response carries no sid"))?;
+        Ok(self.store.set(DevCredentials { device_id: current.device_id.clone(), sid }))
+    }
+}
+
+/// The session id in a refresh response, in order of preference: `sid`
+/// cookie, `sid` header, JSON body field.
+fn sid_from_response(headers: &HeaderMap, body: &Value) -> Option<String> {
+    let from_cookie = headers.get_all(header::SET_COOKIE).iter().find_map(|cookie| {
+        let (name, value) = cookie.to_str().ok()?.split(';').next()?.split_once('=')?;
+        (name.trim() == "sid").then(|| value.trim().to_string())
+    });
+    let from_header = || headers.get("sid").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let from_body = || {
+        ["/sid", "/data/sid", "/token", "/data/token"]
+            .iter()
+            .find_map(|pointer| body.pointer(pointer).and_then(Value::as_str))
+            .map(str::to_string)
+    };
+    from_cookie.or_else(from_header).or_else(from_body).filter(|sid| !sid.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::BoxFuture;
+    use futures_util::FutureExt;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_sid_from_response_sources() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, "lang=en; Path=/".parse().unwrap());
+        headers.append(header::SET_COOKIE, "sid=fromcookie; Path=/; HttpOnly".parse().unwrap());
+        headers.insert("sid", "fromheader".parse().unwrap());
+        let body = serde_json::json!({"data": {"sid": "frombody"}});
+        assert_eq!(sid_from_response(&headers, &body).as_deref(), Some("fromcookie"));
+        headers.remove(header::SET_COOKIE);
+        assert_eq!(sid_from_response(&headers, &body).as_deref(), Some("fromheader"));
+        assert_eq!(sid_from_response(&HeaderMap::new(), &body).as_deref(), Some("frombody"));
+        assert_eq!(sid_from_response(&HeaderMap::new(), &Value::Null), None);
+    }
+
+    struct CountingProvider(AtomicUsize);
+
+    impl SecretsProvider for CountingProvider {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, String>>> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            async move { Ok(HashMap::from([("SID".to_string(), format!("sid{}", n))])) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refreshes_share_one_fetch() {
+        let store = CredentialStore::new(DevCredentials { device_id: "d".to_string(), sid: "sid0".to_string() });
+        let provider = Arc::new(CountingProvider(AtomicUsize::new(0)));
+        let refresher = SessionRefresher::secrets(provider.clone(), String::new(), store.clone());
+        let (a, b) = tokio::join!(refresher.refresh("sid0", "3"), refresher.refresh("sid0", "3"));
+        assert!(a && b);
+        assert_eq!(provider.0.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get().sid, "sid1");
+    }
+}
@@ -0,0 +1,110 @@
+// Reuse of request signatures. A signature covers nonce, timestamp, device id
+// and content, so a retried or hedged request with the same content can be
+// sent with the same (nonce, timestamp, sign) triple instead of invoking the
+// signer again. Entries live for one SIGNATURE_CACHE_SECS time bucket; the
+// cache is off by default since it reuses nonces, which Dev could start to
+// reject as replays.
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::config::env_or;
+use crate::utils;
+
+/// The signed headers of one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signed {
+    pub nonce: String,
+    pub timestamp: String,
+    pub signature: String,
+}
+
+struct Cached {
+    signed: Signed,
+    bucket: u64,
+}
+
+pub struct SignatureCache {
+    bucket_secs: u64,
+    entries: Mutex<HashMap<String, Cached>>,
+}
+
+/// Cache lookups by `result` (hit / miss), registered by `Metrics`.
+pub static LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("signature_cache_lookups_total", "Signature cache lookups by result"), &["result"])
+        .expect("valid counter")
+});
+
+static SIGNATURE_CACHE: Lazy<Option<SignatureCache>> = Lazy::new(|| {
+    let bucket_secs: u64 = env_or("SIGNATURE_CACHE_SECS", 0);
+    (bucket_secs > 0).then(|| {
+        info!(bucket_secs, "Signature cache enabled");
+        SignatureCache::new(bucket_secs)
+    })
+});
+
+impl SignatureCache {
+    pub fn new(bucket_secs: u64) -> Self {
+        Self { bucket_secs: bucket_secs.max(1), entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// The process-wide cache; `None` unless SIGNATURE_CACHE_SECS is set.
+    pub fn get_instance() -> Option<&'static Self> {
+        SIGNATURE_CACHE.as_ref()
+    }
+
+    /// Cache key for a request: everything but nonce and timestamp that the
+    /// signature depends on, plus the signer variant.
+    pub fn key(variant: &str, device_id: &str, content: &str) -> String {
+        utils::sha256_hex([variant, device_id, content].join("\0").as_bytes())
+    }
+
+    /// The signature cached for `key` in the current bucket of `now_secs`,
+    /// otherwise the result of `sign`, which is then cached.
+    pub fn get_or_sign(&self, key: &str, now_secs: u64, sign: impl FnOnce() -> Result<Signed>) -> Result<Signed> {
+        let bucket = now_secs / self.bucket_secs;
+        if let Some(cached) = self.entries.lock().unwrap().get(key).filter(|c| c.bucket == bucket) {
+            LOOKUPS.with_label_values(&["hit"]).inc();
+            return Ok(cached.signed.clone());
+        }
+        LOOKUPS.with_label_values(&["miss"]).inc();
+        let signed = sign()?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, cached| cached.bucket == bucket);
+        entries.insert(key.to_string(), Cached { signed: signed.clone(), bucket });
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(nonce: &str) -> Result<Signed> {
+        Ok(Signed { nonce: nonce.to_string(), timestamp: "0".to_string(), signature: "sig".to_string() })
+    }
+
+    #[test]
+    fn test_reuses_signature_within_bucket() {
+        let cache = SignatureCache::new(10);
+        let key = SignatureCache::key("stable", "device", "hello");
+        assert_eq!(cache.get_or_sign(&key, 100, || signed("a")).unwrap().nonce, "a");
+        assert_eq!(cache.get_or_sign(&key, 109, || signed("b")).unwrap().nonce, "a");
+        // Next bucket, and a different payload
+        assert_eq!(cache.get_or_sign(&key, 110, || signed("c")).unwrap().nonce, "c");
+        let other = SignatureCache::key("stable", "device", "bye");
+        assert_eq!(cache.get_or_sign(&other, 110, || signed("d")).unwrap().nonce, "d");
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sign_errors_are_not_cached() {
+        let cache = SignatureCache::new(10);
+        assert!(cache.get_or_sign("k", 0, || anyhow::bail!("boom")).is_err());
+        assert_eq!(cache.get_or_sign("k", 0, || signed("a")).unwrap().nonce, "a");
+    }
+}
@@ -0,0 +1,252 @@
+// Selection of the request signer. The WASM module from Dev's web client is
+// the default; builds with the `native-signer` feature can use the Rust port
+// instead (SIGNER=native), avoiding the WASM runtime's startup cost. Built
+// without the `wasm-signer` feature, the proxy does not link the runtime and
+// signs natively by default.
+//
+// When Dev ships a new signer bundle the WASM module can be replaced without
+// a redeploy: from WASM_URL at startup, whenever the file at WASM_PATH
+// changes (polled every WASM_WATCH_SECS), or via `POST /admin/signer/reload`.
+// `POST /debug/sign` shows the signed headers for a payload, to diagnose
+// 401s from Dev.
+
+use anyhow::{bail, Result};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::StatusCode;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+use crate::canary::Variant;
+use crate::config::env_or;
+use crate::error::ApiError;
+use crate::failover::{UpstreamKind, Upstreams};
+use crate::utils;
+use crate::wasm_signer::{self, ModuleInfo, WasmSigner};
+
+#[derive(Clone, Copy)]
+pub enum Signer {
+    Wasm(&'static WasmSigner),
+    #[cfg(feature = "native-signer")]
+    Native,
+}
+
+impl Signer {
+    /// SIGNER: `wasm` (default) or `native` (default without `wasm-signer`).
+    pub fn from_env() -> Result<Self> {
+        let default = if cfg!(feature = "wasm-signer") { "wasm" } else { "native" };
+        match std::env::var("SIGNER").ok().as_deref().map(str::trim).filter(|s| !s.is_empty()).unwrap_or(default) {
+            "wasm" => Ok(Self::Wasm(WasmSigner::get_instance()?)),
+            #[cfg(feature = "native-signer")]
+            "native" => Ok(Self::Native),
+            #[cfg(not(feature = "native-signer"))]
+            "native" => bail!("SIGNER=native requires a build with the 'native-signer' feature"),
+            other => bail!("Unknown SIGNER '{}', expected 'wasm' or 'native'", other),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Wasm(_) => "wasm",
+            #[cfg(feature = "native-signer")]
+            Self::Native => "native",
+        }
+    }
+
+    pub fn sign(&self, nonce: &str, timestamp: &str, device_id: &str, query: &str) -> Result<String> {
+        match self {
+            Self::Wasm(signer) => signer.sign(nonce, timestamp, device_id, query),
+            #[cfg(feature = "native-signer")]
+            Self::Native => Ok(crate::native_signer::sign(nonce, timestamp, device_id, query)),
+        }
+    }
+}
+
+/// Loads WASM_URL over the bundled module and starts watching WASM_PATH when
+/// WASM_WATCH_SECS is set. Failures are logged; the loaded module stays.
+pub async fn start_module_reloading() {
+    let Ok(signer) = WasmSigner::get_instance() else { return };
+    if let Some(url) = std::env::var("WASM_URL").ok().filter(|u| !u.is_empty())
+        && let Err(e) = signer.reload_from_url(&url).await
+    {
+        error!(url, "Failed to load WASM module from WASM_URL: {:#}", e);
+    }
+    let watch_secs: u64 = env_or("WASM_WATCH_SECS", 0);
+    if watch_secs > 0 {
+        signer.spawn_file_watch(wasm_signer::module_path(), Duration::from_secs(watch_secs));
+    }
+}
+
+/// Body of `POST /admin/signer/reload`; without one, WASM_URL (if set) or
+/// WASM_PATH is reloaded.
+#[derive(Debug, Default, Deserialize, utoipa::ToSchema)]
+pub struct ReloadRequest {
+    /// Module file on the proxy host.
+    pub path: Option<String>,
+    /// URL to download the module from.
+    pub url: Option<String>,
+}
+
+fn module_response(signer: &WasmSigner, module: ModuleInfo) -> Response {
+    Json(serde_json::json!({
+        "object": "signer.module",
+        "pool_size": signer.pool_size(),
+        "module": module,
+    }))
+    .into_response()
+}
+
+fn signer_unavailable(e: anyhow::Error) -> Response {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", format!("{:#}", e))
+        .with_code("signer_unavailable")
+        .into_response()
+}
+
+/// `GET /admin/signer`: the active WASM module.
+#[utoipa::path(get, path = "/admin/signer", tag = "admin", security(("admin_token" = [])), responses(
+    (status = 200, description = "`{\"object\": \"signer.module\", \"pool_size\", \"module\": ModuleInfo}`", body = Object),
+    (status = 503, description = "The WASM signer failed to load", body = ErrorBody),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn signer_info_handler() -> Response {
+    match WasmSigner::get_instance() {
+        Ok(signer) => module_response(signer, signer.module_info()),
+        Err(e) => signer_unavailable(e),
+    }
+}
+
+/// `POST /admin/signer/reload`: loads a new WASM module and swaps it in once
+/// it has produced a test signature.
+#[utoipa::path(post, path = "/admin/signer/reload", tag = "admin", security(("admin_token" = [])),
+    request_body(content = Option<ReloadRequest>, description = "Module to load; defaults to WASM_URL or WASM_PATH"),
+    responses(
+        (status = 200, description = "The module now in use", body = Object),
+        (status = 400, description = "The module is invalid or does not sign; the old one stays active", body = ErrorBody),
+        (status = 502, description = "The module could not be downloaded", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn reload_signer_handler(body: Option<Json<ReloadRequest>>) -> Response {
+    let signer = match WasmSigner::get_instance() {
+        Ok(signer) => signer,
+        Err(e) => return signer_unavailable(e),
+    };
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    let url = request.url.or_else(|| std::env::var("WASM_URL").ok().filter(|u| !u.is_empty()));
+    let (bytes, source) = match (request.path, url) {
+        (Some(path), _) => match tokio::fs::read(&path).await {
+            Ok(bytes) => (bytes, path),
+            Err(e) => {
+                return ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", format!("Cannot read '{}': {}", path, e))
+                    .with_code("invalid_signer_module")
+                    .into_response();
+            }
+        },
+        (None, Some(url)) => match wasm_signer::fetch_module(&url).await {
+            Ok(bytes) => (bytes, url),
+            Err(e) => {
+                return ApiError::new(StatusCode::BAD_GATEWAY, "server_error", format!("{:#}", e))
+                    .with_code("signer_download_failed")
+                    .into_response();
+            }
+        },
+        (None, None) => {
+            let path = wasm_signer::module_path();
+            match tokio::fs::read(&path).await {
+                Ok(bytes) => (bytes, path),
+                Err(e) => return signer_unavailable(anyhow::anyhow!("Cannot read '{}': {}", path, e)),
+            }
+        }
+    };
+    // Compiling and instantiating the pool is CPU-bound
+    let result = tokio::task::spawn_blocking(move || signer.reload(&bytes, &source)).await;
+    match result {
+        Ok(Ok(module)) => module_response(signer, module),
+        Ok(Err(e)) => {
+            warn!("Rejected WASM signer module: {:#}", e);
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", format!("{:#}", e))
+                .with_code("invalid_signer_module")
+                .into_response()
+        }
+        Err(e) => signer_unavailable(e.into()),
+    }
+}
+
+/// Body of `POST /debug/sign`.
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct DebugSignRequest {
+    /// Content to sign, i.e. the prompt sent to Dev.
+    pub content: String,
+    /// Unix seconds; defaults to now, corrected for upstream clock skew.
+    pub timestamp: Option<u64>,
+    /// Defaults to a fresh UUID.
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub variant: Variant,
+    /// Defaults to `primary`.
+    pub upstream: Option<UpstreamKind>,
+}
+
+/// `POST /debug/sign`: the headers the proxy would send to Dev for a payload.
+/// The session id is redacted.
+#[utoipa::path(post, path = "/debug/sign", tag = "admin", security(("admin_token" = [])),
+    request_body = DebugSignRequest,
+    responses(
+        (status = 200, description = "Signer, signature and signed headers", body = Object, example = json!({
+            "object": "debug.sign", "upstream": "primary", "variant": "stable", "signer": "wasm",
+            "url": "https://...", "server_time": 1700000000, "clock_offset_secs": 0, "signature": "49f98cad...",
+            "headers": {"nonce": "nonce", "timestamp": "1700000000", "sign": "49f98cad...", "sid": "[redacted]"},
+        })),
+        (status = 400, description = "The requested upstream is not configured", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn debug_sign_handler(
+    State(upstreams): State<Arc<Upstreams>>,
+    Json(request): Json<DebugSignRequest>,
+) -> Response {
+    let kind = request.upstream.unwrap_or(UpstreamKind::Primary);
+    let Some(client) = upstreams.client(kind) else {
+        return ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", format!("No {} upstream is configured", kind.as_str()))
+            .with_code("upstream_not_configured")
+            .into_response();
+    };
+    let server_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let timestamp = request.timestamp.unwrap_or_else(|| client.clock().now_secs()).to_string();
+    let nonce = request.nonce.unwrap_or_else(utils::generate_uuidv4);
+    let credentials = client.credentials().get();
+    let signed = client
+        .sign(&credentials, &request.content, &nonce, &timestamp, request.variant)
+        .and_then(|signed| Ok((client.signed_headers(&credentials, &signed, None)?, signed)));
+    let (headers, signed) = match signed {
+        Ok(signed) => signed,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", format!("{:#}", e))
+                .with_code("sign_failed")
+                .into_response();
+        }
+    };
+    let headers: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == "sid" { "[redacted]" } else { value.to_str().unwrap_or_default() };
+            (name.to_string(), value.into())
+        })
+        .collect();
+    info!(upstream = kind.as_str(), variant = request.variant.as_str(), "Debug signature requested");
+    Json(serde_json::json!({
+        "object": "debug.sign",
+        "upstream": kind.as_str(),
+        "variant": request.variant.as_str(),
+        "signer": client.signer_kind(request.variant),
+        "url": client.api_endpoint(),
+        "server_time": server_time,
+        "clock_offset_secs": client.clock().offset_secs(),
+        "signature": signed.signature,
+        "headers": headers,
+    }))
+    .into_response()
+}
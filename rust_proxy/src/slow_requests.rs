@@ -0,0 +1,112 @@
+// Detection of slow chat requests, so upstream degradations surface before
+// users complain. A request whose time to first token exceeds SLOW_TTFB_MS,
+// or whose stream lasts longer than SLOW_TOTAL_MS, logs a structured warning
+// with its request id and model and counts in `slow_requests_total`; with
+// `slow` in WEBHOOK_EVENTS it also sends a `completion.slow` webhook. A
+// request that never got a first token waited its whole duration for it.
+// Both thresholds are off (0) by default.
+
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+use crate::metrics::StreamSummary;
+
+/// Slow requests by `model` and the `threshold` (ttfb / total) they
+/// exceeded, registered by `Metrics`.
+pub static SLOW: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("slow_requests_total", "Chat requests over a latency threshold"), &["model", "threshold"])
+        .expect("valid counter")
+});
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlowRequests {
+    ttfb: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl SlowRequests {
+    pub fn new(ttfb: Option<Duration>, total: Option<Duration>) -> Self {
+        Self { ttfb, total }
+    }
+
+    pub fn from_env() -> Self {
+        let millis = |key| Some(Duration::from_millis(env_or(key, 0))).filter(|d| !d.is_zero());
+        let slow = Self::new(millis("SLOW_TTFB_MS"), millis("SLOW_TOTAL_MS"));
+        if slow.is_enabled() {
+            info!(ttfb = ?slow.ttfb, total = ?slow.total, "Slow request detection enabled");
+        }
+        slow
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttfb.is_some() || self.total.is_some()
+    }
+
+    /// The thresholds the ended stream exceeded.
+    pub fn exceeded(&self, summary: &StreamSummary) -> Vec<&'static str> {
+        let ttfb = summary.ttfb.unwrap_or(summary.duration);
+        let mut exceeded = Vec::new();
+        if self.ttfb.is_some_and(|limit| ttfb > limit) {
+            exceeded.push("ttfb");
+        }
+        if self.total.is_some_and(|limit| summary.duration > limit) {
+            exceeded.push("total");
+        }
+        exceeded
+    }
+
+    /// Warns about and counts the request if it was slow; returns whether
+    /// it was.
+    pub fn report(&self, request_id: &str, model: Option<&str>, summary: &StreamSummary) -> bool {
+        let exceeded = self.exceeded(summary);
+        if exceeded.is_empty() {
+            return false;
+        }
+        let model = model.unwrap_or("unknown");
+        for threshold in &exceeded {
+            SLOW.with_label_values(&[model, threshold]).inc();
+        }
+        warn!(
+            request_id,
+            model,
+            exceeded = ?exceeded,
+            ttfb_ms = summary.ttfb.map(|d| d.as_millis() as u64),
+            duration_ms = summary.duration.as_millis() as u64,
+            outcome = summary.outcome.as_str(),
+            "Slow chat request"
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Outcome;
+
+    fn summary(ttfb: Option<u64>, duration: u64) -> StreamSummary {
+        StreamSummary {
+            outcome: Outcome::Ok,
+            completion_tokens: 1,
+            ttfb: ttfb.map(Duration::from_millis),
+            duration: Duration::from_millis(duration),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn test_exceeded_thresholds() {
+        let slow = SlowRequests::new(Some(Duration::from_millis(500)), Some(Duration::from_millis(5000)));
+        assert!(slow.exceeded(&summary(Some(100), 1000)).is_empty());
+        assert_eq!(slow.exceeded(&summary(Some(800), 1000)), ["ttfb"]);
+        assert_eq!(slow.exceeded(&summary(Some(100), 6000)), ["total"]);
+        // No first token at all: the whole duration counts as waiting for it
+        assert_eq!(slow.exceeded(&summary(None, 6000)), ["ttfb", "total"]);
+        assert!(SlowRequests::default().exceeded(&summary(None, 60_000)).is_empty());
+        assert!(slow.report("req-1", Some("test-slow-model"), &summary(Some(800), 1000)));
+        assert_eq!(SLOW.with_label_values(&["test-slow-model", "ttfb"]).get(), 1);
+    }
+}
@@ -0,0 +1,468 @@
+use std::str;
+use tracing::{trace, warn};
+
+use crate::log_sampling;
+
+// --- Generic SSE decoding, independent of the Dev event vocabulary ---
+
+/// A fully assembled SSE event, dispatched when a blank line is seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEventRecord {
+    /// Event name, "message" when the block carried no `event:` field.
+    pub event: String,
+    /// All `data:` lines of the block joined with '\n'.
+    pub data: String,
+    /// Last event ID in effect when the event was dispatched.
+    pub id: Option<String>,
+}
+
+// Enum to represent parsed SSE lines
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SseLine {
+    Event(String),
+    Data(String),
+    Retry(String),
+    Id(String),
+    Comment,
+    Empty, // End of an event
+}
+
+// Parses a single line according to SSE format
+pub(crate) fn parse_sse_line(line: &str) -> SseLine {
+    if line.is_empty() {
+        SseLine::Empty
+    } else if line.starts_with(':') {
+        SseLine::Comment
+    } else {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        // Trim leading space from value if present
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => SseLine::Event(value.to_string()),
+            "data" => SseLine::Data(value.to_string()),
+            "id" => SseLine::Id(value.to_string()),
+            "retry" => SseLine::Retry(value.to_string()),
+            _ => SseLine::Comment, // Treat unknown fields as comments
+        }
+    }
+}
+
+/// Stateful UTF-8 decoder for byte chunks that may split a multi-byte
+/// character. An incomplete trailing sequence is held back and prepended to
+/// the next chunk instead of being replaced with U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8StreamDecoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes as much of `pending + bytes` as possible. Genuinely invalid
+    /// sequences are replaced lossily; only an incomplete tail is retained.
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut decoded = String::with_capacity(self.pending.len());
+        let mut input = self.pending.as_slice();
+        loop {
+            match str::from_utf8(input) {
+                Ok(valid) => {
+                    decoded.push_str(valid);
+                    input = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, rest) = input.split_at(e.valid_up_to());
+                    // `valid_up_to` guarantees this prefix is valid UTF-8
+                    decoded.push_str(str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        // Sequence cut off by the chunk boundary: wait for more bytes
+                        None => {
+                            input = rest;
+                            break;
+                        }
+                        Some(len) => {
+                            if log_sampling::allow("invalid_utf8") {
+                                warn!("Invalid UTF-8 sequence: {}, using lossy", e);
+                            }
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            input = &rest[len..];
+                        }
+                    }
+                }
+            }
+        }
+        let retained = input.len();
+        let consumed = self.pending.len() - retained;
+        self.pending.drain(..consumed);
+        decoded
+    }
+
+    /// Flushes a dangling incomplete sequence at end of stream.
+    pub fn finish(&mut self) -> String {
+        if self.pending.is_empty() {
+            return String::new();
+        }
+        warn!(bytes = self.pending.len(), "Incomplete UTF-8 sequence at end of stream, using lossy");
+        let tail = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        tail
+    }
+}
+
+/// Incremental SSE decoder following the WHATWG EventSource parsing rules:
+/// CRLF, LF and bare CR line terminators, a single leading BOM is ignored,
+/// `data:` lines are joined with '\n', and events are dispatched on a blank
+/// line. Feed it raw bytes as they arrive and collect the completed events.
+#[derive(Debug)]
+pub struct SseParser {
+    utf8_decoder: Utf8StreamDecoder,
+    decoder_buffer: String,
+    /// False until the first decoded character has been checked for a BOM.
+    bom_checked: bool,
+    /// The previous chunk ended with '\r'; a '\n' opening the next chunk
+    /// belongs to the same CRLF terminator.
+    skip_leading_lf: bool,
+    current_event_name: String,
+    current_data_buffer: Vec<String>,
+    last_event_id: Option<String>,
+    retry_ms: Option<u64>,
+}
+
+impl Default for SseParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self {
+            utf8_decoder: Utf8StreamDecoder::new(),
+            decoder_buffer: String::new(),
+            bom_checked: false,
+            skip_leading_lf: false,
+            current_event_name: String::new(),
+            current_data_buffer: Vec::new(),
+            last_event_id: None,
+            retry_ms: None,
+        }
+    }
+
+    /// Reconnection time most recently announced with a `retry:` field.
+    pub fn retry_ms(&self) -> Option<u64> {
+        self.retry_ms
+    }
+
+    /// Appends `bytes` to the internal buffer and returns every event whose
+    /// terminating blank line is now available.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<SseEventRecord> {
+        let chunk_str = self.utf8_decoder.decode(bytes);
+        self.push_text(&chunk_str);
+
+        let mut events = Vec::new();
+        while let Some(line) = self.next_line() {
+            trace!(line = %line, "Processing buffered SSE line");
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Flushes whatever is left once the byte stream has ended. Unlike a
+    /// browser EventSource, a trailing line without terminator and a final
+    /// block without its blank line are still dispatched, since the Dev
+    /// backend does not always terminate its last event.
+    pub fn finish(&mut self) -> Vec<SseEventRecord> {
+        let tail = self.utf8_decoder.finish();
+        self.push_text(&tail);
+        let mut events = Vec::new();
+        if !self.decoder_buffer.is_empty() {
+            if log_sampling::allow("residual_buffer") {
+                warn!("Processing residual buffer content after stream end: '{}'", self.decoder_buffer);
+            }
+            let residual = std::mem::take(&mut self.decoder_buffer);
+            trace!(line = %residual, "Processing residual SSE line");
+            if let Some(event) = self.process_line(&residual) {
+                events.push(event);
+            }
+        }
+        if let Some(event) = self.dispatch() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if !self.bom_checked {
+            self.bom_checked = true;
+            self.decoder_buffer.push_str(text.strip_prefix('\u{FEFF}').unwrap_or(text));
+        } else {
+            self.decoder_buffer.push_str(text);
+        }
+    }
+
+    // Removes and returns the next complete line, without its terminator.
+    fn next_line(&mut self) -> Option<String> {
+        if self.skip_leading_lf && !self.decoder_buffer.is_empty() {
+            if self.decoder_buffer.starts_with('\n') {
+                self.decoder_buffer.remove(0);
+            }
+            self.skip_leading_lf = false;
+        }
+        let pos = self.decoder_buffer.find(['\r', '\n'])?;
+        let mut consumed = pos + 1;
+        if self.decoder_buffer.as_bytes()[pos] == b'\r' {
+            match self.decoder_buffer.as_bytes().get(pos + 1) {
+                Some(b'\n') => consumed += 1,
+                Some(_) => {}
+                // CR is the last byte we have: its LF may still be in flight
+                None => self.skip_leading_lf = true,
+            }
+        }
+        let line = self.decoder_buffer[..pos].to_string();
+        self.decoder_buffer.drain(..consumed);
+        Some(line)
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEventRecord> {
+        match parse_sse_line(line) {
+            SseLine::Empty => self.dispatch(),
+            SseLine::Event(name) => {
+                self.current_event_name = name;
+                None
+            }
+            SseLine::Data(data) => {
+                self.current_data_buffer.push(data);
+                None
+            }
+            SseLine::Id(id) => {
+                // Ids containing NULL are ignored per spec
+                if !id.contains('\0') {
+                    self.last_event_id = Some(id);
+                }
+                None
+            }
+            SseLine::Retry(value) => {
+                if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+                    self.retry_ms = value.parse().ok();
+                }
+                None
+            }
+            SseLine::Comment => None,
+        }
+    }
+
+    // Assembles the pending block into a record and resets the event name.
+    fn dispatch(&mut self) -> Option<SseEventRecord> {
+        let event = std::mem::take(&mut self.current_event_name);
+        if self.current_data_buffer.is_empty() {
+            return None;
+        }
+        let data = self.current_data_buffer.join("\n");
+        self.current_data_buffer.clear();
+        Some(SseEventRecord {
+            event: if event.is_empty() { "message".to_string() } else { event },
+            data,
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sse_line_empty() {
+        assert_eq!(parse_sse_line(""), SseLine::Empty);
+    }
+
+    #[test]
+    fn test_parse_sse_line_comment() {
+        assert_eq!(parse_sse_line(": this is a comment"), SseLine::Comment);
+        assert_eq!(parse_sse_line(":"), SseLine::Comment); // Empty comment
+    }
+
+    #[test]
+    fn test_parse_sse_line_event() {
+        assert_eq!(parse_sse_line("event: message"), SseLine::Event("message".to_string()));
+        assert_eq!(parse_sse_line("event:finish"), SseLine::Event("finish".to_string()));
+        assert_eq!(parse_sse_line("event:"), SseLine::Event("".to_string())); // Empty event name
+        assert_eq!(parse_sse_line("event: event with space"), SseLine::Event("event with space".to_string()));
+    }
+
+    #[test]
+    fn test_parse_sse_line_data() {
+        assert_eq!(parse_sse_line("data: {\"key\": \"value\"}"), SseLine::Data("{\"key\": \"value\"}".to_string()));
+        assert_eq!(parse_sse_line("data: simple string"), SseLine::Data("simple string".to_string()));
+        assert_eq!(parse_sse_line("data:"), SseLine::Data("".to_string())); // Empty data
+        assert_eq!(parse_sse_line("data: data with : colon"), SseLine::Data("data with : colon".to_string()));
+        // Test stripping leading space
+        assert_eq!(parse_sse_line("data:  leading space"), SseLine::Data(" leading space".to_string()));
+    }
+     #[test]
+    fn test_parse_sse_line_data_strips_leading_space() {
+        // Should strip only the first leading space after the colon
+        assert_eq!(parse_sse_line("data: {\"key\": \"value\"}"), SseLine::Data("{\"key\": \"value\"}".to_string()));
+        assert_eq!(parse_sse_line("data:  two leading spaces"), SseLine::Data(" two leading spaces".to_string()));
+        assert_eq!(parse_sse_line("data:"), SseLine::Data("".to_string()));
+    }
+
+    #[test]
+    fn test_parse_sse_line_id() {
+        assert_eq!(parse_sse_line("id: 12345"), SseLine::Id("12345".to_string()));
+        assert_eq!(parse_sse_line("id:"), SseLine::Id("".to_string())); // Empty id
+    }
+
+    #[test]
+    fn test_parse_sse_line_retry() {
+        assert_eq!(parse_sse_line("retry: 5000"), SseLine::Retry("5000".to_string()));
+        assert_eq!(parse_sse_line("retry:"), SseLine::Retry("".to_string())); // Empty retry
+    }
+
+     #[test]
+    fn test_parse_sse_line_unknown_field() {
+        // Unknown fields should be treated as comments
+        assert_eq!(parse_sse_line("unknown: some value"), SseLine::Comment);
+        assert_eq!(parse_sse_line("field without colon"), SseLine::Comment); // Treat line without colon as comment (or decide specific behavior)
+    }
+
+    // --- Tests for SseParser ---
+
+    fn record(event: &str, data: &str) -> SseEventRecord {
+        SseEventRecord { event: event.to_string(), data: data.to_string(), id: None }
+    }
+
+    #[test]
+    fn test_parser_dispatches_on_blank_line() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"event: content\ndata: Hello\n\n");
+        assert_eq!(events, vec![record("content", "Hello")]);
+    }
+
+    #[test]
+    fn test_parser_defaults_event_name_to_message() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"data: one\n\nevent: c\ndata: two\n\ndata: three\n\n");
+        assert_eq!(events, vec![record("message", "one"), record("c", "two"), record("message", "three")]);
+    }
+
+    #[test]
+    fn test_parser_joins_multiple_data_lines() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"data: line 1\ndata: line 2\n\n");
+        assert_eq!(events, vec![record("message", "line 1\nline 2")]);
+    }
+
+    #[test]
+    fn test_parser_handles_lines_split_across_feeds() {
+        let mut parser = SseParser::new();
+        assert!(parser.feed(b"event: cont").is_empty());
+        assert!(parser.feed(b"ent\r\ndata: Hel").is_empty());
+        let events = parser.feed(b"lo\r\n\r\n");
+        assert_eq!(events, vec![record("content", "Hello")]);
+    }
+
+    #[test]
+    fn test_parser_ignores_comments_and_blocks_without_data() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b": keep-alive\n\nevent: finish\n\n");
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_parser_finish_flushes_residual_event() {
+        let mut parser = SseParser::new();
+        assert!(parser.feed(b"event: content\ndata: tail").is_empty());
+        assert_eq!(parser.finish(), vec![record("content", "tail")]);
+        assert!(parser.finish().is_empty());
+    }
+
+    // --- Tests for Utf8StreamDecoder ---
+
+    #[test]
+    fn test_utf8_decoder_retains_split_multibyte_char() {
+        let mut decoder = Utf8StreamDecoder::new();
+        let bytes = "你好😀".as_bytes();
+        // Split inside the second CJK char and inside the emoji
+        assert_eq!(decoder.decode(&bytes[..4]), "你");
+        assert_eq!(decoder.decode(&bytes[4..8]), "好");
+        assert_eq!(decoder.decode(&bytes[8..]), "😀");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn test_utf8_decoder_replaces_invalid_bytes() {
+        let mut decoder = Utf8StreamDecoder::new();
+        assert_eq!(decoder.decode(b"a\xffb"), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_utf8_decoder_finish_flushes_incomplete_tail() {
+        let mut decoder = Utf8StreamDecoder::new();
+        assert_eq!(decoder.decode(&"é".as_bytes()[..1]), "");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn test_parser_handles_chars_split_across_feeds() {
+        let mut parser = SseParser::new();
+        let bytes = "data: 你好\n\n".as_bytes();
+        assert!(parser.feed(&bytes[..8]).is_empty());
+        assert_eq!(parser.feed(&bytes[8..]), vec![record("message", "你好")]);
+    }
+
+    #[test]
+    fn test_parser_accepts_cr_only_terminators() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"event: c\rdata: a\r\rdata: b\r\r");
+        assert_eq!(events, vec![record("c", "a"), record("message", "b")]);
+    }
+
+    #[test]
+    fn test_parser_crlf_split_between_feeds_is_one_terminator() {
+        let mut parser = SseParser::new();
+        assert!(parser.feed(b"data: a\r").is_empty());
+        // The LF completes the CRLF above; only the next CRLF ends the block
+        assert!(parser.feed(b"\ndata: b\r").is_empty());
+        assert_eq!(parser.feed(b"\n\r\n"), vec![record("message", "a\nb")]);
+    }
+
+    #[test]
+    fn test_parser_strips_leading_bom_once() {
+        let mut parser = SseParser::new();
+        let events = parser.feed("\u{FEFF}data: x\n\n".as_bytes());
+        assert_eq!(events, vec![record("message", "x")]);
+        let events = parser.feed("\u{FEFF}data: y\n\n".as_bytes());
+        assert!(events.is_empty()); // A later BOM is an unknown field name
+    }
+
+    #[test]
+    fn test_parser_empty_event_name_defaults_to_message() {
+        let mut parser = SseParser::new();
+        assert_eq!(parser.feed(b"event:\ndata: x\n\n"), vec![record("message", "x")]);
+    }
+
+    #[test]
+    fn test_parser_bare_data_field_and_empty_data() {
+        let mut parser = SseParser::new();
+        assert_eq!(parser.feed(b"data\ndata\n\n"), vec![record("message", "\n")]);
+        assert_eq!(parser.feed(b"data:\n\n"), vec![record("message", "")]);
+    }
+
+    #[test]
+    fn test_parser_tracks_last_event_id_and_retry() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"id: 7\nretry: 3000\ndata: a\n\ndata: b\n\nid\nretry: soon\ndata: c\n\n");
+        let ids: Vec<_> = events.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(ids, vec![Some("7".to_string()), Some("7".to_string()), Some("".to_string())]);
+        assert_eq!(parser.retry_ms(), Some(3000));
+    }
+}
@@ -3,12 +3,23 @@ use serde_json::Value;
 use tracing::warn;
 use crate::dev_client::DevRequestOptions; // Needed for model name
 use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
 use futures_util::stream::{self, Stream, StreamExt};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, error, trace};
 use bytes::Bytes;
-use std::str;
+use std::collections::VecDeque;
 use std::pin::Pin;
+use crate::sse_parser::{SseEventRecord, SseParser};
+use crate::event_bus::AccumulatorSink;
+use crate::config;
+use crate::dev_errors::DevError;
+use crate::log_sampling;
+use crate::utils;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, MutexGuard};
 // use std::task::{Context as TaskContext, Poll};
 // use tokio::macros::support::Pin as TokioPin; // Needed for async block
 // use futures_util::pin_mut; // Add this import
@@ -39,6 +50,90 @@ use std::pin::Pin;
     #[serde(flatten)] pub extra: Value, // Capture unknown fields
 }
 
+/// Caps on what the accumulator keeps of one answer, so a runaway Dev
+/// answer cannot grow it without bound. Past a cap the field stops growing
+/// and the stream goes on; the text is only kept for mid-stream retries,
+/// which a capped answer no longer gets.
+#[derive(Debug, Clone, Copy)]
+pub struct AccumulatorLimits {
+    /// ACCUMULATOR_MAX_TEXT_BYTES (4 MiB)
+    pub text_bytes: usize,
+    /// ACCUMULATOR_MAX_REASONING_BYTES (1 MiB)
+    pub reasoning_bytes: usize,
+    /// ACCUMULATOR_MAX_RELATED_BYTES (64 KiB)
+    pub related_questions_bytes: usize,
+    /// ACCUMULATOR_MAX_ACTIONS (1000)
+    pub actions: usize,
+    /// STATELESS_STREAMING: keep nothing of the answer (text, sources,
+    /// related questions, actions, reasoning), so a stream's memory stays
+    /// constant however long the answer. Source footers, linked citations
+    /// and mid-stream retries then have nothing to work with.
+    pub stateless: bool,
+}
+
+impl Default for AccumulatorLimits {
+    fn default() -> Self {
+        Self {
+            text_bytes: 4 << 20,
+            reasoning_bytes: 1 << 20,
+            related_questions_bytes: 64 << 10,
+            actions: 1000,
+            stateless: false,
+        }
+    }
+}
+
+impl AccumulatorLimits {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            text_bytes: config::env_or("ACCUMULATOR_MAX_TEXT_BYTES", default.text_bytes),
+            reasoning_bytes: config::env_or("ACCUMULATOR_MAX_REASONING_BYTES", default.reasoning_bytes),
+            related_questions_bytes: config::env_or("ACCUMULATOR_MAX_RELATED_BYTES", default.related_questions_bytes),
+            actions: config::env_or("ACCUMULATOR_MAX_ACTIONS", default.actions),
+            stateless: config::env_or("STATELESS_STREAMING", false),
+        }
+    }
+}
+
+static LIMITS: Lazy<AccumulatorLimits> = Lazy::new(AccumulatorLimits::from_env);
+
+/// Answers whose accumulator hit a cap, by `field`; registered by `Metrics`.
+pub static TRUNCATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("accumulator_truncations_total", "Answers whose accumulated field hit its cap"),
+        &["field"],
+    )
+    .expect("valid counter")
+});
+
+/// Dev events by `event`, `unknown` for types this proxy does not handle;
+/// registered by `Metrics`. A rise in unknown events is the first sign the
+/// upstream wire format changed.
+pub static EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("dev_events_total", "Dev stream events by type"), &["event"]).expect("valid counter")
+});
+
+/// The `EVENTS` label of a Dev event: its canonical name, so aliases share a
+/// series and unknown names cannot grow the label set.
+fn event_label(event_name: &str) -> &'static str {
+    match event_name {
+        "message" | "content" | "c" => "content",
+        "action" => "action",
+        "sources" => "sources",
+        "repoSources" => "repoSources",
+        "rlq" | "q" => "rlq",
+        "r" => "r",
+        "threadId" => "threadId",
+        "queryMessageId" => "queryMessageId",
+        "answerMessageId" => "answerMessageId",
+        "threadTitle" => "threadTitle",
+        "error" => "error",
+        "finish" => "finish",
+        _ => "unknown",
+    }
+}
+
 // Main accumulator state, mirroring JS accumulator
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -62,10 +157,43 @@ pub struct SseAccumulator {
 
     pub is_finished: bool,
     pub error: Option<String>,
+    #[serde(skip)]
+    limits: AccumulatorLimits,
+    /// Fields that hit their cap.
+    #[serde(skip)]
+    truncated: BTreeSet<&'static str>,
     // extra: Value, // Could store original ExtraPayload if needed
 }
 
 impl SseAccumulator {
+    /// Appends `data` to `field` up to `cap` bytes; the first time the cap is
+    /// hit is logged and counted.
+    fn append_capped(
+        field: &mut String,
+        data: &str,
+        cap: usize,
+        name: &'static str,
+        truncated: &mut BTreeSet<&'static str>,
+    ) {
+        if truncated.contains(name) {
+            return;
+        }
+        let mut room = cap.saturating_sub(field.len()).min(data.len());
+        while !data.is_char_boundary(room) {
+            room -= 1;
+        }
+        field.push_str(&data[..room]);
+        if room < data.len() && truncated.insert(name) {
+            warn!(field = name, cap, "Accumulator field hit its cap, no longer collecting it");
+            TRUNCATIONS.with_label_values(&[name]).inc();
+        }
+    }
+
+    /// Whether some field stopped growing at its cap.
+    pub fn is_truncated(&self) -> bool {
+        !self.truncated.is_empty()
+    }
+
     // Helper to parse related questions, similar to JS logic
     fn update_related_questions(&mut self) {
         self.related_questions = self.related_questions_raw
@@ -79,26 +207,90 @@ impl SseAccumulator {
 
 // --- OpenAI Chat Completion Chunk Structures ---
 
-#[derive(Debug, Serialize)]
+/// Identifies the proxy build in `system_fingerprint`: `fp_` and a hash of
+/// the BUILD_ID set at compile time (e.g. a git commit), or of the version.
+pub static SYSTEM_FINGERPRINT: Lazy<String> = Lazy::new(|| {
+    let build = option_env!("BUILD_ID").unwrap_or(env!("CARGO_PKG_VERSION"));
+    format!("fp_{}", &utils::sha256_hex(format!("rust_proxy/{}", build).as_bytes())[..10])
+});
+
+/// A new completion id in OpenAI's `chatcmpl-<random>` format, which some
+/// SDKs validate.
+pub fn new_completion_id() -> String {
+    format!("chatcmpl-{}", utils::generate_uuidv4().replace('-', ""))
+}
+
+/// What Dev sent about an answer besides its text.
+#[derive(Debug, Clone, Default)]
+pub struct Extras {
+    pub sources: Vec<DevSource>,
+    pub github_sources: Vec<DevGithubSource>,
+    pub related_questions: Vec<String>,
+}
+
+/// The `Extras` of one choice as the stream processor learns them: the
+/// sources as they arrive, the related questions once the answer is
+/// complete. The choice's chain stages (linked citations, footers) read
+/// them, see `transform::ChoiceContext`.
+#[derive(Debug, Clone, Default)]
+pub struct AnswerExtras(Arc<Mutex<Extras>>);
+
+impl AnswerExtras {
+    pub fn lock(&self) -> MutexGuard<'_, Extras> {
+        self.0.lock().unwrap()
+    }
+
+    fn publish(&self, accumulator: &SseAccumulator) {
+        let mut extras = self.lock();
+        extras.sources = accumulator.sources.clone();
+        extras.github_sources = accumulator.github_sources.clone();
+        extras.related_questions = accumulator.related_questions.clone();
+    }
+}
+
+/// One `data:` event of the chat completion stream.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 pub struct ChatCompletionChunk {
-    pub id: String, // Consider using nonce or generating new IDs
+    pub id: String, // chatcmpl-..., shared by all chunks of a completion
     pub object: String, // Typically "chat.completion.chunk"
     pub created: u64, // Unix timestamp
     pub model: String, // Model name from request or default
     pub choices: Vec<Choice>,
-    // pub system_fingerprint: Option<String>, // Optional
+    pub system_fingerprint: Option<String>,
+    /// Set on the chunk ending a stream on a Dev `error` event. Never sent
+    /// with the chunk: OpenAI clients raise on any chunk with an `error` key,
+    /// so the chunk itself only carries the error as content.
+    #[serde(skip)]
+    pub error: Option<ChunkError>,
     // pub usage: Option<Usage>, // Typically null for chunks, present in final non-stream response
 }
 
-#[derive(Debug, Serialize)]
+/// The OpenAI error object of an error chunk, see `DevError`.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct ChunkError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: &'static str,
+    pub code: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 pub struct Choice {
     pub index: u32,
     pub delta: Delta,
     pub finish_reason: Option<String>, // e.g., "stop", "length"
-    // pub logprobs: Option<LogProbs>, // Optional
+    /// Only present (as `null`) when the request asked for logprobs and
+    /// LOGPROBS_MODE=null.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub logprobs: Option<NoLogprobs>,
 }
 
-#[derive(Debug, Serialize, Default)]
+/// Serializes as `null`: Dev does not provide token probabilities.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NoLogprobs;
+
+#[derive(Debug, Clone, Serialize, Default, utoipa::ToSchema)]
 pub struct Delta {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<String>, // e.g., "assistant"
@@ -107,6 +299,68 @@ pub struct Delta {
     // pub tool_calls: Option<Vec<ToolCall>>, // Optional for tool usage
 }
 
+/// A one-choice chunk of completion `chatcmpl-1` for model `m`, the fixture
+/// of every test that runs chunks through a stage.
+#[cfg(test)]
+pub(crate) fn test_chunk(content: Option<&str>, finish_reason: Option<&str>) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: "chatcmpl-1".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        created: 0,
+        model: "m".to_string(),
+        system_fingerprint: None,
+        choices: vec![Choice {
+            index: 0,
+            delta: Delta { role: None, content: content.map(str::to_string) },
+            finish_reason: finish_reason.map(str::to_string),
+            logprobs: None,
+        }],
+        error: None,
+    }
+}
+
+// Boxed upstream byte stream owned by the unfold state
+pub type DevByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static>>;
+
+/// Re-issues the upstream request after the Dev byte stream failed midway.
+pub type Reconnect = Box<dyn FnMut() -> BoxFuture<'static, Result<DevByteStream>> + Send>;
+
+/// Mid-stream retry policy: up to `attempts` reconnects per stream.
+pub struct StreamRetry {
+    pub attempts: u32,
+    pub reconnect: Reconnect,
+}
+
+/// Text already delivered when the request was re-issued. The new stream
+/// repeats the answer from the start, so its copy of this text is dropped.
+#[derive(Debug)]
+struct Replayed {
+    delivered: String,
+    matched: usize,
+}
+
+impl Replayed {
+    /// The part of a content event the client has not seen yet.
+    fn unseen<'a>(&mut self, data: &'a str) -> &'a str {
+        let expected = &self.delivered[self.matched..];
+        let mut common = data.bytes().zip(expected.bytes()).take_while(|(a, b)| a == b).count();
+        while !data.is_char_boundary(common) {
+            common -= 1;
+        }
+        self.matched += common;
+        if common < data.len() && common < expected.len() {
+            // The answer came out differently this time; keep what is new
+            warn!(matched = self.matched, delivered = self.delivered.len(), "Re-issued Dev stream diverged from the delivered text");
+            self.matched = self.delivered.len();
+        }
+        &data[common..]
+    }
+
+    fn caught_up(&self) -> bool {
+        self.matched >= self.delivered.len()
+    }
+}
+
 // Helper function to safely parse JSON from SSE data
 fn safe_json_parse<'a, T>(data: &'a str) -> Option<T>
 where
@@ -115,75 +369,65 @@ where
     match serde_json::from_str::<T>(data) {
         Ok(parsed) => Some(parsed),
         Err(e) => {
-            warn!("Failed to parse JSON from SSE data: {}. Data: {}", e, data);
+            if log_sampling::allow("malformed_json") {
+                warn!("Failed to parse JSON from SSE data: {}. Data: {}", e, data);
+            }
             None
         }
     }
 }
 
-// Enum to represent parsed SSE lines
-#[derive(Debug, PartialEq, Eq)]
-enum SseLine {
-    Event(String),
-    Data(String),
-    Retry(String),
-    Id(String),
-    Comment,
-    Empty, // End of an event
-}
-
-// Parses a single line according to SSE format
-fn parse_sse_line(line: &str) -> SseLine {
-    if line.is_empty() {
-        SseLine::Empty
-    } else if line.starts_with(':') {
-        SseLine::Comment
-    } else {
-        let (field, value) = line.split_once(':').unwrap_or((line, ""));
-        // Trim leading space from value if present
-        let value = value.strip_prefix(' ').unwrap_or(value);
-        match field {
-            "event" => SseLine::Event(value.to_string()),
-            "data" => SseLine::Data(value.to_string()),
-            "id" => SseLine::Id(value.to_string()),
-            "retry" => SseLine::Retry(value.to_string()),
-            _ => SseLine::Comment, // Treat unknown fields as comments
-        }
-    }
-}
-
-/// Processes a stream of Dev Bytes and transforms it into a 
+/// Processes a stream of Dev Bytes and transforms it into a
 /// stream of OpenAI-compatible ChatCompletionChunks using stream::unfold.
+/// Every chunk carries `completion_id` as its id.
 pub fn process_dev_bytes_stream_unfold(
     byte_stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
-    options: DevRequestOptions, 
-    request_id: String, 
+    options: DevRequestOptions,
+    completion_id: String,
+) -> impl Stream<Item = Result<ChatCompletionChunk>> {
+    process_dev_bytes_stream_with_retry(byte_stream, options, completion_id, None)
+}
+
+/// Like `process_dev_bytes_stream_unfold`, but a byte stream that fails
+/// midway is replaced through `retry` while attempts remain; the text the
+/// client already received is skipped in the new stream.
+pub fn process_dev_bytes_stream_with_retry(
+    byte_stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+    options: DevRequestOptions,
+    completion_id: String,
+    retry: Option<StreamRetry>,
 ) -> impl Stream<Item = Result<ChatCompletionChunk>> {
     let model_name = options.model.unwrap_or_else(|| "unknown-dev-model".to_string());
 
     // State for unfold
     struct State {
-        byte_stream: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static>>,
-        decoder_buffer: String,
+        byte_stream: DevByteStream,
+        parser: SseParser,
+        pending_events: VecDeque<SseEventRecord>,
         accumulator: SseAccumulator,
-        current_event_name: String,
-        current_data_buffer: Vec<String>,
         model_name: String,
         request_id: String,
-        // finished_normally: bool, // Not strictly needed if we check accumulator.is_finished
         final_chunk_sent: bool, // Flag to ensure unfold terminates correctly
+        bytes_done: bool, // The byte stream ended; it is not polled again
+        retry: Option<StreamRetry>,
+        replayed: Option<Replayed>,
+        snapshots: Vec<AccumulatorSink>,
+        extras: Option<AnswerExtras>,
     }
 
     let initial_state = State {
         byte_stream: Box::pin(byte_stream),
-        decoder_buffer: String::new(),
-        accumulator: SseAccumulator::default(),
-        current_event_name: "message".to_string(),
-        current_data_buffer: Vec::new(),
+        parser: SseParser::new(),
+        pending_events: VecDeque::new(),
+        accumulator: SseAccumulator { limits: *LIMITS, ..Default::default() },
         model_name,
-        request_id,
-        // finished_normally: false, 
+        request_id: completion_id,
         final_chunk_sent: false, // Initialize the flag
+        bytes_done: false,
+        retry,
+        replayed: None,
+        snapshots: options.snapshots,
+        extras: options.extras,
     };
 
     stream::unfold(initial_state, |mut state| async move {
@@ -192,117 +436,98 @@ pub fn process_dev_bytes_stream_unfold(
             return None; // Terminate the unfold stream
         }
 
-        // Loop to read bytes and process lines until an event is dispatched or stream ends
+        // Loop to read bytes and process events until a chunk is produced or stream ends
         loop {
-            let mut event_chunk: Option<Result<ChatCompletionChunk>> = None;
-
-            // --- Process buffered lines first ---
-            // Process complete lines ending with '\n'
-            while let Some(newline_pos) = state.decoder_buffer.find('\n') {
-                let line = state.decoder_buffer.drain(..=newline_pos).collect::<String>();
-                let trimmed_line = line.trim_end_matches(|c| c == '\n' || c == '\r');
-                trace!(line = trimmed_line, "Processing buffered SSE line");
-
-                match parse_sse_line(trimmed_line) {
-                    SseLine::Empty => {
-                        if !state.current_data_buffer.is_empty() {
-                            let data = state.current_data_buffer.join("\n");
-                            debug!(event_type = %state.current_event_name, event_data = %data, "Dispatching buffered Dev event");
-                            state.current_data_buffer.clear();
-                            let event_name = std::mem::replace(&mut state.current_event_name, "message".to_string());
-
-                            if let Some(chunk) = process_single_dev_event(
-                                &mut state.accumulator,
-                                event_name,
-                                data,
-                                &state.request_id,
-                                &state.model_name
-                            ) {
-                                event_chunk = Some(Ok(chunk));
-                                break; // Break inner while loop to yield the chunk
-                            }
-                        }
-                        // Reset event name after processing an event block
-                        state.current_event_name = "message".to_string(); 
+            // --- Process already parsed events first ---
+            while let Some(mut event) = state.pending_events.pop_front() {
+                debug!(event_type = %event.event, event_data = %event.data, "Dispatching buffered Dev event");
+                // After a re-issue, drop the text the client already has
+                if let Some(replayed) = state.replayed.as_mut()
+                    && matches!(event.event.as_str(), "message" | "content" | "c")
+                {
+                    event.data = replayed.unseen(&event.data).to_string();
+                    if replayed.caught_up() {
+                        debug!("Re-issued Dev stream caught up with the delivered text");
+                        state.replayed = None;
                     }
-                    SseLine::Event(name) => state.current_event_name = name,
-                    SseLine::Data(data) => state.current_data_buffer.push(data),
-                    SseLine::Id(_) | SseLine::Retry(_) | SseLine::Comment => { /* Ignore */ },
                 }
-            }
-
-            // If we processed an event from the buffer and have a chunk, yield it
-            if event_chunk.is_some() {
-                return Some((event_chunk.unwrap(), state));
+                let sources = matches!(event.event.as_str(), "sources" | "repoSources");
+                let chunk = process_single_dev_event(
+                    &mut state.accumulator,
+                    event.event,
+                    event.data,
+                    &state.request_id,
+                    &state.model_name
+                );
+                // The chain stages see the sources before the text citing them
+                if sources && let Some(extras) = &state.extras {
+                    extras.publish(&state.accumulator);
+                }
+                if let Some(chunk) = chunk {
+                    return Some((Ok(chunk), state)); // Yield the chunk
+                }
             }
 
             // --- If no chunk generated from buffer, read more bytes ---
-            match state.byte_stream.next().await {
+            let next = if state.bytes_done { None } else { state.byte_stream.next().await };
+            match next {
                 Some(Ok(bytes)) => {
-                    match str::from_utf8(&bytes) {
-                        Ok(chunk_str) => state.decoder_buffer.push_str(chunk_str),
-                        Err(e) => {
-                            warn!("Invalid UTF-8 sequence: {}, using lossy", e);
-                            state.decoder_buffer.push_str(&String::from_utf8_lossy(&bytes));
-                        }
-                    }
-                    // Loop again to process the newly added buffer content
+                    let events = state.parser.feed(&bytes);
+                    state.pending_events.extend(events);
+                    // Loop again to process the newly parsed events
                 }
                 Some(Err(e)) => {
+                    // Without the full text there is no telling what the client already has
+                    let resumable = !state.accumulator.limits.stateless && !state.accumulator.truncated.contains("text");
+                    if let Some(retry) = state.retry.as_mut().filter(|r| r.attempts > 0 && resumable) {
+                        retry.attempts -= 1;
+                        warn!(error = %e, remaining = retry.attempts, "Dev byte stream failed, re-issuing the request");
+                        match (retry.reconnect)().await {
+                            Ok(byte_stream) => {
+                                state.byte_stream = byte_stream;
+                                state.parser = SseParser::new();
+                                state.pending_events.clear();
+                                // The new stream repeats everything; the text the
+                                // client has is skipped, the rest is collected anew
+                                let accumulator = &mut state.accumulator;
+                                accumulator.actions.clear();
+                                accumulator.related_questions_raw.clear();
+                                accumulator.reasoning = None;
+                                accumulator.truncated.clear();
+                                state.replayed = Some(Replayed { delivered: accumulator.text.clone(), matched: 0 });
+                                continue;
+                            }
+                            Err(retry_error) => error!("Failed to re-issue the Dev request: {:#}", retry_error),
+                        }
+                    }
                     error!("Error reading from byte stream: {}", e);
                     state.final_chunk_sent = true; // Ensure termination on error too
                     return Some((Err(anyhow!(e)), state)); // Yield error and stop
                 }
                 None => {
                     // End of byte stream
-                    info!("Dev byte stream finished.");
-                    trace!(buffer = %state.decoder_buffer, "Processing end of stream. Residual buffer content.");
-
-
-                    // --- Process any remaining data in the buffer ---
-                    if !state.decoder_buffer.is_empty() {
-                        warn!("Processing residual buffer content after stream end: '{}'", state.decoder_buffer);
-                        // Treat the remaining buffer as potentially incomplete lines or data fragments.
-                        // Attempt to parse lines, but handle potential lack of final newline/empty line.
-                        let lines: Vec<&str> = state.decoder_buffer.split('\n').collect();
-                        for (i, line) in lines.iter().enumerate() {
-                             let trimmed_line = line.trim_end_matches('\r');
-                             if trimmed_line.is_empty() && i == lines.len() -1 {
-                                // Ignore trailing empty string after split if it was the last char
-                                continue;
-                             }
-                             trace!(line = trimmed_line, "Processing residual SSE line");
-                             match parse_sse_line(trimmed_line) {
-                                 // Don't dispatch on Empty here, wait till the end
-                                 SseLine::Empty => {},
-                                 SseLine::Event(name) => state.current_event_name = name,
-                                 SseLine::Data(data) => state.current_data_buffer.push(data),
-                                 _ => { /* Ignore */ }
-                             }
-                         }
-                         // Dispatch any remaining data collected from the residual buffer
-                         if !state.current_data_buffer.is_empty() {
-                            let data = state.current_data_buffer.join("\n");
-                            debug!(event_type = %state.current_event_name, event_data = %data, "Dispatching residual Dev event from buffer");
-                            // Don't clear buffers here, just process
-                            let event_name = state.current_event_name.clone(); // Use last known event name
-
-                            // Update accumulator but DON'T yield a chunk here,
-                            // accumulate everything before the final chunk.
-                            // This ensures the last piece of text is in the accumulator,
-                            // even if it doesn't generate its own content chunk immediately.
-                            process_single_dev_event(
-                                &mut state.accumulator,
-                                event_name,
-                                data,
-                                &state.request_id,
-                                &state.model_name
-                            );
+                    if !state.bytes_done {
+                        info!("Dev byte stream finished.");
+                        state.bytes_done = true;
+                        // --- Process any remaining data in the parser ---
+                        // An unterminated last event is streamed like any
+                        // other, so the client gets the text the answer has
+                        let residual = state.parser.finish();
+                        if !residual.is_empty() {
+                            debug!(count = residual.len(), "Dispatching residual Dev events from buffer");
+                            state.pending_events.extend(residual);
+                            continue;
+                        }
+                    }
+                    // The answer is complete: hand its snapshot to the event bus
+                    // and the history
+                    if !state.snapshots.is_empty() {
+                        let mut accumulator = state.accumulator.clone();
+                        accumulator.update_related_questions();
+                        accumulator.is_finished = true;
+                        for snapshot in std::mem::take(&mut state.snapshots) {
+                            let _ = snapshot.send(accumulator.clone());
                         }
-                        trace!("Finished processing residual buffer.");
-                        state.decoder_buffer.clear(); // Clear buffer after processing
-                    } else {
-                       trace!("Residual buffer is empty. No residual processing needed.");
                     }
 
 
@@ -317,13 +542,17 @@ pub fn process_dev_bytes_stream_unfold(
                         state.accumulator.is_finished = true; // Mark as finished now
                         trace!("Accumulator not finished, updating related questions.");
                         state.accumulator.update_related_questions(); // Final update for related questions
-                        let final_chunk = create_final_chunk(
+                        // The footers stage reads them when the final chunk reaches it
+                        if let Some(extras) = &state.extras {
+                            extras.publish(&state.accumulator);
+                        }
+                        let chunk = create_final_chunk(
                             state.request_id.clone(),
                             state.model_name.clone(),
                             "stop".to_string() // OpenAI standard reason for normal completion
                         );
                         debug!(request_id = %state.request_id, "Yielding final 'stop' chunk for normally finished stream.");
-                        return Some((Ok(final_chunk), state)); // Yield final chunk with finish_reason: "stop"
+                        return Some((Ok(chunk), state)); // Yield final chunk with finish_reason: "stop"
                     } else {
                          // Stream ended, but an error was already processed and is_finished is true.
                          // The error chunk (which includes finish_reason: "stop") should have already
@@ -347,28 +576,41 @@ fn process_single_dev_event(
     model_name: &str,
 ) -> Option<ChatCompletionChunk> {
     trace!(event = %event_name, data = %data, request_id = request_id, "Processing single Dev event");
+    EVENTS.with_label_values(&[event_label(&event_name)]).inc();
     match event_name.as_str() {
         "message" | "content" | "c" => {
             if data.is_empty() { // Avoid creating empty content chunks
                 trace!("Skipping empty content/message event.");
                 None
             } else {
-                let delta_content = data; // Already have the data string
-                accumulator.text += &delta_content;
+                if !accumulator.limits.stateless {
+                    let limit = accumulator.limits.text_bytes;
+                    SseAccumulator::append_capped(&mut accumulator.text, &data, limit, "text", &mut accumulator.truncated);
+                }
                 Some(create_content_chunk(
                     request_id.to_string(),
                     model_name.to_string(),
-                    delta_content,
+                    data,
                 ))
             }
         }
+         "action" | "sources" | "repoSources" | "rlq" | "q" | "r" if accumulator.limits.stateless => {
+            trace!(event_name = event_name, "Stateless streaming, not collecting the event");
+            None
+         }
          "action" => {
             match safe_json_parse::<DevAction>(&data) {
                 Some(a) => {
                     trace!(action = ?a, "Parsed action event");
-                    accumulator.actions.push(a);
+                    if accumulator.actions.len() < accumulator.limits.actions {
+                        accumulator.actions.push(a);
+                    } else if accumulator.truncated.insert("actions") {
+                        warn!(cap = accumulator.limits.actions, "Accumulator field hit its cap, no longer collecting it");
+                        TRUNCATIONS.with_label_values(&["actions"]).inc();
+                    }
                 }
-                None => warn!(data = %data, "Failed to parse action event data"),
+                None if log_sampling::allow("malformed_action") => warn!(data = %data, "Failed to parse action event data"),
+                None => {}
             }
             None // Actions don't generate OpenAI chunks directly
          }
@@ -378,7 +620,8 @@ fn process_single_dev_event(
                     trace!(sources = ?s, "Parsed sources event");
                     accumulator.sources = s; // Overwrite sources with the latest list
                  }
-                 None => warn!(data = %data, "Failed to parse sources event data"),
+                 None if log_sampling::allow("malformed_sources") => warn!(data = %data, "Failed to parse sources event data"),
+                 None => {}
              }
              None
          }
@@ -388,19 +631,24 @@ fn process_single_dev_event(
                     trace!(github_sources = ?gs, "Parsed repoSources event");
                     accumulator.github_sources = gs; // Overwrite repo sources
                  }
-                 None => warn!(data = %data, "Failed to parse repoSources event data"),
+                 None if log_sampling::allow("malformed_repo_sources") => warn!(data = %data, "Failed to parse repoSources event data"),
+                 None => {}
             }
             None
          }
          "rlq" | "q" => {
             if !data.is_empty() { // Append only if data is not empty
-                accumulator.related_questions_raw += &format!("\n{}", data.trim()); // Trim whitespace
+                let limit = accumulator.limits.related_questions_bytes;
+                let data = format!("\n{}", data.trim()); // Trim whitespace
+                SseAccumulator::append_capped(&mut accumulator.related_questions_raw, &data, limit, "related_questions", &mut accumulator.truncated);
                 trace!(raw_related = %accumulator.related_questions_raw, "Appended related question data");
             }
             None
          }
          "r" => {
-            accumulator.reasoning.get_or_insert_with(String::new).push_str(&data);
+            let limit = accumulator.limits.reasoning_bytes;
+            let reasoning = accumulator.reasoning.get_or_insert_with(String::new);
+            SseAccumulator::append_capped(reasoning, &data, limit, "reasoning", &mut accumulator.truncated);
             trace!(reasoning = ?accumulator.reasoning, "Appended reasoning data");
             None
          }
@@ -416,7 +664,7 @@ fn process_single_dev_event(
             Some(create_error_chunk(
                 request_id.to_string(),
                 model_name.to_string(),
-                data
+                DevError::parse(None, &data)
             ))
         }
         // Handle potential "finish" event from Dev if it exists (though not seen in JS)
@@ -430,7 +678,9 @@ fn process_single_dev_event(
              None
         }
         _ => {
-            trace!(event_name = event_name, "Ignoring unknown or unhandled Dev event type.");
+            if log_sampling::allow("unknown_event") {
+                warn!(event_name = event_name, request_id = request_id, "Ignoring unknown Dev event type");
+            }
             None /* Ignore unknown event types */
         }
     }
@@ -443,6 +693,7 @@ fn create_content_chunk(id: String, model: String, content: String) -> ChatCompl
         object: "chat.completion.chunk".to_string(),
         created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
         model,
+        system_fingerprint: Some(SYSTEM_FINGERPRINT.clone()),
         choices: vec![Choice {
             index: 0,
             delta: Delta {
@@ -450,7 +701,9 @@ fn create_content_chunk(id: String, model: String, content: String) -> ChatCompl
                 content: Some(content),
             },
             finish_reason: None,
+            logprobs: None,
         }],
+        error: None,
     }
 }
 
@@ -462,33 +715,83 @@ fn create_final_chunk(id: String, model: String, finish_reason: String) -> ChatC
         object: "chat.completion.chunk".to_string(),
         created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
         model,
+        system_fingerprint: Some(SYSTEM_FINGERPRINT.clone()),
         choices: vec![Choice {
             index: 0,
             delta: Delta::default(), // Final chunk has an empty delta
             finish_reason: Some(finish_reason),
+            logprobs: None,
         }],
+        error: None,
+    }
+}
+
+/// Content prefix of chunks produced for Dev `error` events.
+pub const STREAM_ERROR_PREFIX: &str = "[STREAM_ERROR]: ";
+
+/// How the chat stream tells clients about errors (STREAM_ERROR_MODE).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamErrorMode {
+    /// A content chunk `[STREAM_ERROR]: <message>` ending the choice, which
+    /// chat UIs show as part of the answer.
+    #[default]
+    Content,
+    /// An `event: error` whose data is an OpenAI error object, ending the
+    /// stream without `[DONE]`.
+    Event,
+    /// A plain `data:` OpenAI error object ending the stream without
+    /// `[DONE]`, as the OpenAI API does.
+    Data,
+}
+
+impl std::str::FromStr for StreamErrorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "content" => Ok(Self::Content),
+            "event" => Ok(Self::Event),
+            "data" | "openai" => Ok(Self::Data),
+            other => Err(format!("unknown stream error mode '{}', expected content, event or data", other)),
+        }
+    }
+}
+
+impl StreamErrorMode {
+    /// The event ending the stream on `error`; `None` when errors are sent
+    /// as content.
+    pub fn error_event(self, error: &ChunkError) -> Option<(Option<&'static str>, String)> {
+        let data = serde_json::json!({ "error": { "message": error.message, "type": error.error_type, "param": null, "code": error.code } });
+        match self {
+            Self::Content => None,
+            Self::Event => Some((Some("error"), data.to_string())),
+            Self::Data => Some((None, data.to_string())),
+        }
     }
 }
 
 // Helper to create a chunk representing an error received from the Dev stream
 // This chunk includes content indicating the error and a "stop" finish_reason.
-fn create_error_chunk(id: String, model: String, error_message: String) -> ChatCompletionChunk {
-    warn!(request_id = %id, error = %error_message, "Creating error chunk");
+fn create_error_chunk(id: String, model: String, error: DevError) -> ChatCompletionChunk {
+    warn!(request_id = %id, error = %error.message, code = error.code(), "Creating error chunk");
     ChatCompletionChunk {
         id,
         object: "chat.completion.chunk".to_string(),
         created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
         model,
+        system_fingerprint: Some(SYSTEM_FINGERPRINT.clone()),
         choices: vec![Choice {
             index: 0,
             delta: Delta {
                 role: Some("assistant".to_string()), // Maintain assistant role
                 // Include error message in content for visibility, though consumers might handle errors differently
-                content: Some(format!("[STREAM_ERROR]: {}", error_message)),
+                content: Some(format!("{}{}", STREAM_ERROR_PREFIX, error.message)),
             },
             // Crucially, set finish_reason to "stop" so the consumer knows the stream ended here.
             finish_reason: Some("stop".to_string()),
+            logprobs: None,
         }],
+        error: Some(error.to_chunk_error()),
     }
 }
 
@@ -501,61 +804,6 @@ fn create_error_chunk(id: String, model: String, error_message: String) -> ChatC
 mod tests {
     use super::*; // Import items from the parent module (sse_processor)
 
-    #[test]
-    fn test_parse_sse_line_empty() {
-        assert_eq!(parse_sse_line(""), SseLine::Empty);
-    }
-
-    #[test]
-    fn test_parse_sse_line_comment() {
-        assert_eq!(parse_sse_line(": this is a comment"), SseLine::Comment);
-        assert_eq!(parse_sse_line(":"), SseLine::Comment); // Empty comment
-    }
-
-    #[test]
-    fn test_parse_sse_line_event() {
-        assert_eq!(parse_sse_line("event: message"), SseLine::Event("message".to_string()));
-        assert_eq!(parse_sse_line("event:finish"), SseLine::Event("finish".to_string()));
-        assert_eq!(parse_sse_line("event:"), SseLine::Event("".to_string())); // Empty event name
-        assert_eq!(parse_sse_line("event: event with space"), SseLine::Event("event with space".to_string()));
-    }
-
-    #[test]
-    fn test_parse_sse_line_data() {
-        assert_eq!(parse_sse_line("data: {\"key\": \"value\"}"), SseLine::Data("{\"key\": \"value\"}".to_string()));
-        assert_eq!(parse_sse_line("data: simple string"), SseLine::Data("simple string".to_string()));
-        assert_eq!(parse_sse_line("data:"), SseLine::Data("".to_string())); // Empty data
-        assert_eq!(parse_sse_line("data: data with : colon"), SseLine::Data("data with : colon".to_string()));
-        // Test stripping leading space
-        assert_eq!(parse_sse_line("data:  leading space"), SseLine::Data(" leading space".to_string()));
-    }
-     #[test]
-    fn test_parse_sse_line_data_strips_leading_space() {
-        // Should strip only the first leading space after the colon
-        assert_eq!(parse_sse_line("data: {\"key\": \"value\"}"), SseLine::Data("{\"key\": \"value\"}".to_string()));
-        assert_eq!(parse_sse_line("data:  two leading spaces"), SseLine::Data(" two leading spaces".to_string()));
-        assert_eq!(parse_sse_line("data:"), SseLine::Data("".to_string()));
-    }
-
-    #[test]
-    fn test_parse_sse_line_id() {
-        assert_eq!(parse_sse_line("id: 12345"), SseLine::Id("12345".to_string()));
-        assert_eq!(parse_sse_line("id:"), SseLine::Id("".to_string())); // Empty id
-    }
-
-    #[test]
-    fn test_parse_sse_line_retry() {
-        assert_eq!(parse_sse_line("retry: 5000"), SseLine::Retry("5000".to_string()));
-        assert_eq!(parse_sse_line("retry:"), SseLine::Retry("".to_string())); // Empty retry
-    }
-
-     #[test]
-    fn test_parse_sse_line_unknown_field() {
-        // Unknown fields should be treated as comments
-        assert_eq!(parse_sse_line("unknown: some value"), SseLine::Comment);
-        assert_eq!(parse_sse_line("field without colon"), SseLine::Comment); // Treat line without colon as comment (or decide specific behavior)
-    }
-
     // --- Tests for process_single_dev_event ---
 
     // Helper to create a default accumulator for testing
@@ -619,7 +867,7 @@ mod tests {
         let mut acc = default_accumulator();
         let event = "action".to_string();
         // Simple valid JSON for DevAction
-        let data = r#"{"type": "search", "query": "rust sse"}"#.to_string();
+        let data = r#"{"type": 1, "query": "rust sse"}"#.to_string();
 
         let chunk = process_single_dev_event(&mut acc, event, data, TEST_REQ_ID, TEST_MODEL_NAME);
 
@@ -741,6 +989,24 @@ mod tests {
 
         assert_eq!(acc.error, Some("Something went wrong".to_string()));
         assert!(acc.is_finished);
+        // The error object stays off the wire
+        assert!(chunk.error.is_some());
+        assert!(serde_json::to_value(&chunk).unwrap().get("error").is_none());
+    }
+
+    #[test]
+    fn test_stream_error_modes() {
+        let mut acc = default_accumulator();
+        let chunk = process_single_dev_event(&mut acc, "error".to_string(), "quota exceeded".to_string(), TEST_REQ_ID, TEST_MODEL_NAME).unwrap();
+        let error = chunk.error.unwrap();
+        assert_eq!(StreamErrorMode::Content.error_event(&error), None);
+        let (event, data) = StreamErrorMode::Event.error_event(&error).unwrap();
+        assert_eq!(event, Some("error"));
+        let data: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!((data["error"]["message"].as_str(), data["error"]["code"].as_str()), (Some("quota exceeded"), Some("insufficient_quota")));
+        assert_eq!(StreamErrorMode::Data.error_event(&error).unwrap().0, None);
+        assert_eq!("OpenAI".parse(), Ok(StreamErrorMode::Data));
+        assert!("banner".parse::<StreamErrorMode>().is_err());
     }
 
     #[test]
@@ -766,4 +1032,123 @@ mod tests {
     }
 
     // TODO: Add tests for safe_json_parse (optional, low priority)
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_replayed_skips_delivered_text() {
+        let mut replayed = Replayed { delivered: "Hello wörld".to_string(), matched: 0 };
+        assert_eq!(replayed.unseen("Hel"), "");
+        assert_eq!(replayed.unseen("lo w"), "");
+        assert!(!replayed.caught_up());
+        assert_eq!(replayed.unseen("örld, again"), ", again");
+        assert!(replayed.caught_up());
+    }
+
+    #[test]
+    fn test_replayed_divergence_keeps_new_text() {
+        let mut replayed = Replayed { delivered: "The answer is 4".to_string(), matched: 0 };
+        assert_eq!(replayed.unseen("The answer was"), "was");
+        assert!(replayed.caught_up());
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_reissued_after_failure() {
+        // A connection reset midway, produced by a real (failing) request
+        let reset = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        let first: DevByteStream = Box::pin(stream::iter(vec![
+            Ok(Bytes::from("event: c\ndata: Hello\n\nevent: c\ndata:  wor\n\n")),
+            Err(reset),
+        ]));
+        let retry = StreamRetry {
+            attempts: 1,
+            reconnect: Box::new(|| {
+                let second: DevByteStream = Box::pin(stream::iter(vec![Ok(Bytes::from(
+                    "event: c\ndata: Hello world\n\nevent: c\ndata: !\n\n",
+                ))]));
+                futures_util::FutureExt::boxed(async move { Ok(second) })
+            }),
+        };
+        let options = DevRequestOptions::default();
+        let chunks: Vec<_> = process_dev_bytes_stream_with_retry(first, options, TEST_REQ_ID.to_string(), Some(retry))
+            .collect()
+            .await;
+        let text: String = chunks
+            .iter()
+            .map(|c| c.as_ref().unwrap().choices[0].delta.content.clone().unwrap_or_default())
+            .collect();
+        assert_eq!(text, "Hello world!");
+        assert_eq!(chunks.last().unwrap().as_ref().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_last_event_is_streamed() {
+        let bytes = stream::iter(vec![Ok(Bytes::from("event: c\ndata: Hello\n\nevent: c\ndata:  world"))]);
+        let options = DevRequestOptions::default();
+        let chunks: Vec<_> = process_dev_bytes_stream_unfold(bytes, options, TEST_REQ_ID.to_string()).collect().await;
+        let text: String = chunks
+            .iter()
+            .map(|c| c.as_ref().unwrap().choices[0].delta.content.clone().unwrap_or_default())
+            .collect();
+        assert_eq!(text, "Hello world");
+        assert_eq!(chunks.last().unwrap().as_ref().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[test]
+    fn test_accumulator_caps_keep_streaming() {
+        let limits = AccumulatorLimits { text_bytes: 5, reasoning_bytes: 4, related_questions_bytes: 64, actions: 1, stateless: false };
+        let mut acc = SseAccumulator { limits, ..default_accumulator() };
+        let first = process_single_dev_event(&mut acc, "c".to_string(), "Grüße, ".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        let second = process_single_dev_event(&mut acc, "c".to_string(), "world".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        assert_eq!(first.unwrap().choices[0].delta.content.as_deref(), Some("Grüße, "));
+        assert_eq!(second.unwrap().choices[0].delta.content.as_deref(), Some("world"));
+        // The cap falls inside "ß", which is left out whole
+        assert_eq!(acc.text, "Grü");
+        process_single_dev_event(&mut acc, "r".to_string(), "thinking".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        for _ in 0..3 {
+            process_single_dev_event(&mut acc, "action".to_string(), r#"{"type": 1}"#.to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        }
+        assert_eq!(acc.reasoning.as_deref(), Some("thin"));
+        assert_eq!(acc.actions.len(), 1);
+        assert_eq!(acc.truncated.iter().copied().collect::<Vec<_>>(), ["actions", "reasoning", "text"]);
+        assert!(acc.is_truncated());
+    }
+
+    #[test]
+    fn test_events_are_counted_by_canonical_name() {
+        assert_eq!([event_label("c"), event_label("message"), event_label("q"), event_label("r")], ["content", "content", "rlq", "r"]);
+        assert_eq!(event_label("brandNewEvent"), "unknown");
+        let unknown = EVENTS.with_label_values(&["unknown"]);
+        let before = unknown.get();
+        let mut acc = default_accumulator();
+        let chunk = process_single_dev_event(&mut acc, "brandNewEvent".to_string(), "{}".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        assert!(chunk.is_none());
+        assert!(unknown.get() > before);
+    }
+
+    #[test]
+    fn test_stateless_accumulator_keeps_nothing() {
+        let limits = AccumulatorLimits { stateless: true, ..Default::default() };
+        let mut acc = SseAccumulator { limits, ..default_accumulator() };
+        let chunk = process_single_dev_event(&mut acc, "c".to_string(), "Hello".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        assert_eq!(chunk.unwrap().choices[0].delta.content.as_deref(), Some("Hello"));
+        for (event, data) in [("sources", r#"[{"url": "https://a"}]"#), ("rlq", "Why?"), ("r", "hmm"), ("action", r#"{"type": 1}"#)] {
+            assert!(process_single_dev_event(&mut acc, event.to_string(), data.to_string(), TEST_REQ_ID, TEST_MODEL_NAME).is_none());
+        }
+        process_single_dev_event(&mut acc, "threadId".to_string(), "t-1".to_string(), TEST_REQ_ID, TEST_MODEL_NAME);
+        assert!(acc.text.is_empty() && acc.sources.is_empty() && acc.actions.is_empty());
+        assert!(acc.related_questions_raw.is_empty() && acc.reasoning.is_none());
+        assert_eq!(acc.thread_id.as_deref(), Some("t-1"));
+    }
+
+    #[test]
+    fn test_completion_id_and_fingerprint_format() {
+        let id = new_completion_id();
+        assert!(id.starts_with("chatcmpl-"));
+        assert_eq!(id.len(), "chatcmpl-".len() + 32);
+        assert_ne!(id, new_completion_id());
+        assert!(SYSTEM_FINGERPRINT.starts_with("fp_"));
+        assert_eq!(SYSTEM_FINGERPRINT.len(), 13);
+        let chunk = create_final_chunk(id, "m".to_string(), "stop".to_string());
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(json["system_fingerprint"], serde_json::json!(*SYSTEM_FINGERPRINT));
+    }
+}
@@ -0,0 +1,665 @@
+// State shared by the proxy's replicas, so a deployment of several behind a
+// load balancer behaves like one proxy: resumable streams (`replay`),
+// deduplicated in-flight requests (`inflight`), the rate windows of stored
+// API keys (`key_store`), admin login sessions (`oidc`) and assistants
+// threads (`assistants`) live here. STATE_STORE picks the backend: `memory`
+// (the default) keeps the state in the process, `redis` in the Redis server
+// at STATE_STORE_URL (`redis://host:6379/0`). Redis needs the `redis` cargo
+// feature; without it, or without a URL, the state stays in memory with a
+// warning. Keys start with STATE_STORE_PREFIX (`rust_proxy:`), so several
+// deployments can share a server.
+//
+// Besides values, which may expire, a store keeps counters and logs: capped
+// lists of entries numbered from 1 that one replica appends to while others
+// read. Readers on the appending replica are woken at once; readers
+// elsewhere poll every STATE_STORE_POLL_MS (100).
+
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::env_or;
+
+/// How often `update` retries after losing a race with another writer.
+const UPDATE_ATTEMPTS: usize = 16;
+
+/// Where shared state is kept.
+pub trait StateBackend: Send + Sync {
+    /// Name for logs.
+    fn name(&self) -> &'static str;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Stores `value`, expiring after `ttl` if given.
+    fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<()>>;
+
+    /// Replaces what `key` holds with `value` (`None` removes it) if it
+    /// still holds `current` (`None`: nothing); whether it did.
+    fn swap<'a>(
+        &'a self,
+        key: &'a str,
+        current: Option<String>,
+        value: Option<String>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Adds `delta` to the counter at `key`, which starts at 0 and expires
+    /// `ttl` after it was created; the new count.
+    fn add<'a>(&'a self, key: &'a str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'a, Result<i64>>;
+
+    /// Appends `entry` to the log at `key`, keeping its last `capacity`
+    /// entries, and makes the log expire `ttl` from now; the entry's number.
+    fn append<'a>(&'a self, key: &'a str, entry: String, capacity: usize, ttl: Duration) -> BoxFuture<'a, Result<u64>>;
+
+    /// The entries of the log at `key` numbered above `after`, oldest first.
+    fn entries<'a>(&'a self, key: &'a str, after: u64) -> BoxFuture<'a, Result<Vec<(u64, String)>>>;
+
+    /// Makes `key` expire `ttl` from now.
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The shared state of the proxy, on the configured backend.
+#[derive(Clone)]
+pub struct StateStore {
+    backend: Arc<dyn StateBackend>,
+    prefix: Arc<str>,
+    poll: Duration,
+    /// Wakes the readers of a log on this replica when it is appended to.
+    wakers: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryBackend::default()))
+    }
+}
+
+impl std::fmt::Debug for StateStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateStore").field("backend", &self.backend.name()).field("prefix", &self.prefix).finish()
+    }
+}
+
+impl StateStore {
+    pub fn new(backend: Arc<dyn StateBackend>) -> Self {
+        Self { backend, prefix: Arc::from("rust_proxy:"), poll: Duration::from_millis(100), wakers: Arc::default() }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Arc::from(prefix);
+        self
+    }
+
+    pub fn with_poll(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// STATE_STORE, STATE_STORE_URL, STATE_STORE_PREFIX and STATE_STORE_POLL_MS.
+    pub fn from_env() -> Self {
+        let backend = std::env::var("STATE_STORE").unwrap_or_default().trim().to_ascii_lowercase();
+        let store = match backend_from_env(&backend) {
+            Ok(Some(backend)) => Self::new(backend),
+            Ok(None) => Self::default(),
+            Err(e) => {
+                warn!("Keeping shared state in memory: {:#}", e);
+                Self::default()
+            }
+        };
+        let store = store
+            .with_prefix(&env_or("STATE_STORE_PREFIX", "rust_proxy:".to_string()))
+            .with_poll(Duration::from_millis(env_or("STATE_STORE_POLL_MS", 100)));
+        if store.backend.name() != "memory" {
+            info!(backend = store.backend.name(), prefix = %store.prefix, "Shared state is kept outside the process");
+        }
+        store
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.backend.get(&self.key(key)).await
+    }
+
+    pub async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<()> {
+        self.backend.set(&self.key(key), value, ttl).await
+    }
+
+    /// Stores `value` unless `key` holds something; whether it did.
+    pub async fn set_new(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<bool> {
+        self.backend.swap(&self.key(key), None, Some(value), ttl).await
+    }
+
+    /// Removes `key` if it still holds `current`.
+    pub async fn remove_if(&self, key: &str, current: String) -> Result<bool> {
+        self.backend.swap(&self.key(key), Some(current), None, None).await
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        self.backend.remove(&self.key(key)).await
+    }
+
+    pub async fn add(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64> {
+        self.backend.add(&self.key(key), delta, ttl).await
+    }
+
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<()> {
+        self.backend.expire(&self.key(key), ttl).await
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let Some(value) = self.get(key).await? else { return Ok(None) };
+        serde_json::from_str(&value).with_context(|| format!("Invalid state at '{}'", key)).map(Some)
+    }
+
+    pub async fn set_json<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        self.set(key, serde_json::to_string(value)?, None).await
+    }
+
+    /// Runs `f` on the JSON value at `key` and stores what it leaves there
+    /// (`None` removes it), again from the start when another writer changed
+    /// the value meanwhile. Nothing is stored when `f` fails.
+    pub async fn update<T, R, E>(
+        &self,
+        key: &str,
+        mut f: impl FnMut(&mut Option<T>) -> Result<R, E>,
+    ) -> Result<Result<R, E>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let key = self.key(key);
+        for _ in 0..UPDATE_ATTEMPTS {
+            let current = self.backend.get(&key).await?;
+            let mut value = current
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .with_context(|| format!("Invalid state at '{}'", key))?;
+            let result = match f(&mut value) {
+                Ok(result) => result,
+                Err(e) => return Ok(Err(e)),
+            };
+            let value = value.map(|value| serde_json::to_string(&value)).transpose()?;
+            if value == current || self.backend.swap(&key, current, value, None).await? {
+                return Ok(Ok(result));
+            }
+        }
+        bail!("'{}' kept changing while it was updated", key)
+    }
+
+    /// Appends `entry` to the log at `key`, see `StateBackend::append`.
+    pub async fn append(&self, key: &str, entry: String, capacity: usize, ttl: Duration) -> Result<u64> {
+        let number = self.backend.append(&self.key(key), entry, capacity.max(1), ttl).await?;
+        self.wake(key);
+        Ok(number)
+    }
+
+    /// Wakes the readers of the log at `key` on this replica, as an append
+    /// does; for news they find elsewhere.
+    pub fn wake(&self, key: &str) {
+        if let Some(waker) = self.wakers.lock().unwrap().get(key) {
+            waker.notify_waiters();
+        }
+    }
+
+    pub async fn entries(&self, key: &str, after: u64) -> Result<Vec<(u64, String)>> {
+        self.backend.entries(&self.key(key), after).await
+    }
+
+    /// Waits for an append to the log at `key`: resolves when one happens on
+    /// this replica, or after the poll interval (at most `timeout`). Create
+    /// it before reading the log, so no append goes unnoticed.
+    pub fn appended(&self, key: &str, timeout: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let waker = WakerGuard::new(self.wakers.clone(), key);
+        let timeout = timeout.min(self.poll);
+        let notify = waker.notify.clone();
+        let mut notified = Box::pin(async move { notify.notified().await });
+        // Polled once so it counts as waiting from now on
+        let _ = futures_util::FutureExt::now_or_never(notified.as_mut());
+        async move {
+            let _ = tokio::time::timeout(timeout, notified).await;
+            drop(waker);
+        }
+    }
+}
+
+/// A reader's hold on the waker of a log; the last one removes it.
+struct WakerGuard {
+    wakers: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+    key: String,
+    notify: Arc<Notify>,
+}
+
+impl WakerGuard {
+    fn new(wakers: Arc<Mutex<HashMap<String, Arc<Notify>>>>, key: &str) -> Self {
+        let notify = wakers.lock().unwrap().entry(key.to_string()).or_default().clone();
+        Self { wakers, key: key.to_string(), notify }
+    }
+}
+
+impl Drop for WakerGuard {
+    fn drop(&mut self) {
+        let mut wakers = self.wakers.lock().unwrap();
+        // Held by the map and this guard only
+        if wakers.get(&self.key).is_some_and(|n| Arc::strong_count(n) <= 2) {
+            wakers.remove(&self.key);
+        }
+    }
+}
+
+fn backend_from_env(backend: &str) -> Result<Option<Arc<dyn StateBackend>>> {
+    match backend {
+        "" | "memory" => Ok(None),
+        "redis" => {
+            let Some(url) = std::env::var("STATE_STORE_URL").ok().filter(|v| !v.trim().is_empty()) else {
+                bail!("STATE_STORE is 'redis' but STATE_STORE_URL is not set");
+            };
+            redis_backend::backend(&url).map(Some)
+        }
+        other => bail!("Unknown STATE_STORE '{}', expected 'memory' or 'redis'", other),
+    }
+}
+
+enum Value {
+    Text(String),
+    Counter(i64),
+    /// The entries and the number of the last one.
+    Log(VecDeque<(u64, String)>, u64),
+}
+
+struct Stored {
+    value: Value,
+    expires: Option<Instant>,
+}
+
+impl Stored {
+    fn live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|at| at > now)
+    }
+}
+
+/// State in the process, for a single replica.
+#[derive(Default)]
+pub struct MemoryBackend {
+    entries: Mutex<HashMap<String, Stored>>,
+    swept: Mutex<Option<Instant>>,
+}
+
+impl MemoryBackend {
+    /// Runs `f` on the entries, after dropping expired ones at most once a
+    /// second; `f` skips those expired since.
+    fn with<T>(&self, f: impl FnOnce(&mut HashMap<String, Stored>, Instant) -> T) -> T {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut swept = self.swept.lock().unwrap();
+        if swept.is_none_or(|at| now.duration_since(at) >= Duration::from_secs(1)) {
+            entries.retain(|_, stored| stored.live(now));
+            *swept = Some(now);
+        }
+        drop(swept);
+        f(&mut entries, now)
+    }
+
+    fn text(stored: Option<&Stored>, key: &str) -> Result<Option<String>> {
+        match stored.map(|s| &s.value) {
+            None => Ok(None),
+            Some(Value::Text(text)) => Ok(Some(text.clone())),
+            Some(Value::Counter(count)) => Ok(Some(count.to_string())),
+            Some(Value::Log(..)) => bail!("'{}' holds a log", key),
+        }
+    }
+}
+
+impl StateBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        let result = self.with(|entries, now| Self::text(entries.get(key).filter(|s| s.live(now)), key));
+        Box::pin(async move { result })
+    }
+
+    fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<()>> {
+        self.with(|entries, now| {
+            entries.insert(key.to_string(), Stored { value: Value::Text(value), expires: ttl.map(|ttl| now + ttl) });
+        });
+        Box::pin(async { Ok(()) })
+    }
+
+    fn swap<'a>(
+        &'a self,
+        key: &'a str,
+        current: Option<String>,
+        value: Option<String>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let result = self.with(|entries, now| {
+            if Self::text(entries.get(key).filter(|s| s.live(now)), key)? != current {
+                return Ok(false);
+            }
+            match value {
+                Some(value) => {
+                    entries.insert(key.to_string(), Stored { value: Value::Text(value), expires: ttl.map(|ttl| now + ttl) });
+                }
+                None => {
+                    entries.remove(key);
+                }
+            }
+            Ok(true)
+        });
+        Box::pin(async move { result })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.with(|entries, _| entries.remove(key));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn add<'a>(&'a self, key: &'a str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'a, Result<i64>> {
+        let result = self.with(|entries, now| {
+            if !entries.get(key).is_some_and(|s| s.live(now)) {
+                entries.insert(key.to_string(), Stored { value: Value::Counter(0), expires: ttl.map(|ttl| now + ttl) });
+            }
+            let stored = entries.get_mut(key).expect("counter inserted");
+            if let Value::Text(text) = &stored.value
+                && let Ok(count) = text.parse()
+            {
+                stored.value = Value::Counter(count);
+            }
+            match &mut stored.value {
+                Value::Counter(count) => {
+                    *count += delta;
+                    Ok(*count)
+                }
+                _ => bail!("'{}' does not hold a counter", key),
+            }
+        });
+        Box::pin(async move { result })
+    }
+
+    fn append<'a>(&'a self, key: &'a str, entry: String, capacity: usize, ttl: Duration) -> BoxFuture<'a, Result<u64>> {
+        let result = self.with(|entries, now| {
+            if !entries.get(key).is_some_and(|s| s.live(now)) {
+                entries.insert(key.to_string(), Stored { value: Value::Log(VecDeque::new(), 0), expires: None });
+            }
+            let stored = entries.get_mut(key).expect("log inserted");
+            stored.expires = Some(now + ttl);
+            let Value::Log(log, last) = &mut stored.value else { bail!("'{}' does not hold a log", key) };
+            *last += 1;
+            log.push_back((*last, entry));
+            while log.len() > capacity {
+                log.pop_front();
+            }
+            Ok(*last)
+        });
+        Box::pin(async move { result })
+    }
+
+    fn entries<'a>(&'a self, key: &'a str, after: u64) -> BoxFuture<'a, Result<Vec<(u64, String)>>> {
+        let result = self.with(|entries, now| match entries.get(key).filter(|s| s.live(now)).map(|s| &s.value) {
+            None => Ok(Vec::new()),
+            Some(Value::Log(log, _)) => Ok(log.iter().filter(|(number, _)| *number > after).cloned().collect()),
+            Some(_) => bail!("'{}' does not hold a log", key),
+        });
+        Box::pin(async move { result })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        self.with(|entries, now| {
+            if let Some(stored) = entries.get_mut(key).filter(|s| s.live(now)) {
+                stored.expires = Some(now + ttl);
+            }
+        });
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use super::*;
+    use futures_util::FutureExt;
+    use once_cell::sync::Lazy;
+    use redis::aio::ConnectionManager;
+    use redis::Script;
+    use tokio::sync::OnceCell;
+
+    /// KEYS[1]; ARGV: whether a current value is expected, it, whether a new
+    /// value is given, it, the expiry in ms (0: none).
+    static SWAP: Lazy<Script> = Lazy::new(|| {
+        Script::new(
+            r"local current = redis.call('GET', KEYS[1])
+            if (ARGV[1] == '0' and current) or (ARGV[1] == '1' and current ~= ARGV[2]) then return 0 end
+            if ARGV[3] == '0' then
+                redis.call('DEL', KEYS[1])
+            elseif tonumber(ARGV[5]) > 0 then
+                redis.call('SET', KEYS[1], ARGV[4], 'PX', ARGV[5])
+            else
+                redis.call('SET', KEYS[1], ARGV[4])
+            end
+            return 1",
+        )
+    });
+
+    /// KEYS[1]; ARGV: the delta, the expiry in ms (0: none).
+    static ADD: Lazy<Script> = Lazy::new(|| {
+        Script::new(
+            r"local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+            if tonumber(ARGV[2]) > 0 and redis.call('PTTL', KEYS[1]) == -1 then
+                redis.call('PEXPIRE', KEYS[1], ARGV[2])
+            end
+            return count",
+        )
+    });
+
+    /// A log is a sorted set of `<number>:<entry>` scored by number.
+    /// KEYS[1]; ARGV: the entry, the capacity, the expiry in ms.
+    static APPEND: Lazy<Script> = Lazy::new(|| {
+        Script::new(
+            r"local last = redis.call('ZRANGE', KEYS[1], -1, -1, 'WITHSCORES')
+            local number = 1
+            if #last > 0 then number = tonumber(last[2]) + 1 end
+            redis.call('ZADD', KEYS[1], number, number .. ':' .. ARGV[1])
+            redis.call('ZREMRANGEBYRANK', KEYS[1], 0, -(tonumber(ARGV[2]) + 1))
+            redis.call('PEXPIRE', KEYS[1], ARGV[3])
+            return number",
+        )
+    });
+
+    fn millis(ttl: Option<Duration>) -> u64 {
+        ttl.map_or(0, |ttl| ttl.as_millis().max(1) as u64)
+    }
+
+    /// Connects on first use, so startup does not wait for the server.
+    struct RedisBackend {
+        client: redis::Client,
+        connection: OnceCell<ConnectionManager>,
+    }
+
+    impl RedisBackend {
+        async fn connection(&self) -> Result<ConnectionManager> {
+            let connection = self
+                .connection
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await
+                .context("connecting to the state store")?;
+            Ok(connection.clone())
+        }
+    }
+
+    impl StateBackend for RedisBackend {
+        fn name(&self) -> &'static str {
+            "redis"
+        }
+
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+            async move { Ok(redis::cmd("GET").arg(key).query_async(&mut self.connection().await?).await?) }.boxed()
+        }
+
+        fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut command = redis::cmd("SET");
+                command.arg(key).arg(value);
+                if ttl.is_some() {
+                    command.arg("PX").arg(millis(ttl));
+                }
+                Ok(command.query_async(&mut self.connection().await?).await?)
+            }
+            .boxed()
+        }
+
+        fn swap<'a>(
+            &'a self,
+            key: &'a str,
+            current: Option<String>,
+            value: Option<String>,
+            ttl: Option<Duration>,
+        ) -> BoxFuture<'a, Result<bool>> {
+            async move {
+                let swapped: i64 = SWAP
+                    .key(key)
+                    .arg(current.is_some() as u8)
+                    .arg(current.unwrap_or_default())
+                    .arg(value.is_some() as u8)
+                    .arg(value.unwrap_or_default())
+                    .arg(millis(ttl))
+                    .invoke_async(&mut self.connection().await?)
+                    .await?;
+                Ok(swapped == 1)
+            }
+            .boxed()
+        }
+
+        fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+            async move { Ok(redis::cmd("DEL").arg(key).query_async(&mut self.connection().await?).await?) }.boxed()
+        }
+
+        fn add<'a>(&'a self, key: &'a str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'a, Result<i64>> {
+            async move { Ok(ADD.key(key).arg(delta).arg(millis(ttl)).invoke_async(&mut self.connection().await?).await?) }
+                .boxed()
+        }
+
+        fn append<'a>(&'a self, key: &'a str, entry: String, capacity: usize, ttl: Duration) -> BoxFuture<'a, Result<u64>> {
+            async move {
+                let number = APPEND
+                    .key(key)
+                    .arg(entry)
+                    .arg(capacity)
+                    .arg(millis(Some(ttl)))
+                    .invoke_async(&mut self.connection().await?)
+                    .await?;
+                Ok(number)
+            }
+            .boxed()
+        }
+
+        fn entries<'a>(&'a self, key: &'a str, after: u64) -> BoxFuture<'a, Result<Vec<(u64, String)>>> {
+            async move {
+                let members: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+                    .arg(key)
+                    .arg(format!("({}", after))
+                    .arg("+inf")
+                    .query_async(&mut self.connection().await?)
+                    .await?;
+                members
+                    .into_iter()
+                    .map(|member| {
+                        let (number, entry) = member.split_once(':').context("log entry without a number")?;
+                        Ok((number.parse()?, entry.to_string()))
+                    })
+                    .collect()
+            }
+            .boxed()
+        }
+
+        fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+            async move {
+                Ok(redis::cmd("PEXPIRE").arg(key).arg(millis(Some(ttl))).query_async(&mut self.connection().await?).await?)
+            }
+            .boxed()
+        }
+    }
+
+    pub(super) fn backend(url: &str) -> Result<Arc<dyn StateBackend>> {
+        let client = redis::Client::open(url).with_context(|| format!("Invalid STATE_STORE_URL '{}'", url))?;
+        Ok(Arc::new(RedisBackend { client, connection: OnceCell::new() }))
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+mod redis_backend {
+    use super::*;
+
+    pub(super) fn backend(_url: &str) -> Result<Arc<dyn StateBackend>> {
+        bail!("STATE_STORE is 'redis' but this build lacks the `redis` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_values_expire_and_swap() {
+        let store = StateStore::default();
+        store.set("a", "1".to_string(), Some(Duration::from_millis(50))).await.unwrap();
+        assert!(!store.set_new("a", "2".to_string(), None).await.unwrap());
+        assert!(!store.remove_if("a", "2".to_string()).await.unwrap());
+        assert_eq!(store.get("a").await.unwrap().as_deref(), Some("1"));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(store.set_new("a", "2".to_string(), None).await.unwrap());
+        assert!(store.remove_if("a", "2".to_string()).await.unwrap());
+        assert_eq!(store.get("a").await.unwrap(), None);
+
+        assert_eq!(store.add("n", 2, Some(Duration::from_millis(50))).await.unwrap(), 2);
+        assert_eq!(store.add("n", -1, Some(Duration::from_millis(50))).await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(store.add("n", 1, None).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_keeps_failed_changes_out() {
+        let store = StateStore::default();
+        let pushed = store.update("list", |list: &mut Option<Vec<u32>>| {
+            list.get_or_insert_with(Vec::new).push(1);
+            Ok::<_, ()>(list.as_ref().map_or(0, Vec::len))
+        });
+        assert_eq!(pushed.await.unwrap(), Ok(1));
+        let failed = store.update("list", |list: &mut Option<Vec<u32>>| {
+            list.get_or_insert_with(Vec::new).push(2);
+            Err::<(), _>("full")
+        });
+        assert_eq!(failed.await.unwrap(), Err("full"));
+        assert_eq!(store.get_json::<Vec<u32>>("list").await.unwrap(), Some(vec![1]));
+        store.update("list", |list: &mut Option<Vec<u32>>| Ok::<_, ()>(list.take())).await.unwrap().unwrap();
+        assert_eq!(store.get("list").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_logs_wake_their_readers() {
+        let store = StateStore::default().with_poll(Duration::from_secs(60));
+        for entry in ["a", "b", "c"] {
+            store.append("log", entry.to_string(), 2, Duration::from_secs(60)).await.unwrap();
+        }
+        let entries = store.entries("log", 0).await.unwrap();
+        assert_eq!(entries, [(2, "b".to_string()), (3, "c".to_string())]);
+        assert!(store.entries("log", 3).await.unwrap().is_empty());
+
+        let appended = store.appended("log", Duration::from_secs(60));
+        store.append("log", "d".to_string(), 2, Duration::from_secs(60)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), appended).await.unwrap();
+        assert!(store.wakers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+// Registry of in-flight chat streams, backing the admin routes
+// `GET /admin/streams` and `DELETE /admin/streams/{id}`. The chat handler
+// registers each stream and keeps the returned guard alive inside the
+// response body, so entries disappear as soon as the stream ends.
+//
+// `GET /admin/streams/{id}/tail` mirrors a stream to an operator as SSE while
+// it runs: `dev` events carry the raw bytes Dev sent for a choice, `chunk`
+// events what the client received, and `end` follows the last of them.
+// Mirroring costs nothing while nobody tails; a tail that falls behind skips
+// ahead with a `lagged` event.
+
+use axum::extract::{Path, State};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::AbortHandle;
+use futures_util::StreamExt;
+use http::StatusCode;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::error::ApiError;
+
+/// What the handler knows about a stream when it starts.
+#[derive(Debug, Clone)]
+pub struct StreamMeta {
+    pub request_id: String,
+    pub api_key_id: String,
+    pub model: Option<String>,
+}
+
+/// Mirrored events a tail can fall behind by before it skips ahead.
+const TAIL_CAPACITY: usize = 1024;
+
+struct Entry {
+    meta: StreamMeta,
+    started_at: u64,
+    started: Instant,
+    bytes_out: Arc<AtomicU64>,
+    chunks: Arc<AtomicU64>,
+    abort: AbortHandle,
+    tap: StreamTap,
+}
+
+/// What a tail receives.
+#[derive(Debug, Clone)]
+pub enum TailEvent {
+    /// Bytes of the Dev response of choice `choice`, as received.
+    Dev { choice: u32, raw: String },
+    /// An SSE event sent to the client.
+    Chunk { event: Option<&'static str>, data: String },
+}
+
+impl TailEvent {
+    fn into_sse(self) -> SseEvent {
+        match self {
+            TailEvent::Dev { choice, raw } => {
+                SseEvent::default().event("dev").data(serde_json::json!({ "choice": choice, "raw": raw }).to_string())
+            }
+            TailEvent::Chunk { event, data } => {
+                SseEvent::default().event("chunk").data(serde_json::json!({ "event": event, "data": data }).to_string())
+            }
+        }
+    }
+}
+
+/// Mirrors a stream to its tails, if any.
+#[derive(Debug, Clone)]
+pub struct StreamTap(broadcast::Sender<TailEvent>);
+
+impl StreamTap {
+    fn new() -> Self {
+        Self(broadcast::channel(TAIL_CAPACITY).0)
+    }
+
+    fn is_tailed(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    /// Mirrors bytes Dev sent for choice `choice`.
+    pub fn dev(&self, choice: u32, bytes: &[u8]) {
+        if self.is_tailed() {
+            let _ = self.0.send(TailEvent::Dev { choice, raw: String::from_utf8_lossy(bytes).into_owned() });
+        }
+    }
+
+    /// Mirrors an SSE event sent to the client.
+    pub fn chunk(&self, event: Option<&'static str>, data: &str) {
+        if self.is_tailed() {
+            let _ = self.0.send(TailEvent::Chunk { event, data: data.to_string() });
+        }
+    }
+}
+
+/// Snapshot of one stream as returned by the admin API.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct StreamInfo {
+    pub id: u64,
+    pub request_id: String,
+    pub api_key_id: String,
+    pub model: Option<String>,
+    pub started_at: u64,
+    pub age_ms: u64,
+    pub bytes_out: u64,
+    pub chunks: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    entries: Arc<Mutex<HashMap<u64, Entry>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a stream that can be stopped through `abort`.
+    pub fn register(&self, meta: StreamMeta, abort: AbortHandle) -> ActiveStream {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_out = Arc::new(AtomicU64::new(0));
+        let chunks = Arc::new(AtomicU64::new(0));
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let tap = StreamTap::new();
+        self.entries.lock().unwrap().insert(id, Entry {
+            meta,
+            started_at,
+            started: Instant::now(),
+            bytes_out: bytes_out.clone(),
+            chunks: chunks.clone(),
+            abort,
+            tap: tap.clone(),
+        });
+        ActiveStream { id, registry: self.clone(), bytes_out, chunks, tap }
+    }
+
+    /// Tails the stream whose numeric id or request id equals `id`.
+    pub fn subscribe(&self, id: &str) -> Option<broadcast::Receiver<TailEvent>> {
+        let entries = self.entries.lock().unwrap();
+        let numeric = id.parse::<u64>().ok();
+        entries
+            .iter()
+            .find(|(entry_id, entry)| Some(**entry_id) == numeric || entry.meta.request_id == id)
+            .map(|(_, entry)| entry.tap.0.subscribe())
+    }
+
+    /// Active streams, oldest first.
+    pub fn list(&self) -> Vec<StreamInfo> {
+        let entries = self.entries.lock().unwrap();
+        let mut streams: Vec<StreamInfo> = entries
+            .iter()
+            .map(|(id, entry)| StreamInfo {
+                id: *id,
+                request_id: entry.meta.request_id.clone(),
+                api_key_id: entry.meta.api_key_id.clone(),
+                model: entry.meta.model.clone(),
+                started_at: entry.started_at,
+                age_ms: entry.started.elapsed().as_millis() as u64,
+                bytes_out: entry.bytes_out.load(Ordering::Relaxed),
+                chunks: entry.chunks.load(Ordering::Relaxed),
+            })
+            .collect();
+        streams.sort_by_key(|s| s.id);
+        streams
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Aborts every stream whose numeric id or request id equals `id`;
+    /// returns the ids that were cancelled.
+    pub fn cancel(&self, id: &str) -> Vec<u64> {
+        let entries = self.entries.lock().unwrap();
+        let numeric = id.parse::<u64>().ok();
+        entries
+            .iter()
+            .filter(|(entry_id, entry)| Some(**entry_id) == numeric || entry.meta.request_id == id)
+            .map(|(entry_id, entry)| {
+                entry.abort.abort();
+                *entry_id
+            })
+            .collect()
+    }
+}
+
+/// Guard for a registered stream; keep it inside the response body. Drop
+/// removes the entry.
+pub struct ActiveStream {
+    id: u64,
+    registry: StreamRegistry,
+    bytes_out: Arc<AtomicU64>,
+    chunks: Arc<AtomicU64>,
+    tap: StreamTap,
+}
+
+impl ActiveStream {
+    pub fn record_chunk(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mirrors the stream to its tails.
+    pub fn tap(&self) -> &StreamTap {
+        &self.tap
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.registry.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// `GET /admin/streams`
+#[utoipa::path(get, path = "/admin/streams", tag = "admin", security(("admin_token" = [])), responses(
+    (status = 200, description = "`{\"object\": \"list\", \"data\": [StreamInfo]}`", body = Object),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn list_streams_handler(State(streams): State<StreamRegistry>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "object": "list", "data": streams.list() }))
+}
+
+/// `DELETE /admin/streams/{id}`, where `id` is the stream id or request id.
+#[utoipa::path(delete, path = "/admin/streams/{id}", tag = "admin", security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Stream id or request id")),
+    responses(
+        (status = 200, description = "Ids of the cancelled streams", body = Object, example = json!({"cancelled": [3]})),
+        (status = 404, description = "No matching stream", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn cancel_stream_handler(State(streams): State<StreamRegistry>, Path(id): Path<String>) -> Response {
+    let cancelled = streams.cancel(&id);
+    if cancelled.is_empty() {
+        return ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No active stream '{}'", id))
+            .with_code("stream_not_found")
+            .into_response();
+    }
+    warn!(id, ?cancelled, "Stream cancelled by administrator");
+    Json(serde_json::json!({ "cancelled": cancelled })).into_response()
+}
+
+/// `GET /admin/streams/{id}/tail`, where `id` is the stream id or request id.
+#[utoipa::path(get, path = "/admin/streams/{id}/tail", tag = "admin", security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Stream id or request id")),
+    responses(
+        (status = 200, description = "SSE: `dev` (`{\"choice\", \"raw\"}`), `chunk` (`{\"event\", \"data\"}`), `lagged` and a final `end`", body = String),
+        (status = 404, description = "No matching stream", body = ErrorBody),
+        (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+    ),
+)]
+pub async fn tail_stream_handler(State(streams): State<StreamRegistry>, Path(id): Path<String>) -> Response {
+    let Some(receiver) = streams.subscribe(&id) else {
+        return ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No active stream '{}'", id))
+            .with_code("stream_not_found")
+            .into_response();
+    };
+    info!(id, "Administrator is tailing a stream");
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event.into_sse(), receiver)),
+            Err(RecvError::Lagged(skipped)) => Some((SseEvent::default().event("lagged").data(skipped.to_string()), receiver)),
+            Err(RecvError::Closed) => None,
+        }
+    })
+    .chain(futures_util::stream::once(async { SseEvent::default().event("end").data("{}") }));
+    Sse::new(events.map(Ok::<_, Infallible>)).keep_alive(KeepAlive::new().interval(Duration::from_secs(15))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::{self, Abortable, StreamExt};
+
+    fn meta(request_id: &str) -> StreamMeta {
+        StreamMeta { request_id: request_id.to_string(), api_key_id: "key_x".to_string(), model: None }
+    }
+
+    #[test]
+    fn test_register_list_and_drop() {
+        let registry = StreamRegistry::new();
+        let (abort, _) = AbortHandle::new_pair();
+        let active = registry.register(meta("req-1"), abort);
+        active.record_chunk(10);
+        active.record_chunk(5);
+        let streams = registry.list();
+        assert_eq!(streams.len(), 1);
+        assert_eq!((streams[0].bytes_out, streams[0].chunks), (15, 2));
+        drop(active);
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_request_id_stops_stream() {
+        let registry = StreamRegistry::new();
+        let (abort, registration) = AbortHandle::new_pair();
+        let _active = registry.register(meta("req-2"), abort);
+        let mut body = Abortable::new(stream::repeat(1u8), registration);
+        assert_eq!(body.next().await, Some(1));
+
+        assert_eq!(registry.cancel("req-2"), vec![1]);
+        assert_eq!(body.next().await, None);
+        assert!(registry.cancel("unknown").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tail_mirrors_until_the_stream_ends() {
+        let registry = StreamRegistry::new();
+        let (abort, _) = AbortHandle::new_pair();
+        let active = registry.register(meta("req-3"), abort);
+        // Nobody tails yet: nothing is kept
+        active.tap().chunk(None, "lost");
+        let mut tail = registry.subscribe("req-3").unwrap();
+        assert!(registry.subscribe("req-4").is_none());
+
+        active.tap().dev(0, b"data: Hel");
+        active.tap().chunk(None, "{\"choices\":[]}");
+        drop(active);
+        assert!(matches!(tail.recv().await, Ok(TailEvent::Dev { choice: 0, raw }) if raw == "data: Hel"));
+        assert!(matches!(tail.recv().await, Ok(TailEvent::Chunk { event: None, .. })));
+        assert!(matches!(tail.recv().await, Err(RecvError::Closed)));
+    }
+}
@@ -0,0 +1,104 @@
+// Proxy-level system prompt, prepended to the prompt of every chat request
+// so guardrails or persona text apply whatever the client sends. The most
+// specific one wins: per API key id, then per model (as requested), then
+// SYSTEM_PROMPT. The per-key and per-model texts come from SYSTEM_PROMPTS,
+// a JSON object `{"keys": {id: text}, "models": {name: text}}`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    #[serde(default)]
+    models: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemPrompts {
+    default: Option<String>,
+    overrides: Overrides,
+}
+
+impl SystemPrompts {
+    pub fn new(default: Option<String>) -> Self {
+        Self { default, overrides: Overrides::default() }
+    }
+
+    /// Adds the per-key and per-model prompts of a SYSTEM_PROMPTS value.
+    pub fn with_overrides(mut self, json: &str) -> Result<Self> {
+        self.overrides = serde_json::from_str(json).context("Invalid SYSTEM_PROMPTS")?;
+        Ok(self)
+    }
+
+    /// SYSTEM_PROMPT and SYSTEM_PROMPTS; invalid overrides are ignored.
+    pub fn from_env() -> Self {
+        let prompts = Self::new(std::env::var("SYSTEM_PROMPT").ok().filter(|v| !v.trim().is_empty()));
+        let prompts = match std::env::var("SYSTEM_PROMPTS").ok().filter(|v| !v.trim().is_empty()) {
+            Some(json) => prompts.clone().with_overrides(&json).unwrap_or_else(|e| {
+                warn!("Ignoring SYSTEM_PROMPTS: {:#}", e);
+                prompts
+            }),
+            None => prompts,
+        };
+        if !prompts.is_empty() {
+            info!(
+                default = prompts.default.is_some(),
+                keys = prompts.overrides.keys.len(),
+                models = prompts.overrides.models.len(),
+                "System prompt injection enabled"
+            );
+        }
+        prompts
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.overrides.keys.is_empty() && self.overrides.models.is_empty()
+    }
+
+    /// The system prompt for a request by `api_key_id` for `model`.
+    pub fn get(&self, api_key_id: &str, model: Option<&str>) -> Option<&str> {
+        self.overrides
+            .keys
+            .get(api_key_id)
+            .or_else(|| model.and_then(|model| self.overrides.models.get(model)))
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+
+    /// `prompt` preceded by the system prompt, if there is one.
+    pub fn apply(&self, api_key_id: &str, model: Option<&str>, prompt: String) -> String {
+        match self.get(api_key_id, model) {
+            Some(system) => format!("{}\n\n{}", system.trim_end(), prompt),
+            None => prompt,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_specific_prompt_wins() {
+        let prompts = SystemPrompts::new(Some("Be polite.".to_string()))
+            .with_overrides(r#"{"keys": {"key_team": "Answer in German."}, "models": {"gpt-4o": "Be brief."}}"#)
+            .unwrap();
+        assert_eq!(prompts.get("key_team", Some("gpt-4o")), Some("Answer in German."));
+        assert_eq!(prompts.get("key_other", Some("gpt-4o")), Some("Be brief."));
+        assert_eq!(prompts.get("key_other", None), Some("Be polite."));
+        assert_eq!(prompts.apply("key_other", None, "hi".to_string()), "Be polite.\n\nhi");
+    }
+
+    #[test]
+    fn test_no_prompt_leaves_request_alone() {
+        let prompts = SystemPrompts::default();
+        assert!(prompts.is_empty());
+        assert_eq!(prompts.apply("key", Some("m"), "hi".to_string()), "hi");
+        assert!(SystemPrompts::default().with_overrides(r#"{"users": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::{info, warn, Level};
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer, Registry};
+
+use crate::{access_log, audit};
+use crate::config::env_or;
+
+/// Output format of the log lines, selected with `LOG_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable, colored lines (default).
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki/CloudWatch style ingestion.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "pretty" | "" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("unknown log format '{}', expected 'text' or 'json'", other)),
+        }
+    }
+}
+
+/// Installs the global tracing subscriber. Call after loading `.env` so
+/// `RUST_LOG`, `LOG_FORMAT` and the dedicated log paths from the file are honored.
+pub fn init_tracing() {
+    let format: LogFormat = env_or("LOG_FORMAT", LogFormat::Text);
+
+    // JSON lines carry the event fields at the top level plus the fields of
+    // the enclosing span (method, uri, request id, ...).
+    let main_layer: Box<dyn Layer<Registry> + Send + Sync> = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    // Access and audit log lines go to their own JSON-lines files when
+    // ACCESS_LOG_PATH / AUDIT_LOG_PATH are set, and are then kept out of the
+    // main output.
+    let access_file = DedicatedLog::open("ACCESS_LOG_PATH", access_log::TARGET);
+    let audit_file = DedicatedLog::open("AUDIT_LOG_PATH", audit::TARGET);
+
+    let mut env_filter = EnvFilter::new(
+        // Now std::env::var will see variables loaded from .env files
+        std::env::var("RUST_LOG").unwrap_or_else(|_| "bootstrap=debug,main=debug,rust_proxy=debug,tower_http=debug,access_log=info,audit_log=info,script=debug".into()),
+    );
+    for log in [&access_file, &audit_file] {
+        if log.file.is_some() {
+            env_filter = env_filter.add_directive(format!("{}=off", log.target).parse().unwrap());
+        }
+    }
+    let access_report = access_file.report();
+    let audit_report = audit_file.report();
+
+    tracing_subscriber::registry()
+        .with(main_layer.with_filter(env_filter))
+        .with(access_file.layer())
+        .with(audit_file.layer())
+        .init();
+
+    // Now we can use tracing macros like info!, debug!, etc.
+    info!(?format, "Tracing initialized.");
+    for (target, path, opened) in [access_report, audit_report].into_iter().flatten() {
+        if opened {
+            info!(target, path, "Dedicated log written to file");
+        } else {
+            warn!(target, path, "Dedicated log file unavailable, logging to the main output");
+        }
+    }
+}
+
+// A tracing target written to its own append-only JSON-lines file.
+struct DedicatedLog {
+    target: &'static str,
+    path: Option<String>,
+    file: Option<std::fs::File>,
+}
+
+impl DedicatedLog {
+    fn open(env_key: &str, target: &'static str) -> Self {
+        let path = std::env::var(env_key).ok().filter(|v| !v.is_empty());
+        let file = path.as_deref().and_then(|path| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .inspect_err(|e| eprintln!("WARN: Failed to open {} '{}': {}", env_key, path, e))
+                .ok()
+        });
+        Self { target, path, file }
+    }
+
+    fn report(&self) -> Option<(&'static str, String, bool)> {
+        self.path.clone().map(|path| (self.target, path, self.file.is_some()))
+    }
+
+    fn layer<S>(self) -> Option<impl Layer<S>>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let target = self.target;
+        self.file.map(|file| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(Targets::new().with_target(target, Level::INFO))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_parse() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("JSON".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
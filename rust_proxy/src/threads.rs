@@ -0,0 +1,151 @@
+// Dev's server-side conversations (threads), proxied for API key holders:
+// `GET /v1/threads` lists them, `GET /v1/threads/{id}` shows one and
+// `DELETE /v1/threads/{id}` removes it. Requests are signed like chat
+// requests and sent to DEV_THREADS_URL of the primary account; Dev's JSON is
+// returned as is. Threads belong to the Dev account, not to an API key, so
+// every key sees all of them. `thread_` ids are Assistants API threads and
+// are answered by assistants.rs instead.
+
+use axum::body::Body;
+use axum::extract::{Path, RawQuery, State};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use http::{header, Method, StatusCode};
+use std::sync::Arc;
+use tower_http::request_id::RequestId;
+use tracing::{info, warn};
+
+use crate::assistants::{self, AssistantsStore};
+use crate::auth::ApiKeyId;
+use crate::dev_client::UpstreamStatusError;
+use crate::dev_errors::DevError;
+use crate::error::ApiError;
+use crate::failover::Upstreams;
+use crate::request_id;
+
+/// Dev thread ids are opaque, but only path-safe ones are forwarded.
+fn valid_thread_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+async fn forward(upstreams: &Upstreams, method: Method, url: String, request_id: &RequestId) -> Response {
+    let request_id = request_id::as_string(request_id);
+    let client = upstreams.primary();
+    match client.send_api_request(method.clone(), &url, None, Some(&request_id)).await {
+        Ok(response) => {
+            let status = response.status();
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .cloned()
+                .unwrap_or_else(|| header::HeaderValue::from_static("application/json"));
+            match response.bytes().await {
+                Ok(body) => (status, [(header::CONTENT_TYPE, content_type)], Body::from(body)).into_response(),
+                Err(e) => {
+                    warn!(%method, url, "Failed to read Dev thread response: {}", e);
+                    ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "Failed to read the backend response").into_response()
+                }
+            }
+        }
+        Err(e) => match e.downcast_ref::<UpstreamStatusError>() {
+            Some(error) if error.status == StatusCode::NOT_FOUND => {
+                ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "No such thread")
+                    .with_code("thread_not_found")
+                    .into_response()
+            }
+            Some(error) => {
+                warn!(%method, url, status = %error.status, "Dev rejected thread request");
+                DevError::parse(Some(error.status), &error.body).to_api_error(error.retry_after).into_response()
+            }
+            None => {
+                warn!(%method, url, "Thread request to Dev failed: {:#}", e);
+                ApiError::new(StatusCode::BAD_GATEWAY, "server_error", "Failed to contact backend service").into_response()
+            }
+        },
+    }
+}
+
+fn invalid_id() -> Response {
+    ApiError::invalid_param("id", "Thread ids may only contain letters, digits, '-' and '_'").into_response()
+}
+
+/// `GET /v1/threads`; the query string (paging) goes to Dev unchanged.
+#[utoipa::path(get, path = "/v1/threads", tag = "threads", security(("api_key" = [])), responses(
+    (status = 200, description = "Dev's thread list", body = Object),
+    (status = 502, description = "Dev failed", body = ErrorBody),
+))]
+pub async fn list_threads_handler(
+    State(upstreams): State<Arc<Upstreams>>,
+    Extension(request_id): Extension<RequestId>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut url = upstreams.primary().threads_url().to_string();
+    if let Some(query) = query.filter(|q| !q.is_empty()) {
+        url = format!("{}?{}", url, query);
+    }
+    forward(&upstreams, Method::GET, url, &request_id).await
+}
+
+/// `GET /v1/threads/{id}`
+#[utoipa::path(get, path = "/v1/threads/{id}", tag = "threads", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Dev thread id, or an Assistants `thread_` id")),
+    responses(
+        (status = 200, description = "The thread and its messages, as Dev returns them", body = Object),
+        (status = 404, body = ErrorBody), (status = 502, body = ErrorBody),
+    ),
+)]
+pub async fn get_thread_handler(
+    State(upstreams): State<Arc<Upstreams>>,
+    State(store): State<AssistantsStore>,
+    Extension(request_id): Extension<RequestId>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    if assistants::is_assistants_thread(&id) {
+        return assistants::get_thread(&store, api_key_id.as_str(), &id).await;
+    }
+    if !valid_thread_id(&id) {
+        return invalid_id();
+    }
+    let url = format!("{}/{}", upstreams.primary().threads_url(), id);
+    forward(&upstreams, Method::GET, url, &request_id).await
+}
+
+/// `DELETE /v1/threads/{id}`
+#[utoipa::path(delete, path = "/v1/threads/{id}", tag = "threads", security(("api_key" = [])),
+    params(("id" = String, Path, description = "Dev thread id, or an Assistants `thread_` id")),
+    responses(
+        (status = 200, description = "Dev's confirmation", body = Object),
+        (status = 404, body = ErrorBody), (status = 502, body = ErrorBody),
+    ),
+)]
+pub async fn delete_thread_handler(
+    State(upstreams): State<Arc<Upstreams>>,
+    State(store): State<AssistantsStore>,
+    Extension(request_id): Extension<RequestId>,
+    Extension(api_key_id): Extension<ApiKeyId>,
+    Path(id): Path<String>,
+) -> Response {
+    if assistants::is_assistants_thread(&id) {
+        return assistants::delete_thread(&store, api_key_id.as_str(), &id).await;
+    }
+    if !valid_thread_id(&id) {
+        return invalid_id();
+    }
+    info!(thread_id = id, "Deleting Dev thread");
+    let url = format!("{}/{}", upstreams.primary().threads_url(), id);
+    forward(&upstreams, Method::DELETE, url, &request_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_thread_id() {
+        assert!(valid_thread_id("3f2a-91_b"));
+        assert!(!valid_thread_id("../admin"));
+        assert!(!valid_thread_id("a?b"));
+        assert!(!valid_thread_id(""));
+    }
+}
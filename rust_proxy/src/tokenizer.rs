@@ -0,0 +1,42 @@
+// Token counting with the cl100k_base BPE. Dev does not report token usage,
+// so counts are computed locally; they are exact for OpenAI cl100k models
+// and a close estimate for the rest.
+
+use once_cell::sync::Lazy;
+use tiktoken_rs::CoreBPE;
+use tracing::warn;
+
+static BPE: Lazy<Option<CoreBPE>> = Lazy::new(|| {
+    tiktoken_rs::cl100k_base()
+        .inspect_err(|e| warn!("Failed to load cl100k_base tokenizer, falling back to estimates: {}", e))
+        .ok()
+});
+
+/// Number of tokens in `text`.
+pub fn count_tokens(text: &str) -> usize {
+    if text.is_empty() {
+        return 0;
+    }
+    match BPE.as_ref() {
+        Some(bpe) => bpe.encode_ordinary(text).len(),
+        // Rough OpenAI rule of thumb: ~4 characters per token
+        None => text.chars().count().div_ceil(4),
+    }
+}
+
+/// Loads the BPE tables up front so the first request does not pay for it.
+pub fn warm_up() {
+    Lazy::force(&BPE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("hello world"), 2);
+        assert!(count_tokens("The quick brown fox jumps over the lazy dog.") >= 9);
+    }
+}
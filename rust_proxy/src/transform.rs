@@ -0,0 +1,436 @@
+// Chunk transformers: rewrites of a choice's chunks on their way from Dev's
+// stream to the client, run as an ordered chain. Each choice gets its own
+// transformers, so they can hold state (text held back, bytes sent, ...).
+// CHUNK_TRANSFORMS lists the stages in order, comma separated
+// (`citations,footers,coalesce,script,redact,cap,moderate,pace`):
+//
+// - `citations`: the request's citation marker rewriting (see `citations`).
+// - `footers`: the request's sources and related questions footers (see
+//   `footers`).
+// - `coalesce`: COALESCE_INTERVAL_MS (see `coalesce`).
+// - `script`: the `on_chunk` hook of SCRIPT_FILE (see `scripting`).
+// - `redact`: OUTPUT_REDACT_PATTERNS (see `output_filter`).
+// - `cap`: MAX_RESPONSE_BYTES (see `output_cap`).
+// - `moderate`: MODERATION_OUTPUT (see `moderation`).
+// - `pace`: PACING_RATE (see `pacing`).
+// - `wasm:<path>`: the WASM plugin at `path` (see `wasm_plugin`), in builds
+//   with the `wasm-plugins` feature (the default).
+//
+// Stages that are not configured are skipped. `citations` and `footers` run
+// first when left out, and `moderate` last, so a custom order cannot turn
+// off what a request or MODERATION_OUTPUT asked for. Once a chunk finishing
+// the choice leaves the chain, the rest of Dev's stream is dropped.
+//
+// Most stages map chunk to chunk (`ChunkTransformer`); those that wait on
+// timers or other services (coalescing, pacing, moderation) wrap the stream
+// of the stages before them instead (`StreamTransformer`).
+
+use anyhow::Result;
+use futures_util::stream::{BoxStream, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::citations::{self, CitationMode};
+use crate::coalesce::Coalescing;
+use crate::config::parse_list;
+use crate::dev_client::DevRequestOptions;
+use crate::footers::{self, Footers};
+use crate::moderation::Moderation;
+use crate::output_cap::OutputCap;
+use crate::output_filter::OutputFilter;
+use crate::pacing::Pacing;
+use crate::scripting::{self, Scripts};
+use crate::sse_processor::{AnswerExtras, ChatCompletionChunk, Delta};
+#[cfg(feature = "wasm-plugins")]
+use crate::wasm_plugin::WasmPlugin;
+
+const DEFAULT_ORDER: &str = "citations,footers,coalesce,script,redact,cap,moderate,pace";
+
+/// The chunks of a choice, or of every choice interleaved; each carries its
+/// `index`.
+pub type ChunkStream = BoxStream<'static, Result<ChatCompletionChunk>>;
+
+/// One stage of the chain, for one choice.
+pub trait ChunkTransformer: Send {
+    /// The chunk to send on in place of `chunk`, or `None` to drop it.
+    fn transform(&mut self, chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk>;
+
+    /// Called once when the choice ends, before `finishing`, the chunk
+    /// finishing it, reaches this stage (or when Dev's stream ends without
+    /// one); the chunk returned goes through the later stages.
+    fn finalize(&mut self, _finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+        None
+    }
+}
+
+/// A stage that wraps the chunk stream of one choice, for stages that wait
+/// (timers, moderation calls) rather than map chunk to chunk.
+pub trait StreamTransformer: Send + Sync {
+    fn wrap(&self, chunks: ChunkStream) -> ChunkStream;
+}
+
+impl<T: StreamTransformer + ?Sized> StreamTransformer for Arc<T> {
+    fn wrap(&self, chunks: ChunkStream) -> ChunkStream {
+        T::wrap(self, chunks)
+    }
+}
+
+/// What the stages of one choice know about its request: the citation mode
+/// and footers it asked for, and the sources and related questions the
+/// stream processor publishes as Dev sends them.
+#[derive(Debug, Clone, Default)]
+pub struct ChoiceContext {
+    pub citations: CitationMode,
+    pub footers: Footers,
+    pub extras: AnswerExtras,
+}
+
+impl ChoiceContext {
+    /// The context of a choice streamed with `options`, which are set to
+    /// publish its extras.
+    pub fn attach(options: &mut DevRequestOptions) -> Self {
+        let context = Self { citations: options.citations, footers: options.footers, extras: AnswerExtras::default() };
+        options.extras = Some(context.extras.clone());
+        context
+    }
+}
+
+/// A content chunk of the choice `like` belongs to, for stages that add text.
+pub fn content_chunk(like: &ChatCompletionChunk, content: String) -> ChatCompletionChunk {
+    let mut chunk = like.clone();
+    chunk.error = None;
+    for choice in &mut chunk.choices {
+        choice.delta = Delta { role: Some("assistant".to_string()), content: Some(content.clone()) };
+        choice.finish_reason = None;
+    }
+    chunk
+}
+
+type MakeTransformer = Arc<dyn Fn(&ChoiceContext) -> Option<Box<dyn ChunkTransformer>> + Send + Sync>;
+
+#[derive(Clone)]
+enum StageKind {
+    Chunk(MakeTransformer),
+    Stream(Arc<dyn StreamTransformer>),
+}
+
+/// The configuration the built-in stages are made from.
+#[derive(Clone, Default)]
+pub struct Builtins {
+    pub filter: OutputFilter,
+    pub cap: OutputCap,
+    pub scripts: Scripts,
+    pub coalescing: Coalescing,
+    pub pacing: Pacing,
+    pub moderation: Arc<Moderation>,
+}
+
+impl Builtins {
+    /// OUTPUT_REDACT_*, MAX_RESPONSE_BYTES, COALESCE_* and PACING_*, with
+    /// the `script` and `moderate` stages from `scripts` and `moderation`.
+    pub fn from_env(scripts: &Scripts, moderation: &Arc<Moderation>) -> Self {
+        Self {
+            filter: OutputFilter::from_env(),
+            cap: OutputCap::from_env(),
+            scripts: scripts.clone(),
+            coalescing: Coalescing::from_env(),
+            pacing: Pacing::from_env(),
+            moderation: moderation.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TransformChain {
+    stages: Vec<(String, StageKind)>,
+}
+
+impl TransformChain {
+    /// Appends the stage `name`; `make` builds its transformer for each choice.
+    pub fn with<F, T>(self, name: impl Into<String>, make: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+        T: ChunkTransformer + 'static,
+    {
+        self.with_choice(name, move |_| Some(make()))
+    }
+
+    /// Appends the stage `name`, built from each choice's context; choices
+    /// it returns `None` for skip it.
+    pub fn with_choice<F, T>(mut self, name: impl Into<String>, make: F) -> Self
+    where
+        F: Fn(&ChoiceContext) -> Option<T> + Send + Sync + 'static,
+        T: ChunkTransformer + 'static,
+    {
+        let make = move |context: &ChoiceContext| make(context).map(|t| Box::new(t) as Box<dyn ChunkTransformer>);
+        self.stages.push((name.into(), StageKind::Chunk(Arc::new(make))));
+        self
+    }
+
+    /// Appends the stage `name`, which wraps the stream of the stages before it.
+    pub fn with_stream(mut self, name: impl Into<String>, stage: impl StreamTransformer + 'static) -> Self {
+        self.stages.push((name.into(), StageKind::Stream(Arc::new(stage))));
+        self
+    }
+
+    /// The stages carrying out the citation mode and footers of a choice's
+    /// request options, for callers without CHUNK_TRANSFORMS.
+    pub fn request_options() -> Self {
+        Self::default().with_choice("citations", citations::transformer).with_choice("footers", footers::transformer)
+    }
+
+    /// The built-in stages and plugins in the order of `names`; plugins that
+    /// fail to load are left out.
+    pub fn builtin(names: &[String], builtins: &Builtins) -> Self {
+        let Builtins { filter, cap, scripts, coalescing, pacing, moderation } = builtins;
+        let mut chain = Self::default();
+        for name in names {
+            chain = match name.as_str() {
+                "citations" => chain.with_choice("citations", citations::transformer),
+                "footers" => chain.with_choice("footers", footers::transformer),
+                "coalesce" if coalescing.is_enabled() => chain.with_stream("coalesce", *coalescing),
+                "redact" if !filter.is_empty() => {
+                    let filter = filter.clone();
+                    chain.with("redact", move || filter.redactor())
+                }
+                "cap" if cap.is_enabled() => {
+                    let cap = *cap;
+                    chain.with("cap", move || cap.limiter())
+                }
+                "script" if scripts.defines(scripting::ON_CHUNK) => {
+                    let scripts = scripts.clone();
+                    chain.with("script", move || scripts.transformer())
+                }
+                "moderate" if moderation.moderates_output() => chain.with_stream("moderate", moderation.clone()),
+                "pace" if pacing.is_enabled() => chain.with_stream("pace", *pacing),
+                "coalesce" | "redact" | "cap" | "script" | "moderate" | "pace" => chain,
+                #[cfg(feature = "wasm-plugins")]
+                plugin if plugin.starts_with("wasm:") => match WasmPlugin::from_file(&plugin["wasm:".len()..]) {
+                    Ok(plugin) => chain.with(name.clone(), move || plugin.transformer()),
+                    Err(e) => {
+                        warn!(transform = plugin, "Ignoring CHUNK_TRANSFORMS entry: {:#}", e);
+                        chain
+                    }
+                },
+                #[cfg(not(feature = "wasm-plugins"))]
+                plugin if plugin.starts_with("wasm:") => {
+                    warn!(transform = plugin, "Ignoring CHUNK_TRANSFORMS entry: this build lacks the `wasm-plugins` feature");
+                    chain
+                }
+                other => {
+                    warn!(transform = other, "Ignoring unknown CHUNK_TRANSFORMS entry");
+                    chain
+                }
+            };
+        }
+        chain
+    }
+
+    /// CHUNK_TRANSFORMS, made from `builtins`.
+    pub fn from_env(builtins: &Builtins) -> Self {
+        let mut names = parse_list(&std::env::var("CHUNK_TRANSFORMS").unwrap_or_else(|_| DEFAULT_ORDER.to_string()));
+        for name in ["footers", "citations"] {
+            if !names.iter().any(|n| n == name) {
+                names.insert(0, name.to_string());
+            }
+        }
+        if builtins.moderation.moderates_output() && !names.iter().any(|n| n == "moderate") {
+            warn!("CHUNK_TRANSFORMS leaves out `moderate` while MODERATION_OUTPUT is on; moderating last");
+            names.push("moderate".to_string());
+        }
+        let chain = Self::builtin(&names, builtins);
+        if !chain.is_empty() {
+            info!(stages = ?chain.names().collect::<Vec<_>>(), "Chunk transformers configured");
+        }
+        chain
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// `chunks` of the choice `context` belongs to, through a fresh
+    /// transformer of every stage. Errors pass through untouched.
+    pub fn apply<S>(&self, chunks: S, context: &ChoiceContext) -> ChunkStream
+    where
+        S: Stream<Item = Result<ChatCompletionChunk>> + Send + 'static,
+    {
+        let mut chunks = chunks.boxed();
+        let mut stages = Vec::new();
+        for (_, kind) in &self.stages {
+            match kind {
+                StageKind::Chunk(make) => {
+                    stages.extend(make(context).map(|transformer| Stage { transformer, finalized: false }));
+                }
+                StageKind::Stream(stage) => chunks = stage.wrap(run(chunks, std::mem::take(&mut stages))),
+            }
+        }
+        run(chunks, stages)
+    }
+}
+
+/// `chunks` through `stages`, a run of chunk stages.
+fn run(chunks: ChunkStream, stages: Vec<Stage>) -> ChunkStream {
+    struct State {
+        chunks: ChunkStream,
+        stages: Vec<Stage>,
+        ready: VecDeque<Result<ChatCompletionChunk>>,
+        done: bool,
+    }
+
+    if stages.is_empty() {
+        return chunks;
+    }
+    let state = State { chunks, stages, ready: VecDeque::new(), done: false };
+    futures_util::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(item) = state.ready.pop_front() {
+                return Some((item, state));
+            }
+            if state.done {
+                return None;
+            }
+            let mut sent = Vec::new();
+            match state.chunks.next().await {
+                Some(Ok(chunk)) => push(&mut state.stages, chunk, &mut sent),
+                Some(Err(e)) => state.ready.push_back(Err(e)),
+                None => {
+                    for i in 0..state.stages.len() {
+                        let (stage, rest) = state.stages[i..].split_first_mut().expect("stage in range");
+                        if let Some(chunk) = stage.finalize(None) {
+                            push(rest, chunk, &mut sent);
+                        }
+                    }
+                    state.done = true;
+                }
+            }
+            state.done |= sent.iter().any(finishes);
+            state.ready.extend(sent.into_iter().map(Ok));
+        }
+    })
+    .boxed()
+}
+
+struct Stage {
+    transformer: Box<dyn ChunkTransformer>,
+    finalized: bool,
+}
+
+impl Stage {
+    fn finalize(&mut self, finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+        if std::mem::replace(&mut self.finalized, true) {
+            return None;
+        }
+        self.transformer.finalize(finishing)
+    }
+}
+
+fn finishes(chunk: &ChatCompletionChunk) -> bool {
+    chunk.choices.iter().any(|c| c.finish_reason.is_some())
+}
+
+/// Runs `chunk` through `stages`, collecting what leaves the last one.
+fn push(stages: &mut [Stage], chunk: ChatCompletionChunk, sent: &mut Vec<ChatCompletionChunk>) {
+    let Some((stage, rest)) = stages.split_first_mut() else {
+        sent.push(chunk);
+        return;
+    };
+    if finishes(&chunk)
+        && let Some(last) = stage.finalize(Some(&chunk))
+    {
+        push(rest, last, sent);
+    }
+    if let Some(chunk) = stage.transformer.transform(chunk) {
+        push(rest, chunk, sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+
+    /// Upper-cases content and ends the answer with a footer.
+    struct Shout;
+
+    impl ChunkTransformer for Shout {
+        fn transform(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+            for choice in &mut chunk.choices {
+                choice.delta.content = choice.delta.content.take().map(|c| c.to_uppercase());
+            }
+            Some(chunk)
+        }
+
+        fn finalize(&mut self, _finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+            Some(test_chunk(Some("!"), None))
+        }
+    }
+
+    /// Wraps each delta in brackets, a stage on the stream.
+    struct Bracket;
+
+    impl StreamTransformer for Bracket {
+        fn wrap(&self, chunks: ChunkStream) -> ChunkStream {
+            chunks
+                .map(|chunk| {
+                    chunk.map(|mut chunk| {
+                        for choice in &mut chunk.choices {
+                            choice.delta.content = choice.delta.content.take().map(|c| format!("[{}]", c));
+                        }
+                        chunk
+                    })
+                })
+                .boxed()
+        }
+    }
+
+    async fn run(chain: &TransformChain, chunks: Vec<ChatCompletionChunk>) -> Vec<(Option<String>, Option<String>)> {
+        let chunks = futures_util::stream::iter(chunks.into_iter().map(Ok).collect::<Vec<_>>());
+        chain
+            .apply(chunks, &ChoiceContext::default())
+            .map(|chunk| {
+                let choice = chunk.unwrap().choices.remove(0);
+                (choice.delta.content, choice.finish_reason)
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_stages_run_in_order_and_finalize_before_the_end() {
+        let content = |c: &str| (Some(c.to_string()), None);
+        let stop = (None, Some("stop".to_string()));
+        let chunks = || vec![test_chunk(Some("hi "), None), test_chunk(Some("there"), None), test_chunk(None, Some("stop"))];
+
+        let chain = TransformChain::default().with("shout", || Shout);
+        assert_eq!(run(&chain, chunks()).await, [content("HI "), content("THERE"), content("!"), stop.clone()]);
+
+        // The cap ends the choice, so later chunks and the footer never come
+        let capped = chain.clone().with("cap", || OutputCap::new(5).limiter());
+        assert_eq!(run(&capped, chunks()).await, [content("HI "), (Some("TH".to_string()), Some("length".to_string()))]);
+
+        // A stream without a finishing chunk is still finalized
+        assert_eq!(run(&chain, vec![test_chunk(Some("a"), None)]).await, [content("A"), content("!")]);
+
+        // Stream stages see what the chunk stages before them sent
+        let wrapped = TransformChain::default().with_stream("bracket", Bracket).with("shout", || Shout).with_stream("bracket", Bracket);
+        assert_eq!(run(&wrapped, chunks()).await, [content("[[HI ]]"), content("[[THERE]]"), content("[!]"), stop]);
+    }
+
+    #[test]
+    fn test_builtin_order_skips_unconfigured_stages() {
+        let filter = OutputFilter::from_json(r#"["secret"]"#).unwrap();
+        let pacing = Pacing { rate: 20.0, ..Default::default() };
+        let builtins = Builtins { filter, cap: OutputCap::new(10), pacing, ..Default::default() };
+        let names = |list: &str| parse_list(list);
+        let chain = TransformChain::builtin(&names("pace, cap, redact, coalesce, moderate, bogus, wasm:/nonexistent.wasm"), &builtins);
+        assert_eq!(chain.names().collect::<Vec<_>>(), ["pace", "cap", "redact"]);
+        assert!(TransformChain::builtin(&names("redact,cap,pace"), &Builtins::default()).is_empty());
+        assert_eq!(TransformChain::builtin(&names("footers,citations"), &Builtins::default()).names().collect::<Vec<_>>(), ["footers", "citations"]);
+    }
+}
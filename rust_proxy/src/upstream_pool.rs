@@ -0,0 +1,143 @@
+// Connection pool settings for the Dev client and metrics on connection
+// reuse. Long SSE streams hold their connection for minutes, so with
+// reqwest's defaults a burst of requests often finds no idle connection and
+// pays a cold TCP+TLS handshake before the first byte. reqwest does not
+// expose its pool, so reuse is measured from the outside: every connection
+// the pool has to open goes through `ConnectMetricsLayer`, and
+// `1 - opened / requests` is the reuse ratio.
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use once_cell::sync::Lazy;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts};
+use reqwest::ClientBuilder;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::config::env_or;
+
+const CONNECT_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Connections opened by the upstream pool, by `result` (ok / error).
+pub static CONNECTIONS_OPENED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("upstream_connections_opened_total", "New upstream connections (pool misses) by result"),
+        &["result"],
+    )
+    .expect("valid counter")
+});
+
+/// Time to establish an upstream connection, TLS included.
+pub static CONNECT_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new("upstream_connect_seconds", "Time to open an upstream connection, including TLS")
+            .buckets(CONNECT_BUCKETS.to_vec()),
+    )
+    .expect("valid histogram")
+});
+
+/// Requests sent to Dev, retries included.
+pub static REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("upstream_requests_total", "Requests sent to the Dev backend").expect("valid counter")
+});
+
+/// reqwest pool and socket settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+    /// `None` disables TCP keepalive probes.
+    pub tcp_keepalive: Option<Duration>,
+    pub connect_timeout: Duration,
+}
+
+impl PoolConfig {
+    /// DEV_POOL_MAX_IDLE_PER_HOST (32), DEV_POOL_IDLE_TIMEOUT_SECS (90),
+    /// DEV_TCP_KEEPALIVE_SECS (30, 0 = off) and DEV_CONNECT_TIMEOUT_SECS (10).
+    pub fn from_env() -> Self {
+        let keepalive: u64 = env_or("DEV_TCP_KEEPALIVE_SECS", 30);
+        Self {
+            max_idle_per_host: env_or("DEV_POOL_MAX_IDLE_PER_HOST", 32),
+            idle_timeout: Duration::from_secs(env_or("DEV_POOL_IDLE_TIMEOUT_SECS", 90)),
+            tcp_keepalive: (keepalive > 0).then(|| Duration::from_secs(keepalive)),
+            connect_timeout: Duration::from_secs(env_or("DEV_CONNECT_TIMEOUT_SECS", 10)),
+        }
+    }
+
+    /// Applies the settings and the connection metrics layer to `builder`.
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        info!(?self, "Upstream connection pool configured");
+        builder
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .connect_timeout(self.connect_timeout)
+            .connector_layer(ConnectMetricsLayer)
+    }
+}
+
+/// Records `CONNECTIONS_OPENED` and `CONNECT_SECONDS` for every connection
+/// the reqwest connector establishes.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectMetricsLayer;
+
+impl<S> tower::Layer<S> for ConnectMetricsLayer {
+    type Service = ConnectMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectMetrics<S> {
+    inner: S,
+}
+
+impl<S, R> tower::Service<R> for ConnectMetrics<S>
+where
+    S: tower::Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let started = Instant::now();
+        let connecting = self.inner.call(request);
+        async move {
+            let result = connecting.await;
+            let elapsed = started.elapsed();
+            CONNECT_SECONDS.observe(elapsed.as_secs_f64());
+            CONNECTIONS_OPENED.with_label_values(&[if result.is_ok() { "ok" } else { "error" }]).inc();
+            debug!(elapsed_ms = elapsed.as_millis() as u64, ok = result.is_ok(), "Opened upstream connection");
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::{Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_connect_metrics_count_results() {
+        let ok_before = CONNECTIONS_OPENED.with_label_values(&["ok"]).get();
+        let error_before = CONNECTIONS_OPENED.with_label_values(&["error"]).get();
+        let service = ConnectMetricsLayer.layer(tower::service_fn(|fail: bool| async move {
+            if fail { Err("refused") } else { Ok(()) }
+        }));
+        assert!(service.clone().oneshot(false).await.is_ok());
+        assert!(service.oneshot(true).await.is_err());
+        assert_eq!(CONNECTIONS_OPENED.with_label_values(&["ok"]).get(), ok_before + 1);
+        assert_eq!(CONNECTIONS_OPENED.with_label_values(&["error"]).get(), error_before + 1);
+    }
+}
@@ -0,0 +1,391 @@
+// Usage accounting for internal chargeback: request counts and prompt /
+// completion tokens per API key and model, with optional per-model prices.
+// Totals live in memory and are written to USAGE_FILE periodically, and read
+// back on startup so they survive restarts. The tokens of each key in the
+// current UTC day and month are kept too, for the key store's token quotas.
+
+use anyhow::{Context, Result};
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::config::env_or;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UsageEntry {
+    pub api_key_id: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub counters: UsageCounters,
+}
+
+/// The prompt and completion tokens a key used in the current UTC day and
+/// month.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeriodTokens {
+    /// Days since 1970-01-01.
+    pub day: u64,
+    pub day_tokens: u64,
+    /// Months since January 1970.
+    pub month: u64,
+    pub month_tokens: u64,
+}
+
+impl PeriodTokens {
+    /// Starts over the periods `now` (Unix seconds) is past.
+    fn roll(&mut self, now: u64) {
+        let day = now / 86_400;
+        let (year, month, _) = crate::utils::civil_date(day as i64);
+        let month = (year - 1970) as u64 * 12 + u64::from(month) - 1;
+        if self.day != day {
+            self.day = day;
+            self.day_tokens = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_tokens = 0;
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageFile {
+    since: u64,
+    entries: Vec<UsageEntry>,
+    #[serde(default)]
+    periods: HashMap<String, PeriodTokens>,
+}
+
+/// USD prices per 1K tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, counters: &UsageCounters) -> f64 {
+        (counters.prompt_tokens as f64 * self.prompt_per_1k + counters.completion_tokens as f64 * self.completion_per_1k)
+            / 1000.0
+    }
+}
+
+/// Prices by model, parsed from `USAGE_PRICES` such as
+/// `gpt-4o=0.005/0.015,*=0.001/0.002` (prompt/completion per 1K tokens;
+/// `*` applies to every other model).
+#[derive(Debug, Clone, Default)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    pub fn parse(raw: &str) -> Self {
+        let mut prices = HashMap::new();
+        for item in crate::config::parse_list(raw) {
+            let parsed = item.split_once('=').and_then(|(model, price)| {
+                let (prompt, completion) = price.split_once('/')?;
+                Some((
+                    model.trim().to_string(),
+                    ModelPrice {
+                        prompt_per_1k: prompt.trim().parse().ok()?,
+                        completion_per_1k: completion.trim().parse().ok()?,
+                    },
+                ))
+            });
+            match parsed {
+                Some((model, price)) => {
+                    prices.insert(model, price);
+                }
+                None => warn!(entry = %item, "Ignoring malformed USAGE_PRICES entry"),
+            }
+        }
+        Self { prices }
+    }
+
+    pub fn price_for(&self, model: &str) -> Option<ModelPrice> {
+        self.prices.get(model).or_else(|| self.prices.get("*")).copied()
+    }
+}
+
+#[derive(Debug)]
+pub struct UsageTracker {
+    entries: Mutex<HashMap<(String, String), UsageCounters>>,
+    /// Key id -> tokens in the current day and month.
+    periods: Mutex<HashMap<String, PeriodTokens>>,
+    since: u64,
+    path: Option<PathBuf>,
+    dirty: AtomicBool,
+    prices: PriceTable,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl UsageTracker {
+    pub fn new(path: Option<PathBuf>, prices: PriceTable) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            periods: Mutex::new(HashMap::new()),
+            since: now_secs(),
+            path,
+            dirty: AtomicBool::new(false),
+            prices,
+        }
+    }
+
+    /// Builds the tracker from USAGE_FILE / USAGE_PRICES, restoring totals
+    /// from an existing file.
+    pub fn from_env() -> Self {
+        let path = std::env::var("USAGE_FILE").ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+        let prices = PriceTable::parse(&std::env::var("USAGE_PRICES").unwrap_or_default());
+        let mut tracker = Self::new(path, prices);
+        if let Some(path) = tracker.path.clone().filter(|p| p.exists()) {
+            match tracker.load(&path) {
+                Ok(count) => info!(path = %path.display(), entries = count, "Restored usage totals"),
+                Err(e) => warn!(path = %path.display(), "Failed to restore usage totals: {:#}", e),
+            }
+        }
+        tracker
+    }
+
+    fn load(&mut self, path: &PathBuf) -> Result<usize> {
+        let raw = std::fs::read(path).context("read usage file")?;
+        let file: UsageFile = serde_json::from_slice(&raw).context("parse usage file")?;
+        self.since = file.since;
+        *self.periods.get_mut().unwrap() = file.periods;
+        let entries = self.entries.get_mut().unwrap();
+        for entry in file.entries {
+            entries.insert((entry.api_key_id, entry.model), entry.counters);
+        }
+        Ok(entries.len())
+    }
+
+    fn update(&self, api_key_id: &str, model: &str, tokens: u64, apply: impl FnOnce(&mut UsageCounters)) {
+        let mut entries = self.entries.lock().unwrap();
+        apply(entries.entry((api_key_id.to_string(), model.to_string())).or_default());
+        let mut periods = self.periods.lock().unwrap();
+        let period = periods.entry(api_key_id.to_string()).or_default();
+        period.roll(now_secs());
+        period.day_tokens += tokens;
+        period.month_tokens += tokens;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts a new request and its prompt tokens.
+    pub fn record_request(&self, api_key_id: &str, model: &str, prompt_tokens: u64) {
+        self.update(api_key_id, model, prompt_tokens, |c| {
+            c.requests += 1;
+            c.prompt_tokens += prompt_tokens;
+        });
+    }
+
+    /// Adds the completion tokens of a finished (or aborted) stream.
+    pub fn record_completion(&self, api_key_id: &str, model: &str, completion_tokens: u64) {
+        self.update(api_key_id, model, completion_tokens, |c| c.completion_tokens += completion_tokens);
+    }
+
+    /// The tokens `api_key_id` used in the UTC day and month of `now` (Unix
+    /// seconds).
+    pub fn period_tokens(&self, api_key_id: &str, now: u64) -> PeriodTokens {
+        let mut period = self.periods.lock().unwrap().get(api_key_id).copied().unwrap_or_default();
+        period.roll(now);
+        period
+    }
+
+    /// Current totals, sorted by key id and model.
+    pub fn snapshot(&self) -> Vec<UsageEntry> {
+        let entries = self.entries.lock().unwrap();
+        let mut snapshot: Vec<UsageEntry> = entries
+            .iter()
+            .map(|((api_key_id, model), counters)| UsageEntry {
+                api_key_id: api_key_id.clone(),
+                model: model.clone(),
+                counters: *counters,
+            })
+            .collect();
+        snapshot.sort_by(|a, b| (&a.api_key_id, &a.model).cmp(&(&b.api_key_id, &b.model)));
+        snapshot
+    }
+
+    pub fn since(&self) -> u64 {
+        self.since
+    }
+
+    pub fn prices(&self) -> &PriceTable {
+        &self.prices
+    }
+
+    /// Writes the totals to USAGE_FILE (via a temp file and rename) if they
+    /// changed since the last write.
+    pub fn persist(&self) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let periods = self.periods.lock().unwrap().clone();
+        let file = UsageFile { since: self.since, entries: self.snapshot(), periods };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&file)?).with_context(|| format!("write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("rename to {}", path.display()))?;
+        debug!(path = %path.display(), entries = file.entries.len(), "Persisted usage totals");
+        Ok(())
+    }
+
+    /// Persists the totals every USAGE_FLUSH_SECS while the process runs.
+    pub fn spawn_persistence(self: Arc<Self>) {
+        if self.path.is_none() {
+            return;
+        }
+        let interval = Duration::from_secs(env_or("USAGE_FLUSH_SECS", 60u64).max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.persist() {
+                    self.dirty.store(true, Ordering::Relaxed);
+                    warn!("Failed to persist usage totals: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageQuery {
+    /// Only this key id (`key_...`).
+    pub api_key_id: Option<String>,
+    /// Only this model.
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageRow {
+    #[serde(flatten)]
+    entry: UsageEntry,
+    total_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_usd: Option<f64>,
+}
+
+/// `GET /v1/usage` (admin): totals per key and model, optionally filtered.
+#[utoipa::path(get, path = "/v1/usage", tag = "admin", params(UsageQuery), security(("admin_token" = [])), responses(
+    (status = 200, description = "Totals per key and model with optional `cost_usd`", body = Object),
+    (status = 401, body = ErrorBody), (status = 403, body = ErrorBody),
+))]
+pub async fn usage_handler(
+    State(usage): State<Arc<UsageTracker>>,
+    Query(query): Query<UsageQuery>,
+) -> Json<serde_json::Value> {
+    let rows: Vec<UsageRow> = usage
+        .snapshot()
+        .into_iter()
+        .filter(|e| query.api_key_id.as_deref().is_none_or(|id| id == e.api_key_id))
+        .filter(|e| query.model.as_deref().is_none_or(|m| m == e.model))
+        .map(|entry| UsageRow {
+            total_tokens: entry.counters.prompt_tokens + entry.counters.completion_tokens,
+            cost_usd: usage.prices().price_for(&entry.model).map(|p| p.cost(&entry.counters)),
+            entry,
+        })
+        .collect();
+
+    let mut totals = UsageCounters::default();
+    let mut total_cost: Option<f64> = None;
+    for row in &rows {
+        totals.requests += row.entry.counters.requests;
+        totals.prompt_tokens += row.entry.counters.prompt_tokens;
+        totals.completion_tokens += row.entry.counters.completion_tokens;
+        if let Some(cost) = row.cost_usd {
+            *total_cost.get_or_insert(0.0) += cost;
+        }
+    }
+
+    Json(serde_json::json!({
+        "object": "usage",
+        "since": usage.since(),
+        "data": rows,
+        "totals": {
+            "requests": totals.requests,
+            "prompt_tokens": totals.prompt_tokens,
+            "completion_tokens": totals.completion_tokens,
+            "cost_usd": total_cost,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_per_key_and_model() {
+        let usage = UsageTracker::new(None, PriceTable::default());
+        usage.record_request("key_a", "m1", 10);
+        usage.record_completion("key_a", "m1", 5);
+        usage.record_request("key_a", "m1", 3);
+        usage.record_request("key_b", "m2", 7);
+        let snapshot = usage.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].counters, UsageCounters { requests: 2, prompt_tokens: 13, completion_tokens: 5 });
+        assert_eq!(snapshot[1].api_key_id, "key_b");
+    }
+
+    #[test]
+    fn test_price_table() {
+        let prices = PriceTable::parse("gpt-4o=0.005/0.015, *=0.001/0.002, broken");
+        let gpt = prices.price_for("gpt-4o").unwrap();
+        let counters = UsageCounters { requests: 1, prompt_tokens: 2000, completion_tokens: 1000 };
+        assert!((gpt.cost(&counters) - 0.025).abs() < 1e-9);
+        assert_eq!(prices.price_for("other").unwrap().prompt_per_1k, 0.001);
+        assert!(PriceTable::default().price_for("gpt-4o").is_none());
+    }
+
+    #[test]
+    fn test_persist_and_restore() {
+        let path = std::env::temp_dir().join(format!("usage-{}.json", crate::utils::generate_uuidv4()));
+        let usage = UsageTracker::new(Some(path.clone()), PriceTable::default());
+        usage.record_request("key_a", "m1", 4);
+        usage.persist().unwrap();
+
+        let mut restored = UsageTracker::new(Some(path.clone()), PriceTable::default());
+        assert_eq!(restored.load(&path).unwrap(), 1);
+        assert_eq!(restored.snapshot(), usage.snapshot());
+        assert_eq!(restored.period_tokens("key_a", now_secs()).day_tokens, 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_period_tokens_roll_over() {
+        let usage = UsageTracker::new(None, PriceTable::default());
+        usage.record_request("key_a", "m1", 10);
+        usage.record_completion("key_a", "m2", 5);
+        let now = now_secs();
+        let today = usage.period_tokens("key_a", now);
+        assert_eq!((today.day_tokens, today.month_tokens), (15, 15));
+        assert_eq!(usage.period_tokens("key_b", now), PeriodTokens { day: today.day, month: today.month, ..Default::default() });
+
+        // 2024-01-31 and the next two days
+        let mut period = PeriodTokens { day: 19_753, day_tokens: 7, month: 648, month_tokens: 30 };
+        period.roll(19_753 * 86_400 + 3_600);
+        assert_eq!((period.day_tokens, period.month_tokens), (7, 30));
+        period.roll(19_754 * 86_400);
+        assert_eq!(period, PeriodTokens { day: 19_754, day_tokens: 0, month: 649, month_tokens: 0 });
+        period.month_tokens = 3;
+        period.roll(19_755 * 86_400);
+        assert_eq!((period.day_tokens, period.month_tokens), (0, 3));
+    }
+}
@@ -7,13 +7,56 @@ pub fn generate_uuidv4() -> String {
 }
 
 /// Calculates the SHA256 hash of a byte slice and returns it as a lowercase hex string.
-pub fn _sha256_hex(data: &[u8]) -> String {
+pub fn sha256_hex(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     let result = hasher.finalize();
     hex::encode(result)
 }
 
+/// HMAC-SHA256 (RFC 2104) of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// `url` with any userinfo replaced by `***`, for logs and error messages.
+pub fn redact_url_credentials(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => match rest.rsplit_once('@') {
+            Some((_, host)) => format!("{}://***@{}", scheme, host),
+            None => url.to_string(),
+        },
+        None => url.rsplit_once('@').map_or_else(|| url.to_string(), |(_, host)| format!("***@{}", host)),
+    }
+}
+
+/// Year, month and day of `days` since 1970-01-01 (Howard Hinnant's
+/// algorithm).
+pub fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month as u32, day as u32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*; // Import items from parent module (utils)
@@ -29,21 +72,47 @@ mod tests {
     }
 
     #[test]
-    fn test_sha256_hex_known_value() {
+    fn testsha256_hex_known_value() {
         // Test against a known SHA256 hash
         // echo -n "hello world" | sha256sum
         // b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9
         let input = b"hello world";
         let expected_output = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
-        assert_eq!(_sha256_hex(input), expected_output);
+        assert_eq!(sha256_hex(input), expected_output);
     }
 
      #[test]
-    fn test_sha256_hex_empty_string() {
+    fn testsha256_hex_empty_string() {
         // echo -n "" | sha256sum
         // e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
         let input = b"";
         let expected_output = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
-        assert_eq!(_sha256_hex(input), expected_output);
+        assert_eq!(sha256_hex(input), expected_output);
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // RFC 4231 test cases 2 and 6 (key longer than the block size)
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_redact_url_credentials() {
+        assert_eq!(redact_url_credentials("socks5://user:pw@proxy:1080"), "socks5://***@proxy:1080");
+        assert_eq!(redact_url_credentials("http://proxy:3128"), "http://proxy:3128");
+    }
+}
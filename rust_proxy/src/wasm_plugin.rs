@@ -0,0 +1,268 @@
+// WASM chunk-transform plugins: custom post-processing of answers without
+// recompiling the proxy. A `wasm:<path>` entry in CHUNK_TRANSFORMS loads the
+// module at `path` as a stage of the chain (see `transform`). Each choice
+// runs on its own instance, so a plugin may keep state in its memory.
+//
+// A plugin exports `memory`, `alloc(len: i32) -> i32`, and
+//
+// - `transform(ptr: i32, len: i32) -> i64`: gets a chunk as JSON and returns
+//   the JSON of the chunk to send, packed as `ptr << 32 | len`; 0 drops it.
+// - optionally `finalize() -> i64`: the JSON of a last chunk (same packing,
+//   0 for none), called once when the choice ends.
+//
+// From what a plugin returns, only each choice's `delta` and
+// `finish_reason` are used; ids, model and indices stay the proxy's. Calls
+// get WASM_PLUGIN_FUEL (10M) units of fuel; a plugin that traps, runs out or
+// returns invalid JSON leaves the chunk unchanged and is counted in
+// `wasm_plugin_errors_total`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+use crate::sse_processor::ChatCompletionChunk;
+use crate::transform::ChunkTransformer;
+use crate::utils;
+
+const ALLOC_FN: &str = "alloc";
+const TRANSFORM_FN: &str = "transform";
+const FINALIZE_FN: &str = "finalize";
+const MEMORY: &str = "memory";
+const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// Plugin calls that failed, by plugin, registered by `Metrics`.
+pub static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("wasm_plugin_errors_total", "WASM plugin calls that failed, by plugin"), &["plugin"])
+        .expect("valid counter")
+});
+
+/// A compiled plugin module.
+#[derive(Clone)]
+pub struct WasmPlugin {
+    name: Arc<str>,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+}
+
+impl WasmPlugin {
+    /// Compiles `bytes` (binary or text format) and checks that the module
+    /// instantiates with the exports a plugin needs.
+    pub fn new(name: &str, bytes: &[u8], fuel: u64) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes).map_err(|e| anyhow!("Failed to compile WASM plugin '{}': {}", name, e))?;
+        let plugin = Self { name: name.into(), engine, module, fuel };
+        plugin.instantiate().with_context(|| format!("WASM plugin '{}' is unusable", name))?;
+        Ok(plugin)
+    }
+
+    /// Loads the plugin at `path`, with WASM_PLUGIN_FUEL per call.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read WASM plugin '{}'", path))?;
+        let plugin = Self::new(path, &bytes, crate::config::env_or("WASM_PLUGIN_FUEL", DEFAULT_FUEL))?;
+        info!(path, sha256 = %utils::sha256_hex(&bytes), "WASM plugin loaded");
+        Ok(plugin)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn instantiate(&self) -> Result<PluginInstance> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel)?;
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance.get_memory(&mut store, MEMORY).ok_or_else(|| anyhow!("export '{}' not found", MEMORY))?;
+        let alloc = instance.get_typed_func(&mut store, ALLOC_FN)?;
+        let transform = instance.get_typed_func(&mut store, TRANSFORM_FN)?;
+        let finalize = instance.get_typed_func(&mut store, FINALIZE_FN).ok();
+        Ok(PluginInstance { store, memory, alloc, transform, finalize, fuel: self.fuel })
+    }
+
+    /// The plugin's stage for one choice; chunks pass unchanged if the
+    /// module cannot be instantiated.
+    pub fn transformer(&self) -> PluginTransformer {
+        let instance = self
+            .instantiate()
+            .inspect_err(|e| {
+                warn!(plugin = %self.name, "Failed to instantiate WASM plugin: {:#}", e);
+                ERRORS.with_label_values(&[&self.name]).inc();
+            })
+            .ok();
+        PluginTransformer { name: self.name.clone(), instance, template: None }
+    }
+}
+
+struct PluginInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+    finalize: Option<TypedFunc<(), i64>>,
+    fuel: u64,
+}
+
+impl PluginInstance {
+    fn transform(&mut self, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.set_fuel(self.fuel)?;
+        let len = i32::try_from(input.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input)?;
+        let packed = self.transform.call(&mut self.store, (ptr, len))?;
+        self.read(packed)
+    }
+
+    fn finalize(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(finalize) = self.finalize else { return Ok(None) };
+        self.store.set_fuel(self.fuel)?;
+        let packed = finalize.call(&mut self.store, ())?;
+        self.read(packed)
+    }
+
+    fn read(&self, packed: i64) -> Result<Option<Vec<u8>>> {
+        let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut output = vec![0u8; len];
+        self.memory.read(&self.store, ptr, &mut output)?;
+        Ok(Some(output))
+    }
+}
+
+/// The parts of a chunk a plugin may change.
+#[derive(Deserialize)]
+struct PluginChunk {
+    choices: Vec<PluginChoice>,
+}
+
+#[derive(Deserialize)]
+struct PluginChoice {
+    #[serde(default)]
+    delta: PluginDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct PluginDelta {
+    role: Option<String>,
+    content: Option<String>,
+}
+
+/// Applies what the plugin returned to `chunk`.
+fn apply(mut chunk: ChatCompletionChunk, output: &[u8]) -> Result<ChatCompletionChunk> {
+    let output: PluginChunk = serde_json::from_slice(output).context("invalid chunk JSON")?;
+    if output.choices.len() != chunk.choices.len() {
+        bail!("returned {} choices for {}", output.choices.len(), chunk.choices.len());
+    }
+    for (choice, changed) in chunk.choices.iter_mut().zip(output.choices) {
+        choice.delta.role = changed.delta.role;
+        choice.delta.content = changed.delta.content;
+        choice.finish_reason = changed.finish_reason;
+    }
+    Ok(chunk)
+}
+
+/// A plugin instance transforming one choice.
+pub struct PluginTransformer {
+    name: Arc<str>,
+    instance: Option<PluginInstance>,
+    /// The last chunk seen, the shape of the one `finalize` returns.
+    template: Option<ChatCompletionChunk>,
+}
+
+impl PluginTransformer {
+    fn failed(&self, call: &str, e: &anyhow::Error) {
+        warn!(plugin = %self.name, call, "WASM plugin call failed: {:#}", e);
+        ERRORS.with_label_values(&[&self.name]).inc();
+    }
+}
+
+impl ChunkTransformer for PluginTransformer {
+    fn transform(&mut self, chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        let Some(instance) = &mut self.instance else { return Some(chunk) };
+        self.template = Some(chunk.clone());
+        let result = serde_json::to_vec(&chunk)
+            .map_err(anyhow::Error::from)
+            .and_then(|input| instance.transform(&input))
+            .and_then(|output| output.map(|output| apply(chunk.clone(), &output)).transpose());
+        match result {
+            Ok(transformed) => transformed,
+            Err(e) => {
+                self.failed(TRANSFORM_FN, &e);
+                Some(chunk)
+            }
+        }
+    }
+
+    fn finalize(&mut self, _finishing: Option<&ChatCompletionChunk>) -> Option<ChatCompletionChunk> {
+        let instance = self.instance.as_mut()?;
+        let mut template = self.template.take()?;
+        for choice in &mut template.choices {
+            choice.finish_reason = None;
+        }
+        let result = instance.finalize().and_then(|output| output.map(|output| apply(template, &output)).transpose());
+        result.inspect_err(|e| self.failed(FINALIZE_FN, e)).ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse_processor::test_chunk;
+    use crate::transform::{ChoiceContext, TransformChain};
+    use futures_util::StreamExt;
+
+    /// Sends every chunk unchanged and ends with `{"choices":[{"delta":{"content":"!"}}]}`.
+    const ECHO: &str = r#"(module
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (data (i32.const 0) "{\"choices\":[{\"delta\":{\"content\":\"!\"}}]}")
+        (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+        (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32)) (i64.extend_i32_u (local.get $len))))
+        (func (export "finalize") (result i64) (i64.const 39)))"#;
+
+    /// Never returns from `transform`.
+    const SPIN: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) (i32.const 0))
+        (func (export "transform") (param i32 i32) (result i64) (loop $l (br $l)) (i64.const 0)))"#;
+
+    async fn run(plugin: WasmPlugin) -> Vec<(Option<String>, Option<String>)> {
+        let chunks = futures_util::stream::iter(vec![Ok(test_chunk(Some("Hi"), None)), Ok(test_chunk(None, Some("stop")))]);
+        TransformChain::default()
+            .with(plugin.name().to_string(), move || plugin.transformer())
+            .apply(chunks, &ChoiceContext::default())
+            .map(|chunk| {
+                let choice = chunk.unwrap().choices.remove(0);
+                (choice.delta.content, choice.finish_reason)
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_plugin_transforms_and_finalizes() {
+        let out = run(WasmPlugin::new("echo", ECHO.as_bytes(), DEFAULT_FUEL).unwrap()).await;
+        assert_eq!(out, [(Some("Hi".to_string()), None), (Some("!".to_string()), None), (None, Some("stop".to_string()))]);
+    }
+
+    #[tokio::test]
+    async fn test_runaway_plugin_leaves_chunks_unchanged() {
+        let out = run(WasmPlugin::new("spin", SPIN.as_bytes(), 10_000).unwrap()).await;
+        assert_eq!(out, [(Some("Hi".to_string()), None), (None, Some("stop".to_string()))]);
+        assert!(ERRORS.with_label_values(&["spin"]).get() >= 2);
+        assert!(WasmPlugin::new("empty", b"(module)", DEFAULT_FUEL).is_err());
+    }
+}
@@ -1,51 +1,181 @@
-use anyhow::{anyhow, Result, Context};
+// The WASM runtime is compiled in with the `wasm-signer` feature (on by
+// default). Without it `WasmSigner` cannot be created and every constructor
+// fails, so a `native-signer` build does not link wasmtime at all.
+
+use anyhow::{Result, Context};
+use serde::Serialize;
+use std::time::Duration;
+#[cfg(feature = "wasm-signer")]
+use anyhow::anyhow;
+#[cfg(feature = "wasm-signer")]
 use once_cell::sync::Lazy;
-use std::sync::Mutex;
-use tracing::{debug, error, info, instrument};
+#[cfg(feature = "wasm-signer")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "wasm-signer")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+#[cfg(feature = "wasm-signer")]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "wasm-signer")]
+use tracing::{debug, error, info, instrument, warn};
+#[cfg(feature = "wasm-signer")]
 use wasmtime::*;
 
+#[cfg(feature = "wasm-signer")]
+use crate::utils;
+#[cfg(not(feature = "wasm-signer"))]
+pub use disabled::WasmSigner;
+
 // Constants for Wasm function names (based on wasm-bindgen conventions)
+#[cfg(feature = "wasm-signer")]
 const WASM_SIGN_FN: &str = "sign";
+#[cfg(feature = "wasm-signer")]
 const WASM_MALLOC_FN: &str = "__wbindgen_malloc";
 // const WASM_REALLOC_FN: &str = "__wbindgen_realloc";
+#[cfg(feature = "wasm-signer")]
 const WASM_FREE_FN: &str = "__wbindgen_free";
+#[cfg(feature = "wasm-signer")]
 const WASM_MEMORY: &str = "memory";
-const WASM_FILE_PATH: &str = "./sign_bg.wasm"; // Relative path from where the server runs
+const WASM_FILE_PATH: &str = "./sign_bg.wasm"; // Relative path from where the server runs, unless WASM_PATH is set
+
+// wasm-bindgen signature of `sign`: return slot followed by four (ptr, len) string pairs
+#[cfg(feature = "wasm-signer")]
+type SignFunc = TypedFunc<(i32, i32, i32, i32, i32, i32, i32, i32, i32), ()>;
 
+#[cfg(feature = "wasm-signer")]
 struct WasmSignerInner {
     store: Store<()>, 
     // instance: Instance,
     memory: Memory,
-    sign_func: TypedFunc<(i32, i32, i32, i32, i32, i32, i32, i32, i32), ()>,
+    sign_func: SignFunc,
     malloc_func: TypedFunc<(i32, i32), i32>,
     // realloc_func: Option<TypedFunc<(i32, i32, i32, i32), i32>>, // Realloc might not be strictly needed if we pre-allocate enough
     free_func: TypedFunc<(i32, i32, i32), ()>,
 }
 
+/// Instances of one compiled module. Each instance has its own store and can
+/// only sign one request at a time, so concurrent requests check out an idle
+/// instance and wait only when all of them are busy.
+#[cfg(feature = "wasm-signer")]
+struct InstancePool {
+    idle: Mutex<Vec<WasmSignerInner>>,
+    returned: Condvar,
+    module: ModuleInfo,
+}
+
+/// The module a signer is currently running.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct ModuleInfo {
+    /// File path or URL it was loaded from.
+    pub source: String,
+    /// SHA-256 of the module bytes.
+    pub sha256: String,
+    /// Unix seconds.
+    pub loaded_at: u64,
+}
+
+/// Signs with a pool of instances of the active module. Reloading builds and
+/// checks a new pool first and then swaps it in atomically; requests still
+/// holding an instance of the old pool finish on it.
+#[cfg(feature = "wasm-signer")]
 pub struct WasmSigner {
-    inner: Mutex<WasmSignerInner>,
+    engine: Engine,
+    pool_size: usize,
+    active: RwLock<Arc<InstancePool>>,
+}
+
+/// An instance checked out of a pool; dropping it checks it back in.
+#[cfg(feature = "wasm-signer")]
+struct PooledInstance {
+    pool: Arc<InstancePool>,
+    inner: Option<WasmSignerInner>,
+}
+
+#[cfg(feature = "wasm-signer")]
+impl Deref for PooledInstance {
+    type Target = WasmSignerInner;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner.as_ref().expect("instance is checked out")
+    }
+}
+
+#[cfg(feature = "wasm-signer")]
+impl DerefMut for PooledInstance {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.inner.as_mut().expect("instance is checked out")
+    }
+}
+
+#[cfg(feature = "wasm-signer")]
+impl Drop for PooledInstance {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            self.pool.lock_idle().push(inner);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(feature = "wasm-signer")]
+impl InstancePool {
+    /// Compiles `bytes` and instantiates the module `size` times, checking
+    /// that the first instance can sign.
+    fn build(engine: &Engine, bytes: &[u8], source: &str, size: usize) -> Result<Self> {
+        debug!("Compiling WASM module from {}...", source);
+        let module = Module::new(engine, bytes)
+            .map_err(|e| anyhow!("Failed to load WASM module from '{}': {}", source, e))?;
+        let mut idle = (0..size)
+            .map(|_| WasmSignerInner::new(engine, &module))
+            .collect::<Result<Vec<_>>>()?;
+        WasmSigner::sign_with(&mut idle[0], "nonce", "0", "device", "ping")
+            .with_context(|| format!("WASM module from '{}' failed a test signature", source))?;
+        let module = ModuleInfo {
+            source: source.to_string(),
+            sha256: utils::sha256_hex(bytes),
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        };
+        Ok(Self { idle: Mutex::new(idle), returned: Condvar::new(), module })
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<WasmSignerInner>> {
+        self.idle.lock().expect("WASM Signer pool mutex poisoned")
+    }
+
+    /// Takes an idle instance, blocking until one is checked back in. Signing
+    /// takes microseconds, so waiting here is short even under load.
+    fn checkout(self: Arc<Self>) -> PooledInstance {
+        let mut idle = self.lock_idle();
+        loop {
+            if let Some(inner) = idle.pop() {
+                drop(idle);
+                return PooledInstance { pool: self, inner: Some(inner) };
+            }
+            idle = self.returned.wait(idle).expect("WASM Signer pool mutex poisoned");
+        }
+    }
 }
 
 // Lazy static initialization for the Wasm environment
+#[cfg(feature = "wasm-signer")]
 static WASM_SIGNER: Lazy<Result<WasmSigner>> = Lazy::new(|| {
     info!("Initializing WasmSigner...");
-    WasmSigner::new()
+    WasmSigner::from_file(&module_path())
 });
 
+/// WASM_PATH, or the bundled `sign_bg.wasm`.
+pub fn module_path() -> String {
+    std::env::var("WASM_PATH").ok().filter(|p| !p.is_empty()).unwrap_or_else(|| WASM_FILE_PATH.to_string())
+}
+
+#[cfg(feature = "wasm-signer")]
 impl WasmSignerInner {
     #[instrument(skip_all, name = "wasm_inner_new")]
-    fn new() -> Result<Self> {
-        debug!("Creating Wasmtime engine...");
-        let engine = Engine::default();
-        let mut store = Store::new(&engine, ());
-
-        debug!("Loading WASM module from {}...", WASM_FILE_PATH);
-        let module = Module::from_file(&engine, WASM_FILE_PATH)
-            .map_err(|e| anyhow!("Failed to load WASM module from file '{}': {}", WASM_FILE_PATH, e))?;
+    fn new(engine: &Engine, module: &Module) -> Result<Self> {
+        let mut store = Store::new(engine, ());
 
         debug!("Instantiating WASM module...");
         // We don't need any imports for this specific WASM based on sign.mjs analysis
-        let instance = Instance::new(&mut store, &module, &[])
+        let instance = Instance::new(&mut store, module, &[])
             .map_err(|e| anyhow!("Failed to instantiate WASM module: {}", e))?;
 
         debug!("Getting WASM exports...");
@@ -68,7 +198,7 @@ impl WasmSignerInner {
             .get_typed_func::<_, ()>(&mut store, WASM_FREE_FN)
             .map_err(|e| anyhow!("Failed to get typed func '{}': {}", WASM_FREE_FN, e))?;
 
-        info!("WASM module instantiated successfully.");
+        debug!("WASM module instantiated successfully.");
         Ok(Self {
             store,
             // instance,
@@ -108,11 +238,83 @@ impl WasmSignerInner {
     }
 }
 
+#[cfg(feature = "wasm-signer")]
 impl WasmSigner {
+    /// Loads a signer from `path`, e.g. a canary build, with WASM_POOL_SIZE
+    /// instances (default: one per CPU).
+    pub fn from_file(path: &str) -> Result<Self> {
+        let default_size = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        Self::with_pool_size(path, crate::config::env_or("WASM_POOL_SIZE", default_size))
+    }
+
+    /// Compiles the module once and instantiates it `pool_size` times.
     #[instrument(name = "wasm_signer_new")]
-    fn new() -> Result<Self> {
-        let inner = WasmSignerInner::new()?;
-        Ok(WasmSigner { inner: Mutex::new(inner) })
+    pub fn with_pool_size(path: &str, pool_size: usize) -> Result<Self> {
+        let pool_size = pool_size.max(1);
+        debug!("Creating Wasmtime engine...");
+        let engine = Engine::default();
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to load WASM module from file '{}': {}", path, e))?;
+        let pool = InstancePool::build(&engine, &bytes, path, pool_size)?;
+        info!(path, pool_size, sha256 = %pool.module.sha256, "WASM signer pool ready");
+        Ok(WasmSigner { engine, pool_size, active: RwLock::new(Arc::new(pool)) })
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    fn active_pool(&self) -> Arc<InstancePool> {
+        self.active.read().expect("WASM Signer lock poisoned").clone()
+    }
+
+    pub fn module_info(&self) -> ModuleInfo {
+        self.active_pool().module.clone()
+    }
+
+    /// Replaces the active module with `bytes`. The current module stays
+    /// active if the new one fails to compile, instantiate or sign.
+    pub fn reload(&self, bytes: &[u8], source: &str) -> Result<ModuleInfo> {
+        let pool = InstancePool::build(&self.engine, bytes, source, self.pool_size)?;
+        let module = pool.module.clone();
+        let previous = std::mem::replace(&mut *self.active.write().expect("WASM Signer lock poisoned"), Arc::new(pool));
+        info!(source, sha256 = %module.sha256, previous = %previous.module.sha256, "WASM signer module reloaded");
+        Ok(module)
+    }
+
+    pub fn reload_from_file(&self, path: &str) -> Result<ModuleInfo> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read WASM module '{}'", path))?;
+        self.reload(&bytes, path)
+    }
+
+    pub async fn reload_from_url(&self, url: &str) -> Result<ModuleInfo> {
+        let bytes = fetch_module(url).await?;
+        self.reload(&bytes, url)
+    }
+
+    /// Polls `path` every `interval` and reloads when its contents change.
+    pub fn spawn_file_watch(&'static self, path: String, interval: Duration) {
+        info!(path, ?interval, "Watching WASM signer module for changes");
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let bytes = match tokio::fs::read(&path).await {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        warn!(path, "Failed to read watched WASM module: {}", e);
+                        continue;
+                    }
+                };
+                if utils::sha256_hex(&bytes) == self.module_info().sha256 {
+                    continue;
+                }
+                if let Err(e) = self.reload(&bytes, &path) {
+                    error!(path, "Keeping the current WASM module, reload failed: {:#}", e);
+                }
+            }
+        });
     }
 
     /// Gets a handle to the globally initialized WasmSigner instance.
@@ -138,8 +340,18 @@ impl WasmSigner {
         device_id: &str,
         query: &str,
     ) -> Result<String> {
-        // Acquire lock once
-        let mut guard = self.inner.lock().expect("WASM Signer mutex poisoned");
+        // Check out an instance of the active module for the whole call
+        let mut instance = self.active_pool().checkout();
+        Self::sign_with(&mut instance, nonce, timestamp, device_id, query)
+    }
+
+    fn sign_with(
+        inner: &mut WasmSignerInner,
+        nonce: &str,
+        timestamp: &str,
+        device_id: &str,
+        query: &str,
+    ) -> Result<String> {
         let WasmSignerInner {
             store,
             memory,
@@ -147,7 +359,7 @@ impl WasmSigner {
             malloc_func,
             free_func,
             .. // Ignore instance if not needed directly
-        } = &mut *guard;
+        } = inner;
 
         // --- Temporary storage for pointers/lengths --- 
         let nonce_ptr: i32;
@@ -158,15 +370,11 @@ impl WasmSigner {
         let device_id_len: i32;
         let query_ptr: i32;
         let query_len: i32;
-        let ret_ptr_ptr: i32;
-        let result_ptr: i32;
-        let result_len: i32;
-        let result_string: String;
 
-        // --- All operations happen within the lock scope --- 
+        // --- All operations happen on the checked-out instance --- 
 
         // 1. Allocate memory for result pointer
-        ret_ptr_ptr = malloc_func.call(&mut *store, (8, 4))
+        let ret_ptr_ptr = malloc_func.call(&mut *store, (8, 4))
             .context("WASM malloc failed for return pointer allocation")?;
         if ret_ptr_ptr == 0 {
             return Err(anyhow!("WASM malloc failed for return pointer (returned 0)"));
@@ -207,8 +415,8 @@ impl WasmSigner {
         let mut ret_buf = [0u8; 8];
         memory.read(&mut *store, ret_ptr_ptr as usize, &mut ret_buf)
              .context("Failed to read result pointer/length from WASM memory")?;
-        result_ptr = i32::from_le_bytes(ret_buf[0..4].try_into().unwrap());
-        result_len = i32::from_le_bytes(ret_buf[4..8].try_into().unwrap());
+        let result_ptr = i32::from_le_bytes(ret_buf[0..4].try_into().unwrap());
+        let result_len = i32::from_le_bytes(ret_buf[4..8].try_into().unwrap());
         debug!("WASM returned result ptr: {}, len: {}", result_ptr, result_len);
 
         // 5. Read the actual result string (using a helper closure)
@@ -223,19 +431,14 @@ impl WasmSigner {
             String::from_utf8(buffer).map_err(|e| anyhow!("Failed to decode UTF-8 result string from WASM: {}", e))
         };
         // Use deref coercion to get &Store from &mut Store
-        result_string = read_string(store, result_ptr, result_len)?;
+        let result_string = read_string(store, result_ptr, result_len)?;
 
 
         // 6. Free WASM memory
         debug!("Freeing WASM memory...");
-        free_func.call(&mut *store, (nonce_ptr, nonce_len, 1))
-            .context("Failed to free nonce string in WASM")?;
-        free_func.call(&mut *store, (timestamp_ptr, timestamp_len, 1))
-             .context("Failed to free timestamp string in WASM")?;
-        free_func.call(&mut *store, (device_id_ptr, device_id_len, 1))
-              .context("Failed to free device_id string in WASM")?;
-        free_func.call(&mut *store, (query_ptr, query_len, 1))
-              .context("Failed to free query string in WASM")?;
+        // The input strings are owned (and freed) by `sign` itself, as in the
+        // wasm-bindgen glue; freeing them again corrupts the allocator and
+        // makes the next call on this instance trap.
         free_func.call(&mut *store, (ret_ptr_ptr, 8, 4))
               .context("Failed to free return pointer structure in WASM")?;
         if result_ptr != 0 {
@@ -246,4 +449,111 @@ impl WasmSigner {
 
         Ok(result_string)
     }
-} 
\ No newline at end of file
+} 
+
+/// Downloads a module for `reload_from_url`.
+pub async fn fetch_module(url: &str) -> Result<Vec<u8>> {
+    let response = reqwest::Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to download WASM module from '{}'", url))?;
+    let bytes = response.bytes().await.with_context(|| format!("Failed to read WASM module from '{}'", url))?;
+    Ok(bytes.to_vec())
+}
+
+#[cfg(not(feature = "wasm-signer"))]
+mod disabled {
+    use anyhow::{bail, Result};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use super::ModuleInfo;
+
+    /// Stands in for the WASM signer in builds without the runtime; it has
+    /// no values, so only the constructors are ever called.
+    pub struct WasmSigner {
+        never: Infallible,
+    }
+
+    impl WasmSigner {
+        pub fn from_file(path: &str) -> Result<Self> {
+            bail!("Cannot load WASM module '{}': this build lacks the `wasm-signer` feature", path)
+        }
+
+        pub fn with_pool_size(path: &str, _pool_size: usize) -> Result<Self> {
+            Self::from_file(path)
+        }
+
+        pub fn get_instance() -> Result<&'static Self> {
+            bail!("SIGNER is 'wasm' but this build lacks the `wasm-signer` feature")
+        }
+
+        pub fn pool_size(&self) -> usize {
+            match self.never {}
+        }
+
+        pub fn module_info(&self) -> ModuleInfo {
+            match self.never {}
+        }
+
+        pub fn reload(&self, _bytes: &[u8], _source: &str) -> Result<ModuleInfo> {
+            match self.never {}
+        }
+
+        pub fn reload_from_file(&self, _path: &str) -> Result<ModuleInfo> {
+            match self.never {}
+        }
+
+        pub async fn reload_from_url(&self, _url: &str) -> Result<ModuleInfo> {
+            match self.never {}
+        }
+
+        pub fn spawn_file_watch(&'static self, _path: String, _interval: Duration) {
+            match self.never {}
+        }
+
+        pub fn sign(&self, _nonce: &str, _timestamp: &str, _device_id: &str, _query: &str) -> Result<String> {
+            match self.never {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm-signer"))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_pooled_instances_sign_concurrently() {
+        let signer = Arc::new(WasmSigner::with_pool_size(WASM_FILE_PATH, 2).unwrap());
+        let expected = signer.sign("nonce", "1700000000", "device", "hello").unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let signer = signer.clone();
+                std::thread::spawn(move || signer.sign("nonce", "1700000000", "device", "hello").unwrap())
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), expected);
+        }
+        assert_eq!(signer.active_pool().lock_idle().len(), 2);
+    }
+
+    #[test]
+    fn test_reload_swaps_module_and_keeps_it_on_failure() {
+        let signer = WasmSigner::with_pool_size(WASM_FILE_PATH, 1).unwrap();
+        let before = signer.module_info();
+        let held = signer.active_pool().checkout();
+
+        let module = signer.reload_from_file(WASM_FILE_PATH).unwrap();
+        assert_eq!(module.sha256, before.sha256);
+        assert!(signer.sign("n", "1", "d", "q").is_ok(), "new pool is used while the old instance is held");
+        drop(held);
+
+        assert!(signer.reload(b"not wasm", "broken").is_err());
+        assert_eq!(signer.module_info().source, WASM_FILE_PATH);
+    }
+}
@@ -0,0 +1,291 @@
+// Completion lifecycle webhooks, so billing, analytics or alerting can react
+// to completions without polling. Each URL in WEBHOOK_URLS gets a JSON POST
+// when a stream starts, finishes or fails (WEBHOOK_EVENTS narrows this to
+// some of `started`, `finished`, `failed`, or adds `slow` for requests over
+// the SLOW_TTFB_MS / SLOW_TOTAL_MS thresholds). The body names the request,
+// its model and key, and once it ended the outcome, time to first token,
+// duration and token counts; with WEBHOOK_INCLUDE_TEXT it also carries the
+// answer text.
+//
+// With WEBHOOK_SECRET set, every delivery is signed: `X-Webhook-Signature:
+// sha256=<hex HMAC-SHA256 of the body>`. Deliveries run in the background
+// with a WEBHOOK_TIMEOUT_SECS (5) timeout and are not retried.
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::config::{env_or, parse_list};
+use crate::metrics::{Outcome, StreamSummary};
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Deliveries by `event` and `result` (ok / error), registered by `Metrics`.
+pub static DELIVERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(Opts::new("webhook_deliveries_total", "Webhook deliveries by event and result"), &["event", "result"])
+        .expect("valid counter")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Started,
+    Finished,
+    Failed,
+    Slow,
+}
+
+impl LifecycleEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Started => "completion.started",
+            Self::Finished => "completion.finished",
+            Self::Failed => "completion.failed",
+            Self::Slow => "completion.slow",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().trim_start_matches("completion.") {
+            "started" => Some(Self::Started),
+            "finished" => Some(Self::Finished),
+            "failed" => Some(Self::Failed),
+            "slow" => Some(Self::Slow),
+            _ => None,
+        }
+    }
+}
+
+/// The request a webhook is about.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionInfo {
+    pub request_id: String,
+    pub model: Option<String>,
+    pub api_key_id: String,
+    pub prompt_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    timestamp: u64,
+    #[serde(flatten)]
+    completion: &'a CompletionInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttfb_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    urls: Arc<[String]>,
+    events: Vec<LifecycleEvent>,
+    include_text: bool,
+    secret: Option<String>,
+    timeout: Duration,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            urls: urls.into(),
+            events: vec![LifecycleEvent::Started, LifecycleEvent::Finished, LifecycleEvent::Failed],
+            include_text: false,
+            secret: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let mut webhooks = Self::new(parse_list(&std::env::var("WEBHOOK_URLS").unwrap_or_default()));
+        if let Some(events) = std::env::var("WEBHOOK_EVENTS").ok().filter(|v| !v.trim().is_empty()) {
+            webhooks.events = parse_list(&events)
+                .iter()
+                .filter_map(|name| {
+                    LifecycleEvent::from_name(name).or_else(|| {
+                        warn!(event = %name, "Ignoring unknown WEBHOOK_EVENTS entry");
+                        None
+                    })
+                })
+                .collect();
+        }
+        webhooks.include_text = env_or("WEBHOOK_INCLUDE_TEXT", false);
+        webhooks.secret = std::env::var("WEBHOOK_SECRET").ok().filter(|v| !v.is_empty());
+        webhooks.timeout = Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 5));
+        if webhooks.is_enabled() {
+            info!(
+                urls = webhooks.urls.len(),
+                events = ?webhooks.events.iter().map(|e| e.as_str()).collect::<Vec<_>>(),
+                signed = webhooks.secret.is_some(),
+                "Completion webhooks enabled"
+            );
+        }
+        webhooks
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.urls.is_empty() && !self.events.is_empty()
+    }
+
+    /// Whether finish webhooks want the answer text.
+    pub fn include_text(&self) -> bool {
+        self.include_text && self.wants(LifecycleEvent::Finished)
+    }
+
+    fn wants(&self, event: LifecycleEvent) -> bool {
+        !self.urls.is_empty() && self.events.contains(&event)
+    }
+
+    /// Sends `completion.started`.
+    pub fn started(&self, completion: &CompletionInfo) {
+        self.send(LifecycleEvent::Started, completion, None);
+    }
+
+    /// Sends `completion.finished`, or `completion.failed` for any outcome
+    /// but a normal end.
+    pub fn ended(&self, completion: &CompletionInfo, summary: &StreamSummary) {
+        let event = if summary.outcome == Outcome::Ok { LifecycleEvent::Finished } else { LifecycleEvent::Failed };
+        self.send(event, completion, Some(summary));
+    }
+
+    /// Sends `completion.slow` for a request over a latency threshold.
+    pub fn slow(&self, completion: &CompletionInfo, summary: &StreamSummary) {
+        self.send(LifecycleEvent::Slow, completion, Some(summary));
+    }
+
+    fn send(&self, event: LifecycleEvent, completion: &CompletionInfo, summary: Option<&StreamSummary>) {
+        if !self.wants(event) {
+            return;
+        }
+        let payload = Payload {
+            event: event.as_str(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            completion,
+            outcome: summary.map(|s| s.outcome.as_str()),
+            ttfb_ms: summary.and_then(|s| s.ttfb).map(|d| d.as_millis() as u64),
+            duration_ms: summary.map(|s| s.duration.as_millis() as u64),
+            completion_tokens: summary.map(|s| s.completion_tokens),
+            text: summary.and_then(|s| s.text.as_deref()).filter(|_| self.include_text),
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => return warn!("Failed to serialize webhook payload: {}", e),
+        };
+        let signature = self.secret.as_deref().map(|secret| sign(secret, &body));
+        for url in self.urls.iter() {
+            let mut request = self
+                .client
+                .post(url)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .timeout(self.timeout)
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            let (event, url) = (event.as_str(), url.clone());
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {
+                        debug!(event, url, "Webhook delivered");
+                        DELIVERIES.with_label_values(&[event, "ok"]).inc();
+                    }
+                    Err(e) => {
+                        warn!(event, url, error = %e, "Webhook delivery failed");
+                        DELIVERIES.with_label_values(&[event, "error"]).inc();
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// `sha256=<hex HMAC-SHA256 of body>`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(LifecycleEvent::from_name("completion.failed"), Some(LifecycleEvent::Failed));
+        assert_eq!(LifecycleEvent::from_name("Started"), Some(LifecycleEvent::Started));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_deliveries() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: http::HeaderMap, body: String| async move {
+                let signature = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap().to_string();
+                tx.send((signature, body)).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut webhooks = Webhooks::new(vec![url]);
+        webhooks.secret = Some("s3cret".to_string());
+        webhooks.include_text = true;
+        let completion = CompletionInfo {
+            request_id: "req-1".to_string(),
+            model: Some("gpt-4o".to_string()),
+            api_key_id: "key_a".to_string(),
+            prompt_tokens: 3,
+        };
+        webhooks.started(&completion);
+        let (signature, body) = rx.recv().await.unwrap();
+        assert_eq!(signature, sign("s3cret", body.as_bytes()));
+        let started: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(started["event"], "completion.started");
+        assert_eq!(started["request_id"], "req-1");
+        assert!(started.get("outcome").is_none());
+
+        let summary = StreamSummary {
+            outcome: Outcome::StreamError,
+            completion_tokens: 5,
+            ttfb: Some(Duration::from_millis(900)),
+            duration: Duration::from_millis(1500),
+            text: Some("partial".to_string()),
+        };
+        webhooks.ended(&completion, &summary);
+        let failed: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().1).unwrap();
+        assert_eq!(failed["event"], "completion.failed");
+        assert_eq!((failed["outcome"].as_str(), failed["duration_ms"].as_u64()), (Some("stream_error"), Some(1500)));
+        assert_eq!((failed["completion_tokens"].as_u64(), failed["text"].as_str()), (Some(5), Some("partial")));
+        assert_eq!(failed["ttfb_ms"].as_u64(), Some(900));
+
+        // Slow requests are only reported when asked for
+        webhooks.slow(&completion, &summary);
+        webhooks.events.push(LifecycleEvent::Slow);
+        webhooks.slow(&completion, &summary);
+        let slow: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().1).unwrap();
+        assert_eq!(slow["event"], "completion.slow");
+    }
+}